@@ -0,0 +1,459 @@
+//! The commands of `vetkd-maps`.
+
+use candid::Principal;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use ic_agent::identity::{AnonymousIdentity, BasicIdentity, Secp256k1Identity};
+use ic_agent::{Agent, Identity};
+use ic_vetkd_cdk_client::EncryptedMaps;
+use ic_vetkd_cdk_types::{AccessRights, AuditEntry, Rights};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+#[derive(Parser)]
+#[command(
+    name = "vetkd-maps",
+    version,
+    about = "Manages encrypted maps and their sharing"
+)]
+pub struct Cli {
+    /// The URL of the replica or boundary node.
+    #[arg(long, default_value = "http://127.0.0.1:4943")]
+    url: String,
+
+    /// The ID of the canister holding the maps.
+    #[arg(long)]
+    canister_id: Principal,
+
+    /// A PEM file with an Ed25519 or secp256k1 key, e.g., of a dfx identity.
+    /// Calls are anonymous if not given.
+    #[arg(long)]
+    identity: Option<PathBuf>,
+
+    /// Fetches the root key from the replica. Defaults to true unless the URL
+    /// points to the IC mainnet.
+    #[arg(long)]
+    fetch_root_key: Option<bool>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Lists the caller's non-empty maps and the maps shared with the caller.
+    Maps,
+    /// Prints the decrypted values of a map.
+    List(MapArgs),
+    /// Prints the decrypted value of a map key.
+    Get {
+        #[command(flatten)]
+        map: MapArgs,
+        key: String,
+    },
+    /// Encrypts and stores a value.
+    Set {
+        #[command(flatten)]
+        map: MapArgs,
+        key: String,
+        value: String,
+    },
+    /// Removes a value, keeping it as a tombstone unless `--hard` is given.
+    Remove {
+        #[command(flatten)]
+        map: MapArgs,
+        key: String,
+        #[arg(long)]
+        hard: bool,
+    },
+    /// Lists the users a map is shared with and their rights.
+    Access(MapArgs),
+    /// Shares a map with a user, optionally for a time window.
+    Share {
+        #[command(flatten)]
+        map: MapArgs,
+        user: Principal,
+        #[arg(long, value_enum, default_value_t = RightsArg::Read)]
+        rights: RightsArg,
+        /// The start of the access in seconds since the Unix epoch.
+        #[arg(long)]
+        start: Option<u64>,
+        /// The end of the access in seconds since the Unix epoch.
+        #[arg(long, conflicts_with = "duration")]
+        end: Option<u64>,
+        /// The length of the access in seconds from its start or from now.
+        #[arg(long)]
+        duration: Option<u64>,
+    },
+    /// Revokes the access of a user to a map.
+    Unshare {
+        #[command(flatten)]
+        map: MapArgs,
+        user: Principal,
+    },
+    /// Prints the audit log of a map.
+    AuditLog(MapArgs),
+    /// Prints the tombstones of a map.
+    Tombstones {
+        #[command(flatten)]
+        map: MapArgs,
+        /// Also prints the decrypted removed values.
+        #[arg(long)]
+        decrypt: bool,
+    },
+    /// Writes the decrypted values of a map as JSON.
+    Export {
+        #[command(flatten)]
+        map: MapArgs,
+        /// The output file. Defaults to stdout.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Encrypts and stores the values of an exported map.
+    Import {
+        input: PathBuf,
+        /// The map to import into. Defaults to the name of the exported map.
+        #[arg(long)]
+        map: Option<String>,
+        #[arg(long)]
+        owner: Option<Principal>,
+    },
+}
+
+#[derive(Args)]
+struct MapArgs {
+    map: String,
+    /// The owner of the map. Defaults to the caller.
+    #[arg(long)]
+    owner: Option<Principal>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum RightsArg {
+    Read,
+    ReadWrite,
+    ReadWriteManage,
+}
+
+impl From<RightsArg> for Rights {
+    fn from(rights: RightsArg) -> Self {
+        match rights {
+            RightsArg::Read => Rights::Read,
+            RightsArg::ReadWrite => Rights::ReadWrite,
+            RightsArg::ReadWriteManage => Rights::ReadWriteManage,
+        }
+    }
+}
+
+/// The JSON format of exported maps, with hex-encoded bytes.
+#[derive(Serialize, Deserialize)]
+struct ExportedMap {
+    map_owner: String,
+    map_name: String,
+    values: Vec<ExportedValue>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportedValue {
+    key: String,
+    value: String,
+}
+
+pub async fn run(cli: Cli) -> Result<(), String> {
+    let agent = agent(&cli).await?;
+    let caller = agent.get_principal()?;
+    let encrypted_maps = EncryptedMaps::new(agent, cli.canister_id);
+    let owner = |map: &MapArgs| map.owner.unwrap_or(caller);
+
+    match cli.command {
+        Command::Maps => {
+            for map_name in encrypted_maps.get_owned_non_empty_map_names().await? {
+                println!("{caller}\t{}", display_bytes(&map_name));
+            }
+            for (map_owner, map_name) in encrypted_maps.get_accessible_shared_map_names().await? {
+                println!("{map_owner}\t{}", display_bytes(&map_name));
+            }
+        }
+        Command::List(map) => {
+            let map_name = parse_bytes(&map.map)?;
+            for (key, value) in encrypted_maps
+                .get_values_for_map(owner(&map), &map_name)
+                .await?
+            {
+                println!("{}\t{}", display_bytes(&key), display_bytes(&value));
+            }
+        }
+        Command::Get { map, key } => {
+            let map_name = parse_bytes(&map.map)?;
+            match encrypted_maps
+                .get_value(owner(&map), &map_name, &parse_bytes(&key)?)
+                .await?
+            {
+                Some(value) => println!("{}", display_bytes(&value)),
+                None => return Err("no value".to_string()),
+            }
+        }
+        Command::Set { map, key, value } => {
+            let map_name = parse_bytes(&map.map)?;
+            encrypted_maps
+                .set_value(
+                    owner(&map),
+                    &map_name,
+                    &parse_bytes(&key)?,
+                    &parse_bytes(&value)?,
+                )
+                .await?;
+        }
+        Command::Remove { map, key, hard } => {
+            let map_name = parse_bytes(&map.map)?;
+            let key = parse_bytes(&key)?;
+            let removed = if hard {
+                encrypted_maps
+                    .canister()
+                    .hard_delete_encrypted_value(owner(&map), &map_name, &key)
+                    .await?
+                    .is_some()
+            } else {
+                encrypted_maps
+                    .canister()
+                    .remove_encrypted_value(owner(&map), &map_name, &key)
+                    .await?
+                    .is_some()
+            };
+            if !removed {
+                return Err("no value".to_string());
+            }
+        }
+        Command::Access(map) => {
+            let map_name = parse_bytes(&map.map)?;
+            for (user, access_rights) in encrypted_maps
+                .get_shared_user_access_for_map(owner(&map), &map_name)
+                .await?
+            {
+                println!("{user}\t{}", display_access_rights(&access_rights));
+            }
+        }
+        Command::Share {
+            map,
+            user,
+            rights,
+            start,
+            end,
+            duration,
+        } => {
+            let map_name = parse_bytes(&map.map)?;
+            let access_rights = access_rights(rights, start, end, duration)?;
+            encrypted_maps
+                .set_user_rights(owner(&map), &map_name, user, access_rights)
+                .await?;
+        }
+        Command::Unshare { map, user } => {
+            let map_name = parse_bytes(&map.map)?;
+            if encrypted_maps
+                .remove_user(owner(&map), &map_name, user)
+                .await?
+                .is_none()
+            {
+                return Err("the map is not shared with the user".to_string());
+            }
+        }
+        Command::AuditLog(map) => {
+            let map_name = parse_bytes(&map.map)?;
+            for entry in encrypted_maps
+                .canister()
+                .get_audit_log(owner(&map), &map_name)
+                .await?
+            {
+                println!("{}", display_audit_entry(&entry));
+            }
+        }
+        Command::Tombstones { map, decrypt } => {
+            let map_owner = owner(&map);
+            let map_name = parse_bytes(&map.map)?;
+            for (key, tombstone) in encrypted_maps
+                .canister()
+                .get_tombstones(map_owner, &map_name)
+                .await?
+            {
+                let mut line = format!(
+                    "{}\t{}\t{}\t{}",
+                    display_bytes(key.as_ref()),
+                    tombstone.deletion_timestamp,
+                    tombstone.deleted_by,
+                    if tombstone.marked_for_purge {
+                        "marked for purge"
+                    } else {
+                        "-"
+                    },
+                );
+                if decrypt {
+                    let value = encrypted_maps
+                        .decrypt_for(map_owner, &map_name, key.as_ref(), tombstone.value.as_ref())
+                        .await?;
+                    line = format!("{line}\t{}", display_bytes(&value));
+                }
+                println!("{line}");
+            }
+        }
+        Command::Export { map, output } => {
+            let map_owner = owner(&map);
+            let map_name = parse_bytes(&map.map)?;
+            let values = encrypted_maps
+                .get_values_for_map(map_owner, &map_name)
+                .await?;
+            let exported = ExportedMap {
+                map_owner: map_owner.to_text(),
+                map_name: hex::encode(&map_name),
+                values: values
+                    .into_iter()
+                    .map(|(key, value)| ExportedValue {
+                        key: hex::encode(key),
+                        value: hex::encode(value),
+                    })
+                    .collect(),
+            };
+            let json = serde_json::to_string_pretty(&exported).map_err(|e| e.to_string())?;
+            match output {
+                Some(path) => std::fs::write(&path, json)
+                    .map_err(|e| format!("failed to write {}: {e}", path.display()))?,
+                None => println!("{json}"),
+            }
+        }
+        Command::Import { input, map, owner } => {
+            let exported = read_exported_map(&input)?;
+            let map_name = match map {
+                Some(map) => parse_bytes(&map)?,
+                None => decode_hex(&exported.map_name)?,
+            };
+            let map_owner = owner.unwrap_or(caller);
+            for value in exported.values {
+                encrypted_maps
+                    .set_value(
+                        map_owner,
+                        &map_name,
+                        &decode_hex(&value.key)?,
+                        &decode_hex(&value.value)?,
+                    )
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn agent(cli: &Cli) -> Result<Agent, String> {
+    let identity: Box<dyn Identity> = match &cli.identity {
+        Some(path) => load_identity(path)?,
+        None => Box::new(AnonymousIdentity),
+    };
+    let agent = Agent::builder()
+        .with_url(cli.url.as_str())
+        .with_boxed_identity(identity)
+        .build()
+        .map_err(|e| format!("failed to create the agent: {e}"))?;
+    let is_mainnet = ["ic0.app", "icp0.io", "icp-api.io"]
+        .iter()
+        .any(|domain| cli.url.contains(domain));
+    if cli.fetch_root_key.unwrap_or(!is_mainnet) {
+        agent
+            .fetch_root_key()
+            .await
+            .map_err(|e| format!("failed to fetch the root key: {e}"))?;
+    }
+    Ok(agent)
+}
+
+fn load_identity(path: &Path) -> Result<Box<dyn Identity>, String> {
+    if let Ok(identity) = BasicIdentity::from_pem_file(path) {
+        return Ok(Box::new(identity));
+    }
+    Secp256k1Identity::from_pem_file(path)
+        .map(|identity| Box::new(identity) as Box<dyn Identity>)
+        .map_err(|e| format!("failed to load the identity from {}: {e}", path.display()))
+}
+
+fn access_rights(
+    rights: RightsArg,
+    start: Option<u64>,
+    end: Option<u64>,
+    duration: Option<u64>,
+) -> Result<AccessRights, String> {
+    let end = match duration {
+        Some(duration) => Some(start.unwrap_or_else(unix_now) + duration),
+        None => end,
+    };
+    if let (Some(start), Some(end)) = (start, end) {
+        if start > end {
+            return Err("the start must not be after the end".to_string());
+        }
+    }
+    let to_nanos = |secs: u64| {
+        secs.checked_mul(NANOS_PER_SEC)
+            .ok_or_else(|| "too large timestamp".to_string())
+    };
+    Ok(AccessRights::new(
+        rights.into(),
+        start.map(to_nanos).transpose()?,
+        end.map(to_nanos).transpose()?,
+    ))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("the system time is before the Unix epoch")
+        .as_secs()
+}
+
+fn read_exported_map(path: &Path) -> Result<ExportedMap, String> {
+    let json = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+    serde_json::from_str(&json).map_err(|e| format!("invalid export file: {e}"))
+}
+
+fn parse_bytes(arg: &str) -> Result<Vec<u8>, String> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => decode_hex(hex),
+        None => Ok(arg.as_bytes().to_vec()),
+    }
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, String> {
+    hex::decode(hex).map_err(|e| format!("invalid hex {hex}: {e}"))
+}
+
+fn display_bytes(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => format!("0x{}", hex::encode(bytes)),
+    }
+}
+
+fn display_access_rights(access_rights: &AccessRights) -> String {
+    let bound =
+        |time: Option<u64>| time.map_or("-".to_string(), |time| (time / NANOS_PER_SEC).to_string());
+    format!(
+        "{:?}\t{}\t{}\tpermissions={:#x}",
+        access_rights.rights(),
+        bound(access_rights.start()),
+        bound(access_rights.end()),
+        access_rights.permissions().bits()
+    )
+}
+
+fn display_audit_entry(entry: &AuditEntry) -> String {
+    format!(
+        "{}\t{:?}\t{}\t{}\t{}",
+        entry.timestamp(),
+        entry.audit_type(),
+        entry.caller(),
+        entry.user().map_or("-".to_string(), |user| user.to_text()),
+        entry
+            .access_rights()
+            .map_or("-".to_string(), |access_rights| display_access_rights(
+                &access_rights
+            )),
+    )
+}
@@ -0,0 +1,24 @@
+//! `vetkd-maps` manages the maps of a canister exposing the interface of the
+//! `encrypted_maps_example` canister, see the README of the client.
+//!
+//! Map names, map keys and values are passed as text, or as hex if prefixed
+//! with `0x`, and printed as text if they are valid UTF-8 and as `0x`-prefixed
+//! hex otherwise. Maps are owned by the caller unless `--owner` is given.
+
+#[cfg(not(target_arch = "wasm32"))]
+mod cli;
+
+#[cfg(not(target_arch = "wasm32"))]
+#[tokio::main]
+async fn main() {
+    use clap::Parser;
+
+    if let Err(error) = cli::run(cli::Cli::parse()).await {
+        eprintln!("error: {error}");
+        std::process::exit(1);
+    }
+}
+
+// The client is empty when built for wasm32, see the crate documentation.
+#[cfg(target_arch = "wasm32")]
+fn main() {}
@@ -0,0 +1,524 @@
+//! Typed Candid calls to the standard endpoints, without any cryptography.
+//!
+//! [`EncryptedMapsCanister`] calls a canister exposing the interface of the
+//! `encrypted_maps_example` canister, see
+//! `ic_vetkd_cdk_encrypted_maps::export_encrypted_maps_api`, and
+//! [`KeyManagerCanister`] one exposing the interface of the
+//! `key_manager_example` canister. Names and map keys are passed as byte
+//! slices, and errors of the agent and of the canister are returned as
+//! strings.
+
+use candid::utils::ArgumentEncoder;
+use candid::{decode_one, encode_args, CandidType, Principal};
+use ic_agent::Agent;
+use ic_vetkd_cdk_encrypted_maps::{EncryptedMapData, TombstoneEntry};
+use ic_vetkd_cdk_types::{AccessRights, AuditEntry, ByteBuf, EncryptedMapValue};
+use serde::de::DeserializeOwned;
+
+/// A map ID as returned by the endpoints, i.e., the owner and the name.
+pub type RawMapId = (Principal, ByteBuf);
+
+#[derive(Clone)]
+struct Canister {
+    agent: Agent,
+    canister_id: Principal,
+}
+
+impl Canister {
+    async fn query<A, R>(&self, method: &str, args: A) -> Result<R, String>
+    where
+        A: ArgumentEncoder,
+        R: CandidType + DeserializeOwned,
+    {
+        let reply = self
+            .agent
+            .query(&self.canister_id, method)
+            .with_arg(encode_args(args).map_err(|e| e.to_string())?)
+            .call()
+            .await
+            .map_err(|e| format!("query call to {method} failed: {e}"))?;
+        decode_one(&reply).map_err(|e| format!("failed to decode reply of {method}: {e}"))
+    }
+
+    async fn update<A, R>(&self, method: &str, args: A) -> Result<R, String>
+    where
+        A: ArgumentEncoder,
+        R: CandidType + DeserializeOwned,
+    {
+        let reply = self
+            .agent
+            .update(&self.canister_id, method)
+            .with_arg(encode_args(args).map_err(|e| e.to_string())?)
+            .call_and_wait()
+            .await
+            .map_err(|e| format!("update call to {method} failed: {e}"))?;
+        decode_one(&reply).map_err(|e| format!("failed to decode reply of {method}: {e}"))
+    }
+}
+
+fn bytebuf(bytes: &[u8]) -> ByteBuf {
+    ByteBuf::from(bytes.to_vec())
+}
+
+/// A canister exposing the endpoints of the `encrypted_maps_example` canister.
+#[derive(Clone)]
+pub struct EncryptedMapsCanister {
+    canister: Canister,
+}
+
+impl EncryptedMapsCanister {
+    /// Creates a client of `canister_id` that calls it with `agent`.
+    #[must_use]
+    pub fn new(agent: Agent, canister_id: Principal) -> Self {
+        Self {
+            canister: Canister { agent, canister_id },
+        }
+    }
+
+    /// Returns the agent with which the canister is called.
+    #[must_use]
+    pub fn agent(&self) -> &Agent {
+        &self.canister.agent
+    }
+
+    /// Returns the ID of the canister.
+    #[must_use]
+    pub fn canister_id(&self) -> Principal {
+        self.canister.canister_id
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails.
+    pub async fn get_accessible_shared_map_names(&self) -> Result<Vec<RawMapId>, String> {
+        self.canister
+            .query("get_accessible_shared_map_names", ())
+            .await
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails.
+    pub async fn get_owned_non_empty_map_names(&self) -> Result<Vec<ByteBuf>, String> {
+        self.canister
+            .query("get_owned_non_empty_map_names", ())
+            .await
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the canister returns an error.
+    pub async fn get_shared_user_access_for_map(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+    ) -> Result<Vec<(Principal, AccessRights)>, String> {
+        self.canister
+            .query::<_, Result<_, String>>(
+                "get_shared_user_access_for_map",
+                (map_owner, bytebuf(map_name)),
+            )
+            .await?
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the canister returns an error.
+    pub async fn get_encrypted_values_for_map(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+    ) -> Result<Vec<(ByteBuf, EncryptedMapValue)>, String> {
+        self.canister
+            .query::<_, Result<_, String>>(
+                "get_encrypted_values_for_map",
+                (map_owner, bytebuf(map_name)),
+            )
+            .await?
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails.
+    pub async fn get_all_accessible_encrypted_values(
+        &self,
+    ) -> Result<Vec<(RawMapId, Vec<(ByteBuf, EncryptedMapValue)>)>, String> {
+        self.canister
+            .query("get_all_accessible_encrypted_values", ())
+            .await
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails.
+    pub async fn get_all_accessible_encrypted_maps(&self) -> Result<Vec<EncryptedMapData>, String> {
+        self.canister
+            .query("get_all_accessible_encrypted_maps", ())
+            .await
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the canister returns an error.
+    pub async fn get_encrypted_value(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+        map_key: &[u8],
+    ) -> Result<Option<EncryptedMapValue>, String> {
+        self.canister
+            .query::<_, Result<_, String>>(
+                "get_encrypted_value",
+                (map_owner, bytebuf(map_name), bytebuf(map_key)),
+            )
+            .await?
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the canister returns an error.
+    pub async fn insert_encrypted_value(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+        map_key: &[u8],
+        value: EncryptedMapValue,
+    ) -> Result<Option<EncryptedMapValue>, String> {
+        self.canister
+            .update::<_, Result<_, String>>(
+                "insert_encrypted_value",
+                (map_owner, bytebuf(map_name), bytebuf(map_key), value),
+            )
+            .await?
+    }
+
+    /// Removes a value and keeps it as a tombstone.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the canister returns an error.
+    pub async fn remove_encrypted_value(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+        map_key: &[u8],
+    ) -> Result<Option<EncryptedMapValue>, String> {
+        self.canister
+            .update::<_, Result<_, String>>(
+                "remove_encrypted_value",
+                (map_owner, bytebuf(map_name), bytebuf(map_key)),
+            )
+            .await?
+    }
+
+    /// Removes a value permanently.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the canister returns an error.
+    pub async fn hard_delete_encrypted_value(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+        map_key: &[u8],
+    ) -> Result<Option<EncryptedMapValue>, String> {
+        self.canister
+            .update::<_, Result<_, String>>(
+                "hard_delete_encrypted_value",
+                (map_owner, bytebuf(map_name), bytebuf(map_key)),
+            )
+            .await?
+    }
+
+    /// Removes all values of a map and keeps them as tombstones. Returns the
+    /// removed map keys.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the canister returns an error.
+    pub async fn remove_map_values(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+    ) -> Result<Vec<ByteBuf>, String> {
+        self.canister
+            .update::<_, Result<_, String>>("remove_map_values", (map_owner, bytebuf(map_name)))
+            .await?
+    }
+
+    /// Removes all values of a map permanently. Returns the removed map keys.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the canister returns an error.
+    pub async fn hard_delete_map_values(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+    ) -> Result<Vec<ByteBuf>, String> {
+        self.canister
+            .update::<_, Result<_, String>>(
+                "hard_delete_map_values",
+                (map_owner, bytebuf(map_name)),
+            )
+            .await?
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the canister returns an error.
+    pub async fn get_tombstones(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+    ) -> Result<Vec<(ByteBuf, TombstoneEntry)>, String> {
+        self.canister
+            .query::<_, Result<_, String>>("get_tombstones", (map_owner, bytebuf(map_name)))
+            .await?
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the canister returns an error.
+    pub async fn restore_value(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+        map_key: &[u8],
+    ) -> Result<Option<EncryptedMapValue>, String> {
+        self.canister
+            .update::<_, Result<_, String>>(
+                "restore_value",
+                (map_owner, bytebuf(map_name), bytebuf(map_key)),
+            )
+            .await?
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the canister returns an error.
+    pub async fn purge_tombstone(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+        map_key: &[u8],
+    ) -> Result<Option<TombstoneEntry>, String> {
+        self.canister
+            .update::<_, Result<_, String>>(
+                "purge_tombstone",
+                (map_owner, bytebuf(map_name), bytebuf(map_key)),
+            )
+            .await?
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the caller cannot view the audit
+    /// log of the map.
+    pub async fn get_audit_log(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+    ) -> Result<Vec<AuditEntry>, String> {
+        self.canister
+            .query::<_, Result<_, String>>("get_audit_log", (map_owner, bytebuf(map_name)))
+            .await?
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails.
+    pub async fn get_vetkey_verification_key(&self) -> Result<ByteBuf, String> {
+        self.canister
+            .update("get_vetkey_verification_key", ())
+            .await
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the canister returns an error.
+    pub async fn get_encrypted_vetkey(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+        transport_key: Vec<u8>,
+    ) -> Result<ByteBuf, String> {
+        self.canister
+            .update::<_, Result<_, String>>(
+                "get_encrypted_vetkey",
+                (map_owner, bytebuf(map_name), ByteBuf::from(transport_key)),
+            )
+            .await?
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the canister returns an error.
+    pub async fn get_user_rights(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+        user: Principal,
+    ) -> Result<Option<AccessRights>, String> {
+        self.canister
+            .query::<_, Result<_, String>>("get_user_rights", (map_owner, bytebuf(map_name), user))
+            .await?
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the canister returns an error.
+    pub async fn set_user_rights(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+        user: Principal,
+        access_rights: AccessRights,
+    ) -> Result<Option<AccessRights>, String> {
+        self.canister
+            .update::<_, Result<_, String>>(
+                "set_user_rights",
+                (map_owner, bytebuf(map_name), user, access_rights),
+            )
+            .await?
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the canister returns an error.
+    pub async fn remove_user(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+        user: Principal,
+    ) -> Result<Option<AccessRights>, String> {
+        self.canister
+            .update::<_, Result<_, String>>("remove_user", (map_owner, bytebuf(map_name), user))
+            .await?
+    }
+}
+
+/// A canister exposing the endpoints of the `key_manager_example` canister.
+#[derive(Clone)]
+pub struct KeyManagerCanister {
+    canister: Canister,
+}
+
+impl KeyManagerCanister {
+    /// Creates a client of `canister_id` that calls it with `agent`.
+    #[must_use]
+    pub fn new(agent: Agent, canister_id: Principal) -> Self {
+        Self {
+            canister: Canister { agent, canister_id },
+        }
+    }
+
+    /// Returns the agent with which the canister is called.
+    #[must_use]
+    pub fn agent(&self) -> &Agent {
+        &self.canister.agent
+    }
+
+    /// Returns the ID of the canister.
+    #[must_use]
+    pub fn canister_id(&self) -> Principal {
+        self.canister.canister_id
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails.
+    pub async fn get_accessible_shared_key_ids(&self) -> Result<Vec<(Principal, ByteBuf)>, String> {
+        self.canister
+            .query("get_accessible_shared_key_ids", ())
+            .await
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the canister returns an error.
+    pub async fn get_shared_user_access_for_key(
+        &self,
+        key_owner: Principal,
+        key_name: &[u8],
+    ) -> Result<Vec<(Principal, AccessRights)>, String> {
+        self.canister
+            .query::<_, Result<_, String>>(
+                "get_shared_user_access_for_key",
+                (key_owner, bytebuf(key_name)),
+            )
+            .await?
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails.
+    pub async fn get_vetkey_verification_key(&self) -> Result<ByteBuf, String> {
+        self.canister
+            .update("get_vetkey_verification_key", ())
+            .await
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the canister returns an error.
+    pub async fn get_encrypted_vetkey(
+        &self,
+        key_owner: Principal,
+        key_name: &[u8],
+        transport_key: Vec<u8>,
+    ) -> Result<ByteBuf, String> {
+        self.canister
+            .update::<_, Result<_, String>>(
+                "get_encrypted_vetkey",
+                (key_owner, bytebuf(key_name), ByteBuf::from(transport_key)),
+            )
+            .await?
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the canister returns an error.
+    pub async fn get_user_rights(
+        &self,
+        key_owner: Principal,
+        key_name: &[u8],
+        user: Principal,
+    ) -> Result<Option<AccessRights>, String> {
+        self.canister
+            .query::<_, Result<_, String>>("get_user_rights", (key_owner, bytebuf(key_name), user))
+            .await?
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the canister returns an error.
+    pub async fn set_user_rights(
+        &self,
+        key_owner: Principal,
+        key_name: &[u8],
+        user: Principal,
+        access_rights: AccessRights,
+    ) -> Result<Option<AccessRights>, String> {
+        self.canister
+            .update::<_, Result<_, String>>(
+                "set_user_rights",
+                (key_owner, bytebuf(key_name), user, access_rights),
+            )
+            .await?
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the canister returns an error.
+    pub async fn remove_user(
+        &self,
+        key_owner: Principal,
+        key_name: &[u8],
+        user: Principal,
+    ) -> Result<Option<AccessRights>, String> {
+        self.canister
+            .update::<_, Result<_, String>>("remove_user", (key_owner, bytebuf(key_name), user))
+            .await?
+    }
+}
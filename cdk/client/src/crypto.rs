@@ -0,0 +1,125 @@
+//! Derivation of vetkeys and encryption of map values.
+//!
+//! The functions produce the same keys and ciphertexts as the TypeScript
+//! SDK (`ic_vetkd_sdk_encrypted_maps`), so that values written by one can be
+//! read by the other:
+//!
+//! 1. The encrypted vetkey of a map is decrypted and verified with a random
+//!    transport key and hashed to [`VETKEY_BYTES`] bytes, see [`decrypt_vetkey`].
+//!    The derivation id is the owner's principal followed by the map name.
+//! 2. A subkey is derived per map key with HKDF-SHA256, using the map key as
+//!    salt and [`SUBKEY_INFO`] as info, see [`derive_subkey`].
+//! 3. Values are encrypted with AES-256-GCM under the subkey and stored as the
+//!    random 12-byte IV followed by the ciphertext, see [`encrypt`].
+
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use candid::Principal;
+use hkdf::Hkdf;
+use ic_vetkd_utils::TransportSecretKey;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::Sha256;
+
+/// The length of the symmetric key hashed from a vetkey.
+pub const VETKEY_BYTES: usize = 16;
+
+/// The associated data with which the vetkeys of encrypted maps are hashed.
+pub const ENCRYPTED_MAPS_ASSOCIATED_DATA: &[u8] = b"ic-vetkd-sdk-encrypted-maps";
+
+/// The HKDF info with which the subkey of a map key is derived.
+pub const SUBKEY_INFO: &[u8] = b"ic_vetkd_sdk_encrypted_maps_subkey";
+
+/// The length of the IV prepended to encrypted values.
+pub const IV_BYTES: usize = 12;
+
+/// An AES-256-GCM key encrypting the value of a single map key.
+pub type SubKey = [u8; 32];
+
+/// Returns the derivation id of the key or map `name` owned by `owner`.
+#[must_use]
+pub fn derivation_id(owner: Principal, name: &[u8]) -> Vec<u8> {
+    [owner.as_slice(), name].concat()
+}
+
+/// Generates a transport key from a random seed.
+///
+/// # Panics
+///
+/// Panics if the transport key cannot be created from the seed.
+#[must_use]
+pub fn random_transport_secret_key() -> TransportSecretKey {
+    let mut seed = vec![0u8; 32];
+    OsRng.fill_bytes(&mut seed);
+    TransportSecretKey::from_seed(seed).expect("failed to create a transport key")
+}
+
+/// Decrypts `encrypted_vetkey` with `transport_key`, verifies it against
+/// `verification_key` and the derivation id of `name` owned by `owner`, and
+/// hashes it to a symmetric key of [`VETKEY_BYTES`] bytes.
+///
+/// # Errors
+///
+/// Returns an error if the encrypted vetkey does not decrypt or verify.
+pub fn decrypt_vetkey(
+    transport_key: &TransportSecretKey,
+    encrypted_vetkey: &[u8],
+    verification_key: &[u8],
+    owner: Principal,
+    name: &[u8],
+    associated_data: &[u8],
+) -> Result<Vec<u8>, String> {
+    transport_key.decrypt_and_hash(
+        encrypted_vetkey,
+        verification_key,
+        &derivation_id(owner, name),
+        VETKEY_BYTES,
+        associated_data,
+    )
+}
+
+/// Derives the subkey of `map_key` from the vetkey of its map.
+///
+/// # Panics
+///
+/// Never panics, since the subkey is shorter than the maximum HKDF output.
+#[must_use]
+pub fn derive_subkey(vetkey: &[u8], map_key: &[u8]) -> SubKey {
+    let mut subkey = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(map_key), vetkey)
+        .expand(SUBKEY_INFO, &mut subkey)
+        .expect("32 bytes are a valid HKDF-SHA256 output length");
+    subkey
+}
+
+/// Encrypts `cleartext` under `subkey` with a random IV.
+///
+/// # Panics
+///
+/// Panics if AES-GCM fails to encrypt, which only happens for cleartexts of
+/// more than 64 GiB.
+#[must_use]
+pub fn encrypt(subkey: &SubKey, cleartext: &[u8]) -> Vec<u8> {
+    let mut iv = [0u8; IV_BYTES];
+    OsRng.fill_bytes(&mut iv);
+    let ciphertext = Aes256Gcm::new(subkey.into())
+        .encrypt(Nonce::from_slice(&iv), cleartext)
+        .expect("failed to encrypt");
+    [iv.as_slice(), &ciphertext].concat()
+}
+
+/// Decrypts a value encrypted with [`encrypt`].
+///
+/// # Errors
+///
+/// Returns an error if the value is shorter than the IV or fails to decrypt
+/// under `subkey`.
+pub fn decrypt(subkey: &SubKey, encrypted_value: &[u8]) -> Result<Vec<u8>, String> {
+    if encrypted_value.len() < IV_BYTES {
+        return Err("encrypted value is too short".to_string());
+    }
+    let (iv, ciphertext) = encrypted_value.split_at(IV_BYTES);
+    Aes256Gcm::new(subkey.into())
+        .decrypt(Nonce::from_slice(iv), ciphertext)
+        .map_err(|_| "failed to decrypt value".to_string())
+}
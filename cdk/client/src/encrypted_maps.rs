@@ -0,0 +1,427 @@
+use crate::canister::EncryptedMapsCanister;
+use crate::crypto::{self, SubKey, ENCRYPTED_MAPS_ASSOCIATED_DATA};
+use candid::Principal;
+use ic_agent::Agent;
+use ic_vetkd_cdk_types::{AccessRights, ByteBuf};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// A decrypted map, see [`EncryptedMaps::get_all_accessible_maps`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MapData {
+    pub map_owner: Principal,
+    pub map_name: Vec<u8>,
+    pub keyvals: Vec<(Vec<u8>, Vec<u8>)>,
+    pub access_control: Vec<(Principal, AccessRights)>,
+}
+
+/// A client of a canister exposing the `EncryptedMaps` endpoints that
+/// encrypts and decrypts the values of maps, see [`crate`].
+///
+/// The verification key and the vetkeys of maps are cached, so that each is
+/// fetched at most once per client.
+pub struct EncryptedMaps {
+    canister: EncryptedMapsCanister,
+    verification_key: Mutex<Option<Vec<u8>>>,
+    vetkeys: Mutex<BTreeMap<(Principal, Vec<u8>), Vec<u8>>>,
+}
+
+impl EncryptedMaps {
+    /// Creates a client of `canister_id` that calls it with `agent`.
+    #[must_use]
+    pub fn new(agent: Agent, canister_id: Principal) -> Self {
+        Self::from_canister(EncryptedMapsCanister::new(agent, canister_id))
+    }
+
+    /// Creates a client that calls the endpoints with `canister`.
+    #[must_use]
+    pub fn from_canister(canister: EncryptedMapsCanister) -> Self {
+        Self {
+            canister,
+            verification_key: Mutex::new(None),
+            vetkeys: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Returns the client of the endpoints, e.g., to call endpoints that
+    /// neither take nor return values.
+    #[must_use]
+    pub fn canister(&self) -> &EncryptedMapsCanister {
+        &self.canister
+    }
+
+    /// Returns the owners and names of the maps shared with the caller.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the call fails.
+    pub async fn get_accessible_shared_map_names(
+        &self,
+    ) -> Result<Vec<(Principal, Vec<u8>)>, String> {
+        Ok(self
+            .canister
+            .get_accessible_shared_map_names()
+            .await?
+            .into_iter()
+            .map(|(map_owner, map_name)| (map_owner, map_name.into()))
+            .collect())
+    }
+
+    /// Returns the names of the caller's maps that hold at least one value.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the call fails.
+    pub async fn get_owned_non_empty_map_names(&self) -> Result<Vec<Vec<u8>>, String> {
+        Ok(self
+            .canister
+            .get_owned_non_empty_map_names()
+            .await?
+            .into_iter()
+            .map(Vec::from)
+            .collect())
+    }
+
+    /// Returns the decrypted values of all maps the caller can read, grouped
+    /// by map.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a call fails or a value does not decrypt.
+    pub async fn get_all_accessible_values(
+        &self,
+    ) -> Result<Vec<((Principal, Vec<u8>), Vec<(Vec<u8>, Vec<u8>)>)>, String> {
+        let mut result = Vec::new();
+        for ((map_owner, map_name), encrypted_values) in
+            self.canister.get_all_accessible_encrypted_values().await?
+        {
+            let map_name = Vec::from(map_name);
+            let values = self
+                .decrypt_all(map_owner, &map_name, encrypted_values)
+                .await?;
+            result.push(((map_owner, map_name), values));
+        }
+        Ok(result)
+    }
+
+    /// Returns all maps the caller can read with their decrypted values and
+    /// access rights.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a call fails or a value does not decrypt.
+    pub async fn get_all_accessible_maps(&self) -> Result<Vec<MapData>, String> {
+        let mut result = Vec::new();
+        for map in self.canister.get_all_accessible_encrypted_maps().await? {
+            let map_name = Vec::from(map.map_name);
+            let keyvals = self
+                .decrypt_all(map.map_owner, &map_name, map.keyvals)
+                .await?;
+            result.push(MapData {
+                map_owner: map.map_owner,
+                map_name,
+                keyvals,
+                access_control: map.access_control,
+            });
+        }
+        Ok(result)
+    }
+
+    /// Returns the decrypted value of `map_key`, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a call fails, the caller cannot read the map or the
+    /// value does not decrypt.
+    pub async fn get_value(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+        map_key: &[u8],
+    ) -> Result<Option<Vec<u8>>, String> {
+        match self
+            .canister
+            .get_encrypted_value(map_owner, map_name, map_key)
+            .await?
+        {
+            Some(encrypted_value) => self
+                .decrypt_for(map_owner, map_name, map_key, encrypted_value.as_ref())
+                .await
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the decrypted values of a map.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a call fails, the caller cannot read the map or a
+    /// value does not decrypt.
+    pub async fn get_values_for_map(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, String> {
+        let encrypted_values = self
+            .canister
+            .get_encrypted_values_for_map(map_owner, map_name)
+            .await?;
+        self.decrypt_all(map_owner, map_name, encrypted_values)
+            .await
+    }
+
+    /// Encrypts `value` and stores it under `map_key`. Returns the decrypted
+    /// value it replaced, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a call fails, the caller cannot write to the map or
+    /// the replaced value does not decrypt.
+    pub async fn set_value(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+        map_key: &[u8],
+        value: &[u8],
+    ) -> Result<Option<Vec<u8>>, String> {
+        let encrypted_value = self
+            .encrypt_for(map_owner, map_name, map_key, value)
+            .await?;
+        match self
+            .canister
+            .insert_encrypted_value(map_owner, map_name, map_key, encrypted_value.into())
+            .await?
+        {
+            Some(replaced) => self
+                .decrypt_for(map_owner, map_name, map_key, replaced.as_ref())
+                .await
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes the value of `map_key` and keeps it as a tombstone. Returns
+    /// the decrypted removed value, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a call fails, the caller cannot delete values of
+    /// the map or the removed value does not decrypt.
+    pub async fn remove_encrypted_value(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+        map_key: &[u8],
+    ) -> Result<Option<Vec<u8>>, String> {
+        match self
+            .canister
+            .remove_encrypted_value(map_owner, map_name, map_key)
+            .await?
+        {
+            Some(removed) => self
+                .decrypt_for(map_owner, map_name, map_key, removed.as_ref())
+                .await
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes all values of a map and keeps them as tombstones. Returns the
+    /// removed map keys.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the caller cannot delete values
+    /// of the map.
+    pub async fn remove_map_values(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+    ) -> Result<Vec<Vec<u8>>, String> {
+        Ok(self
+            .canister
+            .remove_map_values(map_owner, map_name)
+            .await?
+            .into_iter()
+            .map(Vec::from)
+            .collect())
+    }
+
+    /// Encrypts `cleartext` for `map_key` of a map.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the vetkey of the map cannot be obtained.
+    pub async fn encrypt_for(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+        map_key: &[u8],
+        cleartext: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        let subkey = self.subkey(map_owner, map_name, map_key).await?;
+        Ok(crypto::encrypt(&subkey, cleartext))
+    }
+
+    /// Decrypts a value of `map_key` of a map.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the vetkey of the map cannot be obtained or the
+    /// value does not decrypt.
+    pub async fn decrypt_for(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+        map_key: &[u8],
+        encrypted_value: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        let subkey = self.subkey(map_owner, map_name, map_key).await?;
+        crypto::decrypt(&subkey, encrypted_value)
+    }
+
+    /// Returns the symmetric key hashed from the verified vetkey of a map,
+    /// fetching it if it is not cached.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a call fails, the caller cannot fetch the vetkey of
+    /// the map or the vetkey does not verify.
+    pub async fn get_symmetric_vetkey(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        let cache_key = (map_owner, map_name.to_vec());
+        if let Some(vetkey) = self.vetkeys.lock().unwrap().get(&cache_key) {
+            return Ok(vetkey.clone());
+        }
+
+        let transport_key = crypto::random_transport_secret_key();
+        let encrypted_vetkey = self
+            .canister
+            .get_encrypted_vetkey(map_owner, map_name, transport_key.public_key())
+            .await?;
+        let verification_key = self.get_vetkey_verification_key().await?;
+        let vetkey = crypto::decrypt_vetkey(
+            &transport_key,
+            encrypted_vetkey.as_ref(),
+            &verification_key,
+            map_owner,
+            map_name,
+            ENCRYPTED_MAPS_ASSOCIATED_DATA,
+        )?;
+
+        self.vetkeys
+            .lock()
+            .unwrap()
+            .insert(cache_key, vetkey.clone());
+        Ok(vetkey)
+    }
+
+    /// Returns the verification key of the canister's vetkeys, fetching it if
+    /// it is not cached.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the call fails.
+    pub async fn get_vetkey_verification_key(&self) -> Result<Vec<u8>, String> {
+        if let Some(verification_key) = self.verification_key.lock().unwrap().as_ref() {
+            return Ok(verification_key.clone());
+        }
+        let verification_key = Vec::from(self.canister.get_vetkey_verification_key().await?);
+        *self.verification_key.lock().unwrap() = Some(verification_key.clone());
+        Ok(verification_key)
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the caller cannot inspect the map.
+    pub async fn get_shared_user_access_for_map(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+    ) -> Result<Vec<(Principal, AccessRights)>, String> {
+        self.canister
+            .get_shared_user_access_for_map(map_owner, map_name)
+            .await
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the caller cannot inspect the map.
+    pub async fn get_user_rights(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+        user: Principal,
+    ) -> Result<Option<AccessRights>, String> {
+        self.canister
+            .get_user_rights(map_owner, map_name, user)
+            .await
+    }
+
+    /// Grants `access_rights` to `user`. Returns the replaced rights, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the canister rejects the grant.
+    pub async fn set_user_rights(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+        user: Principal,
+        access_rights: AccessRights,
+    ) -> Result<Option<AccessRights>, String> {
+        self.canister
+            .set_user_rights(map_owner, map_name, user, access_rights)
+            .await
+    }
+
+    /// Revokes the rights of `user`. Returns the revoked rights, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the canister rejects the
+    /// revocation.
+    pub async fn remove_user(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+        user: Principal,
+    ) -> Result<Option<AccessRights>, String> {
+        self.canister.remove_user(map_owner, map_name, user).await
+    }
+
+    async fn subkey(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+        map_key: &[u8],
+    ) -> Result<SubKey, String> {
+        let vetkey = self.get_symmetric_vetkey(map_owner, map_name).await?;
+        Ok(crypto::derive_subkey(&vetkey, map_key))
+    }
+
+    async fn decrypt_all(
+        &self,
+        map_owner: Principal,
+        map_name: &[u8],
+        encrypted_values: Vec<(ByteBuf, ByteBuf)>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, String> {
+        let mut values = Vec::with_capacity(encrypted_values.len());
+        for (map_key, encrypted_value) in encrypted_values {
+            let value = self
+                .decrypt_for(
+                    map_owner,
+                    map_name,
+                    map_key.as_ref(),
+                    encrypted_value.as_ref(),
+                )
+                .await?;
+            values.push((map_key.into(), value));
+        }
+        Ok(values)
+    }
+}
@@ -0,0 +1,141 @@
+use crate::canister::KeyManagerCanister;
+use crate::crypto;
+use candid::Principal;
+use ic_agent::Agent;
+use ic_vetkd_cdk_types::AccessRights;
+
+/// A client of a canister exposing the `KeyManager` endpoints that decrypts
+/// and verifies the vetkeys of keys, see [`crate`].
+pub struct KeyManager {
+    canister: KeyManagerCanister,
+}
+
+impl KeyManager {
+    /// Creates a client of `canister_id` that calls it with `agent`.
+    #[must_use]
+    pub fn new(agent: Agent, canister_id: Principal) -> Self {
+        Self::from_canister(KeyManagerCanister::new(agent, canister_id))
+    }
+
+    /// Creates a client that calls the endpoints with `canister`.
+    #[must_use]
+    pub fn from_canister(canister: KeyManagerCanister) -> Self {
+        Self { canister }
+    }
+
+    /// Returns the client of the endpoints.
+    #[must_use]
+    pub fn canister(&self) -> &KeyManagerCanister {
+        &self.canister
+    }
+
+    /// Returns the owners and names of the keys shared with the caller.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the call fails.
+    pub async fn get_accessible_shared_key_ids(&self) -> Result<Vec<(Principal, Vec<u8>)>, String> {
+        Ok(self
+            .canister
+            .get_accessible_shared_key_ids()
+            .await?
+            .into_iter()
+            .map(|(key_owner, key_name)| (key_owner, key_name.into()))
+            .collect())
+    }
+
+    /// Fetches the vetkey of a key with a random transport key, verifies it
+    /// and returns the symmetric key hashed from it, like `get_encrypted_vetkey`
+    /// of the TypeScript SDK.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a call fails, the caller cannot fetch the vetkey or
+    /// the vetkey does not verify.
+    pub async fn get_vetkey(
+        &self,
+        key_owner: Principal,
+        key_name: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        let transport_key = crypto::random_transport_secret_key();
+        let encrypted_vetkey = self
+            .canister
+            .get_encrypted_vetkey(key_owner, key_name, transport_key.public_key())
+            .await?;
+        let verification_key = self.get_vetkey_verification_key().await?;
+        crypto::decrypt_vetkey(
+            &transport_key,
+            encrypted_vetkey.as_ref(),
+            &verification_key,
+            key_owner,
+            key_name,
+            &[],
+        )
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails.
+    pub async fn get_vetkey_verification_key(&self) -> Result<Vec<u8>, String> {
+        Ok(self.canister.get_vetkey_verification_key().await?.into())
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the caller cannot inspect the key.
+    pub async fn get_shared_user_access_for_key(
+        &self,
+        key_owner: Principal,
+        key_name: &[u8],
+    ) -> Result<Vec<(Principal, AccessRights)>, String> {
+        self.canister
+            .get_shared_user_access_for_key(key_owner, key_name)
+            .await
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the caller cannot inspect the key.
+    pub async fn get_user_rights(
+        &self,
+        key_owner: Principal,
+        key_name: &[u8],
+        user: Principal,
+    ) -> Result<Option<AccessRights>, String> {
+        self.canister
+            .get_user_rights(key_owner, key_name, user)
+            .await
+    }
+
+    /// Grants `access_rights` to `user`. Returns the replaced rights, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the canister rejects the grant.
+    pub async fn set_user_rights(
+        &self,
+        key_owner: Principal,
+        key_name: &[u8],
+        user: Principal,
+        access_rights: AccessRights,
+    ) -> Result<Option<AccessRights>, String> {
+        self.canister
+            .set_user_rights(key_owner, key_name, user, access_rights)
+            .await
+    }
+
+    /// Revokes the rights of `user`. Returns the revoked rights, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the call fails or the canister rejects the
+    /// revocation.
+    pub async fn remove_user(
+        &self,
+        key_owner: Principal,
+        key_name: &[u8],
+        user: Principal,
+    ) -> Result<Option<AccessRights>, String> {
+        self.canister.remove_user(key_owner, key_name, user).await
+    }
+}
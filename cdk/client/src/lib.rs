@@ -0,0 +1,42 @@
+//! # `VetKD` CDK - Client
+//!
+//! ## Overview
+//!
+//! The client calls canisters exposing the `KeyManager` or `EncryptedMaps`
+//! endpoints with an [`ic_agent::Agent`], mirroring the TypeScript SDKs
+//! `ic_vetkd_sdk_key_manager` and `ic_vetkd_sdk_encrypted_maps`:
+//!
+//! - [`EncryptedMaps`] fetches the vetkeys of maps with random transport
+//!   keys, decrypts and verifies them, and encrypts and decrypts the values
+//!   of maps with AES-GCM. Values written with it can be read with the
+//!   TypeScript SDK and vice versa, see [`crypto`].
+//! - [`KeyManager`] fetches, decrypts and verifies the vetkeys of keys.
+//! - Both manage the sharing of maps and keys.
+//!
+//! The typed Candid calls without any cryptography are in [`canister`], and
+//! the `vetkd-maps` binary manages maps from the command line. Names and map
+//! keys are passed as byte slices and all errors are returned as strings.
+//!
+//! ```ignore
+//! let agent = ic_agent::Agent::builder().with_url(url).with_identity(identity).build()?;
+//! let encrypted_maps = EncryptedMaps::new(agent, canister_id);
+//! let owner = encrypted_maps.canister().agent().get_principal()?;
+//! encrypted_maps.set_value(owner, b"passwords", b"github", b"secret").await?;
+//! assert_eq!(
+//!     encrypted_maps.get_value(owner, b"passwords", b"github").await?,
+//!     Some(b"secret".to_vec())
+//! );
+//! ```
+//!
+//! The crate is empty when built for wasm32, so that the canisters of the
+//! workspace can be built together with it.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+pub mod canister;
+pub mod crypto;
+mod encrypted_maps;
+mod key_manager;
+
+pub use encrypted_maps::{EncryptedMaps, MapData};
+pub use key_manager::KeyManager;
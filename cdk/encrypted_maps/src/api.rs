@@ -0,0 +1,589 @@
+//! Standard canister endpoints of `EncryptedMaps`.
+//!
+//! [`export_encrypted_maps_api`](crate::export_encrypted_maps_api) installs
+//! the endpoints with which users list, read, write, delete and restore the
+//! values of maps, retrieve encrypted vetkeys, manage access rights and read
+//! the audit logs of maps, i.e., the service of the `encrypted_maps_example`
+//! canister. Map names and map keys are passed as `ByteBuf`s that are
+//! validated with [`key_id`] and [`map_key`]. `remove_encrypted_value` and
+//! `remove_map_values` keep the removed values as tombstones, while
+//! `hard_delete_encrypted_value` and `hard_delete_map_values` remove them
+//! permanently.
+//!
+//! As for the endpoints of a `KeyManager`, see
+//! [`ic_vetkd_cdk_key_manager::api`], individual endpoints can be left out
+//! with `except: [...]` or listed with `only: [...]`, and a `guard` is called
+//! with the method name before every installed endpoint. The Candid
+//! declarations of [`ENCRYPTED_MAPS_API_METHODS`] are generated from their
+//! Rust types by [`did_service`].
+
+use crate::{EncryptedMapData, TombstoneEntry, VetKey, VetKeyVerificationKey};
+use candid::Principal;
+use ic_vetkd_cdk_key_manager::__api_method;
+pub use ic_vetkd_cdk_key_manager::api::{
+    allow_all, did_service, key_id, name_to_bytebuf, AccessRights, ApiMethod, ByteBuf, TransportKey,
+};
+use ic_vetkd_cdk_types::MapKey;
+pub use ic_vetkd_cdk_types::{AuditEntry, EncryptedMapValue};
+
+/// The standard endpoints.
+pub const ENCRYPTED_MAPS_API_METHODS: [ApiMethod; 21] = [
+    __api_method!(get_accessible_shared_map_names() -> Vec<(Principal, ByteBuf)>, Query),
+    __api_method!(get_all_accessible_encrypted_maps() -> Vec<EncryptedMapData>, Query),
+    __api_method!(
+        get_all_accessible_encrypted_values()
+            -> Vec<((Principal, ByteBuf), Vec<(ByteBuf, EncryptedMapValue)>)>,
+        Query
+    ),
+    __api_method!(
+        get_audit_log(Principal, ByteBuf) -> Result<Vec<AuditEntry>, String>,
+        Query
+    ),
+    __api_method!(
+        get_encrypted_value(Principal, ByteBuf, ByteBuf)
+            -> Result<Option<EncryptedMapValue>, String>,
+        Query
+    ),
+    __api_method!(
+        get_encrypted_values_for_map(Principal, ByteBuf)
+            -> Result<Vec<(ByteBuf, EncryptedMapValue)>, String>,
+        Query
+    ),
+    __api_method!(
+        get_encrypted_vetkey(Principal, ByteBuf, TransportKey) -> Result<VetKey, String>
+    ),
+    __api_method!(get_owned_non_empty_map_names() -> Vec<ByteBuf>, Query),
+    __api_method!(
+        get_shared_user_access_for_map(Principal, ByteBuf)
+            -> Result<Vec<(Principal, AccessRights)>, String>,
+        Query
+    ),
+    __api_method!(
+        get_tombstones(Principal, ByteBuf) -> Result<Vec<(ByteBuf, TombstoneEntry)>, String>,
+        Query
+    ),
+    __api_method!(
+        get_user_rights(Principal, ByteBuf, Principal) -> Result<Option<AccessRights>, String>,
+        Query
+    ),
+    __api_method!(get_vetkey_verification_key() -> VetKeyVerificationKey),
+    __api_method!(
+        hard_delete_encrypted_value(Principal, ByteBuf, ByteBuf)
+            -> Result<Option<EncryptedMapValue>, String>
+    ),
+    __api_method!(hard_delete_map_values(Principal, ByteBuf) -> Result<Vec<ByteBuf>, String>),
+    __api_method!(
+        insert_encrypted_value(Principal, ByteBuf, ByteBuf, EncryptedMapValue)
+            -> Result<Option<EncryptedMapValue>, String>
+    ),
+    __api_method!(
+        purge_tombstone(Principal, ByteBuf, ByteBuf) -> Result<Option<TombstoneEntry>, String>
+    ),
+    __api_method!(
+        remove_encrypted_value(Principal, ByteBuf, ByteBuf)
+            -> Result<Option<EncryptedMapValue>, String>
+    ),
+    __api_method!(remove_map_values(Principal, ByteBuf) -> Result<Vec<ByteBuf>, String>),
+    __api_method!(
+        remove_user(Principal, ByteBuf, Principal) -> Result<Option<AccessRights>, String>
+    ),
+    __api_method!(
+        restore_value(Principal, ByteBuf, ByteBuf) -> Result<Option<EncryptedMapValue>, String>
+    ),
+    __api_method!(
+        set_user_rights(Principal, ByteBuf, Principal, AccessRights)
+            -> Result<Option<AccessRights>, String>
+    ),
+];
+
+/// Converts a map key passed to an endpoint.
+///
+/// # Errors
+///
+/// Returns an error if the map key is longer than
+/// [`MAX_NAME_BYTES`](ic_vetkd_cdk_types::MAX_NAME_BYTES).
+pub fn map_key(map_key: &ByteBuf) -> Result<MapKey, String> {
+    ic_vetkd_cdk_key_manager::api::name(map_key)
+}
+
+/// Installs the standard endpoints of `EncryptedMaps` in a canister, see
+/// [`crate::api`].
+///
+/// `$state` is a `thread_local!` `RefCell` holding `EncryptedMaps`. The
+/// canister must depend on `candid` and `ic-cdk`.
+///
+/// ```ignore
+/// ic_vetkd_cdk_encrypted_maps::export_encrypted_maps_api!(ENCRYPTED_MAPS);
+///
+/// // Without the endpoints that permanently delete values:
+/// ic_vetkd_cdk_encrypted_maps::export_encrypted_maps_api!(
+///     ENCRYPTED_MAPS,
+///     except: [hard_delete_encrypted_value, hard_delete_map_values],
+///     guard: reject_anonymous
+/// );
+/// ```
+#[macro_export]
+macro_rules! export_encrypted_maps_api {
+    ($state:ident $(,)?) => {
+        $crate::export_encrypted_maps_api!($state, except: [], guard: $crate::api::allow_all);
+    };
+    ($state:ident, guard: $guard:path $(,)?) => {
+        $crate::export_encrypted_maps_api!($state, except: [], guard: $guard);
+    };
+    ($state:ident, except: [$($skip:ident),* $(,)?] $(,)?) => {
+        $crate::export_encrypted_maps_api!(
+            $state,
+            except: [$($skip),*],
+            guard: $crate::api::allow_all
+        );
+    };
+    ($state:ident, only: [$($method:ident),* $(,)?] $(,)?) => {
+        $crate::export_encrypted_maps_api!(
+            $state,
+            only: [$($method),*],
+            guard: $crate::api::allow_all
+        );
+    };
+    ($state:ident, only: [$($method:ident),* $(,)?], guard: $guard:path $(,)?) => {
+        $($crate::__encrypted_maps_api_method!($method; $state, $guard;);)*
+    };
+    ($state:ident, except: [$($skip:ident),* $(,)?], guard: $guard:path $(,)?) => {
+        $crate::__encrypted_maps_api_method!(get_accessible_shared_map_names; $state, $guard; $($skip)*);
+        $crate::__encrypted_maps_api_method!(get_owned_non_empty_map_names; $state, $guard; $($skip)*);
+        $crate::__encrypted_maps_api_method!(get_shared_user_access_for_map; $state, $guard; $($skip)*);
+        $crate::__encrypted_maps_api_method!(get_encrypted_values_for_map; $state, $guard; $($skip)*);
+        $crate::__encrypted_maps_api_method!(get_all_accessible_encrypted_values; $state, $guard; $($skip)*);
+        $crate::__encrypted_maps_api_method!(get_all_accessible_encrypted_maps; $state, $guard; $($skip)*);
+        $crate::__encrypted_maps_api_method!(get_encrypted_value; $state, $guard; $($skip)*);
+        $crate::__encrypted_maps_api_method!(insert_encrypted_value; $state, $guard; $($skip)*);
+        $crate::__encrypted_maps_api_method!(remove_encrypted_value; $state, $guard; $($skip)*);
+        $crate::__encrypted_maps_api_method!(hard_delete_encrypted_value; $state, $guard; $($skip)*);
+        $crate::__encrypted_maps_api_method!(remove_map_values; $state, $guard; $($skip)*);
+        $crate::__encrypted_maps_api_method!(hard_delete_map_values; $state, $guard; $($skip)*);
+        $crate::__encrypted_maps_api_method!(get_tombstones; $state, $guard; $($skip)*);
+        $crate::__encrypted_maps_api_method!(restore_value; $state, $guard; $($skip)*);
+        $crate::__encrypted_maps_api_method!(purge_tombstone; $state, $guard; $($skip)*);
+        $crate::__encrypted_maps_api_method!(get_audit_log; $state, $guard; $($skip)*);
+        $crate::__encrypted_maps_api_method!(get_vetkey_verification_key; $state, $guard; $($skip)*);
+        $crate::__encrypted_maps_api_method!(get_encrypted_vetkey; $state, $guard; $($skip)*);
+        $crate::__encrypted_maps_api_method!(get_user_rights; $state, $guard; $($skip)*);
+        $crate::__encrypted_maps_api_method!(set_user_rights; $state, $guard; $($skip)*);
+        $crate::__encrypted_maps_api_method!(remove_user; $state, $guard; $($skip)*);
+    };
+}
+
+/// Installs a single endpoint of [`export_encrypted_maps_api`] unless it is
+/// listed after the second `;`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __encrypted_maps_api_method {
+    (get_accessible_shared_map_names; $state:ident, $guard:path; get_accessible_shared_map_names $($rest:ident)*) => {};
+    (get_owned_non_empty_map_names; $state:ident, $guard:path; get_owned_non_empty_map_names $($rest:ident)*) => {};
+    (get_shared_user_access_for_map; $state:ident, $guard:path; get_shared_user_access_for_map $($rest:ident)*) => {};
+    (get_encrypted_values_for_map; $state:ident, $guard:path; get_encrypted_values_for_map $($rest:ident)*) => {};
+    (get_all_accessible_encrypted_values; $state:ident, $guard:path; get_all_accessible_encrypted_values $($rest:ident)*) => {};
+    (get_all_accessible_encrypted_maps; $state:ident, $guard:path; get_all_accessible_encrypted_maps $($rest:ident)*) => {};
+    (get_encrypted_value; $state:ident, $guard:path; get_encrypted_value $($rest:ident)*) => {};
+    (insert_encrypted_value; $state:ident, $guard:path; insert_encrypted_value $($rest:ident)*) => {};
+    (remove_encrypted_value; $state:ident, $guard:path; remove_encrypted_value $($rest:ident)*) => {};
+    (hard_delete_encrypted_value; $state:ident, $guard:path; hard_delete_encrypted_value $($rest:ident)*) => {};
+    (remove_map_values; $state:ident, $guard:path; remove_map_values $($rest:ident)*) => {};
+    (hard_delete_map_values; $state:ident, $guard:path; hard_delete_map_values $($rest:ident)*) => {};
+    (get_tombstones; $state:ident, $guard:path; get_tombstones $($rest:ident)*) => {};
+    (restore_value; $state:ident, $guard:path; restore_value $($rest:ident)*) => {};
+    (purge_tombstone; $state:ident, $guard:path; purge_tombstone $($rest:ident)*) => {};
+    (get_audit_log; $state:ident, $guard:path; get_audit_log $($rest:ident)*) => {};
+    (get_vetkey_verification_key; $state:ident, $guard:path; get_vetkey_verification_key $($rest:ident)*) => {};
+    (get_encrypted_vetkey; $state:ident, $guard:path; get_encrypted_vetkey $($rest:ident)*) => {};
+    (get_user_rights; $state:ident, $guard:path; get_user_rights $($rest:ident)*) => {};
+    (set_user_rights; $state:ident, $guard:path; set_user_rights $($rest:ident)*) => {};
+    (remove_user; $state:ident, $guard:path; remove_user $($rest:ident)*) => {};
+    ($method:ident; $state:ident, $guard:path; $skip:ident $($rest:ident)*) => {
+        $crate::__encrypted_maps_api_method!($method; $state, $guard; $($rest)*);
+    };
+
+    (get_accessible_shared_map_names; $state:ident, $guard:path;) => {
+        #[::ic_cdk::query]
+        fn get_accessible_shared_map_names() -> ::std::vec::Vec<(::candid::Principal, $crate::api::ByteBuf)> {
+            if let ::std::result::Result::Err(error) = $guard("get_accessible_shared_map_names") {
+                ::ic_cdk::trap(&error);
+            }
+            $state.with_borrow(|encrypted_maps| {
+                encrypted_maps
+                    .get_accessible_shared_map_names(::ic_cdk::caller())
+                    .into_iter()
+                    .map(|(map_owner, map_name)| {
+                        (map_owner, $crate::api::name_to_bytebuf(&map_name))
+                    })
+                    .collect()
+            })
+        }
+    };
+    (get_owned_non_empty_map_names; $state:ident, $guard:path;) => {
+        #[::ic_cdk::query]
+        fn get_owned_non_empty_map_names() -> ::std::vec::Vec<$crate::api::ByteBuf> {
+            if let ::std::result::Result::Err(error) = $guard("get_owned_non_empty_map_names") {
+                ::ic_cdk::trap(&error);
+            }
+            $state.with_borrow(|encrypted_maps| {
+                encrypted_maps
+                    .get_owned_non_empty_map_names(::ic_cdk::caller())
+                    .iter()
+                    .map($crate::api::name_to_bytebuf)
+                    .collect()
+            })
+        }
+    };
+    (get_shared_user_access_for_map; $state:ident, $guard:path;) => {
+        #[::ic_cdk::query]
+        #[allow(clippy::needless_pass_by_value)]
+        fn get_shared_user_access_for_map(
+            map_owner: ::candid::Principal,
+            map_name: $crate::api::ByteBuf,
+        ) -> ::std::result::Result<
+            ::std::vec::Vec<(::candid::Principal, $crate::api::AccessRights)>,
+            ::std::string::String,
+        > {
+            $guard("get_shared_user_access_for_map")?;
+            let map_id = $crate::api::key_id(map_owner, &map_name)?;
+            $state.with_borrow(|encrypted_maps| {
+                encrypted_maps.get_shared_user_access_for_map(::ic_cdk::caller(), map_id)
+            })
+        }
+    };
+    (get_encrypted_values_for_map; $state:ident, $guard:path;) => {
+        #[::ic_cdk::query]
+        #[allow(clippy::needless_pass_by_value)]
+        fn get_encrypted_values_for_map(
+            map_owner: ::candid::Principal,
+            map_name: $crate::api::ByteBuf,
+        ) -> ::std::result::Result<
+            ::std::vec::Vec<($crate::api::ByteBuf, $crate::api::EncryptedMapValue)>,
+            ::std::string::String,
+        > {
+            $guard("get_encrypted_values_for_map")?;
+            let map_id = $crate::api::key_id(map_owner, &map_name)?;
+            let values = $state.with_borrow(|encrypted_maps| {
+                encrypted_maps.get_encrypted_values_for_map(::ic_cdk::caller(), map_id)
+            })?;
+            ::std::result::Result::Ok(
+                values
+                    .into_iter()
+                    .map(|(map_key, value)| ($crate::api::name_to_bytebuf(&map_key), value))
+                    .collect(),
+            )
+        }
+    };
+    (get_all_accessible_encrypted_values; $state:ident, $guard:path;) => {
+        #[::ic_cdk::query]
+        fn get_all_accessible_encrypted_values() -> ::std::vec::Vec<(
+            (::candid::Principal, $crate::api::ByteBuf),
+            ::std::vec::Vec<($crate::api::ByteBuf, $crate::api::EncryptedMapValue)>,
+        )> {
+            if let ::std::result::Result::Err(error) = $guard("get_all_accessible_encrypted_values") {
+                ::ic_cdk::trap(&error);
+            }
+            $state
+                .with_borrow(|encrypted_maps| {
+                    encrypted_maps.get_all_accessible_encrypted_values(::ic_cdk::caller())
+                })
+                .into_iter()
+                .map(|((map_owner, map_name), values)| {
+                    (
+                        (map_owner, $crate::api::name_to_bytebuf(&map_name)),
+                        values
+                            .into_iter()
+                            .map(|(map_key, value)| ($crate::api::name_to_bytebuf(&map_key), value))
+                            .collect(),
+                    )
+                })
+                .collect()
+        }
+    };
+    (get_all_accessible_encrypted_maps; $state:ident, $guard:path;) => {
+        #[::ic_cdk::query]
+        fn get_all_accessible_encrypted_maps() -> ::std::vec::Vec<$crate::EncryptedMapData> {
+            if let ::std::result::Result::Err(error) = $guard("get_all_accessible_encrypted_maps") {
+                ::ic_cdk::trap(&error);
+            }
+            $state.with_borrow(|encrypted_maps| {
+                encrypted_maps.get_all_accessible_encrypted_maps(::ic_cdk::caller())
+            })
+        }
+    };
+    (get_encrypted_value; $state:ident, $guard:path;) => {
+        #[::ic_cdk::query]
+        #[allow(clippy::needless_pass_by_value)]
+        fn get_encrypted_value(
+            map_owner: ::candid::Principal,
+            map_name: $crate::api::ByteBuf,
+            map_key: $crate::api::ByteBuf,
+        ) -> ::std::result::Result<
+            ::std::option::Option<$crate::api::EncryptedMapValue>,
+            ::std::string::String,
+        > {
+            $guard("get_encrypted_value")?;
+            let map_id = $crate::api::key_id(map_owner, &map_name)?;
+            let map_key = $crate::api::map_key(&map_key)?;
+            $state.with_borrow(|encrypted_maps| {
+                encrypted_maps.get_encrypted_value(::ic_cdk::caller(), map_id, map_key)
+            })
+        }
+    };
+    (insert_encrypted_value; $state:ident, $guard:path;) => {
+        #[::ic_cdk::update]
+        #[allow(clippy::needless_pass_by_value)]
+        fn insert_encrypted_value(
+            map_owner: ::candid::Principal,
+            map_name: $crate::api::ByteBuf,
+            map_key: $crate::api::ByteBuf,
+            value: $crate::api::EncryptedMapValue,
+        ) -> ::std::result::Result<
+            ::std::option::Option<$crate::api::EncryptedMapValue>,
+            ::std::string::String,
+        > {
+            $guard("insert_encrypted_value")?;
+            let map_id = $crate::api::key_id(map_owner, &map_name)?;
+            let map_key = $crate::api::map_key(&map_key)?;
+            $state.with_borrow_mut(|encrypted_maps| {
+                encrypted_maps.insert_encrypted_value(::ic_cdk::caller(), map_id, map_key, value)
+            })
+        }
+    };
+    (remove_encrypted_value; $state:ident, $guard:path;) => {
+        #[::ic_cdk::update]
+        #[allow(clippy::needless_pass_by_value)]
+        fn remove_encrypted_value(
+            map_owner: ::candid::Principal,
+            map_name: $crate::api::ByteBuf,
+            map_key: $crate::api::ByteBuf,
+        ) -> ::std::result::Result<
+            ::std::option::Option<$crate::api::EncryptedMapValue>,
+            ::std::string::String,
+        > {
+            $guard("remove_encrypted_value")?;
+            let map_id = $crate::api::key_id(map_owner, &map_name)?;
+            let map_key = $crate::api::map_key(&map_key)?;
+            $state.with_borrow_mut(|encrypted_maps| {
+                encrypted_maps.remove_encrypted_value(::ic_cdk::caller(), map_id, map_key, false)
+            })
+        }
+    };
+    (hard_delete_encrypted_value; $state:ident, $guard:path;) => {
+        #[::ic_cdk::update]
+        #[allow(clippy::needless_pass_by_value)]
+        fn hard_delete_encrypted_value(
+            map_owner: ::candid::Principal,
+            map_name: $crate::api::ByteBuf,
+            map_key: $crate::api::ByteBuf,
+        ) -> ::std::result::Result<
+            ::std::option::Option<$crate::api::EncryptedMapValue>,
+            ::std::string::String,
+        > {
+            $guard("hard_delete_encrypted_value")?;
+            let map_id = $crate::api::key_id(map_owner, &map_name)?;
+            let map_key = $crate::api::map_key(&map_key)?;
+            $state.with_borrow_mut(|encrypted_maps| {
+                encrypted_maps.remove_encrypted_value(::ic_cdk::caller(), map_id, map_key, true)
+            })
+        }
+    };
+    (remove_map_values; $state:ident, $guard:path;) => {
+        #[::ic_cdk::update]
+        #[allow(clippy::needless_pass_by_value)]
+        fn remove_map_values(
+            map_owner: ::candid::Principal,
+            map_name: $crate::api::ByteBuf,
+        ) -> ::std::result::Result<::std::vec::Vec<$crate::api::ByteBuf>, ::std::string::String> {
+            $guard("remove_map_values")?;
+            let map_id = $crate::api::key_id(map_owner, &map_name)?;
+            let removed = $state.with_borrow_mut(|encrypted_maps| {
+                encrypted_maps.remove_map_values(::ic_cdk::caller(), map_id, true)
+            })?;
+            ::std::result::Result::Ok(removed.iter().map($crate::api::name_to_bytebuf).collect())
+        }
+    };
+    (hard_delete_map_values; $state:ident, $guard:path;) => {
+        #[::ic_cdk::update]
+        #[allow(clippy::needless_pass_by_value)]
+        fn hard_delete_map_values(
+            map_owner: ::candid::Principal,
+            map_name: $crate::api::ByteBuf,
+        ) -> ::std::result::Result<::std::vec::Vec<$crate::api::ByteBuf>, ::std::string::String> {
+            $guard("hard_delete_map_values")?;
+            let map_id = $crate::api::key_id(map_owner, &map_name)?;
+            let removed = $state.with_borrow_mut(|encrypted_maps| {
+                encrypted_maps.remove_map_values(::ic_cdk::caller(), map_id, false)
+            })?;
+            ::std::result::Result::Ok(removed.iter().map($crate::api::name_to_bytebuf).collect())
+        }
+    };
+    (get_tombstones; $state:ident, $guard:path;) => {
+        #[::ic_cdk::query]
+        #[allow(clippy::needless_pass_by_value)]
+        fn get_tombstones(
+            map_owner: ::candid::Principal,
+            map_name: $crate::api::ByteBuf,
+        ) -> ::std::result::Result<
+            ::std::vec::Vec<($crate::api::ByteBuf, $crate::TombstoneEntry)>,
+            ::std::string::String,
+        > {
+            $guard("get_tombstones")?;
+            let map_id = $crate::api::key_id(map_owner, &map_name)?;
+            let tombstones = $state.with_borrow(|encrypted_maps| {
+                encrypted_maps.get_tombstones_for_map(::ic_cdk::caller(), map_id)
+            })?;
+            ::std::result::Result::Ok(
+                tombstones
+                    .into_iter()
+                    .map(|(map_key, tombstone)| ($crate::api::name_to_bytebuf(&map_key), tombstone))
+                    .collect(),
+            )
+        }
+    };
+    (restore_value; $state:ident, $guard:path;) => {
+        #[::ic_cdk::update]
+        #[allow(clippy::needless_pass_by_value)]
+        fn restore_value(
+            map_owner: ::candid::Principal,
+            map_name: $crate::api::ByteBuf,
+            map_key: $crate::api::ByteBuf,
+        ) -> ::std::result::Result<
+            ::std::option::Option<$crate::api::EncryptedMapValue>,
+            ::std::string::String,
+        > {
+            $guard("restore_value")?;
+            let map_id = $crate::api::key_id(map_owner, &map_name)?;
+            let map_key = $crate::api::map_key(&map_key)?;
+            $state.with_borrow_mut(|encrypted_maps| {
+                encrypted_maps.restore_value(::ic_cdk::caller(), map_id, map_key)
+            })
+        }
+    };
+    (purge_tombstone; $state:ident, $guard:path;) => {
+        #[::ic_cdk::update]
+        #[allow(clippy::needless_pass_by_value)]
+        fn purge_tombstone(
+            map_owner: ::candid::Principal,
+            map_name: $crate::api::ByteBuf,
+            map_key: $crate::api::ByteBuf,
+        ) -> ::std::result::Result<
+            ::std::option::Option<$crate::TombstoneEntry>,
+            ::std::string::String,
+        > {
+            $guard("purge_tombstone")?;
+            let map_id = $crate::api::key_id(map_owner, &map_name)?;
+            let map_key = $crate::api::map_key(&map_key)?;
+            $state.with_borrow_mut(|encrypted_maps| {
+                encrypted_maps.purge_tombstone(::ic_cdk::caller(), map_id, map_key)
+            })
+        }
+    };
+    (get_audit_log; $state:ident, $guard:path;) => {
+        #[::ic_cdk::query]
+        #[allow(clippy::needless_pass_by_value)]
+        fn get_audit_log(
+            map_owner: ::candid::Principal,
+            map_name: $crate::api::ByteBuf,
+        ) -> ::std::result::Result<
+            ::std::vec::Vec<$crate::api::AuditEntry>,
+            ::std::string::String,
+        > {
+            $guard("get_audit_log")?;
+            let map_id = $crate::api::key_id(map_owner, &map_name)?;
+            let audit_log = $state.with_borrow(|encrypted_maps| {
+                encrypted_maps
+                    .key_manager
+                    .get_audit_log_for_caller(::ic_cdk::caller(), map_id)
+            })?;
+            ::std::result::Result::Ok(audit_log.map(|audit_log| audit_log.0).unwrap_or_default())
+        }
+    };
+    (get_vetkey_verification_key; $state:ident, $guard:path;) => {
+        #[::ic_cdk::update]
+        async fn get_vetkey_verification_key() -> $crate::VetKeyVerificationKey {
+            if let ::std::result::Result::Err(error) = $guard("get_vetkey_verification_key") {
+                ::ic_cdk::trap(&error);
+            }
+            $state
+                .with_borrow(|encrypted_maps| encrypted_maps.get_vetkey_verification_key())
+                .await
+                .unwrap_or_else(|error| ::ic_cdk::trap(&error))
+        }
+    };
+    (get_encrypted_vetkey; $state:ident, $guard:path;) => {
+        #[::ic_cdk::update]
+        #[allow(clippy::needless_pass_by_value)]
+        async fn get_encrypted_vetkey(
+            map_owner: ::candid::Principal,
+            map_name: $crate::api::ByteBuf,
+            transport_key: $crate::api::TransportKey,
+        ) -> ::std::result::Result<$crate::VetKey, ::std::string::String> {
+            $guard("get_encrypted_vetkey")?;
+            let map_id = $crate::api::key_id(map_owner, &map_name)?;
+            let derived = $state
+                .with_borrow(|encrypted_maps| {
+                    encrypted_maps.get_encrypted_vetkey(::ic_cdk::caller(), map_id, transport_key)
+                })?
+                .await?;
+            ::std::result::Result::Ok(
+                $state.with_borrow_mut(|encrypted_maps| encrypted_maps.record_vetkey_access(derived)),
+            )
+        }
+    };
+    (get_user_rights; $state:ident, $guard:path;) => {
+        #[::ic_cdk::query]
+        #[allow(clippy::needless_pass_by_value)]
+        fn get_user_rights(
+            map_owner: ::candid::Principal,
+            map_name: $crate::api::ByteBuf,
+            user: ::candid::Principal,
+        ) -> ::std::result::Result<
+            ::std::option::Option<$crate::api::AccessRights>,
+            ::std::string::String,
+        > {
+            $guard("get_user_rights")?;
+            let map_id = $crate::api::key_id(map_owner, &map_name)?;
+            $state.with_borrow(|encrypted_maps| {
+                encrypted_maps.get_user_rights(::ic_cdk::caller(), map_id, user)
+            })
+        }
+    };
+    (set_user_rights; $state:ident, $guard:path;) => {
+        #[::ic_cdk::update]
+        #[allow(clippy::needless_pass_by_value)]
+        fn set_user_rights(
+            map_owner: ::candid::Principal,
+            map_name: $crate::api::ByteBuf,
+            user: ::candid::Principal,
+            access_rights: $crate::api::AccessRights,
+        ) -> ::std::result::Result<
+            ::std::option::Option<$crate::api::AccessRights>,
+            ::std::string::String,
+        > {
+            $guard("set_user_rights")?;
+            let map_id = $crate::api::key_id(map_owner, &map_name)?;
+            $state.with_borrow_mut(|encrypted_maps| {
+                encrypted_maps.set_user_rights(::ic_cdk::caller(), map_id, user, access_rights)
+            })
+        }
+    };
+    (remove_user; $state:ident, $guard:path;) => {
+        #[::ic_cdk::update]
+        #[allow(clippy::needless_pass_by_value)]
+        fn remove_user(
+            map_owner: ::candid::Principal,
+            map_name: $crate::api::ByteBuf,
+            user: ::candid::Principal,
+        ) -> ::std::result::Result<
+            ::std::option::Option<$crate::api::AccessRights>,
+            ::std::string::String,
+        > {
+            $guard("remove_user")?;
+            let map_id = $crate::api::key_id(map_owner, &map_name)?;
+            $state.with_borrow_mut(|encrypted_maps| {
+                encrypted_maps.remove_user(::ic_cdk::caller(), map_id, user)
+            })
+        }
+    };
+}
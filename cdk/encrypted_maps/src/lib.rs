@@ -9,7 +9,8 @@
 //! ## Core Features
 //!
 //! - **Encrypted Key-Value Storage:** Securely store and manage encrypted key-value pairs within named maps.
-//! - **User-Specific Map Access:** Control precisely which users can read or modify entries in an encrypted map.
+//! - **User-Specific Map Access:** Control precisely which users can read, insert, update, delete, restore
+//!   or purge entries in an encrypted map, using the [`Permissions`](ic_vetkd_cdk_types::Permissions) of their `AccessRights`.
 //! - **Integrated Access Control:** Leverages the **`KeyManager`** library to manage and enforce user permissions.
 //! - **Stable Storage:** Utilizes **`StableBTreeMap`** for reliable, persistent storage across canister upgrades.
 //!
@@ -19,21 +20,35 @@
 //!
 //! - **Encrypted Values Storage:** Maps `(KeyId, MapKey)` to `EncryptedMapValue`, securely storing encrypted data.
 //! - **`KeyManager` Integration:** Uses **`KeyManager`** to handle user permissions, ensuring authorized access to maps.
+//!
+//! The standard canister endpoints can be installed with
+//! [`export_encrypted_maps_api`], see [`api`].
 
 use candid::Principal;
-use ic_stable_structures::memory_manager::VirtualMemory;
+use ic_stable_structures::memory_manager::{MemoryManager, VirtualMemory};
 use ic_stable_structures::storable::{Blob, Bound};
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+use ic_stable_structures::{Memory, Storable};
 use std::borrow::Cow;
 use std::cell::RefCell;
 use std::future::Future;
 
-use ic_vetkd_cdk_key_manager::KeyId;
+use ic_vetkd_cdk_key_manager::events::EventSubscriber;
+use ic_vetkd_cdk_key_manager::export::{
+    build_chunk, clear_map_entries, export_map_entries, import_map_entries, ExportChunk,
+    ExportCursor, ExportSection, ExportSummary, ImportSession,
+};
+use ic_vetkd_cdk_key_manager::key_metadata::{KeyMetadata, KeyMetadataLimits, KeyMetadataUpdate};
+use ic_vetkd_cdk_key_manager::layout::{MemoryLayout, StableStructure};
+use ic_vetkd_cdk_key_manager::migration::{LegacyEntries, MigratedStructure, NamedMap};
+use ic_vetkd_cdk_key_manager::policy::{AccessPolicy, AsyncAccessPolicy, Operation};
+use ic_vetkd_cdk_key_manager::{DefaultMemory, DerivedVetKey, KeyId};
 use ic_vetkd_cdk_types::{
-    now, AccessRights, AuditEntry, ByteBuf, EncryptedMapValue, MapId, MapKey, MapName, Rights,
-    TransportKey,
+    decode_versioned, encode_versioned, now, AccessRights, AuditEntry, ByteBuf, EncryptedMapValue,
+    MapId, MapKey, MapName, TransportKey,
 };
 
+pub mod api;
+
 // On a high level,
 // `ENCRYPTED_MAPS[MapName][MapKey] = EncryptedMapValue`, e.g.
 // `ENCRYPTED_MAPS[b"alex's map".into()][b"github API token".into()] = b"secret-api-token-to-be-encrypted".into()`.
@@ -45,8 +60,6 @@ thread_local! {
     static ENCRYPTED_MAPS: RefCell<Option<EncryptedMaps>> = const { RefCell::new(None) };
 }
 
-type Memory = VirtualMemory<DefaultMemoryImpl>;
-
 /// Represents a soft-deleted entry that preserves the data for audit purposes
 #[derive(candid::CandidType, serde::Deserialize, Clone, Debug)]
 pub struct TombstoneEntry {
@@ -60,48 +73,76 @@ pub struct TombstoneEntry {
     pub marked_for_purge: bool,
 }
 
+/// Stored as Candid, prefixed with the encoding version since version 1.
 impl Storable for TombstoneEntry {
     fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).expect("Failed to encode TombstoneEntry"))
+        let payload = candid::encode_one(self).expect("Failed to encode TombstoneEntry");
+        Cow::Owned(encode_versioned(1, &payload))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(bytes.as_ref()).expect("Failed to decode TombstoneEntry")
+        match decode_versioned(bytes.as_ref()) {
+            (0 | 1, payload) => {
+                candid::decode_one(payload).expect("Failed to decode TombstoneEntry")
+            }
+            (version, _) => panic!("unsupported TombstoneEntry encoding version {version}"),
+        }
     }
 
     const BOUND: Bound = Bound::Unbounded;
 }
 
-pub struct EncryptedMaps {
-    pub key_manager: ic_vetkd_cdk_key_manager::KeyManager,
-    pub mapkey_vals: StableBTreeMap<(KeyId, MapKey), EncryptedMapValue, Memory>,
+/// The sections exported by `EncryptedMaps`, in export order.
+pub const ENCRYPTED_MAPS_SECTIONS: [ExportSection; 5] = [
+    ExportSection::AccessControl,
+    ExportSection::SharedKeys,
+    ExportSection::AuditLogs,
+    ExportSection::EncryptedMapValues,
+    ExportSection::Tombstones,
+];
+
+/// Stores encrypted maps in memories of type `M`, see
+/// [`ic_vetkd_cdk_key_manager::DefaultMemory`].
+pub struct EncryptedMaps<M: Memory = DefaultMemory> {
+    pub key_manager: ic_vetkd_cdk_key_manager::KeyManager<M>,
+    pub mapkey_vals: NamedMap<(KeyId, MapKey), EncryptedMapValue, M>,
     /// Storage for soft-deleted entries, allowing audit history to be preserved
-    pub tombstones: StableBTreeMap<(KeyId, MapKey), TombstoneEntry, Memory>,
+    pub tombstones: NamedMap<(KeyId, MapKey), TombstoneEntry, M>,
 }
 
-impl EncryptedMaps {
+impl<M: Memory> EncryptedMaps<M> {
     /// Initializes the `EncryptedMaps` and the underlying `KeyManager`.
     /// Must be called before any other `EncryptedMaps` operations.
+    ///
+    /// If the stored data uses an older schema version, the stored version is
+    /// updated. Data written before schema version 2 is migrated from the
+    /// memories attached with [`Self::with_legacy_memory`], see
+    /// [`ic_vetkd_cdk_key_manager::migration`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if one of the memories holds data written before schema version 2.
     #[must_use]
     pub fn init(
         domain_separator: &str,
-        memory_domain_separator: Memory,
-        memory_access_control: Memory,
-        memory_shared_keys: Memory,
-        memory_encrypted_maps: Memory,
-        memory_tombstones: Memory,
-        memory_audit_log: Option<Memory>,
+        memory_domain_separator: M,
+        memory_access_control: M,
+        memory_shared_keys: M,
+        memory_encrypted_maps: M,
+        memory_tombstones: M,
+        memory_audit_log: Option<M>,
     ) -> Self {
-        let key_manager = ic_vetkd_cdk_key_manager::KeyManager::init(
+        let mut key_manager = ic_vetkd_cdk_key_manager::KeyManager::init(
             domain_separator,
             memory_domain_separator,
             memory_access_control,
             memory_shared_keys,
             memory_audit_log,
         );
-
-        let mapkey_vals = StableBTreeMap::init(memory_encrypted_maps);
-        let tombstones = StableBTreeMap::init(memory_tombstones);
+        let mapkey_vals = key_manager
+            .init_named_map(MigratedStructure::EncryptedMapValues, memory_encrypted_maps);
+        let tombstones =
+            key_manager.init_named_map(MigratedStructure::Tombstones, memory_tombstones);
 
         Self {
             key_manager,
@@ -110,12 +151,364 @@ impl EncryptedMaps {
         }
     }
 
+    /// Attaches the memory `structure` was stored in before schema version 2
+    /// and queues its migration, see [`ic_vetkd_cdk_key_manager::migration`].
+    /// Structures of optional features must be enabled first.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `structure` is not enabled.
+    #[must_use]
+    pub fn with_legacy_memory(mut self, structure: MigratedStructure, memory: M) -> Self {
+        let map: &mut dyn LegacyEntries<M> = match structure {
+            MigratedStructure::EncryptedMapValues => &mut self.mapkey_vals,
+            MigratedStructure::Tombstones => &mut self.tombstones,
+            _ => {
+                self.key_manager = self.key_manager.with_legacy_memory(structure, memory);
+                return self;
+            }
+        };
+        map.attach_legacy_memory(memory);
+        if map.legacy_len() > 0 {
+            self.key_manager.queue_migration(structure);
+        }
+        self
+    }
+
+    /// Registers a custom access policy with the underlying `KeyManager`, see
+    /// [`ic_vetkd_cdk_key_manager::policy`].
+    #[must_use]
+    pub fn with_access_policy(mut self, policy: impl AccessPolicy<M> + 'static) -> Self {
+        self.key_manager = self.key_manager.with_access_policy(policy);
+        self
+    }
+
+    /// Registers a custom async access policy with the underlying `KeyManager`,
+    /// see [`ic_vetkd_cdk_key_manager::policy`].
+    #[must_use]
+    pub fn with_async_access_policy(mut self, policy: impl AsyncAccessPolicy + 'static) -> Self {
+        self.key_manager = self.key_manager.with_async_access_policy(policy);
+        self
+    }
+
+    /// Registers a subscriber that is notified of changes of access rights and
+    /// values, see [`ic_vetkd_cdk_key_manager::events`].
+    #[must_use]
+    pub fn with_event_subscriber(mut self, subscriber: impl EventSubscriber + 'static) -> Self {
+        self.key_manager = self.key_manager.with_event_subscriber(subscriber);
+        self
+    }
+
+    /// Enables token-gated access in the underlying `KeyManager`, see
+    /// [`ic_vetkd_cdk_key_manager::token_gating`].
+    #[must_use]
+    pub fn with_token_gating(mut self, memory_gates: M, memory_owners: M, cache_ttl: u64) -> Self {
+        self.key_manager =
+            self.key_manager
+                .with_token_gating(memory_gates, memory_owners, cache_ttl);
+        self
+    }
+
+    /// Enables emergency freezes of maps in the underlying `KeyManager`, see
+    /// [`ic_vetkd_cdk_key_manager::freeze`].
+    #[must_use]
+    pub fn with_freezing(mut self, memory_keys: M, memory_canister: M) -> Self {
+        self.key_manager = self.key_manager.with_freezing(memory_keys, memory_canister);
+        self
+    }
+
+    /// Enables canister administrators and key recovery in the underlying
+    /// `KeyManager`, see [`ic_vetkd_cdk_key_manager::admin`].
+    #[must_use]
+    pub fn with_admins(
+        mut self,
+        memory_admins: M,
+        memory_recoveries: M,
+        memory_recovery_principals: M,
+        memory_recoveries_by_key: M,
+        recovery_delay: u64,
+    ) -> Self {
+        self.key_manager = self.key_manager.with_admins(
+            memory_admins,
+            memory_recoveries,
+            memory_recovery_principals,
+            memory_recoveries_by_key,
+            recovery_delay,
+        );
+        self
+    }
+
+    /// Enables authorization checks for allow-listed canisters in the
+    /// underlying `KeyManager`, see [`ic_vetkd_cdk_key_manager::authorization`].
+    #[must_use]
+    pub fn with_authorized_canisters(mut self, memory: M) -> Self {
+        self.key_manager = self.key_manager.with_authorized_canisters(memory);
+        self
+    }
+
+    /// Enables metadata records of maps in the underlying `KeyManager`, see
+    /// [`ic_vetkd_cdk_key_manager::key_metadata`].
+    #[must_use]
+    pub fn with_key_metadata(mut self, memory: M, limits: KeyMetadataLimits) -> Self {
+        self.key_manager = self.key_manager.with_key_metadata(memory, limits);
+        self
+    }
+
+    /// Enables the registry of owned maps in the underlying `KeyManager`, see
+    /// [`ic_vetkd_cdk_key_manager::lifecycle`].
+    #[must_use]
+    pub fn with_key_lifecycle(mut self, memory: M) -> Self {
+        self.key_manager = self.key_manager.with_key_lifecycle(memory);
+        self
+    }
+
+    /// Migrates up to `limit` entries of the stored maps and of the underlying
+    /// `KeyManager`. Maps whose legacy memory is not attached are skipped.
+    /// Returns the number of migrated entries.
+    pub fn run_migration_batch(&mut self, limit: usize) -> usize {
+        let mut migrated = 0;
+        for structure in [
+            MigratedStructure::EncryptedMapValues,
+            MigratedStructure::Tombstones,
+        ] {
+            if migrated >= limit || !self.key_manager.is_migration_pending(structure) {
+                continue;
+            }
+            let map: &mut dyn LegacyEntries<M> = if structure == MigratedStructure::Tombstones {
+                &mut self.tombstones
+            } else {
+                &mut self.mapkey_vals
+            };
+            let Some(count) = map.migrate_map_batch(limit - migrated) else {
+                continue;
+            };
+            migrated += count;
+            if map.legacy_len() == 0 {
+                self.key_manager.complete_migration(structure);
+            }
+        }
+        if migrated < limit {
+            migrated += self.key_manager.run_migration_batch(limit - migrated);
+        }
+        migrated
+    }
+
+    /// Returns true if no migrations are pending.
+    #[must_use]
+    pub fn is_migration_complete(&self) -> bool {
+        self.key_manager.is_migration_complete()
+    }
+
+    /// Describes an export of the stored maps and of the underlying
+    /// `KeyManager`'s state, see [`ic_vetkd_cdk_key_manager::export`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caller is not an admin or a migration is in
+    /// progress.
+    pub fn export_summary(&self, caller: Principal) -> Result<ExportSummary, String> {
+        self.key_manager.ensure_admin(caller)?;
+        self.key_manager.ensure_migration_complete()?;
+        ExportSummary::new(
+            self.key_manager.domain_separator(),
+            &ENCRYPTED_MAPS_SECTIONS,
+            |section| self.stored_entries(section),
+            |section, after, limit| self.export_entries(section, after, limit),
+        )
+    }
+
+    /// Exports the chunk at `cursor`, or the first chunk if `cursor` is `None`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caller is not an admin, a migration is in
+    /// progress, or the cursor or `max_entries` is invalid.
+    pub fn export_chunk(
+        &self,
+        caller: Principal,
+        cursor: Option<ExportCursor>,
+        max_entries: usize,
+    ) -> Result<ExportChunk, String> {
+        self.key_manager.ensure_admin(caller)?;
+        self.key_manager.ensure_migration_complete()?;
+        build_chunk(
+            &ENCRYPTED_MAPS_SECTIONS,
+            cursor,
+            max_entries,
+            |section, after, limit| self.export_entries(section, after, limit),
+        )
+    }
+
+    /// Starts an import of the export described by `summary`, replacing an
+    /// unfinished import.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caller is not an admin, a migration is in
+    /// progress, the summary does not describe an `EncryptedMaps` export with
+    /// the same domain separator, or data is already stored.
+    pub fn begin_import(
+        &mut self,
+        caller: Principal,
+        summary: ExportSummary,
+    ) -> Result<(), String> {
+        self.key_manager.ensure_admin(caller)?;
+        self.key_manager.ensure_migration_complete()?;
+        let session = ImportSession::new(
+            summary,
+            self.key_manager.domain_separator(),
+            &ENCRYPTED_MAPS_SECTIONS,
+        )?;
+        if ENCRYPTED_MAPS_SECTIONS
+            .iter()
+            .any(|section| self.stored_entries(*section) > 0)
+        {
+            return Err("import target is not empty".to_string());
+        }
+        self.key_manager.set_import_session(Some(session));
+        Ok(())
+    }
+
+    /// Verifies and stores the next chunk of the import.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caller is not an admin, a migration is in
+    /// progress, no import was started, or the chunk does not continue the export.
+    pub fn import_chunk(&mut self, caller: Principal, chunk: ExportChunk) -> Result<(), String> {
+        self.key_manager.ensure_admin(caller)?;
+        self.key_manager.ensure_migration_complete()?;
+        let mut session = self
+            .key_manager
+            .import_session()
+            .ok_or_else(|| "no import in progress".to_string())?;
+        session.accept(&chunk)?;
+        match chunk.section {
+            ExportSection::EncryptedMapValues => {
+                import_map_entries(self.mapkey_vals.current_mut(), chunk.entries);
+            }
+            ExportSection::Tombstones => {
+                import_map_entries(self.tombstones.current_mut(), chunk.entries);
+            }
+            section => self.key_manager.import_entries(section, chunk.entries)?,
+        }
+        self.key_manager.set_import_session(Some(session));
+        Ok(())
+    }
+
+    /// Completes the import after checking its consistency, see
+    /// [`ic_vetkd_cdk_key_manager::export`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caller is not an admin, no import was started,
+    /// or the import is incomplete or inconsistent.
+    pub fn finish_import(&mut self, caller: Principal) -> Result<(), String> {
+        self.key_manager.ensure_admin(caller)?;
+        let session = self
+            .key_manager
+            .import_session()
+            .ok_or_else(|| "no import in progress".to_string())?;
+        session.finish(|section| self.stored_entries(section))?;
+        self.key_manager.set_import_session(None);
+        Ok(())
+    }
+
+    /// Ends the import in progress and removes the entries imported so far,
+    /// see [`ic_vetkd_cdk_key_manager::KeyManager::abort_import`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caller is not an admin or no import was started.
+    pub fn abort_import(&mut self, caller: Principal) -> Result<(), String> {
+        self.key_manager.ensure_admin(caller)?;
+        if self.key_manager.import_session().is_none() {
+            return Err("no import in progress".to_string());
+        }
+        for section in ENCRYPTED_MAPS_SECTIONS {
+            match section {
+                ExportSection::EncryptedMapValues => {
+                    clear_map_entries(self.mapkey_vals.current_mut());
+                }
+                ExportSection::Tombstones => clear_map_entries(self.tombstones.current_mut()),
+                section => self.key_manager.clear_entries(section)?,
+            }
+        }
+        self.key_manager.set_import_session(None);
+        Ok(())
+    }
+
+    fn export_entries(
+        &self,
+        section: ExportSection,
+        after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<(Vec<(serde_bytes::ByteBuf, serde_bytes::ByteBuf)>, bool), String> {
+        match section {
+            ExportSection::EncryptedMapValues => {
+                Ok(export_map_entries(self.mapkey_vals.current(), after, limit))
+            }
+            ExportSection::Tombstones => {
+                Ok(export_map_entries(self.tombstones.current(), after, limit))
+            }
+            _ => self.key_manager.export_entries(section, after, limit),
+        }
+    }
+
+    fn stored_entries(&self, section: ExportSection) -> u64 {
+        match section {
+            ExportSection::EncryptedMapValues => self.mapkey_vals.len(),
+            ExportSection::Tombstones => self.tombstones.len(),
+            _ => self.key_manager.stored_entries(section),
+        }
+    }
+
     /// Lists all map names shared with the caller.
     #[must_use]
     pub fn get_accessible_shared_map_names(&self, caller: Principal) -> Vec<KeyId> {
         self.key_manager.get_accessible_shared_key_ids(caller)
     }
 
+    /// Lists all map names shared with the caller together with the metadata
+    /// records of the maps.
+    #[must_use]
+    pub fn get_accessible_shared_maps_with_metadata(
+        &self,
+        caller: Principal,
+    ) -> Vec<(KeyId, Option<KeyMetadata>)> {
+        self.key_manager
+            .get_accessible_shared_keys_with_metadata(caller)
+    }
+
+    /// Retrieves the metadata record of a map, if one was created.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if key metadata is not enabled or if the caller has no
+    /// valid grant for the map.
+    pub fn get_map_metadata(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+    ) -> Result<Option<KeyMetadata>, String> {
+        self.key_manager.get_key_metadata(caller, key_id)
+    }
+
+    /// Updates the metadata record of a map.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if key metadata is not enabled, if the caller doesn't
+    /// have manage permission for the map or if the update is too large.
+    pub fn set_map_metadata(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        update: KeyMetadataUpdate,
+    ) -> Result<KeyMetadata, String> {
+        self.key_manager.set_key_metadata(caller, key_id, update)
+    }
+
     /// Retrieves all users and their access rights for a specific map.
     ///
     /// # Errors
@@ -130,12 +523,77 @@ impl EncryptedMaps {
             .get_shared_user_access_for_key(caller, key_id)
     }
 
+    /// Creates a map owned by the caller and returns its ID.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key lifecycle is not enabled, if the caller is
+    /// anonymous or if the map already exists.
+    pub fn create_map(&mut self, caller: Principal, map_name: MapName) -> Result<KeyId, String> {
+        self.key_manager.create_key(caller, map_name)
+    }
+
+    /// Lists the maps owned by the caller: registered maps, maps the caller
+    /// has shared and maps that contain values.
+    #[must_use]
+    pub fn list_owned_maps(&self, caller: Principal) -> Vec<KeyId> {
+        let mut map_ids: std::collections::BTreeSet<KeyId> = self
+            .key_manager
+            .list_owned_keys(caller)
+            .into_iter()
+            .collect();
+        map_ids.extend(
+            self.get_owned_non_empty_map_names(caller)
+                .into_iter()
+                .map(|map_name| (caller, map_name)),
+        );
+        map_ids.into_iter().collect()
+    }
+
+    /// Deletes a map: revokes all grants, see
+    /// [`ic_vetkd_cdk_key_manager::KeyManager::delete_key`], and, if
+    /// `remove_values` is true, removes all values and tombstones of the map
+    /// without notifying subscribers of the individual values. Returns the
+    /// users whose grants were revoked.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caller is not the owner of the map or may not
+    /// manage it.
+    pub fn delete_map(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        remove_values: bool,
+    ) -> Result<Vec<Principal>, String> {
+        let revoked = self.key_manager.delete_key(caller, key_id)?;
+        if remove_values {
+            let map_keys: Vec<_> = self
+                .mapkey_vals
+                .keys_range((key_id, Blob::default())..)
+                .take_while(|(k, _)| k == &key_id)
+                .collect();
+            for map_key in map_keys {
+                self.mapkey_vals.remove(&map_key);
+            }
+            let tombstone_keys: Vec<_> = self
+                .tombstones
+                .keys_range((key_id, Blob::default())..)
+                .take_while(|(k, _)| k == &key_id)
+                .collect();
+            for tombstone_key in tombstone_keys {
+                self.tombstones.remove(&tombstone_key);
+            }
+        }
+        Ok(revoked)
+    }
+
     /// Removes all values from a map if the caller has sufficient rights.
     /// Returns the removed keys.
     ///
     /// # Errors
     ///
-    /// Returns an error if the caller doesn't have write permission for the map.
+    /// Returns an error if the caller doesn't have the `DELETE` permission for the map.
     /// Removes all values from a map.
     ///
     /// If `soft_delete` is true, the entries will be preserved as tombstones for audit purposes.
@@ -146,25 +604,8 @@ impl EncryptedMaps {
         key_id: KeyId,
         soft_delete: bool,
     ) -> Result<Vec<MapKey>, String> {
-        let access_rights = match self.key_manager.get_user_rights(caller, key_id, caller)? {
-            Some(rights) => match rights.rights() {
-                Rights::ReadWrite | Rights::ReadWriteManage => {
-                    if let Some(start) = rights.start() {
-                        if start < now() {
-                            return Err("unauthorized".to_string());
-                        }
-                    }
-                    if let Some(end) = rights.end() {
-                        if end >= now() {
-                            return Err("unauthorized".to_string());
-                        }
-                    }
-                    rights
-                }
-                Rights::Read => return Err("unauthorized".to_string()),
-            },
-            _ => return Err("unauthorized".to_string()),
-        };
+        self.key_manager
+            .authorize(caller, key_id, Operation::Delete)?;
 
         // First, collect all the keys and values to avoid borrowing issues
         let key_values: Vec<_> = self
@@ -197,8 +638,11 @@ impl EncryptedMaps {
             }
 
             // Now remove all the values
-            for (key, _) in &key_values {
+            for (key, value) in &key_values {
                 self.mapkey_vals.remove(&(key_id, *key));
+                self.key_manager.notify_subscribers(|subscriber| {
+                    subscriber.on_value_removed(caller, key_id, *key, value, soft_delete);
+                });
             }
         }
 
@@ -219,13 +663,13 @@ impl EncryptedMaps {
     ///
     /// # Errors
     ///
-    /// Returns an error if the caller does not have access rights to the map.
+    /// Returns an error if the caller does not have the `READ_VALUES` permission for the map.
     pub fn get_tombstones_for_map(
         &self,
         caller: Principal,
         key_id: KeyId,
     ) -> Result<Vec<(MapKey, TombstoneEntry)>, String> {
-        self.key_manager.get_user_rights(caller, key_id, caller)?;
+        self.ensure_user_can_read_values(caller, key_id)?;
 
         Ok(self
             .tombstones
@@ -240,7 +684,7 @@ impl EncryptedMaps {
     /// # Errors
     ///
     /// Returns an error if:
-    /// - The caller does not have write access to the map
+    /// - The caller does not have the `RESTORE` permission for the map
     /// - The specified key does not exist in the tombstones
     pub fn restore_value(
         &mut self,
@@ -248,25 +692,8 @@ impl EncryptedMaps {
         key_id: KeyId,
         key: MapKey,
     ) -> Result<Option<EncryptedMapValue>, String> {
-        let access_rights = match self.key_manager.get_user_rights(caller, key_id, caller)? {
-            Some(rights) => match rights.rights() {
-                Rights::ReadWrite | Rights::ReadWriteManage => {
-                    if let Some(start) = rights.start() {
-                        if start < now() {
-                            return Err("unauthorized".to_string());
-                        }
-                    }
-                    if let Some(end) = rights.end() {
-                        if end >= now() {
-                            return Err("unauthorized".to_string());
-                        }
-                    }
-                    rights
-                }
-                Rights::Read => return Err("unauthorized".to_string()),
-            },
-            None => return Err("unauthorized".to_string()),
-        };
+        self.key_manager
+            .authorize(caller, key_id, Operation::Restore)?;
 
         // Check if the tombstone exists
         if let Some(tombstone) = self.tombstones.get(&(key_id, key)) {
@@ -282,6 +709,9 @@ impl EncryptedMaps {
             // Log the restoration
             self.key_manager
                 .add_audit_log(key_id, move || AuditEntry::restored(now(), caller));
+            self.key_manager.notify_subscribers(|subscriber| {
+                subscriber.on_value_restored(caller, key_id, key, &value);
+            });
 
             Ok(Some(value))
         } else {
@@ -294,7 +724,7 @@ impl EncryptedMaps {
     /// # Errors
     ///
     /// Returns an error if:
-    /// - The caller does not have manage access to the map
+    /// - The caller does not have the `PURGE` permission for the map
     /// - The specified key does not exist in the tombstones
     pub fn purge_tombstone(
         &mut self,
@@ -302,8 +732,8 @@ impl EncryptedMaps {
         key_id: KeyId,
         key: MapKey,
     ) -> Result<Option<TombstoneEntry>, String> {
-        // Check for management rights
-        match self.key_manager.ensure_user_can_manage(caller, key_id) {
+        // Check for purge rights
+        match self.key_manager.authorize(caller, key_id, Operation::Purge) {
             Ok(_) => {
                 // Log the permanent deletion
                 if self.tombstones.contains_key(&(key_id, key)) {
@@ -322,13 +752,13 @@ impl EncryptedMaps {
     ///
     /// # Errors
     ///
-    /// Returns an error if the caller does not have access rights to the map.
+    /// Returns an error if the caller does not have the `READ_VALUES` permission for the map.
     pub fn get_encrypted_values_for_map(
         &self,
         caller: Principal,
         key_id: KeyId,
     ) -> Result<Vec<(MapKey, EncryptedMapValue)>, String> {
-        self.key_manager.get_user_rights(caller, key_id, caller)?;
+        self.ensure_user_can_read_values(caller, key_id)?;
 
         Ok(self
             .mapkey_vals
@@ -342,23 +772,28 @@ impl EncryptedMaps {
     ///
     /// # Errors
     ///
-    /// Returns an error if the caller does not have access rights to the map.
+    /// Returns an error if the caller does not have the `READ_VALUES` permission for the map.
     pub fn get_encrypted_value(
         &self,
         caller: Principal,
         key_id: KeyId,
         key: MapKey,
     ) -> Result<Option<EncryptedMapValue>, String> {
-        self.key_manager
-            .get_user_rights(caller, key_id, caller)
+        self.ensure_user_can_read_values(caller, key_id)
             .map(|_| self.mapkey_vals.get(&(key_id, key)))
     }
 
-    /// Retrieves the non-empty map names owned by the caller.
-    ///
-    /// # Panics
-    ///
-    /// Will panic if there's an error retrieving values for a map that the user should have access to.
+    /// Ensures that the caller may read the values of a map.
+    fn ensure_user_can_read_values(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+    ) -> Result<AccessRights, String> {
+        self.key_manager
+            .authorize(caller, key_id, Operation::ReadValues)
+    }
+
+    /// Retrieves the values of all maps the caller can read values of.
     #[must_use]
     pub fn get_all_accessible_encrypted_values(
         &self,
@@ -366,24 +801,22 @@ impl EncryptedMaps {
     ) -> Vec<(MapId, Vec<(MapKey, EncryptedMapValue)>)> {
         let mut result = Vec::new();
         for map_id in self.get_accessible_map_ids_iter(caller) {
-            let map_values = self.get_encrypted_values_for_map(caller, map_id).unwrap();
-            result.push((map_id, map_values));
+            if let Ok(map_values) = self.get_encrypted_values_for_map(caller, map_id) {
+                result.push((map_id, map_values));
+            }
         }
         result
     }
 
-    /// Retrieves all accessible encrypted maps for a caller.
-    ///
-    /// # Panics
-    ///
-    /// Will panic if there's an error retrieving values for a map that the user should have access to.
+    /// Retrieves all encrypted maps the caller can read values of.
     #[must_use]
     pub fn get_all_accessible_encrypted_maps(&self, caller: Principal) -> Vec<EncryptedMapData> {
         let mut result = Vec::new();
         for map_id in self.get_accessible_map_ids_iter(caller) {
-            let keyvals = self
-                .get_encrypted_values_for_map(caller, map_id)
-                .unwrap()
+            let Ok(map_values) = self.get_encrypted_values_for_map(caller, map_id) else {
+                continue;
+            };
+            let keyvals = map_values
                 .into_iter()
                 .map(|(key, value)| (ByteBuf::from(key.as_ref().to_vec()), value))
                 .collect();
@@ -414,7 +847,7 @@ impl EncryptedMaps {
     ///
     /// # Panics
     ///
-    /// Panics if a map name cannot be converted to a `Blob<32>`.
+    /// Panics if a map name cannot be converted to a `MapName`.
     #[must_use]
     pub fn get_owned_non_empty_map_names(&self, caller: Principal) -> Vec<MapName> {
         let map_names: std::collections::HashSet<Vec<u8>> = self
@@ -425,7 +858,7 @@ impl EncryptedMaps {
             .collect();
         map_names
             .into_iter()
-            .map(|map_name| Blob::<32>::try_from(map_name.as_slice()).unwrap())
+            .map(|map_name| MapName::try_from(map_name.as_slice()).unwrap())
             .collect()
     }
 
@@ -433,7 +866,8 @@ impl EncryptedMaps {
     ///
     /// # Errors
     ///
-    /// Returns an error if the caller does not have write access to the map.
+    /// Returns an error if the caller does not have the `INSERT` permission for
+    /// a new value or the `UPDATE` permission for an existing one.
     pub fn insert_encrypted_value(
         &mut self,
         caller: Principal,
@@ -441,40 +875,46 @@ impl EncryptedMaps {
         key: MapKey,
         encrypted_value: EncryptedMapValue,
     ) -> Result<Option<EncryptedMapValue>, String> {
-        let access_rights = match self.key_manager.get_user_rights(caller, key_id, caller)? {
-            Some(rights) => match rights.rights() {
-                Rights::ReadWrite | Rights::ReadWriteManage => {
-                    if let Some(start) = rights.start() {
-                        if start < now() {
-                            return Err("unauthorized".to_string());
-                        }
-                    }
-                    if let Some(end) = rights.end() {
-                        if end >= now() {
-                            return Err("unauthorized".to_string());
-                        }
-                    }
-                    rights
-                }
-                Rights::Read => return Err("unauthorized".to_string()),
-            },
-            None => return Err("unauthorized".to_string()),
+        // Check if this is an update or a creation, which require different permissions
+        let previous_value = self.mapkey_vals.get(&(key_id, key));
+        let required_operation = if previous_value.is_none() {
+            Operation::Insert
+        } else {
+            Operation::Update
         };
+        self.key_manager
+            .authorize(caller, key_id, required_operation)?;
 
-        // Check if this is an update or a creation
-        let previous_value = self.mapkey_vals.get(&(key_id, key));
-        let result = self.mapkey_vals.insert((key_id, key), encrypted_value);
+        let result = self
+            .mapkey_vals
+            .insert((key_id, key), encrypted_value.clone());
 
         // Log an audit event - if it's a new value, we'll log a creation,
         // otherwise we'll log an update
-        if previous_value.is_none() {
-            // This is a new value being created
-            self.key_manager
-                .add_audit_log(key_id, move || AuditEntry::created(now(), caller));
-        } else {
-            // This is an update to an existing value
-            self.key_manager
-                .add_audit_log(key_id, move || AuditEntry::updated(now(), caller));
+        match &previous_value {
+            None => {
+                // This is a new value being created
+                self.key_manager
+                    .add_audit_log(key_id, move || AuditEntry::created(now(), caller));
+                self.key_manager.record_key_creation(caller, key_id);
+                self.key_manager.notify_subscribers(|subscriber| {
+                    subscriber.on_value_inserted(caller, key_id, key, &encrypted_value);
+                });
+            }
+            Some(previous_value) => {
+                // This is an update to an existing value
+                self.key_manager
+                    .add_audit_log(key_id, move || AuditEntry::updated(now(), caller));
+                self.key_manager.notify_subscribers(|subscriber| {
+                    subscriber.on_value_updated(
+                        caller,
+                        key_id,
+                        key,
+                        previous_value,
+                        &encrypted_value,
+                    );
+                });
+            }
         }
 
         Ok(result)
@@ -484,7 +924,7 @@ impl EncryptedMaps {
     ///
     /// # Errors
     ///
-    /// Returns an error if the caller does not have write access to the map.
+    /// Returns an error if the caller does not have the `DELETE` permission for the map.
     /// Removes an encrypted value and moves it to tombstones for audit purposes.
     ///
     /// If `hard_delete` is true, the entry will be completely removed.
@@ -496,25 +936,8 @@ impl EncryptedMaps {
         key: MapKey,
         hard_delete: bool,
     ) -> Result<Option<EncryptedMapValue>, String> {
-        let access_rights = match self.key_manager.get_user_rights(caller, key_id, caller)? {
-            Some(rights) => match rights.rights() {
-                Rights::ReadWrite | Rights::ReadWriteManage => {
-                    if let Some(start) = rights.start() {
-                        if start < now() {
-                            return Err("unauthorized".to_string());
-                        }
-                    }
-                    if let Some(end) = rights.end() {
-                        if end >= now() {
-                            return Err("unauthorized".to_string());
-                        }
-                    }
-                    rights
-                }
-                Rights::Read => return Err("unauthorized".to_string()),
-            },
-            None => return Err("unauthorized".to_string()),
-        };
+        self.key_manager
+            .authorize(caller, key_id, Operation::Delete)?;
 
         // Get the value to be removed
         let value = self.mapkey_vals.get(&(key_id, key));
@@ -544,6 +967,9 @@ impl EncryptedMaps {
 
             // Now remove the actual entry
             let result = self.mapkey_vals.remove(&(key_id, key));
+            self.key_manager.notify_subscribers(|subscriber| {
+                subscriber.on_value_removed(caller, key_id, key, &value, !hard_delete);
+            });
             Ok(result)
         } else {
             Ok(None)
@@ -564,7 +990,7 @@ impl EncryptedMaps {
     /// Retrieves the public verification key from `KeyManager`.
     pub fn get_vetkey_verification_key(
         &self,
-    ) -> impl Future<Output = VetKeyVerificationKey> + Send + Sync {
+    ) -> impl Future<Output = Result<VetKeyVerificationKey, String>> + Send + Sync {
         self.key_manager.get_vetkey_verification_key()
     }
 
@@ -573,16 +999,25 @@ impl EncryptedMaps {
     /// # Errors
     ///
     /// Returns an error if the caller doesn't have read permission for the key.
+    /// The returned future resolves to an error if an async access policy
+    /// denies the access or the key derivation fails. The access is audited
+    /// by [`Self::record_vetkey_access`].
     pub fn get_encrypted_vetkey(
-        &mut self,
+        &self,
         caller: Principal,
         key_id: KeyId,
         transport_key: TransportKey,
-    ) -> Result<impl Future<Output = VetKey> + Send + Sync, String> {
+    ) -> Result<impl Future<Output = Result<DerivedVetKey, String>> + Send + Sync, String> {
         self.key_manager
             .get_encrypted_vetkey(caller, key_id, transport_key)
     }
 
+    /// Records the access to a derived encrypted vetkey in the audit log and
+    /// returns the key, see [`KeyManager::record_vetkey_access`].
+    pub fn record_vetkey_access(&mut self, derived: DerivedVetKey) -> VetKey {
+        self.key_manager.record_vetkey_access(derived)
+    }
+
     /// Retrieves access rights for a user to a map.
     ///
     /// # Errors
@@ -628,6 +1063,48 @@ impl EncryptedMaps {
     }
 }
 
+impl<M: Memory> EncryptedMaps<VirtualMemory<M>> {
+    /// Initializes the `EncryptedMaps` with the memories that `layout` assigns
+    /// to the stored values and tombstones and to the structures of the
+    /// underlying `KeyManager`, see [`ic_vetkd_cdk_key_manager::layout`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the layout is invalid, e.g., if two structures share a memory
+    /// id, or lacks one of the required structures.
+    #[must_use]
+    pub fn init_with_layout(
+        domain_separator: &str,
+        memory_manager: &MemoryManager<M>,
+        layout: &MemoryLayout,
+    ) -> Self {
+        layout.assert_valid();
+        Self::init(
+            domain_separator,
+            layout.memory(memory_manager, StableStructure::Metadata),
+            layout.memory(memory_manager, StableStructure::AccessControl),
+            layout.memory(memory_manager, StableStructure::SharedKeys),
+            layout.memory(memory_manager, StableStructure::EncryptedMapValues),
+            layout.memory(memory_manager, StableStructure::Tombstones),
+            layout.optional_memory(memory_manager, StableStructure::AuditLogs),
+        )
+    }
+}
+
+/// Allows installing the `KeyManager` endpoints, e.g., with
+/// [`ic_vetkd_cdk_key_manager::export_authorization_api`], for `EncryptedMaps`.
+impl<M: Memory> AsRef<ic_vetkd_cdk_key_manager::KeyManager<M>> for EncryptedMaps<M> {
+    fn as_ref(&self) -> &ic_vetkd_cdk_key_manager::KeyManager<M> {
+        &self.key_manager
+    }
+}
+
+impl<M: Memory> AsMut<ic_vetkd_cdk_key_manager::KeyManager<M>> for EncryptedMaps<M> {
+    fn as_mut(&mut self) -> &mut ic_vetkd_cdk_key_manager::KeyManager<M> {
+        &mut self.key_manager
+    }
+}
+
 #[derive(serde::Deserialize, candid::CandidType)]
 pub struct EncryptedMapData {
     pub map_owner: Principal,
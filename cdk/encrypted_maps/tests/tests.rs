@@ -1,15 +1,16 @@
 use std::{
+    cell::RefCell,
     collections::{BTreeMap, HashSet},
     iter::FromIterator,
     mem::transmute_copy,
+    rc::Rc,
 };
 
 use assert_matches::assert_matches;
 use candid::Principal;
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager},
-    storable::Blob,
-    DefaultMemoryImpl,
+    DefaultMemoryImpl, VectorMemory,
 };
 use ic_vetkd_cdk_test_utils::{
     random_access_rights, random_bytebuf, random_key, random_name,
@@ -17,9 +18,17 @@ use ic_vetkd_cdk_test_utils::{
     reproducible_rng,
 };
 use rand::{CryptoRng, Rng};
+use strum::IntoEnumIterator;
 
-use ic_vetkd_cdk_encrypted_maps::EncryptedMaps;
-use ic_vetkd_cdk_types::AccessRights;
+use ic_vetkd_cdk_encrypted_maps::{api, EncryptedMaps};
+use ic_vetkd_cdk_key_manager::{
+    events::EventSubscriber,
+    layout::{MemoryLayout, StableStructure},
+    KeyId,
+};
+use ic_vetkd_cdk_types::{
+    AccessRights, AuditEntryType, ByteBuf, MapKey, MapName, Permissions, Rights, MAX_NAME_BYTES,
+};
 
 #[test]
 fn can_init_memory() {
@@ -384,6 +393,131 @@ fn remove_of_key_from_map_by_unauthorized_fails() {
     );
 }
 
+#[test]
+fn fine_grained_permissions_are_enforced() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let map_id = (owner, random_name(rng));
+    let key = random_key(rng);
+    let value = random_bytebuf(rng, 0..100);
+    let mut encrypted_maps = random_encrypted_maps(rng);
+
+    encrypted_maps
+        .set_user_rights(
+            owner,
+            map_id,
+            user,
+            AccessRights::with_permissions(
+                Permissions::INSERT | Permissions::READ_VALUES,
+                None,
+                None,
+            ),
+        )
+        .unwrap();
+
+    assert_eq!(
+        encrypted_maps.insert_encrypted_value(user, map_id, key, value.clone()),
+        Ok(None)
+    );
+    assert_eq!(
+        encrypted_maps.insert_encrypted_value(user, map_id, key, value.clone()),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        encrypted_maps.remove_encrypted_value(user, map_id, key, false),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        encrypted_maps.remove_map_values(user, map_id, true),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        encrypted_maps.get_encrypted_value(user, map_id, key),
+        Ok(Some(value.clone()))
+    );
+
+    encrypted_maps
+        .set_user_rights(
+            owner,
+            map_id,
+            user,
+            AccessRights::with_permissions(Permissions::DELETE, None, None),
+        )
+        .unwrap();
+
+    assert_eq!(
+        encrypted_maps.get_encrypted_value(user, map_id, key),
+        Err("unauthorized".to_string())
+    );
+    assert!(encrypted_maps
+        .get_all_accessible_encrypted_values(user)
+        .is_empty());
+    assert_eq!(
+        encrypted_maps.remove_encrypted_value(user, map_id, key, false),
+        Ok(Some(value))
+    );
+    assert_eq!(
+        encrypted_maps.restore_value(user, map_id, key),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        encrypted_maps.purge_tombstone(user, map_id, key),
+        Err("unauthorized".to_string())
+    );
+}
+
+#[test]
+fn time_limited_write_grants_are_honored() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let map_id = (owner, random_name(rng));
+    let mut encrypted_maps = random_encrypted_maps(rng);
+
+    let access_rights = AccessRights::new(Rights::ReadWriteManage, Some(1000), Some(2000));
+    encrypted_maps
+        .set_user_rights(owner, map_id, user, access_rights)
+        .unwrap();
+    let key = random_key(rng);
+
+    for (now, authorized) in [(999, false), (1000, true), (1999, true), (2000, false)] {
+        ic_vetkd_cdk_types::set_mock_now(now);
+        let value = random_bytebuf(rng, 0..100);
+        assert_eq!(
+            encrypted_maps
+                .insert_encrypted_value(user, map_id, key, value)
+                .is_ok(),
+            authorized,
+            "insert at {now}"
+        );
+        assert_eq!(
+            encrypted_maps
+                .remove_encrypted_value(user, map_id, key, false)
+                .is_ok(),
+            authorized,
+            "remove at {now}"
+        );
+        assert_eq!(
+            encrypted_maps.restore_value(user, map_id, key).is_ok(),
+            authorized,
+            "restore at {now}"
+        );
+        assert_eq!(
+            encrypted_maps
+                .set_user_rights(
+                    user,
+                    map_id,
+                    random_self_authenticating_principal(rng),
+                    AccessRights::read_only()
+                )
+                .is_ok(),
+            authorized,
+            "share at {now}"
+        );
+    }
+}
+
 #[test]
 fn can_access_map_values() {
     let rng = &mut reproducible_rng();
@@ -445,14 +579,11 @@ fn can_access_map_values() {
             all_maps
                 .iter()
                 .map(|m| (
-                    (
-                        m.map_owner,
-                        Blob::<32>::try_from(m.map_name.as_ref()).unwrap()
-                    ),
+                    (m.map_owner, MapName::try_from(m.map_name.as_ref()).unwrap()),
                     m.keyvals
                         .iter()
                         .map(|(map_key, value)| (
-                            Blob::<32>::try_from(map_key.as_ref()).unwrap(),
+                            MapKey::try_from(map_key.as_ref()).unwrap(),
                             value.clone()
                         ))
                         .collect::<Vec<_>>()
@@ -590,6 +721,341 @@ fn can_get_owned_map_names() {
     }
 }
 
+#[test]
+fn maps_can_be_deleted_with_their_values() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let mut encrypted_maps = random_encrypted_maps(rng);
+    let kept_map_id = (owner, random_name(rng));
+    let deleted_map_id = (owner, random_name(rng));
+
+    for map_id in [kept_map_id, deleted_map_id] {
+        for _ in 0..3 {
+            encrypted_maps
+                .insert_encrypted_value(owner, map_id, random_key(rng), random_bytebuf(rng, 0..100))
+                .unwrap();
+        }
+        encrypted_maps
+            .set_user_rights(owner, map_id, user, AccessRights::read_write())
+            .unwrap();
+    }
+    let map_key = random_key(rng);
+    encrypted_maps
+        .insert_encrypted_value(owner, deleted_map_id, map_key, random_bytebuf(rng, 0..100))
+        .unwrap();
+    encrypted_maps
+        .remove_encrypted_value(owner, deleted_map_id, map_key, false)
+        .unwrap();
+    assert_eq!(encrypted_maps.list_owned_maps(owner).len(), 2);
+
+    assert_eq!(
+        encrypted_maps.delete_map(user, deleted_map_id, true),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        encrypted_maps.delete_map(owner, deleted_map_id, true),
+        Ok(vec![user])
+    );
+    assert_eq!(
+        encrypted_maps.get_encrypted_values_for_map(owner, deleted_map_id),
+        Ok(vec![])
+    );
+    assert!(encrypted_maps
+        .get_tombstones_for_map(owner, deleted_map_id)
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        encrypted_maps.get_accessible_shared_map_names(user),
+        vec![kept_map_id]
+    );
+    assert_eq!(encrypted_maps.list_owned_maps(owner), vec![kept_map_id]);
+    assert_eq!(
+        encrypted_maps
+            .get_encrypted_values_for_map(owner, kept_map_id)
+            .unwrap()
+            .len(),
+        3
+    );
+}
+
+#[test]
+fn maps_can_be_exported_and_imported() {
+    let rng = &mut reproducible_rng();
+    let admin = random_self_authenticating_principal(rng);
+    let owner = random_self_authenticating_principal(rng);
+    let map_id = (owner, random_name(rng));
+    let mut source = encrypted_maps_with_admins("maps");
+    source.key_manager.init_admins([admin]);
+    let keys: Vec<_> = (0..5).map(|_| random_key(rng)).collect();
+    for key in &keys {
+        source
+            .insert_encrypted_value(owner, map_id, *key, random_bytebuf(rng, 0..100))
+            .unwrap();
+    }
+    source
+        .remove_encrypted_value(owner, map_id, keys[0], false)
+        .unwrap();
+
+    let summary = source.export_summary(admin).unwrap();
+    let mut target = encrypted_maps_with_admins("maps");
+    target.key_manager.init_admins([admin]);
+    target.begin_import(admin, summary.clone()).unwrap();
+    let mut cursor = None;
+    loop {
+        let chunk = source.export_chunk(admin, cursor, 2).unwrap();
+        cursor = chunk.next.clone();
+        target.import_chunk(admin, chunk).unwrap();
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert_eq!(target.finish_import(admin), Ok(()));
+
+    assert_eq!(target.export_summary(admin), Ok(summary));
+    assert_eq!(
+        target.get_encrypted_values_for_map(owner, map_id),
+        source.get_encrypted_values_for_map(owner, map_id)
+    );
+    assert_eq!(
+        target.get_tombstones_for_map(owner, map_id).unwrap().len(),
+        1
+    );
+    assert_eq!(
+        target.key_manager.get_audit_log(map_id),
+        source.key_manager.get_audit_log(map_id)
+    );
+}
+
+#[test]
+fn encrypted_maps_work_over_vector_memory() {
+    let rng = &mut reproducible_rng();
+    let caller = random_self_authenticating_principal(rng);
+    let key_id = (caller, random_name(rng));
+    let key = random_key(rng);
+    let value = random_bytebuf(rng, 0..100);
+    let memories: Vec<VectorMemory> = (0..6).map(|_| VectorMemory::default()).collect();
+    let init = |memories: &[VectorMemory]| -> EncryptedMaps<VectorMemory> {
+        EncryptedMaps::init(
+            "vector_memory",
+            memories[0].clone(),
+            memories[1].clone(),
+            memories[2].clone(),
+            memories[3].clone(),
+            memories[4].clone(),
+            Some(memories[5].clone()),
+        )
+    };
+
+    let mut encrypted_maps = init(&memories);
+    encrypted_maps
+        .insert_encrypted_value(caller, key_id, key, value.clone())
+        .unwrap();
+
+    // The values are kept in the memories and survive a re-initialization.
+    let encrypted_maps = init(&memories);
+    assert_eq!(
+        encrypted_maps.get_encrypted_value(caller, key_id, key),
+        Ok(Some(value))
+    );
+}
+
+#[test]
+fn encrypted_maps_can_be_initialized_with_a_memory_layout() {
+    let rng = &mut reproducible_rng();
+    let caller = random_self_authenticating_principal(rng);
+    let key_id = (caller, random_name(rng));
+    let key = random_key(rng);
+    let value = random_bytebuf(rng, 0..100);
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    let layout = MemoryLayout::standard(0);
+
+    let mut encrypted_maps = EncryptedMaps::init_with_layout("layout", &memory_manager, &layout);
+    encrypted_maps
+        .insert_encrypted_value(caller, key_id, key, value.clone())
+        .unwrap();
+
+    let encrypted_maps = EncryptedMaps::init_with_layout("layout", &memory_manager, &layout);
+    assert_eq!(
+        encrypted_maps.get_encrypted_value(caller, key_id, key),
+        Ok(Some(value))
+    );
+}
+
+#[test]
+#[should_panic(
+    expected = "invalid memory layout: memory id 4 is assigned to both AuditLogs and Tombstones"
+)]
+fn init_with_layout_rejects_shared_memory_ids() {
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    let layout = MemoryLayout::new(0..5)
+        .with_memory_id(StableStructure::Metadata, 0)
+        .with_memory_id(StableStructure::AccessControl, 1)
+        .with_memory_id(StableStructure::SharedKeys, 2)
+        .with_memory_id(StableStructure::EncryptedMapValues, 3)
+        .with_memory_id(StableStructure::Tombstones, 4)
+        .with_memory_id(StableStructure::AuditLogs, 4);
+    let _ = EncryptedMaps::init_with_layout("layout", &memory_manager, &layout);
+}
+
+#[test]
+fn api_rejects_too_long_map_keys() {
+    let rng = &mut reproducible_rng();
+    let map_key = random_bytebuf(rng, 0..MAX_NAME_BYTES + 1);
+    assert_eq!(api::map_key(&map_key).unwrap().as_slice(), map_key.as_ref());
+    assert_eq!(
+        api::map_key(&ByteBuf::from(vec![0; MAX_NAME_BYTES + 1])),
+        Err("too large input".to_string())
+    );
+}
+
+#[test]
+fn api_did_service_skips_excluded_methods() {
+    let excluded = ["hard_delete_encrypted_value", "hard_delete_map_values"];
+    let service = api::did_service(&api::ENCRYPTED_MAPS_API_METHODS, &excluded);
+    assert!(!service.contains("hard_delete"));
+    assert!(service.contains("  remove_encrypted_value : ("));
+    assert!(service.contains("type TombstoneEntry = record {"));
+
+    // The declarations follow the Rust types.
+    for audit_type in AuditEntryType::iter() {
+        assert!(service.contains(&format!("{audit_type:?}")));
+    }
+}
+
+#[test]
+fn subscribers_are_notified_of_changes() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let map_id = (owner, random_name(rng));
+    let key = random_key(rng);
+    let events = Rc::new(RefCell::new(vec![]));
+    let mut encrypted_maps =
+        random_encrypted_maps(rng).with_event_subscriber(RecordingSubscriber(events.clone()));
+
+    let value = random_bytebuf(rng, 0..100);
+    let new_value = random_bytebuf(rng, 0..100);
+    encrypted_maps
+        .insert_encrypted_value(owner, map_id, key, value.clone())
+        .unwrap();
+    encrypted_maps
+        .insert_encrypted_value(owner, map_id, key, new_value.clone())
+        .unwrap();
+    encrypted_maps
+        .set_user_rights(owner, map_id, user, AccessRights::read_only())
+        .unwrap();
+    assert!(encrypted_maps
+        .insert_encrypted_value(user, map_id, key, value.clone())
+        .is_err());
+    encrypted_maps
+        .remove_encrypted_value(owner, map_id, key, false)
+        .unwrap();
+    encrypted_maps.restore_value(owner, map_id, key).unwrap();
+    encrypted_maps
+        .remove_map_values(owner, map_id, false)
+        .unwrap();
+    encrypted_maps.remove_user(owner, map_id, user).unwrap();
+    encrypted_maps.remove_user(owner, map_id, user).unwrap();
+
+    assert_eq!(
+        *events.borrow(),
+        vec![
+            Event::Inserted(key, value.clone()),
+            Event::Updated(key, value, new_value.clone()),
+            Event::Shared(user, AccessRights::read_only()),
+            Event::Removed(key, new_value.clone(), true),
+            Event::Restored(key, new_value.clone()),
+            Event::Removed(key, new_value, true),
+            Event::Unshared(user, AccessRights::read_only()),
+        ]
+    );
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Event {
+    Shared(Principal, AccessRights),
+    Unshared(Principal, AccessRights),
+    Inserted(MapKey, ByteBuf),
+    Updated(MapKey, ByteBuf, ByteBuf),
+    Removed(MapKey, ByteBuf, bool),
+    Restored(MapKey, ByteBuf),
+}
+
+struct RecordingSubscriber(Rc<RefCell<Vec<Event>>>);
+
+impl EventSubscriber for RecordingSubscriber {
+    fn on_share(&self, _: Principal, _: KeyId, user: Principal, access_rights: AccessRights) {
+        self.0.borrow_mut().push(Event::Shared(user, access_rights));
+    }
+
+    fn on_unshare(&self, _: Principal, _: KeyId, user: Principal, previous_rights: AccessRights) {
+        self.0
+            .borrow_mut()
+            .push(Event::Unshared(user, previous_rights));
+    }
+
+    fn on_value_inserted(&self, _: Principal, _: KeyId, map_key: MapKey, value: &ByteBuf) {
+        self.0
+            .borrow_mut()
+            .push(Event::Inserted(map_key, value.clone()));
+    }
+
+    fn on_value_updated(
+        &self,
+        _: Principal,
+        _: KeyId,
+        map_key: MapKey,
+        previous_value: &ByteBuf,
+        value: &ByteBuf,
+    ) {
+        self.0.borrow_mut().push(Event::Updated(
+            map_key,
+            previous_value.clone(),
+            value.clone(),
+        ));
+    }
+
+    fn on_value_removed(
+        &self,
+        _: Principal,
+        _: KeyId,
+        map_key: MapKey,
+        value: &ByteBuf,
+        soft_deleted: bool,
+    ) {
+        self.0
+            .borrow_mut()
+            .push(Event::Removed(map_key, value.clone(), soft_deleted));
+    }
+
+    fn on_value_restored(&self, _: Principal, _: KeyId, map_key: MapKey, value: &ByteBuf) {
+        self.0
+            .borrow_mut()
+            .push(Event::Restored(map_key, value.clone()));
+    }
+}
+
+fn encrypted_maps_with_admins(domain_separator: &str) -> EncryptedMaps {
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    EncryptedMaps::init(
+        domain_separator,
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+        memory_manager.get(MemoryId::new(3)),
+        memory_manager.get(MemoryId::new(4)),
+        Some(memory_manager.get(MemoryId::new(5))),
+    )
+    .with_admins(
+        memory_manager.get(MemoryId::new(6)),
+        memory_manager.get(MemoryId::new(7)),
+        memory_manager.get(MemoryId::new(8)),
+        memory_manager.get(MemoryId::new(9)),
+        0,
+    )
+}
+
 fn random_encrypted_maps<R: Rng + CryptoRng>(rng: &mut R) -> EncryptedMaps {
     let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
     let (memory_id_encrypted_maps, memory_ids_key_manager) = random_unique_memory_ids(rng);
@@ -1,5 +1,5 @@
 use candid::{decode_one, encode_args, encode_one, CandidType, Principal};
-use ic_vetkd_cdk_encrypted_maps::{VetKey, VetKeyVerificationKey};
+use ic_vetkd_cdk_encrypted_maps::{TombstoneEntry, VetKey, VetKeyVerificationKey};
 use ic_vetkd_cdk_test_utils::random_self_authenticating_principal;
 use ic_vetkd_cdk_types::{AccessRights, ByteBuf, TransportKey};
 use ic_vetkd_utils::TransportSecretKey;
@@ -164,6 +164,88 @@ fn key_sharing_should_work() {
     assert_eq!(get_vetkey(env.principal_0), get_vetkey(env.principal_1));
 }
 
+#[test]
+fn maps_should_be_migrated_when_upgrading_from_baseline() {
+    let rng = &mut reproducible_rng();
+    let env = TestEnvironment::with_example_wasm(
+        rng,
+        load_baseline_encrypted_maps_example_canister_wasm(),
+    );
+    let map_owner = env.principal_0;
+    let map_name = random_key_name(rng);
+    let map_keys: Vec<ByteBuf> = (0..3).map(|_| random_key_name(rng)).collect();
+    for map_key in &map_keys {
+        let _: Result<Option<ByteBuf>, String> = env.update(
+            map_owner,
+            "insert_encrypted_value",
+            encode_args((
+                map_owner,
+                map_name.clone(),
+                map_key.clone(),
+                map_key.clone(),
+            ))
+            .unwrap(),
+        );
+    }
+    let removed_key = map_keys[0].clone();
+    let _: Result<Option<ByteBuf>, String> = env.update(
+        map_owner,
+        "remove_encrypted_value",
+        encode_args((map_owner, map_name.clone(), removed_key.clone())).unwrap(),
+    );
+    let _: Result<Option<AccessRights>, String> = env.update(
+        map_owner,
+        "set_user_rights",
+        encode_args((
+            map_owner,
+            map_name.clone(),
+            env.principal_1,
+            AccessRights::read_only(),
+        ))
+        .unwrap(),
+    );
+
+    env.pic
+        .upgrade_canister(
+            env.example_canister_id,
+            load_key_manager_example_canister_wasm(),
+            encode_one(()).unwrap(),
+            None,
+        )
+        .expect("failed to upgrade the example canister");
+    // The migration runs in timers started by `post_upgrade`.
+    fast_forward(&env.pic, 5);
+
+    assert_eq!(
+        env.query::<Result<Option<AccessRights>, String>>(
+            env.principal_1,
+            "get_user_rights",
+            encode_args((map_owner, map_name.clone(), env.principal_1)).unwrap(),
+        ),
+        Ok(Some(AccessRights::read_only()))
+    );
+    for map_key in &map_keys[1..] {
+        assert_eq!(
+            env.query::<Result<Option<ByteBuf>, String>>(
+                env.principal_1,
+                "get_encrypted_value",
+                encode_args((map_owner, map_name.clone(), map_key.clone())).unwrap(),
+            ),
+            Ok(Some(map_key.clone()))
+        );
+    }
+    let tombstones = env
+        .query::<Result<Vec<(ByteBuf, TombstoneEntry)>, String>>(
+            map_owner,
+            "get_tombstones",
+            encode_args((map_owner, map_name)).unwrap(),
+        )
+        .unwrap();
+    assert_eq!(tombstones.len(), 1);
+    assert_eq!(tombstones[0].0, removed_key);
+    assert_eq!(tombstones[0].1.value, removed_key);
+}
+
 struct TestEnvironment {
     pic: PocketIc,
     example_canister_id: Principal,
@@ -173,6 +255,10 @@ struct TestEnvironment {
 
 impl TestEnvironment {
     fn new<R: Rng + CryptoRng>(rng: &mut R) -> Self {
+        Self::with_example_wasm(rng, load_key_manager_example_canister_wasm())
+    }
+
+    fn with_example_wasm<R: Rng + CryptoRng>(rng: &mut R, example_wasm_bytes: Vec<u8>) -> Self {
         let pic = PocketIcBuilder::new()
             .with_application_subnet()
             .with_ii_subnet()
@@ -188,7 +274,6 @@ impl TestEnvironment {
         let example_canister_id = pic.create_canister();
         pic.add_cycles(example_canister_id, 2_000_000_000_000);
 
-        let example_wasm_bytes = load_key_manager_example_canister_wasm();
         pic.install_canister(example_canister_id, example_wasm_bytes, vec![], None);
 
         // Make sure the canister is properly initialized
@@ -254,6 +339,16 @@ fn load_key_manager_example_canister_wasm() -> Vec<u8> {
     wasm_bytes
 }
 
+/// Loads the example canister built from the baseline revision, whose maps
+/// use the layout before schema version 2.
+fn load_baseline_encrypted_maps_example_canister_wasm() -> Vec<u8> {
+    let wasm_path = Path::new(
+        "../../target/baseline/wasm32-unknown-unknown/release/ic_vetkd_cdk_encrypted_maps_example.wasm",
+    );
+    std::fs::read(wasm_path)
+        .expect("baseline wasm does not exist - run `make compile-baseline-wasm`")
+}
+
 fn load_vetkd_mock_canister_wasm() -> Vec<u8> {
     let wasm_url = "https://github.com/dfinity/chainkey-testing-canister/releases/download/v0.1.0/chainkey_testing_canister.wasm.gz";
     reqwest::blocking::get(wasm_url)
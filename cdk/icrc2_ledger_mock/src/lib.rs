@@ -0,0 +1,196 @@
+//! A minimal ICRC-1/ICRC-2 ledger for testing purchases of access.
+//!
+//! Implements `icrc1_balance_of`, `icrc1_fee`, `icrc1_transfer`, `icrc2_approve`
+//! and `icrc2_transfer_from` with a fixed fee and without expirations, memos
+//! or deduplication. Tokens are created with the `mint` method, and the ledger
+//! state is not persisted across upgrades.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::{query, update};
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+
+const FEE: u64 = 10;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<ByteBuf>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TransferArg {
+    pub from_subaccount: Option<ByteBuf>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ApproveArgs {
+    pub from_subaccount: Option<ByteBuf>,
+    pub spender: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<ByteBuf>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum ApproveError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+}
+
+#[derive(Default)]
+struct Ledger {
+    balances: BTreeMap<Account, Nat>,
+    allowances: BTreeMap<(Account, Account), Nat>,
+    blocks: u64,
+}
+
+impl Ledger {
+    fn balance(&self, account: &Account) -> Nat {
+        self.balances.get(account).cloned().unwrap_or_default()
+    }
+
+    fn credit(&mut self, account: Account, amount: Nat) {
+        let balance = self.balance(&account);
+        self.balances.insert(account, balance + amount);
+    }
+
+    /// Debits `amount` if the balance covers it, otherwise returns the balance.
+    fn debit(&mut self, account: &Account, amount: Nat) -> Result<(), Nat> {
+        let balance = self.balance(account);
+        if balance < amount {
+            return Err(balance);
+        }
+        self.balances.insert(account.clone(), balance - amount);
+        Ok(())
+    }
+
+    fn next_block(&mut self) -> Nat {
+        self.blocks += 1;
+        Nat::from(self.blocks - 1)
+    }
+}
+
+thread_local! {
+    static LEDGER: RefCell<Ledger> = RefCell::default();
+}
+
+fn caller_account(subaccount: Option<ByteBuf>) -> Account {
+    Account {
+        owner: ic_cdk::caller(),
+        subaccount,
+    }
+}
+
+fn is_bad_fee(fee: Option<&Nat>) -> bool {
+    fee.is_some_and(|fee| *fee != Nat::from(FEE))
+}
+
+#[query]
+fn icrc1_fee() -> Nat {
+    Nat::from(FEE)
+}
+
+#[query]
+fn icrc1_balance_of(account: Account) -> Nat {
+    LEDGER.with_borrow(|ledger| ledger.balance(&account))
+}
+
+#[update]
+fn mint(to: Account, amount: Nat) -> Nat {
+    LEDGER.with_borrow_mut(|ledger| {
+        ledger.credit(to, amount);
+        ledger.next_block()
+    })
+}
+
+#[update]
+fn icrc1_transfer(args: TransferArg) -> Result<Nat, TransferError> {
+    if is_bad_fee(args.fee.as_ref()) {
+        return Err(TransferError::BadFee {
+            expected_fee: Nat::from(FEE),
+        });
+    }
+    let from = caller_account(args.from_subaccount);
+    LEDGER.with_borrow_mut(|ledger| {
+        ledger
+            .debit(&from, args.amount.clone() + Nat::from(FEE))
+            .map_err(|balance| TransferError::InsufficientFunds { balance })?;
+        ledger.credit(args.to, args.amount);
+        Ok(ledger.next_block())
+    })
+}
+
+#[update]
+fn icrc2_approve(args: ApproveArgs) -> Result<Nat, ApproveError> {
+    if is_bad_fee(args.fee.as_ref()) {
+        return Err(ApproveError::BadFee {
+            expected_fee: Nat::from(FEE),
+        });
+    }
+    let from = caller_account(args.from_subaccount);
+    LEDGER.with_borrow_mut(|ledger| {
+        ledger
+            .debit(&from, Nat::from(FEE))
+            .map_err(|balance| ApproveError::InsufficientFunds { balance })?;
+        ledger.allowances.insert((from, args.spender), args.amount);
+        Ok(ledger.next_block())
+    })
+}
+
+#[update]
+fn icrc2_transfer_from(args: TransferFromArgs) -> Result<Nat, TransferFromError> {
+    if is_bad_fee(args.fee.as_ref()) {
+        return Err(TransferFromError::BadFee {
+            expected_fee: Nat::from(FEE),
+        });
+    }
+    let spender = caller_account(args.spender_subaccount);
+    let total = args.amount.clone() + Nat::from(FEE);
+    LEDGER.with_borrow_mut(|ledger| {
+        let allowance_key = (args.from.clone(), spender);
+        let allowance = ledger
+            .allowances
+            .get(&allowance_key)
+            .cloned()
+            .unwrap_or_default();
+        if allowance < total {
+            return Err(TransferFromError::InsufficientAllowance { allowance });
+        }
+        ledger
+            .debit(&args.from, total.clone())
+            .map_err(|balance| TransferFromError::InsufficientFunds { balance })?;
+        ledger.allowances.insert(allowance_key, allowance - total);
+        ledger.credit(args.to, args.amount);
+        Ok(ledger.next_block())
+    })
+}
+
+ic_cdk::export_candid!();
@@ -0,0 +1,62 @@
+//! A minimal ICRC-7 ledger for testing token-gated access.
+//!
+//! Only `icrc7_owner_of` is implemented from the standard. Tokens are minted
+//! and transferred with the simplified `mint` and `transfer` methods, and
+//! the ledger state is not persisted across upgrades.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::{query, update};
+use serde::Deserialize;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<serde_bytes::ByteBuf>,
+}
+
+thread_local! {
+    static OWNERS: RefCell<BTreeMap<Nat, Principal>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+#[query]
+fn icrc7_owner_of(token_ids: Vec<Nat>) -> Vec<Option<Account>> {
+    OWNERS.with_borrow(|owners| {
+        token_ids
+            .iter()
+            .map(|token_id| {
+                owners.get(token_id).map(|owner| Account {
+                    owner: *owner,
+                    subaccount: None,
+                })
+            })
+            .collect()
+    })
+}
+
+#[update]
+fn mint(token_id: Nat, owner: Principal) -> Result<(), String> {
+    OWNERS.with_borrow_mut(|owners| {
+        if owners.contains_key(&token_id) {
+            return Err("token already exists".to_string());
+        }
+        owners.insert(token_id, owner);
+        Ok(())
+    })
+}
+
+#[update]
+fn transfer(token_id: Nat, to: Principal) -> Result<(), String> {
+    OWNERS.with_borrow_mut(|owners| match owners.get_mut(&token_id) {
+        Some(owner) if *owner == ic_cdk::caller() => {
+            *owner = to;
+            Ok(())
+        }
+        Some(_) => Err("unauthorized".to_string()),
+        None => Err("token does not exist".to_string()),
+    })
+}
+
+ic_cdk::export_candid!();
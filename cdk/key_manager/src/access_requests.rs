@@ -0,0 +1,284 @@
+//! Requests for access to a key by users that do not have (sufficient) access yet.
+//!
+//! A user who knows a key id can ask for access with [`KeyManager::request_access`].
+//! Users with the `SHARE` permission list pending requests and approve or deny them;
+//! approving a request grants the access rights via [`KeyManager::set_user_rights`],
+//! so all of its checks apply, including multi-party approval if configured.
+//! Each user has at most one request per key and, after a denial, has to wait
+//! for [`AccessRequestLimits::retry_cooldown`] before asking again.
+
+use crate::migration::{MigratedStructure, NamedMap};
+use crate::policy::Operation;
+use crate::{Caller, DefaultMemory, KeyId, KeyManager};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Memory, Storable};
+use ic_vetkd_cdk_types::{decode_versioned, encode_versioned, now, AccessRights, AuditEntry};
+use serde::Deserialize;
+use std::borrow::Cow;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessRequestStatus {
+    /// Waiting for a decision.
+    Pending,
+    /// Access rights were granted.
+    Approved,
+    /// The request was denied.
+    Denied,
+}
+
+/// A user's request for access to a key.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AccessRequest {
+    pub desired_rights: AccessRights,
+    pub message: String,
+    pub created_at: u64,
+    pub status: AccessRequestStatus,
+    pub decided_by: Option<Principal>,
+    pub decided_at: Option<u64>,
+    /// The rights that were granted, which may differ from `desired_rights`.
+    pub granted_rights: Option<AccessRights>,
+}
+
+impl Storable for AccessRequest {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let payload = Encode!(self).expect("failed to encode AccessRequest");
+        Cow::Owned(encode_versioned(1, &payload))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        match decode_versioned(bytes.as_ref()) {
+            (0 | 1, payload) => Decode!(payload, Self).expect("failed to decode AccessRequest"),
+            (version, _) => panic!("unsupported AccessRequest encoding version {version}"),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Limits that protect key managers from request spam.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccessRequestLimits {
+    /// Maximum size of a request message in bytes.
+    pub max_message_bytes: usize,
+    /// Maximum number of pending requests per key.
+    pub max_pending_per_key: usize,
+    /// Time in nanoseconds a user has to wait after a denial before asking again.
+    pub retry_cooldown: u64,
+}
+
+impl Default for AccessRequestLimits {
+    fn default() -> Self {
+        Self {
+            max_message_bytes: 512,
+            max_pending_per_key: 100,
+            retry_cooldown: 24 * 60 * 60 * 1_000_000_000,
+        }
+    }
+}
+
+/// Stable storage of access requests.
+pub struct AccessRequestStore<M: Memory = DefaultMemory> {
+    pub requests: NamedMap<(KeyId, Caller), AccessRequest, M>,
+    pub limits: AccessRequestLimits,
+}
+
+impl<M: Memory> KeyManager<M> {
+    /// Enables access requests, see [`crate::access_requests`].
+    #[must_use]
+    pub fn with_access_requests(mut self, memory: M, limits: AccessRequestLimits) -> Self {
+        self.access_requests = Some(AccessRequestStore {
+            requests: self.init_named_map(MigratedStructure::AccessRequests, memory),
+            limits,
+        });
+        self
+    }
+
+    /// Asks for access to a key. Replaces a previous request that was
+    /// approved or whose denial is older than the retry cooldown.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if:
+    /// - Access requests are not enabled
+    /// - The caller is anonymous or the key owner
+    /// - The message is too long
+    /// - The caller already has a pending or recently denied request
+    /// - The key has too many pending requests
+    pub fn request_access(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        desired_rights: AccessRights,
+        message: String,
+    ) -> Result<(), String> {
+        if caller == Principal::anonymous() {
+            return Err("unauthorized".to_string());
+        }
+        if caller == key_id.0 {
+            return Err("cannot request access to own key".to_string());
+        }
+        let store = self
+            .access_requests
+            .as_mut()
+            .ok_or_else(|| "access requests are not enabled".to_string())?;
+        if message.len() > store.limits.max_message_bytes {
+            return Err("message too long".to_string());
+        }
+
+        match store.requests.get(&(key_id, caller)) {
+            Some(request) if request.status == AccessRequestStatus::Pending => {
+                return Err("access request already pending".to_string());
+            }
+            Some(request) if request.status == AccessRequestStatus::Denied => {
+                let decided_at = request.decided_at.unwrap_or(request.created_at);
+                if now() < decided_at.saturating_add(store.limits.retry_cooldown) {
+                    return Err("access request rate limited".to_string());
+                }
+            }
+            _ => {}
+        }
+
+        let pending = store
+            .requests
+            .range((key_id, Principal::management_canister())..)
+            .take_while(|((k, _), _)| k == &key_id)
+            .filter(|(_, request)| request.status == AccessRequestStatus::Pending)
+            .count();
+        if pending >= store.limits.max_pending_per_key {
+            return Err("too many pending access requests".to_string());
+        }
+
+        store.requests.insert(
+            (key_id, caller),
+            AccessRequest {
+                desired_rights,
+                message,
+                created_at: now(),
+                status: AccessRequestStatus::Pending,
+                decided_by: None,
+                decided_at: None,
+                granted_rights: None,
+            },
+        );
+        self.add_audit_log(key_id, move || {
+            AuditEntry::access_requested(now(), caller, desired_rights)
+        });
+        Ok(())
+    }
+
+    /// Retrieves the caller's own request for a key, if any.
+    #[must_use]
+    pub fn get_access_request_status(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+    ) -> Option<AccessRequest> {
+        self.access_requests
+            .as_ref()
+            .and_then(|store| store.requests.get(&(key_id, caller)))
+    }
+
+    /// Retrieves all pending requests for a key.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caller does not have the `SHARE` permission.
+    pub fn get_pending_access_requests(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+    ) -> Result<Vec<(Principal, AccessRequest)>, String> {
+        self.authorize(caller, key_id, Operation::Share)?;
+        Ok(self
+            .access_requests
+            .as_ref()
+            .map(|store| {
+                store
+                    .requests
+                    .range((key_id, Principal::management_canister())..)
+                    .take_while(|((k, _), _)| k == &key_id)
+                    .filter(|(_, request)| request.status == AccessRequestStatus::Pending)
+                    .map(|((_, requester), request)| (requester, request))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// Approves a pending request, granting either the desired rights or
+    /// `adjusted_rights` if given, e.g., with fewer permissions or a time window.
+    /// Returns the result of the underlying [`KeyManager::set_user_rights`] call.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is no pending request from `requester` or if
+    /// `set_user_rights` fails for the caller.
+    pub fn approve_access_request(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        requester: Principal,
+        adjusted_rights: Option<AccessRights>,
+    ) -> Result<Option<AccessRights>, String> {
+        let mut request = self.pending_access_request(key_id, requester)?;
+        let access_rights = adjusted_rights.unwrap_or(request.desired_rights);
+
+        let result = self.set_user_rights(caller, key_id, requester, access_rights)?;
+
+        request.status = AccessRequestStatus::Approved;
+        request.decided_by = Some(caller);
+        request.decided_at = Some(now());
+        request.granted_rights = Some(access_rights);
+        self.store_access_request(key_id, requester, request);
+        self.add_audit_log(key_id, move || {
+            AuditEntry::access_request_approved(now(), caller, requester, access_rights)
+        });
+        Ok(result)
+    }
+
+    /// Denies a pending request.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caller does not have the `SHARE` permission or
+    /// if there is no pending request from `requester`.
+    pub fn deny_access_request(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        requester: Principal,
+    ) -> Result<(), String> {
+        self.authorize(caller, key_id, Operation::Share)?;
+        let mut request = self.pending_access_request(key_id, requester)?;
+
+        request.status = AccessRequestStatus::Denied;
+        request.decided_by = Some(caller);
+        request.decided_at = Some(now());
+        self.store_access_request(key_id, requester, request);
+        self.add_audit_log(key_id, move || {
+            AuditEntry::access_request_denied(now(), caller, requester)
+        });
+        Ok(())
+    }
+
+    fn pending_access_request(
+        &self,
+        key_id: KeyId,
+        requester: Principal,
+    ) -> Result<AccessRequest, String> {
+        self.get_access_request_status(requester, key_id)
+            .filter(|request| request.status == AccessRequestStatus::Pending)
+            .ok_or_else(|| "no pending access request".to_string())
+    }
+
+    fn store_access_request(
+        &mut self,
+        key_id: KeyId,
+        requester: Principal,
+        request: AccessRequest,
+    ) {
+        if let Some(store) = self.access_requests.as_mut() {
+            store.requests.insert((key_id, requester), request);
+        }
+    }
+}
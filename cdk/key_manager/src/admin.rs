@@ -0,0 +1,473 @@
+//! Canister administrators and the recovery of keys whose owner lost access.
+//!
+//! The admin set is stored in stable memory. While it is empty, the
+//! controllers of the canister are its admins. It can be seeded once with
+//! [`KeyManager::init_admins`] with principals passed explicitly, e.g., in the
+//! canister's optional init arguments, after which the controllers are no
+//! longer admins unless they are in the set. The caller of `init` is not a
+//! suitable default, since it is whoever installs the canister, e.g., a
+//! deployment tool. Afterwards, admins manage the set themselves. Admins may change
+//! canister-wide settings such as the freeze of [`crate::freeze`], but they
+//! have no implicit access to any key: authorization never consults the admin
+//! set, so an admin can only fetch a vetkey or read values with a grant.
+//!
+//! A key can only be recovered to the recovery principal its owner registered
+//! in advance with [`KeyManager::set_recovery_principal`]. If the owner loses
+//! their identity, an admin proposes to grant that principal full access to
+//! the owner's key with [`KeyManager::propose_recovery`]. The recovery can be
+//! executed with [`KeyManager::execute_recovery`] once
+//! [`AdminStore::recovery_delay`] has passed, which gives the owner and the
+//! key's managers time to notice the proposal in the audit log and to
+//! [`KeyManager::cancel_recovery`] it. Recovery principals cannot be admins,
+//! so an admin cannot recover a key to themselves. Keys whose grants require
+//! approval (see [`crate::approvals`]) cannot be recovered, since a recovery
+//! would grant full access without the approvers.
+//!
+//! Admins cannot choose who gains access, but they decide whether and when a
+//! key is recovered: once the delay has passed, any admin can give the
+//! registered principal full access without the owner's consent. Owners
+//! should thus only register principals they trust as much as their own
+//! identity. Independently of admins, the controllers of the canister can
+//! replace its code and are trusted with all keys.
+
+use crate::policy::Operation;
+use crate::{Caller, DefaultMemory, KeyId, KeyManager};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Memory, StableBTreeMap, Storable};
+use ic_vetkd_cdk_types::{
+    decode_versioned, encode_versioned, is_controller, now, AccessRights, AuditEntry, ByteBuf,
+    KeyName,
+};
+use serde::Deserialize;
+use std::borrow::Cow;
+
+pub type RecoveryId = u64;
+
+/// A pending grant of full access to a key to a recovery principal.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Recovery {
+    /// The owner of the recovered key.
+    pub key_owner: Caller,
+    /// The name of the key, stored as bytes since `KeyName` is not a Candid type.
+    pub key_name: ByteBuf,
+    pub recovery_principal: Principal,
+    pub proposed_by: Caller,
+    pub proposed_at: u64,
+    /// The recovery cannot be executed before this time.
+    pub executable_at: u64,
+}
+
+impl Recovery {
+    /// Returns the recovered key.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stored key name is not a valid `KeyName`.
+    #[must_use]
+    pub fn key_id(&self) -> KeyId {
+        let key_name =
+            KeyName::try_from(self.key_name.as_ref()).expect("invalid key name in recovery");
+        (self.key_owner, key_name)
+    }
+}
+
+impl Storable for Recovery {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let payload = Encode!(self).expect("failed to encode Recovery");
+        Cow::Owned(encode_versioned(1, &payload))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        match decode_versioned(bytes.as_ref()) {
+            (0 | 1, payload) => Decode!(payload, Self).expect("failed to decode Recovery"),
+            (version, _) => panic!("unsupported Recovery encoding version {version}"),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Stable storage of the admin set and of pending recoveries.
+pub struct AdminStore<M: Memory = DefaultMemory> {
+    pub admins: StableBTreeMap<Principal, (), M>,
+    pub recoveries: StableBTreeMap<RecoveryId, Recovery, M>,
+    /// The ids of the pending recoveries of each key.
+    pub recoveries_by_key: StableBTreeMap<(KeyId, RecoveryId), (), M>,
+    /// The recovery principals registered by the key owners.
+    pub recovery_principals: StableBTreeMap<KeyId, Principal, M>,
+    /// Time in nanoseconds between proposing and executing a recovery.
+    pub recovery_delay: u64,
+}
+
+impl<M: Memory> KeyManager<M> {
+    /// Enables canister administrators and key recovery, see [`crate::admin`].
+    #[must_use]
+    pub fn with_admins(
+        mut self,
+        memory_admins: M,
+        memory_recoveries: M,
+        memory_recovery_principals: M,
+        memory_recoveries_by_key: M,
+        recovery_delay: u64,
+    ) -> Self {
+        self.admins = Some(AdminStore {
+            admins: StableBTreeMap::init(memory_admins),
+            recoveries: StableBTreeMap::init(memory_recoveries),
+            recoveries_by_key: StableBTreeMap::init(memory_recoveries_by_key),
+            recovery_principals: StableBTreeMap::init(memory_recovery_principals),
+            recovery_delay,
+        });
+        self
+    }
+
+    /// Seeds the admin set if it is empty, e.g., with principals passed in the
+    /// canister's init arguments. Anonymous principals are ignored. Does
+    /// nothing if admins are not enabled or already set.
+    pub fn init_admins(&mut self, admins: impl IntoIterator<Item = Principal>) {
+        let Some(store) = self.admins.as_mut() else {
+            return;
+        };
+        if !store.admins.is_empty() {
+            return;
+        }
+        for admin in admins {
+            if admin != Principal::anonymous() {
+                store.admins.insert(admin, ());
+            }
+        }
+    }
+
+    /// Returns true if `principal` is a canister administrator, i.e., in the
+    /// admin set or, while the set is empty, a controller of the canister.
+    #[must_use]
+    pub fn is_admin(&self, principal: Principal) -> bool {
+        self.admins.as_ref().is_some_and(|store| {
+            if store.admins.is_empty() {
+                is_controller(&principal)
+            } else {
+                store.admins.contains_key(&principal)
+            }
+        })
+    }
+
+    /// Ensures that `caller` is a canister administrator.
+    ///
+    /// # Errors
+    ///
+    /// Returns `"unauthorized"` if the caller is not an admin or admins are not enabled.
+    pub fn ensure_admin(&self, caller: Principal) -> Result<(), String> {
+        if self.is_admin(caller) {
+            Ok(())
+        } else {
+            Err("unauthorized".to_string())
+        }
+    }
+
+    /// Lists the canister administrators in the admin set. If it is empty,
+    /// the controllers of the canister are the admins.
+    #[must_use]
+    pub fn get_admins(&self) -> Vec<Principal> {
+        self.admins.as_ref().map_or_else(Vec::new, |store| {
+            store.admins.iter().map(|(admin, ())| admin).collect()
+        })
+    }
+
+    /// Adds a canister administrator.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caller is not an admin or if `admin` is anonymous.
+    pub fn add_admin(&mut self, caller: Principal, admin: Principal) -> Result<(), String> {
+        self.ensure_admin(caller)?;
+        if admin == Principal::anonymous() {
+            return Err("anonymous principal cannot be an admin".to_string());
+        }
+        if let Some(store) = self.admins.as_mut() {
+            store.admins.insert(admin, ());
+        }
+        Ok(())
+    }
+
+    /// Removes a canister administrator.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caller is not an admin or if `admin` is the last admin.
+    pub fn remove_admin(&mut self, caller: Principal, admin: Principal) -> Result<bool, String> {
+        self.ensure_admin(caller)?;
+        let Some(store) = self.admins.as_mut() else {
+            return Ok(false);
+        };
+        if store.admins.len() == 1 && store.admins.contains_key(&admin) {
+            return Err("cannot remove the last admin".to_string());
+        }
+        Ok(store.admins.remove(&admin).is_some())
+    }
+
+    /// Registers the principal a key can be recovered to, or removes it if
+    /// `recovery_principal` is `None`, and returns the previously registered
+    /// principal. Only the key owner can perform this action.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if admins are not enabled, the caller is not the key
+    /// owner, or the recovery principal is anonymous, the key owner or an admin.
+    pub fn set_recovery_principal(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        recovery_principal: Option<Principal>,
+    ) -> Result<Option<Principal>, String> {
+        if caller != key_id.0 || caller == Principal::anonymous() {
+            return Err("unauthorized".to_string());
+        }
+        if let Some(recovery_principal) = recovery_principal {
+            self.check_recovery_principal(key_id, recovery_principal)?;
+        }
+        let store = self
+            .admins
+            .as_mut()
+            .ok_or_else(|| "admins are not enabled".to_string())?;
+
+        let previous = match recovery_principal {
+            Some(recovery_principal) => {
+                store.recovery_principals.insert(key_id, recovery_principal)
+            }
+            None => store.recovery_principals.remove(&key_id),
+        };
+        self.add_audit_log(key_id, move || {
+            AuditEntry::recovery_principal_changed(now(), caller, recovery_principal)
+        });
+        Ok(previous)
+    }
+
+    /// Retrieves the recovery principal registered for a key. Admins and users
+    /// with the `MANAGE` permission for the key may retrieve it.
+    ///
+    /// # Errors
+    ///
+    /// Returns `"unauthorized"` if the caller may not retrieve it.
+    pub fn get_recovery_principal(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+    ) -> Result<Option<Principal>, String> {
+        if !self.is_admin(caller) {
+            self.authorize(caller, key_id, Operation::Manage)?;
+        }
+        Ok(self
+            .admins
+            .as_ref()
+            .and_then(|store| store.recovery_principals.get(&key_id)))
+    }
+
+    /// Proposes to grant `recovery_principal` full access to a key and
+    /// returns the id of the recovery.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caller is not an admin, if grants of the key
+    /// require approval, or if the recovery principal is not the one
+    /// registered by the key owner, anonymous, the key owner or an admin.
+    pub fn propose_recovery(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        recovery_principal: Principal,
+    ) -> Result<RecoveryId, String> {
+        self.ensure_admin(caller)?;
+        self.check_recovery_principal(key_id, recovery_principal)?;
+        self.ensure_registered_recovery_principal(key_id, recovery_principal)?;
+        self.ensure_recoverable(key_id)?;
+        let store = self
+            .admins
+            .as_mut()
+            .ok_or_else(|| "admins are not enabled".to_string())?;
+
+        let proposed_at = now();
+        let recovery_id = store
+            .recoveries
+            .last_key_value()
+            .map_or(0, |(recovery_id, _)| recovery_id + 1);
+        store.recoveries.insert(
+            recovery_id,
+            Recovery {
+                key_owner: key_id.0,
+                key_name: ByteBuf::from(key_id.1.as_ref().to_vec()),
+                recovery_principal,
+                proposed_by: caller,
+                proposed_at,
+                executable_at: proposed_at.saturating_add(store.recovery_delay),
+            },
+        );
+        store.recoveries_by_key.insert((key_id, recovery_id), ());
+        self.add_audit_log(key_id, move || {
+            AuditEntry::recovery_proposed(proposed_at, caller, recovery_principal, recovery_id)
+        });
+        Ok(recovery_id)
+    }
+
+    /// Executes a recovery whose delay has passed, granting the recovery
+    /// principal full access to the key. Returns the key and the replaced
+    /// access rights of the recovery principal, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caller is not an admin, the recovery does not
+    /// exist, its delay has not passed, grants of the key require approval,
+    /// or the recovery principal became an admin or is no longer the one
+    /// registered by the key owner.
+    pub fn execute_recovery(
+        &mut self,
+        caller: Principal,
+        recovery_id: RecoveryId,
+    ) -> Result<(KeyId, Option<AccessRights>), String> {
+        self.ensure_admin(caller)?;
+        let recovery = self
+            .get_recovery(recovery_id)
+            .ok_or_else(|| "recovery not found".to_string())?;
+        if now() < recovery.executable_at {
+            return Err("recovery delay has not passed".to_string());
+        }
+        self.check_recovery_principal(recovery.key_id(), recovery.recovery_principal)?;
+        self.ensure_registered_recovery_principal(recovery.key_id(), recovery.recovery_principal)?;
+        self.ensure_recoverable(recovery.key_id())?;
+        self.remove_recovery(recovery.key_id(), recovery_id);
+
+        let key_id = recovery.key_id();
+        let recovery_principal = recovery.recovery_principal;
+        let access_rights = AccessRights::read_write_manage();
+        let previous =
+            self.apply_user_rights(caller, key_id, recovery_principal, access_rights, None);
+        self.add_audit_log(key_id, move || {
+            AuditEntry::recovery_executed(
+                now(),
+                caller,
+                recovery_principal,
+                access_rights,
+                recovery_id,
+            )
+        });
+        Ok((key_id, previous))
+    }
+
+    /// Cancels a pending recovery. Admins and users with the `MANAGE`
+    /// permission for the key may cancel it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the recovery does not exist or the caller may not cancel it.
+    pub fn cancel_recovery(
+        &mut self,
+        caller: Principal,
+        recovery_id: RecoveryId,
+    ) -> Result<Recovery, String> {
+        let recovery = self
+            .get_recovery(recovery_id)
+            .ok_or_else(|| "recovery not found".to_string())?;
+        if !self.is_admin(caller) {
+            self.authorize(caller, recovery.key_id(), Operation::Manage)?;
+        }
+        self.remove_recovery(recovery.key_id(), recovery_id);
+        self.add_audit_log(recovery.key_id(), move || {
+            AuditEntry::recovery_cancelled(now(), caller, recovery_id)
+        });
+        Ok(recovery)
+    }
+
+    fn check_recovery_principal(
+        &self,
+        key_id: KeyId,
+        recovery_principal: Principal,
+    ) -> Result<(), String> {
+        if recovery_principal == Principal::anonymous() || recovery_principal == key_id.0 {
+            return Err("invalid recovery principal".to_string());
+        }
+        if self.is_admin(recovery_principal) {
+            return Err("admins cannot be recovery principals".to_string());
+        }
+        Ok(())
+    }
+
+    fn ensure_recoverable(&self, key_id: KeyId) -> Result<(), String> {
+        if self.get_approval_config(key_id).is_some() {
+            return Err("grants of the key require approval".to_string());
+        }
+        Ok(())
+    }
+
+    fn remove_recovery(&mut self, key_id: KeyId, recovery_id: RecoveryId) {
+        if let Some(store) = self.admins.as_mut() {
+            store.recoveries.remove(&recovery_id);
+            store.recoveries_by_key.remove(&(key_id, recovery_id));
+        }
+    }
+
+    /// Removes the pending recoveries of a deleted key.
+    pub(crate) fn remove_recoveries_of_key(&mut self, key_id: KeyId) {
+        let Some(store) = self.admins.as_mut() else {
+            return;
+        };
+        let recovery_ids: Vec<_> = store
+            .recoveries_by_key
+            .range((key_id, RecoveryId::MIN)..=(key_id, RecoveryId::MAX))
+            .map(|((_, recovery_id), ())| recovery_id)
+            .collect();
+        for recovery_id in recovery_ids {
+            self.remove_recovery(key_id, recovery_id);
+        }
+    }
+
+    fn ensure_registered_recovery_principal(
+        &self,
+        key_id: KeyId,
+        recovery_principal: Principal,
+    ) -> Result<(), String> {
+        let registered = self
+            .admins
+            .as_ref()
+            .and_then(|store| store.recovery_principals.get(&key_id));
+        if registered == Some(recovery_principal) {
+            Ok(())
+        } else {
+            Err("recovery principal is not registered by the key owner".to_string())
+        }
+    }
+
+    /// Retrieves a pending recovery.
+    #[must_use]
+    pub fn get_recovery(&self, recovery_id: RecoveryId) -> Option<Recovery> {
+        self.admins
+            .as_ref()
+            .and_then(|store| store.recoveries.get(&recovery_id))
+    }
+
+    /// Lists the pending recoveries of a key. Admins and users with the
+    /// `MANAGE` permission for the key may list them.
+    ///
+    /// # Errors
+    ///
+    /// Returns `"unauthorized"` if the caller may not list the recoveries.
+    pub fn get_pending_recoveries(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+    ) -> Result<Vec<(RecoveryId, Recovery)>, String> {
+        if !self.is_admin(caller) {
+            self.authorize(caller, key_id, Operation::Manage)?;
+        }
+        Ok(self.admins.as_ref().map_or_else(Vec::new, |store| {
+            store
+                .recoveries_by_key
+                .range((key_id, RecoveryId::MIN)..=(key_id, RecoveryId::MAX))
+                .filter_map(|((_, recovery_id), ())| {
+                    store
+                        .recoveries
+                        .get(&recovery_id)
+                        .map(|recovery| (recovery_id, recovery))
+                })
+                .collect()
+        }))
+    }
+}
@@ -0,0 +1,355 @@
+//! Standard canister endpoints of a `KeyManager`.
+//!
+//! [`export_key_manager_api`](crate::export_key_manager_api) installs the
+//! endpoints with which users list the keys shared with them, retrieve
+//! encrypted vetkeys and manage access rights. Keys are identified by their
+//! owner and name, and names are passed as `ByteBuf`s that are validated with
+//! [`key_id`]. Before an encrypted vetkey is derived, the holder of the token
+//! the key is bound to is refreshed if token gating is enabled, see
+//! [`crate::token_gating`].
+//!
+//! Individual endpoints can be left out, e.g., to implement them differently,
+//! with `except: [...]`, or the installed endpoints can be listed with
+//! `only: [...]`. A `guard` is called with the method name before every
+//! installed endpoint, e.g., to reject anonymous callers or to count calls;
+//! if it returns an error, the endpoint returns the error or, if it does not
+//! return a `Result`, traps.
+//!
+//! The Candid declarations of the endpoints are generated from their Rust
+//! types by [`did_service`], e.g., for canisters that maintain their `.did`
+//! file by hand.
+
+use crate::{KeyId, VetKey, VetKeyVerificationKey};
+use candid::types::internal::TypeContainer;
+use candid::types::{Function, Type, TypeInner};
+use candid::Principal;
+use ic_stable_structures::storable::Blob;
+pub use ic_vetkd_cdk_types::{now, AccessRights, ByteBuf, TransportKey};
+
+/// A standard endpoint and its Candid signature, which is built from the
+/// Rust types of its arguments and result, see [`did_service`].
+#[derive(Clone, Copy)]
+pub struct ApiMethod {
+    pub name: &'static str,
+    /// Adds the types of the arguments and the result to the container and
+    /// returns the signature.
+    pub signature: fn(&mut TypeContainer) -> Function,
+}
+
+/// Declares an [`ApiMethod`] with the given argument and result types, e.g.,
+/// `api_method!(get_user_rights(Principal, ByteBuf, Principal) -> Result<..>, Query)`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __api_method {
+    ($name:ident($($arg:ty),* $(,)?) -> $ret:ty $(, $mode:ident)?) => {
+        $crate::api::ApiMethod {
+            name: stringify!($name),
+            signature: |env| ::candid::types::Function {
+                modes: vec![$(::candid::types::FuncMode::$mode)?],
+                args: vec![$(env.add::<$arg>()),*],
+                rets: vec![env.add::<$ret>()],
+            },
+        }
+    };
+}
+
+/// The standard endpoints.
+pub const KEY_MANAGER_API_METHODS: [ApiMethod; 7] = [
+    crate::__api_method!(get_accessible_shared_key_ids() -> Vec<(Principal, ByteBuf)>, Query),
+    crate::__api_method!(
+        get_encrypted_vetkey(Principal, ByteBuf, TransportKey) -> Result<VetKey, String>
+    ),
+    crate::__api_method!(
+        get_shared_user_access_for_key(Principal, ByteBuf)
+            -> Result<Vec<(Principal, AccessRights)>, String>,
+        Query
+    ),
+    crate::__api_method!(
+        get_user_rights(Principal, ByteBuf, Principal) -> Result<Option<AccessRights>, String>,
+        Query
+    ),
+    crate::__api_method!(get_vetkey_verification_key() -> VetKeyVerificationKey),
+    crate::__api_method!(
+        remove_user(Principal, ByteBuf, Principal) -> Result<Option<AccessRights>, String>
+    ),
+    crate::__api_method!(
+        set_user_rights(Principal, ByteBuf, Principal, AccessRights)
+            -> Result<Option<AccessRights>, String>
+    ),
+];
+
+/// Returns the Candid declaration of a service with `methods`, except for
+/// the methods named in `except`: the definitions of the types used by the
+/// methods, followed by the service.
+#[must_use]
+pub fn did_service(methods: &[ApiMethod], except: &[&str]) -> String {
+    let mut env = TypeContainer::new();
+    let service = methods
+        .iter()
+        .filter(|method| !except.contains(&method.name))
+        .map(|method| {
+            let signature = (method.signature)(&mut env);
+            (method.name.to_string(), TypeInner::Func(signature).into())
+        })
+        .collect();
+    let actor: Type = TypeInner::Service(service).into();
+    candid::pretty::candid::compile(&env.env, &Some(actor))
+}
+
+/// Converts the owner and the name of a key passed to an endpoint to a key ID.
+///
+/// # Errors
+///
+/// Returns an error if the name is longer than
+/// [`MAX_NAME_BYTES`](ic_vetkd_cdk_types::MAX_NAME_BYTES).
+pub fn key_id(key_owner: Principal, key_name: &ByteBuf) -> Result<KeyId, String> {
+    Ok((key_owner, name(key_name)?))
+}
+
+/// Converts a name passed to an endpoint, e.g., of a key or a map key.
+///
+/// # Errors
+///
+/// Returns an error if the name is longer than `N` bytes.
+pub fn name<const N: usize>(name: &ByteBuf) -> Result<Blob<N>, String> {
+    Blob::try_from(name.as_ref()).map_err(|_| "too large input".to_string())
+}
+
+/// Converts a name to the `ByteBuf` returned by an endpoint.
+#[must_use]
+pub fn name_to_bytebuf<const N: usize>(name: &Blob<N>) -> ByteBuf {
+    ByteBuf::from(name.as_slice().to_vec())
+}
+
+/// The default guard of the standard endpoints, which allows all calls.
+///
+/// # Errors
+///
+/// Never returns an error.
+pub fn allow_all(_method: &str) -> Result<(), String> {
+    Ok(())
+}
+
+/// Installs the standard endpoints of a `KeyManager` in a canister, see
+/// [`crate::api`].
+///
+/// `$state` is a `thread_local!` `RefCell` holding a `KeyManager` or any other
+/// type implementing `AsRef<KeyManager>` and `AsMut<KeyManager>`. The canister
+/// must depend on `candid` and `ic-cdk`.
+///
+/// ```ignore
+/// ic_vetkd_cdk_key_manager::export_key_manager_api!(KEY_MANAGER);
+///
+/// // Without `remove_user`, and with a guard `fn(&str) -> Result<(), String>`:
+/// ic_vetkd_cdk_key_manager::export_key_manager_api!(
+///     KEY_MANAGER,
+///     except: [remove_user],
+///     guard: reject_anonymous
+/// );
+///
+/// // Only the listed endpoints:
+/// ic_vetkd_cdk_key_manager::export_key_manager_api!(
+///     KEY_MANAGER,
+///     only: [get_encrypted_vetkey, get_vetkey_verification_key]
+/// );
+/// ```
+#[macro_export]
+macro_rules! export_key_manager_api {
+    ($state:ident $(,)?) => {
+        $crate::export_key_manager_api!($state, except: [], guard: $crate::api::allow_all);
+    };
+    ($state:ident, guard: $guard:path $(,)?) => {
+        $crate::export_key_manager_api!($state, except: [], guard: $guard);
+    };
+    ($state:ident, except: [$($skip:ident),* $(,)?] $(,)?) => {
+        $crate::export_key_manager_api!(
+            $state,
+            except: [$($skip),*],
+            guard: $crate::api::allow_all
+        );
+    };
+    ($state:ident, only: [$($method:ident),* $(,)?] $(,)?) => {
+        $crate::export_key_manager_api!(
+            $state,
+            only: [$($method),*],
+            guard: $crate::api::allow_all
+        );
+    };
+    ($state:ident, only: [$($method:ident),* $(,)?], guard: $guard:path $(,)?) => {
+        $($crate::__key_manager_api_method!($method; $state, $guard;);)*
+    };
+    ($state:ident, except: [$($skip:ident),* $(,)?], guard: $guard:path $(,)?) => {
+        $crate::__key_manager_api_method!(get_accessible_shared_key_ids; $state, $guard; $($skip)*);
+        $crate::__key_manager_api_method!(get_shared_user_access_for_key; $state, $guard; $($skip)*);
+        $crate::__key_manager_api_method!(get_vetkey_verification_key; $state, $guard; $($skip)*);
+        $crate::__key_manager_api_method!(get_encrypted_vetkey; $state, $guard; $($skip)*);
+        $crate::__key_manager_api_method!(get_user_rights; $state, $guard; $($skip)*);
+        $crate::__key_manager_api_method!(set_user_rights; $state, $guard; $($skip)*);
+        $crate::__key_manager_api_method!(remove_user; $state, $guard; $($skip)*);
+    };
+}
+
+/// Installs a single endpoint of [`export_key_manager_api`] unless it is
+/// listed after the second `;`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __key_manager_api_method {
+    (get_accessible_shared_key_ids; $state:ident, $guard:path; get_accessible_shared_key_ids $($rest:ident)*) => {};
+    (get_shared_user_access_for_key; $state:ident, $guard:path; get_shared_user_access_for_key $($rest:ident)*) => {};
+    (get_vetkey_verification_key; $state:ident, $guard:path; get_vetkey_verification_key $($rest:ident)*) => {};
+    (get_encrypted_vetkey; $state:ident, $guard:path; get_encrypted_vetkey $($rest:ident)*) => {};
+    (get_user_rights; $state:ident, $guard:path; get_user_rights $($rest:ident)*) => {};
+    (set_user_rights; $state:ident, $guard:path; set_user_rights $($rest:ident)*) => {};
+    (remove_user; $state:ident, $guard:path; remove_user $($rest:ident)*) => {};
+    ($method:ident; $state:ident, $guard:path; $skip:ident $($rest:ident)*) => {
+        $crate::__key_manager_api_method!($method; $state, $guard; $($rest)*);
+    };
+
+    (get_accessible_shared_key_ids; $state:ident, $guard:path;) => {
+        #[::ic_cdk::query]
+        fn get_accessible_shared_key_ids() -> ::std::vec::Vec<(::candid::Principal, $crate::api::ByteBuf)> {
+            if let ::std::result::Result::Err(error) = $guard("get_accessible_shared_key_ids") {
+                ::ic_cdk::trap(&error);
+            }
+            $state.with_borrow(|state| {
+                ::std::convert::AsRef::<$crate::KeyManager>::as_ref(state)
+                    .get_accessible_shared_key_ids(::ic_cdk::caller())
+                    .into_iter()
+                    .map(|(key_owner, key_name)| {
+                        (key_owner, $crate::api::name_to_bytebuf(&key_name))
+                    })
+                    .collect()
+            })
+        }
+    };
+    (get_shared_user_access_for_key; $state:ident, $guard:path;) => {
+        #[::ic_cdk::query]
+        #[allow(clippy::needless_pass_by_value)]
+        fn get_shared_user_access_for_key(
+            key_owner: ::candid::Principal,
+            key_name: $crate::api::ByteBuf,
+        ) -> ::std::result::Result<
+            ::std::vec::Vec<(::candid::Principal, $crate::api::AccessRights)>,
+            ::std::string::String,
+        > {
+            $guard("get_shared_user_access_for_key")?;
+            let key_id = $crate::api::key_id(key_owner, &key_name)?;
+            $state.with_borrow(|state| {
+                ::std::convert::AsRef::<$crate::KeyManager>::as_ref(state)
+                    .get_shared_user_access_for_key(::ic_cdk::caller(), key_id)
+            })
+        }
+    };
+    (get_vetkey_verification_key; $state:ident, $guard:path;) => {
+        #[::ic_cdk::update]
+        async fn get_vetkey_verification_key() -> $crate::VetKeyVerificationKey {
+            if let ::std::result::Result::Err(error) = $guard("get_vetkey_verification_key") {
+                ::ic_cdk::trap(&error);
+            }
+            $state
+                .with_borrow(|state| {
+                    ::std::convert::AsRef::<$crate::KeyManager>::as_ref(state)
+                        .get_vetkey_verification_key()
+                })
+                .await
+                .unwrap_or_else(|error| ::ic_cdk::trap(&error))
+        }
+    };
+    (get_encrypted_vetkey; $state:ident, $guard:path;) => {
+        #[::ic_cdk::update]
+        #[allow(clippy::needless_pass_by_value)]
+        async fn get_encrypted_vetkey(
+            key_owner: ::candid::Principal,
+            key_name: $crate::api::ByteBuf,
+            transport_key: $crate::api::TransportKey,
+        ) -> ::std::result::Result<$crate::VetKey, ::std::string::String> {
+            $guard("get_encrypted_vetkey")?;
+            let key_id = $crate::api::key_id(key_owner, &key_name)?;
+
+            // Refresh the holder of the token the key is bound to, if any
+            if let ::std::option::Option::Some(fetch_token_owner) = $state.with_borrow(|state| {
+                ::std::convert::AsRef::<$crate::KeyManager>::as_ref(state)
+                    .fetch_token_owner(key_id, $crate::api::now())
+            }) {
+                let token_owner = fetch_token_owner.await?;
+                $state.with_borrow_mut(|state| {
+                    ::std::convert::AsMut::<$crate::KeyManager>::as_mut(state)
+                        .record_token_owner(key_id, token_owner);
+                });
+            }
+
+            let derived = $state
+                .with_borrow(|state| {
+                    ::std::convert::AsRef::<$crate::KeyManager>::as_ref(state)
+                        .get_encrypted_vetkey(::ic_cdk::caller(), key_id, transport_key)
+                })?
+                .await?;
+            ::std::result::Result::Ok($state.with_borrow_mut(|state| {
+                ::std::convert::AsMut::<$crate::KeyManager>::as_mut(state)
+                    .record_vetkey_access(derived)
+            }))
+        }
+    };
+    (get_user_rights; $state:ident, $guard:path;) => {
+        #[::ic_cdk::query]
+        #[allow(clippy::needless_pass_by_value)]
+        fn get_user_rights(
+            key_owner: ::candid::Principal,
+            key_name: $crate::api::ByteBuf,
+            user: ::candid::Principal,
+        ) -> ::std::result::Result<
+            ::std::option::Option<$crate::api::AccessRights>,
+            ::std::string::String,
+        > {
+            $guard("get_user_rights")?;
+            let key_id = $crate::api::key_id(key_owner, &key_name)?;
+            $state.with_borrow(|state| {
+                ::std::convert::AsRef::<$crate::KeyManager>::as_ref(state)
+                    .get_user_rights(::ic_cdk::caller(), key_id, user)
+            })
+        }
+    };
+    (set_user_rights; $state:ident, $guard:path;) => {
+        #[::ic_cdk::update]
+        #[allow(clippy::needless_pass_by_value)]
+        fn set_user_rights(
+            key_owner: ::candid::Principal,
+            key_name: $crate::api::ByteBuf,
+            user: ::candid::Principal,
+            access_rights: $crate::api::AccessRights,
+        ) -> ::std::result::Result<
+            ::std::option::Option<$crate::api::AccessRights>,
+            ::std::string::String,
+        > {
+            $guard("set_user_rights")?;
+            let key_id = $crate::api::key_id(key_owner, &key_name)?;
+            $state.with_borrow_mut(|state| {
+                ::std::convert::AsMut::<$crate::KeyManager>::as_mut(state).set_user_rights(
+                    ::ic_cdk::caller(),
+                    key_id,
+                    user,
+                    access_rights,
+                )
+            })
+        }
+    };
+    (remove_user; $state:ident, $guard:path;) => {
+        #[::ic_cdk::update]
+        #[allow(clippy::needless_pass_by_value)]
+        fn remove_user(
+            key_owner: ::candid::Principal,
+            key_name: $crate::api::ByteBuf,
+            user: ::candid::Principal,
+        ) -> ::std::result::Result<
+            ::std::option::Option<$crate::api::AccessRights>,
+            ::std::string::String,
+        > {
+            $guard("remove_user")?;
+            let key_id = $crate::api::key_id(key_owner, &key_name)?;
+            $state.with_borrow_mut(|state| {
+                ::std::convert::AsMut::<$crate::KeyManager>::as_mut(state)
+                    .remove_user(::ic_cdk::caller(), key_id, user)
+            })
+        }
+    };
+}
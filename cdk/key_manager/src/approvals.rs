@@ -0,0 +1,565 @@
+//! Multi-party approval for granting access to a key.
+//!
+//! A key owner can configure an N-of-M approver set for a key. Once configured,
+//! [`KeyManager::set_user_rights`] no longer changes access rights directly but
+//! records a [`GrantProposal`] that becomes effective as soon as `threshold`
+//! approvers have signed off, or expires after the configured time to live.
+//! [`KeyManager::grant_user_rights`] returns the id of the recorded proposal.
+//! Revoking access via [`KeyManager::remove_user`] is never delayed.
+//!
+//! Once a key has an approver set, [`KeyManager::set_approval_config`] no
+//! longer replaces or removes it directly either but records a proposal that
+//! needs the threshold of the current approver set, so an owner cannot bypass
+//! the approvers by removing them first.
+
+use crate::migration::{MigratedStructure, NamedMap};
+use crate::policy::Operation;
+use crate::{Caller, DefaultMemory, KeyId, KeyManager};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Memory, Storable};
+use ic_vetkd_cdk_types::{decode_versioned, encode_versioned, now, AccessRights, AuditEntry};
+use serde::Deserialize;
+use std::borrow::Cow;
+
+pub type ProposalId = u64;
+
+/// The N-of-M approver set of a key.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ApprovalConfig {
+    /// Principals allowed to approve grants for the key.
+    pub approvers: Vec<Principal>,
+    /// Number of distinct approvals required for a grant to become effective.
+    pub threshold: u32,
+    /// Time in nanoseconds after which a pending proposal expires.
+    pub proposal_ttl: u64,
+}
+
+impl ApprovalConfig {
+    fn validate(&self) -> Result<(), String> {
+        let mut approvers = self.approvers.clone();
+        approvers.sort();
+        approvers.dedup();
+        if approvers.len() != self.approvers.len() {
+            return Err("duplicate approvers".to_string());
+        }
+        if self.threshold == 0 || self.threshold as usize > self.approvers.len() {
+            return Err("invalid approval threshold".to_string());
+        }
+        if self.proposal_ttl == 0 {
+            return Err("invalid proposal time to live".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Storable for ApprovalConfig {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let payload = Encode!(self).expect("failed to encode ApprovalConfig");
+        Cow::Owned(encode_versioned(1, &payload))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        match decode_versioned(bytes.as_ref()) {
+            (0 | 1, payload) => Decode!(payload, Self).expect("failed to decode ApprovalConfig"),
+            (version, _) => panic!("unsupported ApprovalConfig encoding version {version}"),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProposalStatus {
+    /// Waiting for approvals.
+    Pending,
+    /// The threshold was met and the grant has been applied.
+    Executed,
+    /// An approver rejected the grant.
+    Rejected,
+    /// The deadline passed before the threshold was met.
+    Expired,
+}
+
+/// The result of changing the approver set with [`KeyManager::set_approval_config`].
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ApprovalConfigOutcome {
+    /// The approver set was applied directly; holds the set it replaced.
+    Applied(Option<ApprovalConfig>),
+    /// A proposal was recorded because the key already has an approver set.
+    /// Its status is `Executed` if the caller's own approval already met the
+    /// threshold.
+    Proposed {
+        proposal_id: ProposalId,
+        status: ProposalStatus,
+    },
+}
+
+/// The change a proposal applies once it has been approved.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ProposedChange {
+    /// Grants `access_rights` to `user`.
+    UserRights {
+        user: Principal,
+        access_rights: AccessRights,
+    },
+    /// Replaces or, if `None`, removes the approver set of the key.
+    ApprovalConfig(Option<ApprovalConfig>),
+}
+
+/// The result of granting access rights with [`KeyManager::grant_user_rights`].
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GrantOutcome {
+    /// The rights were applied directly; holds the rights they replaced.
+    Applied(Option<AccessRights>),
+    /// A grant proposal was recorded. Its status is `Executed` if the
+    /// caller's own approval already met the threshold.
+    Proposed {
+        proposal_id: ProposalId,
+        status: ProposalStatus,
+    },
+}
+
+/// A pending or finished change to a key that requires approval.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct GrantProposal {
+    pub proposer: Caller,
+    pub change: ProposedChange,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub approvals: Vec<Principal>,
+    pub status: ProposalStatus,
+}
+
+impl GrantProposal {
+    /// Returns the status of the proposal at `time`, where a pending proposal
+    /// past its deadline is expired even if it has not been touched since.
+    #[must_use]
+    pub fn status_at(&self, time: u64) -> ProposalStatus {
+        if self.status == ProposalStatus::Pending && self.expires_at <= time {
+            ProposalStatus::Expired
+        } else {
+            self.status
+        }
+    }
+}
+
+/// Encoding versions 0 and 1 of [`GrantProposal`], which could only grant
+/// access rights.
+#[derive(CandidType, Deserialize)]
+struct GrantProposalV1 {
+    proposer: Caller,
+    user: Principal,
+    access_rights: AccessRights,
+    created_at: u64,
+    expires_at: u64,
+    approvals: Vec<Principal>,
+    status: ProposalStatus,
+}
+
+impl From<GrantProposalV1> for GrantProposal {
+    fn from(proposal: GrantProposalV1) -> Self {
+        Self {
+            proposer: proposal.proposer,
+            change: ProposedChange::UserRights {
+                user: proposal.user,
+                access_rights: proposal.access_rights,
+            },
+            created_at: proposal.created_at,
+            expires_at: proposal.expires_at,
+            approvals: proposal.approvals,
+            status: proposal.status,
+        }
+    }
+}
+
+impl Storable for GrantProposal {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let payload = Encode!(self).expect("failed to encode GrantProposal");
+        Cow::Owned(encode_versioned(2, &payload))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        match decode_versioned(bytes.as_ref()) {
+            (0 | 1, payload) => Decode!(payload, GrantProposalV1)
+                .expect("failed to decode GrantProposal")
+                .into(),
+            (2, payload) => Decode!(payload, Self).expect("failed to decode GrantProposal"),
+            (version, _) => panic!("unsupported GrantProposal encoding version {version}"),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Stable storage of approver sets and proposals.
+pub struct ApprovalStore<M: Memory = DefaultMemory> {
+    pub configs: NamedMap<KeyId, ApprovalConfig, M>,
+    pub proposals: NamedMap<(KeyId, ProposalId), GrantProposal, M>,
+}
+
+impl<M: Memory> KeyManager<M> {
+    /// Enables multi-party approval of grants, see [`crate::approvals`].
+    #[must_use]
+    pub fn with_approvals(mut self, memory_configs: M, memory_proposals: M) -> Self {
+        self.approvals = Some(ApprovalStore {
+            configs: self.init_named_map(MigratedStructure::ApprovalConfigs, memory_configs),
+            proposals: self.init_named_map(MigratedStructure::GrantProposals, memory_proposals),
+        });
+        self
+    }
+
+    /// Sets or, if `config` is `None`, removes the approver set of a key.
+    /// Only the key owner can perform this action. If the key already has an
+    /// approver set, the change is recorded as a proposal that has to be
+    /// approved like a grant.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if:
+    /// - Approvals are not enabled
+    /// - The caller is not the key owner
+    /// - The configuration is invalid
+    pub fn set_approval_config(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        config: Option<ApprovalConfig>,
+    ) -> Result<ApprovalConfigOutcome, String> {
+        if caller != key_id.0 {
+            return Err("unauthorized".to_string());
+        }
+        if let Some(config) = &config {
+            config.validate()?;
+        }
+        if self.approvals.is_none() {
+            return Err("approvals are not enabled".to_string());
+        }
+
+        if let Some(current) = self.get_approval_config(key_id) {
+            let (proposal_id, status) = self.record_proposal(
+                caller,
+                key_id,
+                &current,
+                ProposedChange::ApprovalConfig(config),
+            );
+            return Ok(ApprovalConfigOutcome::Proposed {
+                proposal_id,
+                status,
+            });
+        }
+        Ok(ApprovalConfigOutcome::Applied(
+            self.apply_approval_config(caller, key_id, config, None),
+        ))
+    }
+
+    /// Retrieves the approver set of a key, if any.
+    #[must_use]
+    pub fn get_approval_config(&self, key_id: KeyId) -> Option<ApprovalConfig> {
+        self.approvals
+            .as_ref()
+            .and_then(|store| store.configs.get(&key_id))
+    }
+
+    /// Retrieves all proposals of a key. Pending proposals past their deadline
+    /// are reported as `Expired`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caller neither has access to the key nor is an approver.
+    pub fn get_grant_proposals(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+    ) -> Result<Vec<(ProposalId, GrantProposal)>, String> {
+        let is_approver = self
+            .get_approval_config(key_id)
+            .is_some_and(|config| config.approvers.contains(&caller));
+        if !is_approver {
+            self.authorize(caller, key_id, Operation::Inspect)?;
+        }
+        let time = now();
+        Ok(self
+            .approvals
+            .as_ref()
+            .map(|store| {
+                store
+                    .proposals
+                    .range((key_id, 0)..)
+                    .take_while(|((k, _), _)| k == &key_id)
+                    .map(|((_, id), mut proposal)| {
+                        proposal.status = proposal.status_at(time);
+                        (id, proposal)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// Approves a pending proposal. If the approval threshold is met, the
+    /// proposed change is applied immediately, provided that the proposer of a
+    /// grant is still allowed to grant the access rights.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if:
+    /// - The caller is not an approver of the key
+    /// - The proposal does not exist or is not pending
+    /// - The proposal has expired
+    /// - The threshold is met but the proposer no longer has the `SHARE`
+    ///   permission or all of the granted or replaced permissions
+    pub fn approve_grant_proposal(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        proposal_id: ProposalId,
+    ) -> Result<ProposalStatus, String> {
+        let (config, mut proposal) =
+            self.pending_proposal_for_approver(caller, key_id, proposal_id)?;
+
+        if proposal.approvals.contains(&caller) {
+            return Err("already approved".to_string());
+        }
+        if proposal.approvals.len() + 1 >= config.threshold as usize {
+            if let ProposedChange::UserRights {
+                user,
+                access_rights,
+            } = proposal.change
+            {
+                self.ensure_can_grant(proposal.proposer, key_id, user, access_rights)
+                    .map_err(|_| "proposer is no longer authorized".to_string())?;
+            }
+        }
+        proposal.approvals.push(caller);
+        self.add_audit_log(key_id, move || {
+            AuditEntry::approval_granted(now(), caller, proposal_id)
+        });
+
+        let execute = proposal.approvals.len() >= config.threshold as usize;
+        if execute {
+            proposal.status = ProposalStatus::Executed;
+        }
+        let status = proposal.status;
+        let change = proposal.change.clone();
+        self.store_proposal(key_id, proposal_id, proposal);
+        if execute {
+            self.apply_proposed_change(caller, key_id, proposal_id, change);
+        }
+        Ok(status)
+    }
+
+    /// Rejects a pending proposal.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if:
+    /// - The caller is not an approver of the key
+    /// - The proposal does not exist or is not pending
+    /// - The proposal has expired
+    pub fn reject_grant_proposal(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        proposal_id: ProposalId,
+    ) -> Result<(), String> {
+        let (_config, mut proposal) =
+            self.pending_proposal_for_approver(caller, key_id, proposal_id)?;
+        proposal.status = ProposalStatus::Rejected;
+        self.store_proposal(key_id, proposal_id, proposal);
+        self.add_audit_log(key_id, move || {
+            AuditEntry::approval_rejected(now(), caller, proposal_id)
+        });
+        Ok(())
+    }
+
+    /// Records a proposal for `grant_user_rights` if the key has an approver
+    /// set. Returns `None` if no approval is required.
+    pub(crate) fn propose_user_rights(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        user: Principal,
+        access_rights: AccessRights,
+    ) -> Option<GrantOutcome> {
+        let config = self.get_approval_config(key_id)?;
+        let (proposal_id, status) = self.record_proposal(
+            caller,
+            key_id,
+            &config,
+            ProposedChange::UserRights {
+                user,
+                access_rights,
+            },
+        );
+        Some(GrantOutcome::Proposed {
+            proposal_id,
+            status,
+        })
+    }
+
+    /// Records a proposal for `change` under `config` and applies it right away
+    /// if the caller's own approval meets the threshold.
+    fn record_proposal(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        config: &ApprovalConfig,
+        change: ProposedChange,
+    ) -> (ProposalId, ProposalStatus) {
+        self.expire_grant_proposals(caller, key_id);
+        let store = self
+            .approvals
+            .as_mut()
+            .expect("keys with an approver set have approvals enabled");
+
+        let proposal_id = store
+            .proposals
+            .keys_range((key_id, 0)..)
+            .take_while(|(k, _)| k == &key_id)
+            .map(|(_, id)| id + 1)
+            .last()
+            .unwrap_or(0);
+
+        let created_at = now();
+        let mut proposal = GrantProposal {
+            proposer: caller,
+            change: change.clone(),
+            created_at,
+            expires_at: created_at.saturating_add(config.proposal_ttl),
+            approvals: vec![],
+            status: ProposalStatus::Pending,
+        };
+        // A proposer that is an approver implicitly approves their own proposal.
+        if config.approvers.contains(&caller) {
+            proposal.approvals.push(caller);
+        }
+        let execute = proposal.approvals.len() >= config.threshold as usize;
+        if execute {
+            proposal.status = ProposalStatus::Executed;
+        }
+        let status = proposal.status;
+        store.proposals.insert((key_id, proposal_id), proposal);
+
+        match &change {
+            ProposedChange::UserRights {
+                user,
+                access_rights,
+            } => {
+                let (user, access_rights) = (*user, *access_rights);
+                self.add_audit_log(key_id, move || {
+                    AuditEntry::approval_requested(now(), caller, user, access_rights, proposal_id)
+                });
+            }
+            ProposedChange::ApprovalConfig(_) => {
+                self.add_audit_log(key_id, move || {
+                    AuditEntry::approval_config_proposed(now(), caller, proposal_id)
+                });
+            }
+        }
+        if execute {
+            self.apply_proposed_change(caller, key_id, proposal_id, change);
+        }
+        (proposal_id, status)
+    }
+
+    fn apply_proposed_change(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        proposal_id: ProposalId,
+        change: ProposedChange,
+    ) {
+        match change {
+            ProposedChange::UserRights {
+                user,
+                access_rights,
+            } => {
+                self.apply_user_rights(caller, key_id, user, access_rights, Some(proposal_id));
+            }
+            ProposedChange::ApprovalConfig(config) => {
+                self.apply_approval_config(caller, key_id, config, Some(proposal_id));
+            }
+        }
+    }
+
+    fn apply_approval_config(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        config: Option<ApprovalConfig>,
+        proposal_id: Option<ProposalId>,
+    ) -> Option<ApprovalConfig> {
+        let store = self.approvals.as_mut()?;
+        let previous = match config {
+            Some(config) => store.configs.insert(key_id, config),
+            None => store.configs.remove(&key_id),
+        };
+        self.add_audit_log(key_id, move || {
+            let entry = AuditEntry::approval_config_changed(now(), caller);
+            match proposal_id {
+                Some(proposal_id) => entry.with_reference_id(proposal_id),
+                None => entry,
+            }
+        });
+        previous
+    }
+
+    /// Marks the pending proposals of a key that are past their deadline as
+    /// expired and records the transition in the audit log.
+    fn expire_grant_proposals(&mut self, caller: Principal, key_id: KeyId) {
+        let time = now();
+        let expired: Vec<_> = self
+            .approvals
+            .as_ref()
+            .map(|store| {
+                store
+                    .proposals
+                    .range((key_id, 0)..)
+                    .take_while(|((k, _), _)| k == &key_id)
+                    .filter(|(_, proposal)| proposal.status != proposal.status_at(time))
+                    .map(|((_, id), proposal)| (id, proposal))
+                    .collect()
+            })
+            .unwrap_or_default();
+        for (proposal_id, mut proposal) in expired {
+            proposal.status = ProposalStatus::Expired;
+            self.store_proposal(key_id, proposal_id, proposal);
+            self.add_audit_log(key_id, move || {
+                AuditEntry::approval_expired(now(), caller, proposal_id)
+            });
+        }
+    }
+
+    fn pending_proposal_for_approver(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        proposal_id: ProposalId,
+    ) -> Result<(ApprovalConfig, GrantProposal), String> {
+        let config = self
+            .get_approval_config(key_id)
+            .ok_or_else(|| "no approval configured".to_string())?;
+        if !config.approvers.contains(&caller) {
+            return Err("unauthorized".to_string());
+        }
+        self.expire_grant_proposals(caller, key_id);
+        let proposal = self
+            .approvals
+            .as_ref()
+            .and_then(|store| store.proposals.get(&(key_id, proposal_id)))
+            .ok_or_else(|| "proposal not found".to_string())?;
+        match proposal.status {
+            ProposalStatus::Pending => Ok((config, proposal)),
+            ProposalStatus::Expired => Err("proposal expired".to_string()),
+            ProposalStatus::Executed | ProposalStatus::Rejected => {
+                Err("proposal is not pending".to_string())
+            }
+        }
+    }
+
+    fn store_proposal(&mut self, key_id: KeyId, proposal_id: ProposalId, proposal: GrantProposal) {
+        if let Some(store) = self.approvals.as_mut() {
+            store.proposals.insert((key_id, proposal_id), proposal);
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//! Authorization checks for other canisters.
+//!
+//! Canisters that serve data derived from keys or maps, e.g., search indexes
+//! or thumbnails, can ask the canister holding the `KeyManager` whether a user
+//! may perform an operation before they serve it. Only canisters on an
+//! allow-list, enabled with [`KeyManager::with_authorized_canisters`] and
+//! managed by admins, see [`crate::admin`], may ask.
+//!
+//! [`export_authorization_api!`](crate::export_authorization_api) installs the
+//! endpoints in a canister and [`AuthorizationClient`] calls them. Since
+//! `check_access` is a query, it can also be called from composite queries.
+//! Only synchronous access policies are applied, see [`crate::policy`].
+
+use crate::policy::{Decision, Operation};
+use crate::{KeyId, KeyManager};
+use candid::{CandidType, Principal};
+use ic_stable_structures::{Memory, StableBTreeMap};
+use ic_vetkd_cdk_types::{now, AccessRights, ByteBuf, KeyName};
+use serde::Deserialize;
+use std::future::Future;
+
+/// The arguments of the `check_access` endpoint.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AccessCheck {
+    pub user: Principal,
+    pub key_owner: Principal,
+    pub key_name: ByteBuf,
+    pub operation: Operation,
+}
+
+impl AccessCheck {
+    #[must_use]
+    pub fn new(user: Principal, key_id: KeyId, operation: Operation) -> Self {
+        Self {
+            user,
+            key_owner: key_id.0,
+            key_name: ByteBuf::from(key_id.1.as_ref().to_vec()),
+            operation,
+        }
+    }
+
+    /// Returns the ID of the key to check.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key name is too long.
+    pub fn key_id(&self) -> Result<KeyId, String> {
+        let key_name =
+            KeyName::try_from(self.key_name.as_ref()).map_err(|_| "too large input".to_string())?;
+        Ok((self.key_owner, key_name))
+    }
+}
+
+impl<M: Memory> KeyManager<M> {
+    /// Enables authorization checks for allow-listed canisters,
+    /// see [`crate::authorization`].
+    #[must_use]
+    pub fn with_authorized_canisters(mut self, memory: M) -> Self {
+        self.authorized_canisters = Some(StableBTreeMap::init(memory));
+        self
+    }
+
+    /// Adds a canister to the allow-list and returns false if it was already on it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if authorization checks are not enabled or if the
+    /// caller is not an admin.
+    pub fn add_authorized_canister(
+        &mut self,
+        caller: Principal,
+        canister_id: Principal,
+    ) -> Result<bool, String> {
+        self.ensure_admin(caller)?;
+        let canisters = self
+            .authorized_canisters
+            .as_mut()
+            .ok_or_else(|| "authorization checks are not enabled".to_string())?;
+        Ok(canisters.insert(canister_id, ()).is_none())
+    }
+
+    /// Removes a canister from the allow-list and returns false if it was not on it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if authorization checks are not enabled or if the
+    /// caller is not an admin.
+    pub fn remove_authorized_canister(
+        &mut self,
+        caller: Principal,
+        canister_id: Principal,
+    ) -> Result<bool, String> {
+        self.ensure_admin(caller)?;
+        let canisters = self
+            .authorized_canisters
+            .as_mut()
+            .ok_or_else(|| "authorization checks are not enabled".to_string())?;
+        Ok(canisters.remove(&canister_id).is_some())
+    }
+
+    /// Lists the canisters that may check access.
+    #[must_use]
+    pub fn get_authorized_canisters(&self) -> Vec<Principal> {
+        self.authorized_canisters
+            .as_ref()
+            .map_or_else(Vec::new, |canisters| {
+                canisters
+                    .iter()
+                    .map(|(canister_id, ())| canister_id)
+                    .collect()
+            })
+    }
+
+    /// Decides whether `check.user` may perform `check.operation` on the key
+    /// now, on behalf of the allow-listed canister `caller`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if authorization checks are not enabled, if `caller`
+    /// is not on the allow-list or if the key name is too long.
+    pub fn check_access(&self, caller: Principal, check: &AccessCheck) -> Result<Decision, String> {
+        let canisters = self
+            .authorized_canisters
+            .as_ref()
+            .ok_or_else(|| "authorization checks are not enabled".to_string())?;
+        if !canisters.contains_key(&caller) {
+            return Err("unauthorized".to_string());
+        }
+        Ok(self.evaluate_access(check.user, check.key_id()?, check.operation, now()))
+    }
+}
+
+impl<M: Memory> AsRef<Self> for KeyManager<M> {
+    fn as_ref(&self) -> &Self {
+        self
+    }
+}
+
+impl<M: Memory> AsMut<Self> for KeyManager<M> {
+    fn as_mut(&mut self) -> &mut Self {
+        self
+    }
+}
+
+/// Calls the endpoints installed by
+/// [`export_authorization_api!`](crate::export_authorization_api) from
+/// another canister.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AuthorizationClient {
+    pub canister_id: Principal,
+}
+
+impl AuthorizationClient {
+    #[must_use]
+    pub const fn new(canister_id: Principal) -> Self {
+        Self { canister_id }
+    }
+
+    /// Returns a future that resolves to the decision on whether `user` may
+    /// perform `operation` on `key_id`.
+    ///
+    /// The future resolves to an error if the call fails or is rejected by
+    /// the canister, e.g., because the calling canister is not allow-listed.
+    pub fn check_access(
+        &self,
+        user: Principal,
+        key_id: KeyId,
+        operation: Operation,
+    ) -> impl Future<Output = Result<Decision, String>> + Send + Sync {
+        let call = ic_cdk::api::call::call::<_, (Result<Decision, String>,)>(
+            self.canister_id,
+            "check_access",
+            (AccessCheck::new(user, key_id, operation),),
+        );
+        async move {
+            let (result,) = call.await.map_err(|(code, message)| {
+                format!("call to check_access failed: {code:?} {message}")
+            })?;
+            result
+        }
+    }
+
+    /// Returns a future that resolves to the access rights that allow `user`
+    /// to perform `operation` on `key_id`.
+    ///
+    /// The future resolves to `"unauthorized"` if the operation is denied and
+    /// to an error if the call fails.
+    pub fn authorize(
+        &self,
+        user: Principal,
+        key_id: KeyId,
+        operation: Operation,
+    ) -> impl Future<Output = Result<AccessRights, String>> + Send + Sync {
+        let check = self.check_access(user, key_id, operation);
+        async move { check.await?.into_result() }
+    }
+}
+
+/// Installs the `check_access` query and the endpoints managing the
+/// allow-list in a canister, see [`crate::authorization`].
+///
+/// `$state` is a `thread_local!` `RefCell` holding a `KeyManager` or any other
+/// type implementing `AsRef<KeyManager>` and `AsMut<KeyManager>`, such as
+/// `EncryptedMaps`. The canister must depend on `candid` and `ic-cdk`.
+///
+/// ```ignore
+/// thread_local! {
+///     static KEY_MANAGER: RefCell<KeyManager> = RefCell::new(
+///         KeyManager::init(/* ... */).with_admins(/* ... */).with_authorized_canisters(/* ... */)
+///     );
+/// }
+///
+/// ic_vetkd_cdk_key_manager::export_authorization_api!(KEY_MANAGER);
+/// ```
+#[macro_export]
+macro_rules! export_authorization_api {
+    ($state:ident) => {
+        #[::ic_cdk::query]
+        #[allow(clippy::needless_pass_by_value)]
+        fn check_access(
+            check: $crate::authorization::AccessCheck,
+        ) -> ::std::result::Result<$crate::policy::Decision, ::std::string::String> {
+            $state.with_borrow(|state| {
+                ::std::convert::AsRef::<$crate::KeyManager>::as_ref(state)
+                    .check_access(::ic_cdk::caller(), &check)
+            })
+        }
+
+        #[::ic_cdk::update]
+        fn add_authorized_canister(
+            canister_id: ::candid::Principal,
+        ) -> ::std::result::Result<bool, ::std::string::String> {
+            $state.with_borrow_mut(|state| {
+                ::std::convert::AsMut::<$crate::KeyManager>::as_mut(state)
+                    .add_authorized_canister(::ic_cdk::caller(), canister_id)
+            })
+        }
+
+        #[::ic_cdk::update]
+        fn remove_authorized_canister(
+            canister_id: ::candid::Principal,
+        ) -> ::std::result::Result<bool, ::std::string::String> {
+            $state.with_borrow_mut(|state| {
+                ::std::convert::AsMut::<$crate::KeyManager>::as_mut(state)
+                    .remove_authorized_canister(::ic_cdk::caller(), canister_id)
+            })
+        }
+
+        #[::ic_cdk::query]
+        fn get_authorized_canisters() -> ::std::vec::Vec<::candid::Principal> {
+            $state.with_borrow(|state| {
+                ::std::convert::AsRef::<$crate::KeyManager>::as_ref(state)
+                    .get_authorized_canisters()
+            })
+        }
+    };
+}
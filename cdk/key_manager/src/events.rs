@@ -0,0 +1,111 @@
+//! Notifications of changes to access rights and to map values.
+//!
+//! Canisters that maintain their own state alongside the `KeyManager` or
+//! `EncryptedMaps`, e.g., an index of shared keys or metadata of map values,
+//! implement [`EventSubscriber`] and register it with
+//! [`KeyManager::with_event_subscriber`] instead of wrapping every call. All
+//! callbacks have empty default implementations, so a subscriber only
+//! implements the ones it needs.
+//!
+//! Subscribers are called synchronously, in registration order, after a state
+//! change has been applied successfully. They are not called for rejected
+//! calls, for state written by [`crate::export`] imports or migrations, or for
+//! access that ends because a grant expires. Since the `KeyManager` is
+//! borrowed while subscribers run, they must not call back into it.
+//!
+//! Subscribers are not persisted and have to be registered again after an upgrade.
+
+use crate::{Caller, KeyId, KeyManager};
+use candid::Principal;
+use ic_stable_structures::Memory;
+use ic_vetkd_cdk_types::{AccessRights, EncryptedMapValue, MapKey};
+
+/// Callbacks for changes made by `caller`, see [`crate::events`].
+///
+/// The value callbacks are only invoked by `EncryptedMaps`.
+#[allow(unused_variables)]
+pub trait EventSubscriber {
+    /// `user` was granted `access_rights` to `key_id`, directly or, e.g.,
+    /// through an approved proposal, a redeemed invite, a purchase or a recovery.
+    fn on_share(
+        &self,
+        caller: Caller,
+        key_id: KeyId,
+        user: Principal,
+        access_rights: AccessRights,
+    ) {
+    }
+
+    /// The `previous_rights` of `user` to `key_id` were revoked.
+    fn on_unshare(
+        &self,
+        caller: Caller,
+        key_id: KeyId,
+        user: Principal,
+        previous_rights: AccessRights,
+    ) {
+    }
+
+    /// The key was deleted by removing its owner.
+    fn on_key_deleted(&self, caller: Caller, key_id: KeyId) {}
+
+    /// `value` was stored under the new `map_key`.
+    fn on_value_inserted(
+        &self,
+        caller: Caller,
+        key_id: KeyId,
+        map_key: MapKey,
+        value: &EncryptedMapValue,
+    ) {
+    }
+
+    /// The value stored under `map_key` was replaced by `value`.
+    fn on_value_updated(
+        &self,
+        caller: Caller,
+        key_id: KeyId,
+        map_key: MapKey,
+        previous_value: &EncryptedMapValue,
+        value: &EncryptedMapValue,
+    ) {
+    }
+
+    /// The value stored under `map_key` was removed. If `soft_deleted` is
+    /// true, it was kept as a tombstone and can be restored.
+    fn on_value_removed(
+        &self,
+        caller: Caller,
+        key_id: KeyId,
+        map_key: MapKey,
+        value: &EncryptedMapValue,
+        soft_deleted: bool,
+    ) {
+    }
+
+    /// The soft-deleted `value` was restored under `map_key`.
+    fn on_value_restored(
+        &self,
+        caller: Caller,
+        key_id: KeyId,
+        map_key: MapKey,
+        value: &EncryptedMapValue,
+    ) {
+    }
+}
+
+impl<M: Memory> KeyManager<M> {
+    /// Registers a subscriber that is notified of all changes, see [`crate::events`].
+    #[must_use]
+    pub fn with_event_subscriber(mut self, subscriber: impl EventSubscriber + 'static) -> Self {
+        self.event_subscribers.push(Box::new(subscriber));
+        self
+    }
+
+    /// Calls `notify` for each registered subscriber, in registration order.
+    /// Used by `EncryptedMaps` to report value changes.
+    pub fn notify_subscribers(&self, notify: impl Fn(&dyn EventSubscriber)) {
+        for subscriber in &self.event_subscribers {
+            notify(subscriber.as_ref());
+        }
+    }
+}
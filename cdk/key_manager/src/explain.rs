@@ -0,0 +1,177 @@
+//! Explanations of authorization decisions.
+//!
+//! [`KeyManager::explain_access`] runs the same evaluation as
+//! [`KeyManager::evaluate_access`], see [`crate::policy`], and additionally
+//! reports which rule determined the decision, the access rights of that rule
+//! and whether they are valid at the time of the evaluation. Canisters can
+//! return the explanation to users who are denied access, e.g., to tell them
+//! that their grant has expired rather than that they never had one.
+//!
+//! An explanation only describes the access of the caller, so no permission is
+//! required to request it.
+
+use crate::policy::{AccessQuery, Decision, DenyReason, Operation};
+use crate::{KeyId, KeyManager};
+use candid::{CandidType, Principal};
+use ic_stable_structures::storable::Blob;
+use ic_stable_structures::Memory;
+use ic_vetkd_cdk_types::{now, AccessRights};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+
+/// The rule that determined an authorization decision.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessSource {
+    /// The caller owns the key.
+    Owner,
+    /// A grant to the caller in `access_control`.
+    DirectGrant,
+    /// A grant to the anonymous principal, i.e., to everyone.
+    PublicGrant,
+    /// The caller holds the token the key is bound to, see [`crate::token_gating`].
+    TokenHolder,
+    /// A custom access policy, see [`crate::policy::AccessPolicy`].
+    Policy,
+}
+
+/// Whether access rights are valid at a given time.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WindowStatus {
+    /// The access rights have neither a start nor an end.
+    Unbounded,
+    NotYetValid,
+    Active,
+    Expired,
+}
+
+impl WindowStatus {
+    /// Returns the status of the validity window of `access_rights` at `time`.
+    #[must_use]
+    pub fn of(access_rights: &AccessRights, time: u64) -> Self {
+        match (access_rights.start(), access_rights.end()) {
+            (None, None) => Self::Unbounded,
+            (Some(start), _) if time < start => Self::NotYetValid,
+            (_, Some(end)) if end <= time => Self::Expired,
+            _ => Self::Active,
+        }
+    }
+}
+
+/// An authorization decision and the rule that determined it.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AccessExplanation {
+    pub operation: Operation,
+    pub time: u64,
+    /// The decision, containing the effective access rights if the operation
+    /// is allowed and the reason otherwise.
+    pub decision: Decision,
+    /// The rule that determined the decision, if any rule applied.
+    pub source: Option<AccessSource>,
+    /// The access rights of `source`, whether or not they allow the operation.
+    pub access_rights: Option<AccessRights>,
+    /// The status of the validity window of `access_rights` at `time`.
+    pub window: Option<WindowStatus>,
+}
+
+impl AccessExplanation {
+    pub(crate) fn new(
+        query: &AccessQuery,
+        decision: Decision,
+        source: Option<AccessSource>,
+        access_rights: Option<AccessRights>,
+    ) -> Self {
+        Self {
+            operation: query.operation,
+            time: query.time,
+            decision,
+            source,
+            access_rights,
+            window: access_rights.map(|access_rights| WindowStatus::of(&access_rights, query.time)),
+        }
+    }
+
+    /// Returns the effective access rights if the operation is allowed.
+    #[must_use]
+    pub const fn effective_rights(&self) -> Option<AccessRights> {
+        match self.decision {
+            Decision::Allow(access_rights) => Some(access_rights),
+            Decision::Deny(_) => None,
+        }
+    }
+
+    /// Returns the reason if the operation is denied.
+    #[must_use]
+    pub const fn deny_reason(&self) -> Option<DenyReason> {
+        match self.decision {
+            Decision::Allow(_) => None,
+            Decision::Deny(reason) => Some(reason),
+        }
+    }
+}
+
+impl<M: Memory> KeyManager<M> {
+    /// Explains whether `caller` may perform `operation` on `key_id` now,
+    /// see [`crate::explain`].
+    #[must_use]
+    pub fn explain_access(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+        operation: Operation,
+    ) -> AccessExplanation {
+        self.explain_query(&AccessQuery {
+            caller,
+            key_id,
+            operation,
+            time: now(),
+        })
+    }
+
+    /// Explains whether `caller` may perform `operation` on each key the
+    /// caller has access to through any rule known to the `KeyManager`: keys
+    /// the caller owns and has shared, keys shared with the caller or with
+    /// everyone, and keys bound to a token the caller was last seen holding.
+    ///
+    /// Keys granted only by custom access policies are not listed. To list
+    /// the effective access rights of the caller, pass [`Operation::Inspect`].
+    #[must_use]
+    pub fn explain_access_to_all_keys(
+        &self,
+        caller: Principal,
+        operation: Operation,
+    ) -> Vec<(KeyId, AccessExplanation)> {
+        let mut key_ids: BTreeSet<KeyId> = self
+            .shared_keys
+            .range(((caller, Blob::default()), Principal::management_canister())..)
+            .take_while(|((key_id, _), _)| key_id.0 == caller)
+            .map(|((key_id, _), _)| key_id)
+            .collect();
+        key_ids.extend(self.get_accessible_shared_key_ids(caller));
+        if caller != Principal::anonymous() && operation.allows_anonymous_grant() {
+            key_ids.extend(self.get_accessible_shared_key_ids(Principal::anonymous()));
+        }
+        if let Some(store) = self.token_gates.as_ref() {
+            key_ids.extend(
+                store
+                    .owners
+                    .iter()
+                    .filter(|(_, token_owner)| token_owner.owner == Some(caller))
+                    .map(|(key_id, _)| key_id),
+            );
+        }
+
+        let time = now();
+        key_ids
+            .into_iter()
+            .map(|key_id| {
+                let explanation = self.explain_query(&AccessQuery {
+                    caller,
+                    key_id,
+                    operation,
+                    time,
+                });
+                (key_id, explanation)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,691 @@
+//! Chunked export and import of the stored state, e.g., to move it to another canister.
+//!
+//! An export is a sequence of [`ExportChunk`]s. Each chunk holds up to a
+//! given number of entries of one [`ExportSection`] in their stable-memory
+//! encoding. Chunks are hash-chained: the hash of a chunk covers its contents
+//! and the hash of the previous chunk, so the importer detects modified,
+//! missing, reordered or duplicated chunks. An admin (see [`crate::admin`])
+//! fetches the [`ExportSummary`] and then the chunks, passing the cursor of
+//! each chunk to the next call:
+//!
+//! ```ignore
+//! let summary = source.export_summary(admin)?;
+//! target.begin_import(admin, summary.clone())?;
+//! let mut cursor = None;
+//! loop {
+//!     let chunk = source.export_chunk(admin, cursor, MAX_EXPORT_CHUNK_ENTRIES)?;
+//!     cursor = chunk.next.clone();
+//!     target.import_chunk(admin, chunk)?;
+//!     if cursor.is_none() {
+//!         break;
+//!     }
+//! }
+//! target.finish_import(admin)?;
+//! ```
+//!
+//! [`KeyManager::finish_import`] checks that all chunks were imported, that
+//! the number of stored entries of each section matches the summary, and that
+//! the imported entries hash to the summary's content hash. The content hash
+//! covers the state at the time the summary was created, so an export during
+//! which the state changed fails to import instead of yielding a mix of old
+//! and new entries; export again in this case.
+//!
+//! The import in progress is stored in the `KeyManager`'s metadata cell and
+//! survives upgrades of the target. [`KeyManager::abort_import`] ends it and
+//! removes the entries imported so far, e.g., to start over with a new summary. Imports require an empty target with the
+//! same domain separator as the source, since keys are derived from it: values
+//! encrypted under keys of another domain separator cannot be decrypted.
+
+use crate::migration::NamedMap;
+use crate::KeyManager;
+use candid::CandidType;
+use ic_stable_structures::{Memory, StableBTreeMap, Storable};
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+
+/// The version of the export format.
+pub const EXPORT_FORMAT_VERSION: u32 = 3;
+
+/// Maximum number of entries in a single chunk.
+pub const MAX_EXPORT_CHUNK_ENTRIES: usize = 1_000;
+
+/// The sections exported by a `KeyManager`, in export order.
+pub const KEY_MANAGER_SECTIONS: [ExportSection; 3] = [
+    ExportSection::AccessControl,
+    ExportSection::SharedKeys,
+    ExportSection::AuditLogs,
+];
+
+/// A stable structure included in an export.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportSection {
+    AccessControl,
+    SharedKeys,
+    AuditLogs,
+    EncryptedMapValues,
+    Tombstones,
+}
+
+impl ExportSection {
+    const fn tag(self) -> u8 {
+        match self {
+            Self::AccessControl => 0,
+            Self::SharedKeys => 1,
+            Self::AuditLogs => 2,
+            Self::EncryptedMapValues => 3,
+            Self::Tombstones => 4,
+        }
+    }
+}
+
+/// Describes an export and allows the importer to check its consistency.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ExportSummary {
+    pub format_version: u32,
+    pub domain_separator: String,
+    /// The number of entries of each section, in export order.
+    pub sections: Vec<(ExportSection, u64)>,
+    /// The hash of all entries at the time the summary was created, see
+    /// [`chain_content_hash`].
+    pub content_hash: ByteBuf,
+}
+
+impl ExportSummary {
+    /// Describes an export of `sections`, where `stored_entries` returns the
+    /// number of entries of a section and `read` reads them as in [`build_chunk`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `read` fails.
+    pub fn new<F>(
+        domain_separator: &str,
+        sections: &[ExportSection],
+        stored_entries: impl Fn(ExportSection) -> u64,
+        mut read: F,
+    ) -> Result<Self, String>
+    where
+        F: FnMut(
+            ExportSection,
+            Option<&[u8]>,
+            usize,
+        ) -> Result<(Vec<(ByteBuf, ByteBuf)>, bool), String>,
+    {
+        let mut content_hash = ByteBuf::new();
+        for section in sections {
+            let mut after: Option<ByteBuf> = None;
+            loop {
+                let (entries, more) = read(*section, after.as_deref(), MAX_EXPORT_CHUNK_ENTRIES)?;
+                content_hash = chain_content_hash(&content_hash, *section, &entries);
+                if !more {
+                    break;
+                }
+                after = entries.last().map(|(key, _)| key.clone());
+            }
+        }
+        Ok(Self {
+            format_version: EXPORT_FORMAT_VERSION,
+            domain_separator: domain_separator.to_string(),
+            sections: sections
+                .iter()
+                .map(|section| (*section, stored_entries(*section)))
+                .collect(),
+            content_hash,
+        })
+    }
+}
+
+/// Extends the content hash of an export with encoded entries of `section`.
+/// Each entry is hashed together with the hash so far, so the result only
+/// depends on the entries and their order, not on how they are split into chunks.
+#[must_use]
+pub fn chain_content_hash(
+    content_hash: &[u8],
+    section: ExportSection,
+    entries: &[(ByteBuf, ByteBuf)],
+) -> ByteBuf {
+    let mut content_hash = content_hash.to_vec();
+    for (key, value) in entries {
+        let mut hasher = Sha256::new();
+        hash_bytes(&mut hasher, &content_hash);
+        hasher.update([section.tag()]);
+        hash_bytes(&mut hasher, key);
+        hash_bytes(&mut hasher, value);
+        content_hash = hasher.finalize().to_vec();
+    }
+    ByteBuf::from(content_hash)
+}
+
+/// The position of the next chunk of an export.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ExportCursor {
+    pub section: ExportSection,
+    /// Encoded key of the last exported entry of the section, if any.
+    pub after: Option<ByteBuf>,
+    pub sequence: u64,
+    pub previous_hash: ByteBuf,
+}
+
+/// A chunk of an export.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ExportChunk {
+    pub format_version: u32,
+    pub sequence: u64,
+    pub section: ExportSection,
+    /// Encoded keys and values of the exported entries.
+    pub entries: Vec<(ByteBuf, ByteBuf)>,
+    /// The hash of the previous chunk, or empty for the first chunk.
+    pub previous_hash: ByteBuf,
+    /// The SHA-256 hash of the chunk, see [`ExportChunk::compute_hash`].
+    pub hash: ByteBuf,
+    /// The cursor of the next chunk, or `None` if this is the last chunk.
+    pub next: Option<ExportCursor>,
+}
+
+impl ExportChunk {
+    /// Computes the hash of the chunk's format version, sequence number,
+    /// section, previous hash and entries.
+    #[must_use]
+    pub fn compute_hash(&self) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(self.format_version.to_be_bytes());
+        hasher.update(self.sequence.to_be_bytes());
+        hasher.update([self.section.tag()]);
+        hash_bytes(&mut hasher, &self.previous_hash);
+        for (key, value) in &self.entries {
+            hash_bytes(&mut hasher, key);
+            hash_bytes(&mut hasher, value);
+        }
+        hasher.finalize().to_vec()
+    }
+}
+
+fn hash_bytes(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_be_bytes());
+    hasher.update(bytes);
+}
+
+/// The progress of an import.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ImportSession {
+    pub summary: ExportSummary,
+    next_sequence: u64,
+    previous_hash: ByteBuf,
+    imported: Vec<(ExportSection, u64)>,
+    content_hash: ByteBuf,
+    complete: bool,
+}
+
+impl ImportSession {
+    /// Starts an import of the export described by `summary`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the format version or the domain separator differ,
+    /// or if the summary contains sections other than `sections`.
+    pub fn new(
+        summary: ExportSummary,
+        domain_separator: &str,
+        sections: &[ExportSection],
+    ) -> Result<Self, String> {
+        if summary.format_version != EXPORT_FORMAT_VERSION {
+            return Err(format!(
+                "unsupported export format version {}",
+                summary.format_version
+            ));
+        }
+        if summary.domain_separator != domain_separator {
+            return Err("domain separator mismatch".to_string());
+        }
+        let summary_sections: Vec<ExportSection> = summary
+            .sections
+            .iter()
+            .map(|(section, _)| *section)
+            .collect();
+        if summary_sections != sections {
+            return Err("unexpected export sections".to_string());
+        }
+        let imported = sections.iter().map(|section| (*section, 0)).collect();
+        Ok(Self {
+            summary,
+            next_sequence: 0,
+            previous_hash: ByteBuf::new(),
+            imported,
+            content_hash: ByteBuf::new(),
+            complete: false,
+        })
+    }
+
+    /// Verifies that `chunk` is the next chunk of the export and records it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the chunk does not continue the export or its hash is invalid.
+    pub fn accept(&mut self, chunk: &ExportChunk) -> Result<(), String> {
+        if self.complete {
+            return Err("import is already complete".to_string());
+        }
+        if chunk.format_version != self.summary.format_version {
+            return Err("unexpected export format version".to_string());
+        }
+        if chunk.sequence != self.next_sequence || chunk.previous_hash != self.previous_hash {
+            return Err("chunk does not continue the export".to_string());
+        }
+        if chunk.compute_hash() != chunk.hash.as_ref() {
+            return Err("chunk hash mismatch".to_string());
+        }
+        let imported = self
+            .imported
+            .iter_mut()
+            .find(|(section, _)| *section == chunk.section)
+            .ok_or_else(|| "unexpected export section".to_string())?;
+        imported.1 += chunk.entries.len() as u64;
+        self.content_hash = chain_content_hash(&self.content_hash, chunk.section, &chunk.entries);
+
+        self.next_sequence += 1;
+        self.previous_hash = chunk.hash.clone();
+        self.complete = chunk.next.is_none();
+        Ok(())
+    }
+
+    /// Checks that all chunks were imported, that the number of imported and
+    /// of stored entries of each section match the summary, and that the
+    /// imported entries match the summary's content hash.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error describing the first inconsistency found.
+    pub fn finish(&self, stored_entries: impl Fn(ExportSection) -> u64) -> Result<(), String> {
+        if !self.complete {
+            return Err("import is incomplete".to_string());
+        }
+        for ((section, expected), (_, imported)) in self.summary.sections.iter().zip(&self.imported)
+        {
+            let stored = stored_entries(*section);
+            if *imported != *expected || stored != *expected {
+                return Err(format!(
+                    "inconsistent {section:?}: expected {expected} entries, imported {imported}, stored {stored}"
+                ));
+            }
+        }
+        if self.content_hash != self.summary.content_hash {
+            return Err("exported state changed after the summary was created".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Builds the chunk at `cursor` of an export of `sections`, where `read`
+/// returns up to `limit` encoded entries of a section following the given
+/// encoded key and whether more entries follow.
+///
+/// # Errors
+///
+/// Returns an error if `max_entries` is invalid, the cursor does not belong
+/// to `sections`, or `read` fails.
+pub fn build_chunk<F>(
+    sections: &[ExportSection],
+    cursor: Option<ExportCursor>,
+    max_entries: usize,
+    read: F,
+) -> Result<ExportChunk, String>
+where
+    F: FnOnce(
+        ExportSection,
+        Option<&[u8]>,
+        usize,
+    ) -> Result<(Vec<(ByteBuf, ByteBuf)>, bool), String>,
+{
+    if max_entries == 0 || max_entries > MAX_EXPORT_CHUNK_ENTRIES {
+        return Err("invalid chunk size".to_string());
+    }
+    let cursor = match cursor {
+        Some(cursor) => cursor,
+        None => ExportCursor {
+            section: *sections
+                .first()
+                .ok_or_else(|| "nothing to export".to_string())?,
+            after: None,
+            sequence: 0,
+            previous_hash: ByteBuf::new(),
+        },
+    };
+    let position = sections
+        .iter()
+        .position(|section| *section == cursor.section)
+        .ok_or_else(|| "invalid export cursor".to_string())?;
+
+    let (entries, more) = read(cursor.section, cursor.after.as_deref(), max_entries)?;
+    let next_position = if more {
+        Some((cursor.section, entries.last().map(|(key, _)| key.clone())))
+    } else {
+        sections.get(position + 1).map(|section| (*section, None))
+    };
+
+    let mut chunk = ExportChunk {
+        format_version: EXPORT_FORMAT_VERSION,
+        sequence: cursor.sequence,
+        section: cursor.section,
+        entries,
+        previous_hash: cursor.previous_hash,
+        hash: ByteBuf::new(),
+        next: None,
+    };
+    chunk.hash = ByteBuf::from(chunk.compute_hash());
+    chunk.next = next_position.map(|(section, after)| ExportCursor {
+        section,
+        after,
+        sequence: chunk.sequence + 1,
+        previous_hash: chunk.hash.clone(),
+    });
+    Ok(chunk)
+}
+
+/// Returns up to `limit` encoded entries of `map` following the encoded key
+/// `after`, and whether more entries follow.
+pub fn export_map_entries<K, V, M>(
+    map: &StableBTreeMap<K, V, M>,
+    after: Option<&[u8]>,
+    limit: usize,
+) -> (Vec<(ByteBuf, ByteBuf)>, bool)
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    use std::ops::Bound::{Excluded, Unbounded};
+
+    let start = after.map_or(Unbounded, |after| {
+        Excluded(K::from_bytes(Cow::Owned(after.to_vec())))
+    });
+    let mut entries: Vec<(ByteBuf, ByteBuf)> = map
+        .range((start, Unbounded))
+        .take(limit + 1)
+        .map(|(key, value)| {
+            (
+                ByteBuf::from(key.to_bytes().into_owned()),
+                ByteBuf::from(value.to_bytes().into_owned()),
+            )
+        })
+        .collect();
+    let more = entries.len() > limit;
+    entries.truncate(limit);
+    (entries, more)
+}
+
+/// Removes all entries of `map`.
+pub fn clear_map_entries<K, V, M>(map: &mut StableBTreeMap<K, V, M>)
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    let keys: Vec<K> = map.iter().map(|(key, _)| key).collect();
+    for key in keys {
+        map.remove(&key);
+    }
+}
+
+/// Inserts encoded entries into `map`.
+pub fn import_map_entries<K, V, M>(
+    map: &mut StableBTreeMap<K, V, M>,
+    entries: Vec<(ByteBuf, ByteBuf)>,
+) where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    for (key, value) in entries {
+        map.insert(
+            K::from_bytes(Cow::Owned(key.into_vec())),
+            V::from_bytes(Cow::Owned(value.into_vec())),
+        );
+    }
+}
+
+impl<M: Memory> KeyManager<M> {
+    /// Describes an export of the `KeyManager`'s state.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caller is not an admin or a migration is in
+    /// progress, see [`crate::migration`].
+    pub fn export_summary(&self, caller: candid::Principal) -> Result<ExportSummary, String> {
+        self.ensure_admin(caller)?;
+        self.ensure_migration_complete()?;
+        ExportSummary::new(
+            self.domain_separator(),
+            &KEY_MANAGER_SECTIONS,
+            |section| self.stored_entries(section),
+            |section, after, limit| self.export_entries(section, after, limit),
+        )
+    }
+
+    /// Exports the chunk at `cursor`, or the first chunk if `cursor` is `None`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caller is not an admin, a migration is in
+    /// progress, or the cursor or `max_entries` is invalid.
+    pub fn export_chunk(
+        &self,
+        caller: candid::Principal,
+        cursor: Option<ExportCursor>,
+        max_entries: usize,
+    ) -> Result<ExportChunk, String> {
+        self.ensure_admin(caller)?;
+        self.ensure_migration_complete()?;
+        build_chunk(
+            &KEY_MANAGER_SECTIONS,
+            cursor,
+            max_entries,
+            |section, after, limit| self.export_entries(section, after, limit),
+        )
+    }
+
+    /// Starts an import of the export described by `summary`, replacing an
+    /// unfinished import.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caller is not an admin, a migration is in
+    /// progress, the summary does not describe a `KeyManager` export with the
+    /// same domain separator, or the `KeyManager` already stores data.
+    pub fn begin_import(
+        &mut self,
+        caller: candid::Principal,
+        summary: ExportSummary,
+    ) -> Result<(), String> {
+        self.ensure_admin(caller)?;
+        self.ensure_migration_complete()?;
+        let session = ImportSession::new(summary, self.domain_separator(), &KEY_MANAGER_SECTIONS)?;
+        if KEY_MANAGER_SECTIONS
+            .iter()
+            .any(|section| self.stored_entries(*section) > 0)
+        {
+            return Err("import target is not empty".to_string());
+        }
+        self.set_import_session(Some(session));
+        Ok(())
+    }
+
+    /// Verifies and stores the next chunk of the import.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caller is not an admin, a migration is in
+    /// progress, no import was started, or the chunk does not continue the export.
+    pub fn import_chunk(
+        &mut self,
+        caller: candid::Principal,
+        chunk: ExportChunk,
+    ) -> Result<(), String> {
+        self.ensure_admin(caller)?;
+        self.ensure_migration_complete()?;
+        let mut session = self
+            .import_session()
+            .ok_or_else(|| "no import in progress".to_string())?;
+        session.accept(&chunk)?;
+        self.import_entries(chunk.section, chunk.entries)?;
+        self.set_import_session(Some(session));
+        Ok(())
+    }
+
+    /// Completes the import after checking its consistency, see [`crate::export`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caller is not an admin, no import was started,
+    /// or the import is incomplete or inconsistent.
+    pub fn finish_import(&mut self, caller: candid::Principal) -> Result<(), String> {
+        self.ensure_admin(caller)?;
+        let session = self
+            .import_session()
+            .ok_or_else(|| "no import in progress".to_string())?;
+        session.finish(|section| self.stored_entries(section))?;
+        self.set_import_session(None);
+        Ok(())
+    }
+
+    /// Ends the import in progress and removes the entries imported so far,
+    /// so that the import can be started again, e.g., after it failed because
+    /// the source changed during the export.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caller is not an admin or no import was started.
+    pub fn abort_import(&mut self, caller: candid::Principal) -> Result<(), String> {
+        self.ensure_admin(caller)?;
+        if self.import_session().is_none() {
+            return Err("no import in progress".to_string());
+        }
+        for section in KEY_MANAGER_SECTIONS {
+            self.clear_entries(section)?;
+        }
+        self.set_import_session(None);
+        Ok(())
+    }
+
+    /// Returns the import in progress, if any.
+    #[must_use]
+    pub fn import_session(&self) -> Option<ImportSession> {
+        self.metadata.get().import_session.clone()
+    }
+
+    /// Stores or, if `session` is `None`, ends the import in progress.
+    pub fn set_import_session(&mut self, session: Option<ImportSession>) {
+        let mut metadata = self.metadata.get().clone();
+        metadata.import_session = session;
+        self.set_metadata(metadata);
+    }
+
+    /// Returns the number of stored entries of one of the [`KEY_MANAGER_SECTIONS`].
+    #[must_use]
+    pub fn stored_entries(&self, section: ExportSection) -> u64 {
+        match section {
+            ExportSection::AccessControl => self.access_control.len(),
+            ExportSection::SharedKeys => self.shared_keys.len(),
+            ExportSection::AuditLogs => self.audit_logs.as_ref().map_or(0, NamedMap::len),
+            ExportSection::EncryptedMapValues | ExportSection::Tombstones => 0,
+        }
+    }
+
+    /// Returns encoded entries of one of the [`KEY_MANAGER_SECTIONS`], see
+    /// [`export_map_entries`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `section` is not stored by the `KeyManager`.
+    pub fn export_entries(
+        &self,
+        section: ExportSection,
+        after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<(Vec<(ByteBuf, ByteBuf)>, bool), String> {
+        match section {
+            ExportSection::AccessControl => Ok(export_map_entries(
+                self.access_control.current(),
+                after,
+                limit,
+            )),
+            ExportSection::SharedKeys => {
+                Ok(export_map_entries(self.shared_keys.current(), after, limit))
+            }
+            ExportSection::AuditLogs => Ok(self
+                .audit_logs
+                .as_ref()
+                .map_or((vec![], false), |audit_logs| {
+                    export_map_entries(audit_logs.current(), after, limit)
+                })),
+            ExportSection::EncryptedMapValues | ExportSection::Tombstones => {
+                Err("unexpected export section".to_string())
+            }
+        }
+    }
+
+    /// Stores encoded entries of one of the [`KEY_MANAGER_SECTIONS`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `section` is not stored by the `KeyManager`, or if
+    /// it contains audit logs and audit logging is not enabled.
+    pub fn import_entries(
+        &mut self,
+        section: ExportSection,
+        entries: Vec<(ByteBuf, ByteBuf)>,
+    ) -> Result<(), String> {
+        match section {
+            ExportSection::AccessControl => {
+                import_map_entries(self.access_control.current_mut(), entries);
+            }
+            ExportSection::SharedKeys => {
+                import_map_entries(self.shared_keys.current_mut(), entries)
+            }
+            ExportSection::AuditLogs => match self.audit_logs.as_mut() {
+                Some(audit_logs) => import_map_entries(audit_logs.current_mut(), entries),
+                None if entries.is_empty() => {}
+                None => return Err("audit logs are not enabled".to_string()),
+            },
+            ExportSection::EncryptedMapValues | ExportSection::Tombstones => {
+                return Err("unexpected export section".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes all entries of one of the [`KEY_MANAGER_SECTIONS`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `section` is not stored by the `KeyManager`.
+    pub fn clear_entries(&mut self, section: ExportSection) -> Result<(), String> {
+        match section {
+            ExportSection::AccessControl => clear_map_entries(self.access_control.current_mut()),
+            ExportSection::SharedKeys => clear_map_entries(self.shared_keys.current_mut()),
+            ExportSection::AuditLogs => {
+                if let Some(audit_logs) = self.audit_logs.as_mut() {
+                    clear_map_entries(audit_logs.current_mut());
+                }
+            }
+            ExportSection::EncryptedMapValues | ExportSection::Tombstones => {
+                return Err("unexpected export section".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that no migrations are pending. Exports and imports only copy
+    /// the maps that hold the current layout, see [`crate::migration`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a migration is in progress.
+    pub fn ensure_migration_complete(&self) -> Result<(), String> {
+        if self.is_migration_complete() {
+            Ok(())
+        } else {
+            Err("migration in progress".to_string())
+        }
+    }
+}
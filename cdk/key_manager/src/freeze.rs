@@ -0,0 +1,269 @@
+//! Emergency freezes that stop access to keys without removing any grants.
+//!
+//! A user with the `MANAGE` permission freezes a key with
+//! [`KeyManager::freeze_key`] if its key material may be compromised. While a
+//! key is frozen, every operation covered by the [`FreezeScope`] is denied for
+//! everyone but the key owner, regardless of grants, token gates and custom
+//! access policies. `access_control` is left untouched, so all users regain
+//! their access once the key is unfrozen with [`KeyManager::unfreeze_key`].
+//!
+//! A canister-wide freeze set with [`KeyManager::freeze_canister`] applies the
+//! same restrictions to all keys, including to their owners. Only admins may
+//! set it, so it requires admins to be enabled, see [`crate::admin`]. Since it
+//! does not belong to a single key, it is recorded in a separate log returned
+//! by [`KeyManager::get_canister_freeze_log`].
+
+use crate::migration::{MigratedStructure, NamedMap};
+use crate::policy::{AccessQuery, Decision, DenyReason, Operation};
+use crate::{DefaultMemory, KeyId, KeyManager};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Memory, StableCell, Storable};
+use ic_vetkd_cdk_types::{decode_versioned, encode_versioned, now, AuditEntry, AuditLog};
+use serde::Deserialize;
+use std::borrow::Cow;
+
+/// The operations blocked by a freeze.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FreezeScope {
+    /// Only the derivation of encrypted vetkeys is blocked.
+    VetKeys,
+    /// The derivation of encrypted vetkeys and all reads and writes of the
+    /// values protected by the key are blocked.
+    VetKeysAndValues,
+}
+
+impl FreezeScope {
+    /// Returns true if the freeze blocks `operation`.
+    #[must_use]
+    pub const fn covers(self, operation: Operation) -> bool {
+        match self {
+            Self::VetKeys => matches!(operation, Operation::FetchVetKey),
+            Self::VetKeysAndValues => matches!(
+                operation,
+                Operation::FetchVetKey
+                    | Operation::ReadValues
+                    | Operation::Insert
+                    | Operation::Update
+                    | Operation::Delete
+                    | Operation::Restore
+                    | Operation::Purge
+            ),
+        }
+    }
+}
+
+/// An active freeze of a key or of the whole canister.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Freeze {
+    pub scope: FreezeScope,
+    pub frozen_by: Principal,
+    pub frozen_at: u64,
+}
+
+impl Storable for Freeze {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let payload = Encode!(self).expect("failed to encode Freeze");
+        Cow::Owned(encode_versioned(1, &payload))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        match decode_versioned(bytes.as_ref()) {
+            (0 | 1, payload) => Decode!(payload, Self).expect("failed to decode Freeze"),
+            (version, _) => panic!("unsupported Freeze encoding version {version}"),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// The canister-wide freeze and the log of its changes.
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CanisterFreeze {
+    pub freeze: Option<Freeze>,
+    pub log: AuditLog,
+}
+
+impl Storable for CanisterFreeze {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let payload = Encode!(self).expect("failed to encode CanisterFreeze");
+        Cow::Owned(encode_versioned(1, &payload))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        match decode_versioned(bytes.as_ref()) {
+            (0 | 1, payload) => Decode!(payload, Self).expect("failed to decode CanisterFreeze"),
+            (version, _) => panic!("unsupported CanisterFreeze encoding version {version}"),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Stable storage of frozen keys and of the canister-wide freeze.
+pub struct FreezeStore<M: Memory = DefaultMemory> {
+    pub keys: NamedMap<KeyId, Freeze, M>,
+    pub canister: StableCell<CanisterFreeze, M>,
+}
+
+impl<M: Memory> KeyManager<M> {
+    /// Enables emergency freezes, see [`crate::freeze`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the canister-wide freeze cannot be initialized in stable storage.
+    #[must_use]
+    pub fn with_freezing(mut self, memory_keys: M, memory_canister: M) -> Self {
+        self.freezes = Some(FreezeStore {
+            keys: self.init_named_map(MigratedStructure::FrozenKeys, memory_keys),
+            canister: StableCell::init(memory_canister, CanisterFreeze::default())
+                .expect("failed to initialize canister freeze"),
+        });
+        self
+    }
+
+    /// Freezes a key, replacing a previous freeze of the key.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if freezing is not enabled or if the caller does not
+    /// have the `MANAGE` permission.
+    pub fn freeze_key(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        scope: FreezeScope,
+    ) -> Result<Option<Freeze>, String> {
+        self.authorize(caller, key_id, Operation::Manage)?;
+        let store = self
+            .freezes
+            .as_mut()
+            .ok_or_else(|| "freezing is not enabled".to_string())?;
+        let previous = store.keys.insert(
+            key_id,
+            Freeze {
+                scope,
+                frozen_by: caller,
+                frozen_at: now(),
+            },
+        );
+        self.add_audit_log(key_id, move || AuditEntry::frozen(now(), caller));
+        Ok(previous)
+    }
+
+    /// Lifts the freeze of a key and returns it, if the key was frozen.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if freezing is not enabled or if the caller does not
+    /// have the `MANAGE` permission.
+    pub fn unfreeze_key(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+    ) -> Result<Option<Freeze>, String> {
+        self.authorize(caller, key_id, Operation::Manage)?;
+        let store = self
+            .freezes
+            .as_mut()
+            .ok_or_else(|| "freezing is not enabled".to_string())?;
+        let previous = store.keys.remove(&key_id);
+        if previous.is_some() {
+            self.add_audit_log(key_id, move || AuditEntry::unfrozen(now(), caller));
+        }
+        Ok(previous)
+    }
+
+    /// Retrieves the freeze of a key, if any.
+    #[must_use]
+    pub fn get_key_freeze(&self, key_id: KeyId) -> Option<Freeze> {
+        self.freezes
+            .as_ref()
+            .and_then(|store| store.keys.get(&key_id))
+    }
+
+    /// Freezes all keys, or lifts the canister-wide freeze if `scope` is
+    /// `None`, and returns the previous canister-wide freeze.
+    ///
+    /// The caller must be an admin, see [`crate::admin`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if freezing or admins are not enabled or if the caller
+    /// is not an admin.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the canister-wide freeze cannot be written to stable storage.
+    pub fn freeze_canister(
+        &mut self,
+        caller: Principal,
+        scope: Option<FreezeScope>,
+    ) -> Result<Option<Freeze>, String> {
+        if self.admins.is_none() {
+            return Err("admins are not enabled".to_string());
+        }
+        self.ensure_admin(caller)?;
+        let store = self
+            .freezes
+            .as_mut()
+            .ok_or_else(|| "freezing is not enabled".to_string())?;
+        let mut state = store.canister.get().clone();
+        let previous = state.freeze;
+        state.freeze = scope.map(|scope| Freeze {
+            scope,
+            frozen_by: caller,
+            frozen_at: now(),
+        });
+        let entry = match scope {
+            Some(_) => AuditEntry::frozen(now(), caller),
+            None => AuditEntry::unfrozen(now(), caller),
+        };
+        state.log.0.push(entry);
+        store
+            .canister
+            .set(state)
+            .expect("failed to store canister freeze");
+        Ok(previous)
+    }
+
+    /// Retrieves the canister-wide freeze, if any.
+    #[must_use]
+    pub fn get_canister_freeze(&self) -> Option<Freeze> {
+        self.freezes
+            .as_ref()
+            .and_then(|store| store.canister.get().freeze)
+    }
+
+    /// Retrieves the log of changes of the canister-wide freeze.
+    #[must_use]
+    pub fn get_canister_freeze_log(&self) -> AuditLog {
+        self.freezes
+            .as_ref()
+            .map(|store| store.canister.get().log.clone())
+            .unwrap_or_default()
+    }
+
+    /// Denies operations blocked by a freeze, overriding `decision`,
+    /// see [`crate::policy`].
+    pub(crate) fn evaluate_freeze(&self, query: &AccessQuery, decision: Decision) -> Decision {
+        let Some(store) = self.freezes.as_ref() else {
+            return decision;
+        };
+        let canister_frozen = store
+            .canister
+            .get()
+            .freeze
+            .is_some_and(|freeze| freeze.scope.covers(query.operation));
+        let key_frozen = query.caller != query.key_id.0
+            && store
+                .keys
+                .get(&query.key_id)
+                .is_some_and(|freeze| freeze.scope.covers(query.operation));
+        if canister_frozen || key_frozen {
+            Decision::Deny(DenyReason::Frozen)
+        } else {
+            decision
+        }
+    }
+}
@@ -0,0 +1,400 @@
+//! Invitation codes for sharing a key with users whose principal is not known yet.
+//!
+//! A user with the `SHARE` permission creates an invite with [`create_invite`]
+//! and hands the returned code to the invitee out of band. The invitee redeems it with
+//! [`KeyManager::redeem_invite`] and is granted the invite's access rights under
+//! their own principal. Only a hash of the code's secret is stored.
+//!
+//! The validity window of an invite follows the convention of the window of
+//! [`AccessRights`] (see [`crate::policy`]): an invite can be redeemed from
+//! `valid_from` on and until, but not at, `valid_until`.
+//!
+//! A code is the hex encoding of the 8-byte big-endian invite id followed by a
+//! 32-byte secret drawn from the management canister's `raw_rand`.
+//!
+//! ```ignore
+//! #[update]
+//! async fn create_invite(
+//!     key_name: ByteBuf,
+//!     access_rights: AccessRights,
+//!     max_uses: u32,
+//! ) -> Result<String, String> {
+//!     let caller = ic_cdk::caller();
+//!     let key_name = KeyName::try_from(key_name.as_ref()).map_err(|_| "invalid key name")?;
+//!     let key_id = (caller, key_name);
+//!     invites::create_invite(&KEY_MANAGER, caller, key_id, access_rights, None, None, max_uses)
+//!         .await
+//! }
+//! ```
+
+use crate::approvals::GrantOutcome;
+use crate::policy::Operation;
+use crate::{Caller, DefaultMemory, KeyId, KeyManager};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Memory, StableBTreeMap, Storable};
+use ic_vetkd_cdk_types::{
+    decode_versioned, encode_versioned, now, AccessRights, AuditEntry, ByteBuf, KeyName,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+pub type InviteId = u64;
+
+/// Size of the secret part of an invitation code.
+pub const INVITE_SECRET_BYTES: usize = 32;
+
+/// A claimable grant of access rights.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Invite {
+    /// The owner of the key the invite grants access to.
+    pub key_owner: Caller,
+    /// The name of the key, stored as bytes since `KeyName` is not a Candid type.
+    pub key_name: ByteBuf,
+    pub creator: Caller,
+    pub access_rights: AccessRights,
+    /// The invite cannot be redeemed before this time, if set.
+    pub valid_from: Option<u64>,
+    /// The invite cannot be redeemed at or after this time, if set.
+    pub valid_until: Option<u64>,
+    pub max_uses: u32,
+    pub uses: u32,
+    pub revoked: bool,
+    secret_hash: Vec<u8>,
+}
+
+impl Invite {
+    /// Returns the key the invite grants access to.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stored key name is not a valid `KeyName`.
+    #[must_use]
+    pub fn key_id(&self) -> KeyId {
+        let key_name =
+            KeyName::try_from(self.key_name.as_ref()).expect("invalid key name in invite");
+        (self.key_owner, key_name)
+    }
+}
+
+impl Storable for Invite {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let payload = Encode!(self).expect("failed to encode Invite");
+        Cow::Owned(encode_versioned(1, &payload))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        match decode_versioned(bytes.as_ref()) {
+            (0 | 1, payload) => Decode!(payload, Self).expect("failed to decode Invite"),
+            (version, _) => panic!("unsupported Invite encoding version {version}"),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Stable storage of invites.
+pub struct InviteStore<M: Memory = DefaultMemory> {
+    pub invites: StableBTreeMap<InviteId, Invite, M>,
+    /// The ids of the invites of each key.
+    pub invites_by_key: StableBTreeMap<(KeyId, InviteId), (), M>,
+}
+
+impl<M: Memory> KeyManager<M> {
+    /// Enables invitation codes, see [`crate::invites`].
+    #[must_use]
+    pub fn with_invites(mut self, memory_invites: M, memory_invites_by_key: M) -> Self {
+        self.invites = Some(InviteStore {
+            invites: StableBTreeMap::init(memory_invites),
+            invites_by_key: StableBTreeMap::init(memory_invites_by_key),
+        });
+        self
+    }
+
+    /// Checks that `caller` may create an invite with the given parameters.
+    fn check_invite(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+        access_rights: AccessRights,
+        valid_from: Option<u64>,
+        valid_until: Option<u64>,
+        max_uses: u32,
+    ) -> Result<(), String> {
+        let caller_rights = self.authorize(caller, key_id, Operation::Share)?;
+        if !caller_rights.has(access_rights.permissions()) {
+            return Err("unauthorized".to_string());
+        }
+        if max_uses == 0 {
+            return Err("invalid maximum number of uses".to_string());
+        }
+        if let (Some(from), Some(until)) = (valid_from, valid_until) {
+            if from >= until {
+                return Err("invalid validity window".to_string());
+            }
+        }
+        if self.invites.is_none() {
+            return Err("invites are not enabled".to_string());
+        }
+        Ok(())
+    }
+
+    /// Stores an invite with the given secret and returns its code. The
+    /// checks are repeated since the state may have changed while the secret
+    /// was drawn.
+    #[allow(clippy::too_many_arguments)]
+    fn insert_invite(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        access_rights: AccessRights,
+        valid_from: Option<u64>,
+        valid_until: Option<u64>,
+        max_uses: u32,
+        secret: [u8; INVITE_SECRET_BYTES],
+    ) -> Result<String, String> {
+        self.check_invite(
+            caller,
+            key_id,
+            access_rights,
+            valid_from,
+            valid_until,
+            max_uses,
+        )?;
+        let store = self
+            .invites
+            .as_mut()
+            .ok_or_else(|| "invites are not enabled".to_string())?;
+
+        let invite_id = store
+            .invites
+            .last_key_value()
+            .map_or(0, |(invite_id, _)| invite_id + 1);
+        store.invites.insert(
+            invite_id,
+            Invite {
+                key_owner: key_id.0,
+                key_name: ByteBuf::from(key_id.1.as_ref().to_vec()),
+                creator: caller,
+                access_rights,
+                valid_from,
+                valid_until,
+                max_uses,
+                uses: 0,
+                revoked: false,
+                secret_hash: Sha256::digest(secret).to_vec(),
+            },
+        );
+        store.invites_by_key.insert((key_id, invite_id), ());
+        self.add_audit_log(key_id, move || {
+            AuditEntry::invite_created(now(), caller, access_rights, invite_id)
+        });
+
+        let mut code = invite_id.to_be_bytes().to_vec();
+        code.extend_from_slice(&secret);
+        Ok(hex::encode(code))
+    }
+
+    /// Redeems an invitation code, granting the invite's access rights to the
+    /// caller. The grant is subject to the same checks as
+    /// [`KeyManager::set_user_rights`] performed on behalf of the invite's creator.
+    /// Returns the key the caller was granted access to.
+    ///
+    /// Invites never replace existing rights and never wait for approval: a
+    /// caller that already has access to the key, or a key whose grants require
+    /// approval (see [`crate::approvals`]), makes the redemption fail without
+    /// using up the invite.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caller is anonymous, the code is invalid, the
+    /// invite is revoked, outside its validity window or used up, the caller
+    /// already has access to the key, or grants of the key require approval.
+    pub fn redeem_invite(&mut self, caller: Principal, code: &str) -> Result<KeyId, String> {
+        if caller == Principal::anonymous() {
+            return Err("unauthorized".to_string());
+        }
+        let (invite_id, mut invite) = self.invite_for_code(code)?;
+
+        let now = now();
+        if invite.revoked
+            || invite.uses >= invite.max_uses
+            || invite.valid_from.is_some_and(|from| now < from)
+            || invite.valid_until.is_some_and(|until| now >= until)
+        {
+            return Err("invite is no longer valid".to_string());
+        }
+
+        let key_id = invite.key_id();
+        if caller == key_id.0 || self.access_control.contains_key(&(caller, key_id)) {
+            return Err("already has access to the key".to_string());
+        }
+        if self.get_approval_config(key_id).is_some() {
+            return Err("grants of the key require approval".to_string());
+        }
+        let access_rights = invite.access_rights;
+        let outcome = self.grant_user_rights(invite.creator, key_id, caller, access_rights)?;
+        if let GrantOutcome::Proposed { .. } = outcome {
+            return Err("grants of the key require approval".to_string());
+        }
+
+        invite.uses += 1;
+        self.store_invite(invite_id, invite);
+        self.add_audit_log(key_id, move || {
+            AuditEntry::invite_redeemed(now, caller, access_rights, invite_id)
+        });
+        Ok(key_id)
+    }
+
+    /// Revokes an invite so that it can no longer be redeemed. Only the invite's
+    /// creator or a user with the `SHARE` permission can perform this action.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the invite does not exist or the caller is not authorized.
+    pub fn revoke_invite(&mut self, caller: Principal, invite_id: InviteId) -> Result<(), String> {
+        let mut invite = self
+            .invites
+            .as_ref()
+            .and_then(|store| store.invites.get(&invite_id))
+            .ok_or_else(|| "invite not found".to_string())?;
+        if caller != invite.creator {
+            self.authorize(caller, invite.key_id(), Operation::Share)?;
+        }
+
+        let key_id = invite.key_id();
+        invite.revoked = true;
+        self.store_invite(invite_id, invite);
+        self.add_audit_log(key_id, move || {
+            AuditEntry::invite_revoked(now(), caller, invite_id)
+        });
+        Ok(())
+    }
+
+    /// Retrieves all invites of a key.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caller does not have the `SHARE` permission.
+    pub fn get_invites(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+    ) -> Result<Vec<(InviteId, Invite)>, String> {
+        self.authorize(caller, key_id, Operation::Share)?;
+        Ok(self
+            .invites
+            .as_ref()
+            .map(|store| {
+                store
+                    .invites_by_key
+                    .range((key_id, InviteId::MIN)..=(key_id, InviteId::MAX))
+                    .filter_map(|((_, invite_id), ())| {
+                        store
+                            .invites
+                            .get(&invite_id)
+                            .map(|invite| (invite_id, invite))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// Revokes the invites of a deleted key.
+    pub(crate) fn revoke_invites_of_key(&mut self, key_id: KeyId) {
+        let Some(store) = self.invites.as_mut() else {
+            return;
+        };
+        let invite_ids: Vec<_> = store
+            .invites_by_key
+            .range((key_id, InviteId::MIN)..=(key_id, InviteId::MAX))
+            .map(|((_, invite_id), ())| invite_id)
+            .collect();
+        for invite_id in invite_ids {
+            if let Some(mut invite) = store.invites.get(&invite_id) {
+                invite.revoked = true;
+                store.invites.insert(invite_id, invite);
+            }
+        }
+    }
+
+    fn invite_for_code(&self, code: &str) -> Result<(InviteId, Invite), String> {
+        let invalid_code = || "invalid invite code".to_string();
+        let bytes = hex::decode(code).map_err(|_| invalid_code())?;
+        if bytes.len() != 8 + INVITE_SECRET_BYTES {
+            return Err(invalid_code());
+        }
+        let (invite_id, secret) = bytes.split_at(8);
+        let invite_id = InviteId::from_be_bytes(invite_id.try_into().expect("checked length"));
+
+        let invite = self
+            .invites
+            .as_ref()
+            .and_then(|store| store.invites.get(&invite_id))
+            .ok_or_else(invalid_code)?;
+        if Sha256::digest(secret).as_slice() != invite.secret_hash.as_slice() {
+            return Err(invalid_code());
+        }
+        Ok((invite_id, invite))
+    }
+
+    fn store_invite(&mut self, invite_id: InviteId, invite: Invite) {
+        if let Some(store) = self.invites.as_mut() {
+            store.invites.insert(invite_id, invite);
+        }
+    }
+}
+
+/// Creates an invite for a key on behalf of `caller` and returns its code.
+/// The secret of the code is drawn from the management canister's `raw_rand`.
+///
+/// `state` is the `thread_local!` `RefCell` holding the `KeyManager` or any
+/// other type implementing `AsMut<KeyManager>`, such as `EncryptedMaps`. It is
+/// not borrowed while `raw_rand` is called.
+///
+/// # Errors
+///
+/// Returns an error if:
+/// - Invites are not enabled
+/// - The caller does not have the `SHARE` permission or grants permissions beyond their own
+/// - The validity window or the maximum number of uses is invalid
+/// - The call to `raw_rand` fails
+pub async fn create_invite<T, M>(
+    state: &'static LocalKey<RefCell<T>>,
+    caller: Principal,
+    key_id: KeyId,
+    access_rights: AccessRights,
+    valid_from: Option<u64>,
+    valid_until: Option<u64>,
+    max_uses: u32,
+) -> Result<String, String>
+where
+    T: AsMut<KeyManager<M>> + 'static,
+    M: Memory,
+{
+    state.with_borrow_mut(|state| {
+        state.as_mut().check_invite(
+            caller,
+            key_id,
+            access_rights,
+            valid_from,
+            valid_until,
+            max_uses,
+        )
+    })?;
+    let secret = ic_vetkd_cdk_types::raw_rand().await?;
+    state.with_borrow_mut(|state| {
+        state.as_mut().insert_invite(
+            caller,
+            key_id,
+            access_rights,
+            valid_from,
+            valid_until,
+            max_uses,
+            secret,
+        )
+    })
+}
@@ -0,0 +1,256 @@
+//! Descriptive metadata of keys, e.g., to show users what was shared with them.
+//!
+//! Once enabled with [`KeyManager::with_key_metadata`], the `KeyManager` keeps
+//! one [`KeyMetadata`] record per key. The record is created with the time and
+//! the principal of the first call that recorded the key, i.e., the owner's
+//! first request of an encrypted vetkey, the first grant of access rights or
+//! the first [`KeyManager::set_key_metadata`], and it is removed together
+//! with the key's owner. `EncryptedMaps` also creates it on the first insert
+//! into a map.
+//!
+//! Users with any valid grant can read the record, while changing the label,
+//! description, tags or application data requires the `MANAGE` permission.
+//! Recipients of shared keys retrieve the records of all their keys with
+//! [`KeyManager::get_accessible_shared_keys_with_metadata`].
+
+use crate::policy::Operation;
+use crate::{DefaultMemory, KeyId, KeyManager};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Memory, StableBTreeMap, Storable};
+use ic_vetkd_cdk_types::{decode_versioned, encode_versioned, now, ByteBuf};
+use serde::Deserialize;
+use std::borrow::Cow;
+
+/// Descriptive metadata of a key.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct KeyMetadata {
+    pub created_at: u64,
+    pub created_by: Principal,
+    /// A short display name.
+    pub label: String,
+    pub description: String,
+    pub tags: Vec<String>,
+    /// Application-defined data, e.g., a content type, that the `KeyManager`
+    /// does not interpret.
+    pub app_data: ByteBuf,
+}
+
+impl KeyMetadata {
+    fn new(created_at: u64, created_by: Principal) -> Self {
+        Self {
+            created_at,
+            created_by,
+            label: String::new(),
+            description: String::new(),
+            tags: vec![],
+            app_data: ByteBuf::default(),
+        }
+    }
+}
+
+impl Storable for KeyMetadata {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let payload = Encode!(self).expect("failed to encode KeyMetadata");
+        Cow::Owned(encode_versioned(1, &payload))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        match decode_versioned(bytes.as_ref()) {
+            (0 | 1, payload) => Decode!(payload, Self).expect("failed to decode KeyMetadata"),
+            (version, _) => panic!("unsupported KeyMetadata encoding version {version}"),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Changes to the descriptive fields of a [`KeyMetadata`] record, where
+/// `None` leaves a field unchanged.
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyMetadataUpdate {
+    pub label: Option<String>,
+    pub description: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub app_data: Option<ByteBuf>,
+}
+
+/// Limits on the size of metadata records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyMetadataLimits {
+    /// Maximum size of a label in bytes.
+    pub max_label_bytes: usize,
+    /// Maximum size of a description in bytes.
+    pub max_description_bytes: usize,
+    /// Maximum number of tags.
+    pub max_tags: usize,
+    /// Maximum size of a tag in bytes.
+    pub max_tag_bytes: usize,
+    /// Maximum size of the application data in bytes.
+    pub max_app_data_bytes: usize,
+}
+
+impl Default for KeyMetadataLimits {
+    fn default() -> Self {
+        Self {
+            max_label_bytes: 128,
+            max_description_bytes: 1024,
+            max_tags: 16,
+            max_tag_bytes: 64,
+            max_app_data_bytes: 1024,
+        }
+    }
+}
+
+impl KeyMetadataLimits {
+    fn check(&self, update: &KeyMetadataUpdate) -> Result<(), String> {
+        if update
+            .label
+            .as_ref()
+            .is_some_and(|label| label.len() > self.max_label_bytes)
+        {
+            return Err("label too long".to_string());
+        }
+        if update
+            .description
+            .as_ref()
+            .is_some_and(|description| description.len() > self.max_description_bytes)
+        {
+            return Err("description too long".to_string());
+        }
+        if let Some(tags) = update.tags.as_ref() {
+            if tags.len() > self.max_tags {
+                return Err("too many tags".to_string());
+            }
+            if tags.iter().any(|tag| tag.len() > self.max_tag_bytes) {
+                return Err("tag too long".to_string());
+            }
+        }
+        if update
+            .app_data
+            .as_ref()
+            .is_some_and(|app_data| app_data.len() > self.max_app_data_bytes)
+        {
+            return Err("app data too long".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Stable storage of metadata records.
+pub struct KeyMetadataStore<M: Memory = DefaultMemory> {
+    pub records: StableBTreeMap<KeyId, KeyMetadata, M>,
+    pub limits: KeyMetadataLimits,
+}
+
+impl<M: Memory> KeyManager<M> {
+    /// Enables metadata records of keys, see [`crate::key_metadata`].
+    #[must_use]
+    pub fn with_key_metadata(mut self, memory: M, limits: KeyMetadataLimits) -> Self {
+        self.key_metadata = Some(KeyMetadataStore {
+            records: StableBTreeMap::init(memory),
+            limits,
+        });
+        self
+    }
+
+    /// Retrieves the metadata record of a key, if one was created.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if key metadata is not enabled or if the caller has no
+    /// valid grant for the key.
+    pub fn get_key_metadata(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+    ) -> Result<Option<KeyMetadata>, String> {
+        self.authorize(caller, key_id, Operation::Inspect)?;
+        let store = self
+            .key_metadata
+            .as_ref()
+            .ok_or_else(|| "key metadata is not enabled".to_string())?;
+        Ok(store.records.get(&key_id))
+    }
+
+    /// Applies `update` to the metadata record of a key, creating the record
+    /// if necessary, and returns the updated record.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if key metadata is not enabled, if the caller does not
+    /// have the `MANAGE` permission or if the update exceeds the
+    /// [`KeyMetadataLimits`].
+    pub fn set_key_metadata(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        update: KeyMetadataUpdate,
+    ) -> Result<KeyMetadata, String> {
+        self.authorize(caller, key_id, Operation::Manage)?;
+        let store = self
+            .key_metadata
+            .as_mut()
+            .ok_or_else(|| "key metadata is not enabled".to_string())?;
+        store.limits.check(&update)?;
+
+        let mut metadata = store
+            .records
+            .get(&key_id)
+            .unwrap_or_else(|| KeyMetadata::new(now(), caller));
+        if let Some(label) = update.label {
+            metadata.label = label;
+        }
+        if let Some(description) = update.description {
+            metadata.description = description;
+        }
+        if let Some(tags) = update.tags {
+            metadata.tags = tags;
+        }
+        if let Some(app_data) = update.app_data {
+            metadata.app_data = app_data;
+        }
+        store.records.insert(key_id, metadata.clone());
+        Ok(metadata)
+    }
+
+    /// Retrieves all key IDs shared with the caller together with the
+    /// metadata records of the keys, see [`Self::get_accessible_shared_key_ids`].
+    #[must_use]
+    pub fn get_accessible_shared_keys_with_metadata(
+        &self,
+        caller: Principal,
+    ) -> Vec<(KeyId, Option<KeyMetadata>)> {
+        self.get_accessible_shared_key_ids(caller)
+            .into_iter()
+            .map(|key_id| {
+                let metadata = self
+                    .key_metadata
+                    .as_ref()
+                    .and_then(|store| store.records.get(&key_id));
+                (key_id, metadata)
+            })
+            .collect()
+    }
+
+    /// Creates the metadata record of a key on behalf of `caller` if key
+    /// metadata is enabled and the key has no record yet. Used by
+    /// `EncryptedMaps` when values are inserted into a new map.
+    pub fn record_key_creation(&mut self, caller: Principal, key_id: KeyId) {
+        let Some(store) = self.key_metadata.as_mut() else {
+            return;
+        };
+        if !store.records.contains_key(&key_id) {
+            store
+                .records
+                .insert(key_id, KeyMetadata::new(now(), caller));
+        }
+    }
+
+    /// Removes the metadata record of a deleted key.
+    pub(crate) fn remove_key_metadata(&mut self, key_id: KeyId) {
+        if let Some(store) = self.key_metadata.as_mut() {
+            store.records.remove(&key_id);
+        }
+    }
+}
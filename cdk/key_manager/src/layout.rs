@@ -0,0 +1,225 @@
+//! Assignment of memory ids to the stable structures of a `KeyManager`.
+//!
+//! Instead of passing a separate memory for each stable structure, a
+//! `KeyManager` (and an `EncryptedMaps`) can be initialized from a
+//! `MemoryManager` and a [`MemoryLayout`] with
+//! [`KeyManager::init_with_layout`]. The layout assigns a memory id to each
+//! [`StableStructure`] and is validated before any memory is opened: if two
+//! structures share a memory id, or if an id lies outside the layout's range,
+//! the initialization panics with a message naming the structures.
+//!
+//! [`MemoryLayout::standard`] reserves [`RESERVED_MEMORY_IDS`] consecutive
+//! memory ids starting at a base id and assigns them to the structures in the
+//! order of [`StableStructure`], i.e., `base` holds the metadata, `base + 1`
+//! the access control map, and so on. Ids of the range that are not assigned
+//! yet are reserved for structures added by later versions, so canisters must
+//! not use them for their own data. [`MemoryLayout::new`] starts an empty
+//! layout over a custom range, e.g., to keep the ids of an existing canister.
+
+use crate::KeyManager;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::Memory;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use strum::IntoEnumIterator;
+
+/// The number of memory ids reserved by [`MemoryLayout::standard`].
+pub const RESERVED_MEMORY_IDS: u8 = 32;
+
+/// A stable structure that is stored in its own memory. The declaration order
+/// is the order of the ids in [`MemoryLayout::standard`] and is append-only.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    strum_macros::EnumIter,
+    strum_macros::Display,
+)]
+pub enum StableStructure {
+    /// The domain separator and the schema version.
+    Metadata,
+    AccessControl,
+    SharedKeys,
+    AuditLogs,
+    /// The values of `EncryptedMaps`.
+    EncryptedMapValues,
+    /// The soft-deleted values of `EncryptedMaps`.
+    Tombstones,
+    ApprovalConfigs,
+    GrantProposals,
+    AccessRequests,
+    Invites,
+    TokenGates,
+    TokenOwners,
+    Prices,
+    FrozenKeys,
+    CanisterFreeze,
+    Admins,
+    Recoveries,
+    AuthorizedCanisters,
+    KeyMetadata,
+    OwnedKeys,
+    /// The recovery principals registered by key owners.
+    RecoveryPrincipals,
+    /// The ids of the pending recoveries of each key.
+    RecoveryIndex,
+    /// The payments of purchases that were neither granted nor refunded.
+    PendingPurchases,
+    /// The access rights granted by the last purchase of each buyer of a key.
+    PurchasedRights,
+    /// The ids of the invites of each key.
+    InviteIndex,
+}
+
+/// Memory ids of the stable structures, see [`crate::layout`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryLayout {
+    memory_ids: Range<u8>,
+    assigned: BTreeMap<StableStructure, u8>,
+}
+
+impl MemoryLayout {
+    /// Creates a layout over `memory_ids` in which no structure has a memory
+    /// id yet.
+    #[must_use]
+    pub fn new(memory_ids: Range<u8>) -> Self {
+        Self {
+            memory_ids,
+            assigned: BTreeMap::new(),
+        }
+    }
+
+    /// Creates the standard layout, which reserves the [`RESERVED_MEMORY_IDS`]
+    /// memory ids starting at `base` and assigns them in the order of
+    /// [`StableStructure`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the reserved range exceeds the memory ids supported by a
+    /// `MemoryManager`.
+    #[must_use]
+    pub fn standard(base: u8) -> Self {
+        let end = base.checked_add(RESERVED_MEMORY_IDS).unwrap_or_else(|| {
+            panic!("memory ids reserved from base {base} exceed the supported memory ids")
+        });
+        StableStructure::iter()
+            .zip(base..end)
+            .fold(Self::new(base..end), |layout, (structure, memory_id)| {
+                layout.with_memory_id(structure, memory_id)
+            })
+    }
+
+    /// Assigns `memory_id` to `structure`, replacing a previous assignment.
+    #[must_use]
+    pub fn with_memory_id(mut self, structure: StableStructure, memory_id: u8) -> Self {
+        self.assigned.insert(structure, memory_id);
+        self
+    }
+
+    /// Returns the range of memory ids reserved by this layout.
+    #[must_use]
+    pub fn memory_ids(&self) -> Range<u8> {
+        self.memory_ids.clone()
+    }
+
+    /// Returns the memory id assigned to `structure`, if any.
+    #[must_use]
+    pub fn memory_id(&self, structure: StableStructure) -> Option<u8> {
+        self.assigned.get(&structure).copied()
+    }
+
+    /// Checks that every assigned memory id lies within the layout's range
+    /// and that no two structures share a memory id.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error naming the first offending structures.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut owners: BTreeMap<u8, StableStructure> = BTreeMap::new();
+        for (&structure, &memory_id) in &self.assigned {
+            if !self.memory_ids.contains(&memory_id) {
+                return Err(format!(
+                    "memory id {memory_id} of {structure} is outside the reserved range {:?}",
+                    self.memory_ids
+                ));
+            }
+            if let Some(other) = owners.insert(memory_id, structure) {
+                return Err(format!(
+                    "memory id {memory_id} is assigned to both {other} and {structure}"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the memory of `structure` from `memory_manager`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no memory id is assigned to `structure`.
+    #[must_use]
+    pub fn memory<M: Memory>(
+        &self,
+        memory_manager: &MemoryManager<M>,
+        structure: StableStructure,
+    ) -> VirtualMemory<M> {
+        let memory_id = self
+            .memory_id(structure)
+            .unwrap_or_else(|| panic!("no memory id is assigned to {structure}"));
+        memory_manager.get(MemoryId::new(memory_id))
+    }
+
+    /// Returns the memory of `structure` from `memory_manager`, or `None` if
+    /// no memory id is assigned to it.
+    #[must_use]
+    pub fn optional_memory<M: Memory>(
+        &self,
+        memory_manager: &MemoryManager<M>,
+        structure: StableStructure,
+    ) -> Option<VirtualMemory<M>> {
+        self.memory_id(structure)
+            .map(|memory_id| memory_manager.get(MemoryId::new(memory_id)))
+    }
+
+    /// Panics if the layout is invalid, see [`Self::validate`].
+    ///
+    /// # Panics
+    ///
+    /// Panics with the validation error.
+    pub fn assert_valid(&self) {
+        if let Err(error) = self.validate() {
+            panic!("invalid memory layout: {error}");
+        }
+    }
+}
+
+impl<M: Memory> KeyManager<VirtualMemory<M>> {
+    /// Initializes the `KeyManager` with the memories that `layout` assigns
+    /// to the metadata, the access control and shared keys maps and, if
+    /// assigned, the audit logs, see [`crate::layout`]. The memories of
+    /// optional features are obtained with [`MemoryLayout::memory`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the layout is invalid or lacks one of the required
+    /// structures, or if [`KeyManager::init`] panics.
+    #[must_use]
+    pub fn init_with_layout(
+        domain_separator: &str,
+        memory_manager: &MemoryManager<M>,
+        layout: &MemoryLayout,
+    ) -> Self {
+        layout.assert_valid();
+        Self::init(
+            domain_separator,
+            layout.memory(memory_manager, StableStructure::Metadata),
+            layout.memory(memory_manager, StableStructure::AccessControl),
+            layout.memory(memory_manager, StableStructure::SharedKeys),
+            layout.optional_memory(memory_manager, StableStructure::AuditLogs),
+        )
+    }
+}
@@ -13,9 +13,13 @@
 //!   secured using a **transport key**. Each key is associated with a unique **key id**.
 //! - **Manage Key Sharing:** A user can **share their keys** with other users while controlling access rights.
 //! - **Access Control Management:** Users can define and enforce **fine-grained permissions**
-//!   (read, write, manage) for each key.
+//!   for each key, either via the read, write and manage presets or as individual
+//!   [`Permissions`] such as inserting values, purging tombstones or sharing.
 //! - **Uses Stable Storage:** The library persists key access information using **`StableBTreeMap`**,
 //!   ensuring reliability across canister upgrades.
+//! - **Optional Verification:** Transport keys are validated before a key is derived, and
+//!   encrypted keys can be verified against the transport and derived public keys before
+//!   they are returned (see [`KeyManager::verify_encrypted_vetkeys`]).
 //!
 //! ## `KeyManager` Architecture
 //!
@@ -23,22 +27,74 @@
 //!
 //! 1. **Access Control Map** (`access_control`): Maps `(Caller, KeyId)` to `AccessRights`, defining permissions for each user.
 //! 2. **Shared Keys Map** (`shared_keys`): Tracks which users have access to shared keys.
+//!
+//! Optional features keep their state in additional stable structures that are
+//! enabled with the corresponding `with_*` method after [`KeyManager::init`]:
+//!
+//! - [`approvals`]: multi-party approval of grants ([`KeyManager::with_approvals`]).
+//! - [`access_requests`]: users asking for access ([`KeyManager::with_access_requests`]).
+//! - [`invites`]: claimable invitation codes ([`KeyManager::with_invites`]).
+//! - [`token_gating`]: access for the holder of an ICRC-7 token ([`KeyManager::with_token_gating`]).
+//! - [`payments`]: time-limited access sold for ICRC-2 tokens ([`KeyManager::with_payments`]).
+//! - [`freeze`]: emergency freezes of keys or of the canister ([`KeyManager::with_freezing`]).
+//! - [`admin`]: canister administrators and key recovery ([`KeyManager::with_admins`]).
+//! - [`authorization`]: access checks for allow-listed canisters ([`KeyManager::with_authorized_canisters`]).
+//! - [`key_metadata`]: labels, descriptions and tags of keys ([`KeyManager::with_key_metadata`]).
+//! - [`lifecycle`]: a registry of keys created by their owners ([`KeyManager::with_key_lifecycle`]).
+//!
+//! Admins can export the stored state and import it into another canister, see [`export`].
+//! All operations are authorized by a single policy evaluator that can be extended
+//! with custom (async) access policies, see [`policy`], and its decisions can be
+//! explained to the affected users, see [`explain`]. Canisters can subscribe to
+//! changes of access rights and values, see [`events`].
+//! The standard canister endpoints can be installed with a single macro, see
+//! [`api`].
+//! The layout of the stored data is versioned, see [`migration`].
+//!
+//! All stable structures are stored in memories of the type parameter `M` of
+//! [`KeyManager`], which defaults to the [`DefaultMemory`] of canisters. Other
+//! [`Memory`] implementations, e.g., `VectorMemory` in tests or file-backed
+//! memory in offline tooling, can be used in the same way.
+//! Canisters can instead take all memories from a `MemoryManager` according to
+//! a validated layout of memory ids, see [`layout`].
 
 use candid::Principal;
 use ic_cdk::api::management_canister::main::CanisterId;
 use ic_stable_structures::memory_manager::VirtualMemory;
 use ic_stable_structures::storable::Blob;
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
+use ic_stable_structures::{DefaultMemoryImpl, Memory, StableBTreeMap, StableCell};
 use ic_vetkd_cdk_types::{
-    now, AccessRights, AuditEntry, AuditLog, ByteBuf, KeyName, Rights, TransportKey,
+    now, AccessRights, AuditEntry, AuditLog, ByteBuf, KeyName, Permissions, Rights, TransportKey,
 };
 use std::future::Future;
 use std::str::FromStr;
+use std::sync::Arc;
 
 #[cfg(feature = "expose-testing-api")]
 use std::cell::RefCell;
 
+pub mod access_requests;
+pub mod admin;
+pub mod api;
+pub mod approvals;
+pub mod authorization;
+pub mod events;
+pub mod explain;
+pub mod export;
+pub mod freeze;
+pub mod icrc;
+pub mod invites;
+pub mod key_metadata;
+pub mod layout;
+pub mod lifecycle;
+pub mod migration;
+pub mod payments;
+pub mod policy;
+pub mod token_gating;
 pub mod vetkd_api_types;
+pub use ic_vetkd_cdk_utils::verification;
+use migration::{MigratedStructure, NamedMap};
+use policy::Operation;
 use vetkd_api_types::{
     VetKDCurve, VetKDEncryptedKeyReply, VetKDEncryptedKeyRequest, VetKDKeyId, VetKDPublicKeyReply,
     VetKDPublicKeyRequest,
@@ -61,42 +117,138 @@ thread_local! {
     static VETKD_TESTING_CANISTER_ID: RefCell<Option<Principal>> = const { RefCell::new(None) };
 }
 
-type Memory = VirtualMemory<DefaultMemoryImpl>;
+/// The memory used by canisters: a virtual memory of a `MemoryManager`.
+pub type DefaultMemory = VirtualMemory<DefaultMemoryImpl>;
+
+/// An encrypted vetkey derived by the future of
+/// [`KeyManager::get_encrypted_vetkey`]. The key is only returned by
+/// [`KeyManager::record_vetkey_access`], which audits the access.
+#[must_use = "the encrypted vetkey is returned by `KeyManager::record_vetkey_access`"]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DerivedVetKey {
+    caller: Principal,
+    key_id: KeyId,
+    access_rights: AccessRights,
+    encrypted_vetkey: VetKey,
+}
 
-pub struct KeyManager {
-    pub domain_separator: StableCell<String, Memory>,
-    pub access_control: StableBTreeMap<(Caller, KeyId), AccessRights, Memory>,
-    pub shared_keys: StableBTreeMap<(KeyId, Caller), (), Memory>,
-    pub audit_logs: Option<StableBTreeMap<KeyId, AuditLog, Memory>>,
+/// Manages vetkeys and their access rights in stable structures stored in
+/// memories of type `M`, see [`DefaultMemory`].
+pub struct KeyManager<M: Memory = DefaultMemory> {
+    /// The domain separator and the schema version of the stored data.
+    pub metadata: StableCell<migration::Metadata, M>,
+    pub access_control: NamedMap<(Caller, KeyId), AccessRights, M>,
+    pub shared_keys: NamedMap<(KeyId, Caller), (), M>,
+    pub audit_logs: Option<NamedMap<KeyId, AuditLog, M>>,
+    /// If set, encrypted vetkeys returned by the system API are verified against
+    /// the transport public key and the derived public key before they are
+    /// returned. This costs an additional `vetkd_public_key` call. Disabled by default.
+    pub verify_encrypted_vetkeys: bool,
+    /// Approver sets and grant proposals, if multi-party approval is enabled.
+    pub approvals: Option<approvals::ApprovalStore<M>>,
+    /// Pending and decided access requests, if access requests are enabled.
+    pub access_requests: Option<access_requests::AccessRequestStore<M>>,
+    /// Invites and hashes of their codes, if invitation codes are enabled.
+    pub invites: Option<invites::InviteStore<M>>,
+    /// Keys bound to tokens and the cached token holders, if token gating is enabled.
+    pub token_gates: Option<token_gating::TokenGateStore<M>>,
+    /// Prices of access to keys, if payments are enabled.
+    pub payments: Option<payments::PaymentStore<M>>,
+    /// Frozen keys and the canister-wide freeze, if freezing is enabled.
+    pub freezes: Option<freeze::FreezeStore<M>>,
+    /// Canister administrators and pending recoveries, if admins are enabled.
+    pub admins: Option<admin::AdminStore<M>>,
+    /// Canisters allowed to check access, if authorization checks are enabled.
+    pub authorized_canisters: Option<StableBTreeMap<Principal, (), M>>,
+    /// Metadata records of keys, if key metadata is enabled.
+    pub key_metadata: Option<key_metadata::KeyMetadataStore<M>>,
+    /// Keys registered by their owners and their creation times, if the key
+    /// lifecycle is enabled.
+    pub owned_keys: Option<StableBTreeMap<KeyId, u64, M>>,
+    /// Custom access policies, consulted in order for all operations.
+    pub access_policies: Vec<Box<dyn policy::AccessPolicy<M>>>,
+    /// Custom async access policies, consulted in order before encrypted vetkeys are derived.
+    pub async_access_policies: Vec<Arc<dyn policy::AsyncAccessPolicy>>,
+    /// Subscribers notified of changes, in registration order.
+    pub event_subscribers: Vec<Box<dyn events::EventSubscriber>>,
+    previous_schema_version: Option<u32>,
 }
 
-impl KeyManager {
+impl<M: Memory> KeyManager<M> {
     /// Initializes the `KeyManager` with stable storage.
     /// This function must be called exactly once before any other `KeyManager` operation can be invoked.
     ///
+    /// If the stored data uses an older schema version, the stored version is
+    /// updated. Data written before schema version 2 is migrated from the
+    /// memories attached with [`KeyManager::with_legacy_memory`], see [`migration`].
+    ///
     /// # Panics
     ///
-    /// Panics if the domain separator cannot be initialized in stable storage.
+    /// Panics if the domain separator cannot be initialized in stable storage,
+    /// or if one of the memories holds data written before schema version 2.
     #[must_use]
     pub fn init(
         domain_separator: &str,
-        memory_domain_separator: Memory,
-        memory_access_control: Memory,
-        memory_shared_keys: Memory,
-        memory_audit_log: Option<Memory>,
+        memory_domain_separator: M,
+        memory_access_control: M,
+        memory_shared_keys: M,
+        memory_audit_log: Option<M>,
     ) -> Self {
-        let domain_separator =
-            StableCell::init(memory_domain_separator, domain_separator.to_string())
-                .expect("failed to initialize domain separator");
-        let audit_logs = memory_audit_log.map(StableBTreeMap::init);
+        let mut metadata = StableCell::init(
+            memory_domain_separator,
+            migration::Metadata::new(domain_separator),
+        )
+        .expect("failed to initialize domain separator");
+        let previous_schema_version = migration::upgrade_schema(&mut metadata);
+        let access_control = migration::open_named_map(
+            &mut metadata,
+            MigratedStructure::AccessControl,
+            memory_access_control,
+        );
+        let shared_keys = migration::open_named_map(
+            &mut metadata,
+            MigratedStructure::SharedKeys,
+            memory_shared_keys,
+        );
+        let audit_logs = memory_audit_log.map(|memory| {
+            migration::open_named_map(&mut metadata, MigratedStructure::AuditLogs, memory)
+        });
         Self {
-            domain_separator,
-            access_control: StableBTreeMap::init(memory_access_control),
-            shared_keys: StableBTreeMap::init(memory_shared_keys),
+            metadata,
+            access_control,
+            shared_keys,
             audit_logs,
+            verify_encrypted_vetkeys: false,
+            approvals: None,
+            access_requests: None,
+            invites: None,
+            token_gates: None,
+            payments: None,
+            freezes: None,
+            admins: None,
+            authorized_canisters: None,
+            key_metadata: None,
+            owned_keys: None,
+            access_policies: vec![],
+            async_access_policies: vec![],
+            event_subscribers: vec![],
+            previous_schema_version,
         }
     }
 
+    /// Returns the domain separator used to derive all keys of this `KeyManager`.
+    #[must_use]
+    pub fn domain_separator(&self) -> &str {
+        &self.metadata.get().domain_separator
+    }
+
+    /// Enables verification of encrypted vetkeys in [`Self::get_encrypted_vetkey`].
+    #[must_use]
+    pub fn with_vetkey_verification(mut self) -> Self {
+        self.verify_encrypted_vetkeys = true;
+        self
+    }
+
     /// Retrieves all key IDs shared with the given caller.
     ///
     /// Returns a list of key IDs that the caller has access to.
@@ -123,7 +275,7 @@ impl KeyManager {
         caller: Principal,
         key_id: KeyId,
     ) -> Result<Vec<(Principal, AccessRights)>, String> {
-        self.ensure_user_can_read(caller, key_id)?;
+        self.authorize(caller, key_id, Operation::Inspect)?;
 
         self.shared_keys
             .range((key_id, Principal::management_canister())..)
@@ -138,6 +290,7 @@ impl KeyManager {
                                 rights: Rights::Read,
                                 start: None,
                                 end: Some(now()),
+                                permissions: None,
                             }),
                         )
                     })
@@ -147,19 +300,16 @@ impl KeyManager {
 
     /// Retrieves the VET key verification key from the system API.
     ///
-    /// Returns a future that resolves to the verification key.
-    ///
-    /// # Panics
-    ///
-    /// Panics if the call to the `vetkd_public_key` API fails.
+    /// Returns a future that resolves to the verification key, or to an error
+    /// if the call to the `vetkd_public_key` API fails.
     pub fn get_vetkey_verification_key(
         &self,
-    ) -> impl Future<Output = VetKeyVerificationKey> + Send + Sync {
+    ) -> impl Future<Output = Result<VetKeyVerificationKey, String>> + Send + Sync {
         use futures::future::FutureExt;
 
         let request = VetKDPublicKeyRequest {
             canister_id: None,
-            derivation_path: vec![self.domain_separator.get().to_bytes().to_vec()],
+            derivation_path: vec![self.domain_separator().as_bytes().to_vec()],
             key_id: bls12_381_test_key_1(),
         };
 
@@ -170,29 +320,130 @@ impl KeyManager {
         );
 
         future.map(|call_result| {
-            let (reply,) = call_result.expect("call to vetkd_public_key failed");
-            VetKeyVerificationKey::from(reply.public_key)
+            let (reply,) = call_result.map_err(|(code, message)| {
+                format!("call to vetkd_public_key failed: {code:?} {message}")
+            })?;
+            Ok(VetKeyVerificationKey::from(reply.public_key))
         })
     }
 
     /// Retrieves an encrypted vetkey for caller and key id.
     ///
-    /// # Errors
+    /// The transport key is checked to be a valid BLS12-381 G1 point before any
+    /// key derivation is requested. If [`Self::verify_encrypted_vetkeys`] is set,
+    /// the returned encrypted key is additionally verified against the transport
+    /// key and the derived public key.
     ///
-    /// Returns an error if the caller doesn't have read permission for the key.
+    /// The returned future first consults the async access policies, if any,
+    /// and only requests the key derivation if they allow it, see [`policy`].
+    /// It resolves to a [`DerivedVetKey`], which must be passed to
+    /// [`Self::record_vetkey_access`] to record the access in the audit log
+    /// and to obtain the encrypted key. Denied and failed derivations are
+    /// therefore never audited as accesses.
     ///
-    /// # Panics
+    /// # Errors
     ///
-    /// Panics if the call to the `vetkd_encrypted_key` API fails.
+    /// Returns an error if the caller doesn't have the `FETCH_VETKEY` permission
+    /// for the key or if the transport key is malformed. The returned future
+    /// resolves to an error if an async access policy denies the access, if a
+    /// call to the vetKD API fails or, if verification is enabled, if the
+    /// encrypted key does not verify.
     pub fn get_encrypted_vetkey(
-        &mut self,
+        &self,
         caller: Principal,
         key_id: KeyId,
         transport_key: TransportKey,
-    ) -> Result<impl Future<Output = VetKey> + Send + Sync, String> {
+    ) -> Result<impl Future<Output = Result<DerivedVetKey, String>> + Send + Sync, String> {
         use futures::future::FutureExt;
 
-        let access_rights = self.ensure_user_can_read(caller, key_id)?;
+        let time = now();
+        let access_rights = self
+            .evaluate_access(caller, key_id, Operation::FetchVetKey, time)
+            .into_result()?;
+        verification::validate_transport_key(transport_key.as_ref())?;
+
+        let policy_decision = self.apply_async_access_policies(
+            policy::AccessQuery {
+                caller,
+                key_id,
+                operation: Operation::FetchVetKey,
+                time,
+            },
+            policy::Decision::Allow(access_rights),
+        );
+
+        let derivation_id: Vec<u8> = key_id
+            .0
+            .as_slice()
+            .iter()
+            .chain(key_id.1.as_ref().iter())
+            .copied()
+            .collect();
+
+        let request = VetKDEncryptedKeyRequest {
+            derivation_id: derivation_id.clone(),
+            public_key_derivation_path: vec![self.domain_separator().as_bytes().to_vec()],
+            key_id: bls12_381_test_key_1(),
+            encryption_public_key: transport_key.clone().into(),
+        };
+
+        let verification_key_future = self
+            .verify_encrypted_vetkeys
+            .then(|| self.get_vetkey_verification_key());
+
+        Ok(async move {
+            policy_decision.await.into_result()?;
+
+            let encrypted_key_future = ic_cdk::api::call::call::<_, (VetKDEncryptedKeyReply,)>(
+                vetkd_system_api_canister_id(),
+                "vetkd_encrypted_key",
+                (request,),
+            )
+            .map(|call_result| {
+                let (reply,) = call_result.map_err(|(code, message)| {
+                    format!("call to vetkd_encrypted_key failed: {code:?} {message}")
+                })?;
+                Ok::<_, String>(VetKey::from(reply.encrypted_key))
+            });
+
+            let encrypted_vetkey = match verification_key_future {
+                None => encrypted_key_future.await?,
+                Some(verification_key_future) => {
+                    let (verification_key, encrypted_vetkey) =
+                        futures::future::join(verification_key_future, encrypted_key_future).await;
+                    let (verification_key, encrypted_vetkey) =
+                        (verification_key?, encrypted_vetkey?);
+                    verification::verify_encrypted_vetkey(
+                        encrypted_vetkey.as_ref(),
+                        transport_key.as_ref(),
+                        verification_key.as_ref(),
+                        &derivation_id,
+                    )
+                    .map_err(|_| {
+                        "vetkd_encrypted_key returned an invalid encrypted key".to_string()
+                    })?;
+                    encrypted_vetkey
+                }
+            };
+            Ok(DerivedVetKey {
+                caller,
+                key_id,
+                access_rights,
+                encrypted_vetkey,
+            })
+        })
+    }
+
+    /// Records the access to an encrypted vetkey derived by the future of
+    /// [`Self::get_encrypted_vetkey`] in the audit log and returns the key.
+    /// On the owner's access, the key is registered as created.
+    pub fn record_vetkey_access(&mut self, derived: DerivedVetKey) -> VetKey {
+        let DerivedVetKey {
+            caller,
+            key_id,
+            access_rights,
+            encrypted_vetkey,
+        } = derived;
 
         // Check if this is the first access to this key (implicit creation)
         // We consider a key created when the owner first accesses it and it has no access records
@@ -207,37 +458,16 @@ impl KeyManager {
         if is_owner && no_shared_records {
             self.add_audit_log(key_id, move || AuditEntry::created(now(), caller));
         }
+        if is_owner {
+            self.register_owned_key(key_id);
+            self.record_key_creation(caller, key_id);
+        }
 
         // Log the access - using closure to avoid allocation if audit is disabled
         self.add_audit_log(key_id, move || {
             AuditEntry::access_vet_key(now(), caller, access_rights)
         });
-
-        let derivation_id = key_id
-            .0
-            .as_slice()
-            .iter()
-            .chain(key_id.1.as_ref().iter())
-            .copied()
-            .collect();
-
-        let request = VetKDEncryptedKeyRequest {
-            derivation_id,
-            public_key_derivation_path: vec![self.domain_separator.get().to_bytes().to_vec()],
-            key_id: bls12_381_test_key_1(),
-            encryption_public_key: transport_key.into(),
-        };
-
-        let future = ic_cdk::api::call::call::<_, (VetKDEncryptedKeyReply,)>(
-            vetkd_system_api_canister_id(),
-            "vetkd_encrypted_key",
-            (request,),
-        );
-
-        Ok(future.map(|call_result| {
-            let (reply,) = call_result.expect("call to vetkd_encrypted_key failed");
-            VetKey::from(reply.encrypted_key)
-        }))
+        encrypted_vetkey
     }
 
     /// Retrieves the access rights a given user has to a specific key.
@@ -251,21 +481,25 @@ impl KeyManager {
         key_id: KeyId,
         user: Principal,
     ) -> Result<Option<AccessRights>, String> {
-        self.ensure_user_can_read(caller, key_id)?;
-        if let Ok(access_rights) = self.ensure_user_can_read(user, key_id) {
-            return Ok(Some(access_rights));
-        }
-        Ok(None)
+        self.authorize(caller, key_id, Operation::Inspect)?;
+        Ok(self.authorize(user, key_id, Operation::Inspect).ok())
     }
 
     /// Grants or modifies access rights for a user to a given key.
-    /// Only the key owner or a user with management rights can perform this action.
+    /// Only the key owner or a user with the `SHARE` permission can perform this action,
+    /// and only for permissions the caller holds themselves.
+    ///
+    /// If the key has an approver set (see [`approvals`]), the rights are not
+    /// changed immediately. Instead, a grant proposal is recorded and the
+    /// currently effective rights of the user are returned. Use
+    /// [`Self::grant_user_rights`] to learn the id of the recorded proposal.
     ///
     /// # Errors
     ///
     /// Returns an error if:
-    /// - The caller doesn't have manage permission for the key
+    /// - The caller doesn't have share permission for the key
     /// - The caller is trying to change their own rights as key owner
+    /// - The granted or the replaced rights exceed the caller's own permissions
     pub fn set_user_rights(
         &mut self,
         caller: Principal,
@@ -273,41 +507,121 @@ impl KeyManager {
         user: Principal,
         access_rights: AccessRights,
     ) -> Result<Option<AccessRights>, String> {
-        self.ensure_user_can_manage(caller, key_id)?;
+        match self.grant_user_rights(caller, key_id, user, access_rights)? {
+            approvals::GrantOutcome::Applied(previous) => Ok(previous),
+            approvals::GrantOutcome::Proposed { .. } => {
+                Ok(self.access_control.get(&(user, key_id)))
+            }
+        }
+    }
+
+    /// Grants or modifies access rights like [`Self::set_user_rights`], but
+    /// tells whether the rights were applied or a grant proposal was recorded.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as [`Self::set_user_rights`].
+    pub fn grant_user_rights(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        user: Principal,
+        access_rights: AccessRights,
+    ) -> Result<approvals::GrantOutcome, String> {
+        self.ensure_can_grant(caller, key_id, user, access_rights)?;
 
         if caller == key_id.0 && caller == user {
             return Err("cannot change key owner's user rights".to_string());
         }
 
+        if let Some(outcome) = self.propose_user_rights(caller, key_id, user, access_rights) {
+            return Ok(outcome);
+        }
+
+        Ok(approvals::GrantOutcome::Applied(self.apply_user_rights(
+            caller,
+            key_id,
+            user,
+            access_rights,
+            None,
+        )))
+    }
+
+    /// Ensures that `caller` has the `SHARE` permission for a key and holds
+    /// all permissions that granting `access_rights` to `user` adds or replaces.
+    fn ensure_can_grant(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+        user: Principal,
+        access_rights: AccessRights,
+    ) -> Result<(), String> {
+        let caller_rights = self.authorize(caller, key_id, Operation::Share)?;
+        if !caller_rights.has(access_rights.permissions())
+            || !caller_rights.has(self.current_permissions(key_id, user))
+        {
+            return Err("unauthorized".to_string());
+        }
+        Ok(())
+    }
+
+    /// Stores access rights for a user without any checks and logs the share action.
+    fn apply_user_rights(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        user: Principal,
+        access_rights: AccessRights,
+        proposal_id: Option<approvals::ProposalId>,
+    ) -> Option<AccessRights> {
         // Log the share action - using closure to avoid allocation if audit is disabled
         self.add_audit_log(key_id, move || {
-            AuditEntry::share(now(), caller, user, access_rights)
+            let entry = AuditEntry::share(now(), caller, user, access_rights);
+            match proposal_id {
+                Some(proposal_id) => entry.with_reference_id(proposal_id),
+                None => entry,
+            }
         });
 
         self.shared_keys.insert((key_id, user), ());
-        Ok(self.access_control.insert((user, key_id), access_rights))
+        let previous = self.access_control.insert((user, key_id), access_rights);
+        self.forget_purchased_rights(key_id, user);
+        self.record_key_creation(caller, key_id);
+        self.notify_subscribers(|subscriber| {
+            subscriber.on_share(caller, key_id, user, access_rights);
+        });
+        previous
     }
 
     /// Revokes a user's access to a shared key.
     /// The key owner cannot remove their own access.
     ///
+    /// Removing the owner's entry is recorded as a deletion of the key but
+    /// leaves the other grants in place; use [`Self::delete_key`] to delete a
+    /// key together with all its grants.
+    ///
     /// # Errors
     ///
     /// Returns an error if:
-    /// - The caller doesn't have manage permission for the key
+    /// - The caller doesn't have share permission for the key
     /// - The caller is the key owner and trying to remove themselves
+    /// - The removed rights exceed the caller's own permissions
     pub fn remove_user(
         &mut self,
         caller: Principal,
         key_id: KeyId,
         user: Principal,
     ) -> Result<Option<AccessRights>, String> {
-        self.ensure_user_can_manage(caller, key_id)?;
+        let caller_rights = self.authorize(caller, key_id, Operation::Share)?;
 
         if caller == user && caller == key_id.0 {
             return Err("cannot remove key owner".to_string());
         }
 
+        if !caller_rights.has(self.current_permissions(key_id, user)) {
+            return Err("unauthorized".to_string());
+        }
+
         // If we're removing the owner's access rights from someone else,
         // consider this effectively deleting the key, since the owner is the primary access point
         let is_key_owner = user == key_id.0;
@@ -321,50 +635,17 @@ impl KeyManager {
         }
 
         self.shared_keys.remove(&(key_id, user));
-        Ok(self.access_control.remove(&(user, key_id)))
-    }
-
-    /// Ensures that a user has read access to a key before proceeding.
-    /// Returns an error if the user is not authorized.
-    fn ensure_user_can_read(&self, user: Principal, key_id: KeyId) -> Result<AccessRights, String> {
-        let is_owner = user == key_id.0;
-        if is_owner {
-            return Ok(AccessRights::read_write_manage());
-        }
-
-        let has_shared_access = self.access_control.get(&(user, key_id));
-        if let Some(access_rights) = has_shared_access {
-            if let Some(start) = access_rights.start() {
-                if start > now() {
-                    return Err("unauthorized".to_string());
-                }
-            }
-            if let Some(end) = access_rights.end() {
-                if end <= now() {
-                    return Err("unauthorized".to_string());
-                }
-            }
-            return Ok(access_rights);
-        }
-
-        // Allow anonymous access if it exists for the content.
-        // Recognize 2vxsx-fae as an "everyone" user.
-        let has_shared_access = self.access_control.get(&(Principal::anonymous(), key_id));
-        if let Some(access_rights) = has_shared_access {
-            if let Some(start) = access_rights.start() {
-                if start > now() {
-                    return Err("unauthorized".to_string());
-                }
-            }
-            if let Some(end) = access_rights.end() {
-                if end <= now() {
-                    return Err("unauthorized".to_string());
-                }
-            }
-            return Ok(access_rights);
+        let previous = self.access_control.remove(&(user, key_id));
+        self.forget_purchased_rights(key_id, user);
+        if is_key_owner {
+            self.remove_key_metadata(key_id);
+            self.notify_subscribers(|subscriber| subscriber.on_key_deleted(caller, key_id));
+        } else if let Some(previous_rights) = previous {
+            self.notify_subscribers(|subscriber| {
+                subscriber.on_unshare(caller, key_id, user, previous_rights);
+            });
         }
-
-        Err("unauthorized".to_string())
+        Ok(previous)
     }
 
     /// Ensures that a user has management access to a key before proceeding.
@@ -374,30 +655,20 @@ impl KeyManager {
         user: Principal,
         key_id: KeyId,
     ) -> Result<AccessRights, String> {
-        let is_owner = user == key_id.0;
-        if is_owner {
-            return Ok(AccessRights::read_write_manage());
-        }
+        self.authorize(user, key_id, Operation::Manage)
+    }
 
-        let has_shared_access = self.access_control.get(&(user, key_id));
-        if let Some(access_rights) = has_shared_access {
-            if let Some(start) = access_rights.start() {
-                if start < now() {
-                    return Err("unauthorized".to_string());
-                }
-            }
-            if let Some(end) = access_rights.end() {
-                if end >= now() {
-                    return Err("unauthorized".to_string());
-                }
-            }
-            if access_rights.rights == Rights::ReadWriteManage {
-                return Ok(access_rights);
-            }
+    /// Returns the permissions currently stored for a user, where the key
+    /// owner implicitly holds all permissions.
+    fn current_permissions(&self, key_id: KeyId, user: Principal) -> Permissions {
+        if user == key_id.0 {
+            return Permissions::all();
         }
-        // We do not want to allow anonymous management access ever.
-
-        Err("unauthorized".to_string())
+        self.access_control
+            .get(&(user, key_id))
+            .map_or(Permissions::empty(), |access_rights| {
+                access_rights.permissions()
+            })
     }
 
     /// Adds an audit log entry for a specific key ID.
@@ -437,6 +708,20 @@ impl KeyManager {
         }
         None
     }
+
+    /// Retrieves the audit log of a key on behalf of a caller.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caller doesn't have the `VIEW_AUDIT` permission for the key.
+    pub fn get_audit_log_for_caller(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+    ) -> Result<Option<AuditLog>, String> {
+        self.authorize(caller, key_id, Operation::ViewAudit)?;
+        Ok(self.get_audit_log(key_id))
+    }
 }
 
 fn bls12_381_test_key_1() -> VetKDKeyId {
@@ -0,0 +1,183 @@
+//! Explicit creation, listing and deletion of keys.
+//!
+//! Keys do not have to be created: any principal may request an encrypted
+//! vetkey for a key it owns. To let owners list their keys, including keys that
+//! were never shared, enable a registry of owned keys with
+//! [`KeyManager::with_key_lifecycle`]. Keys are then registered by
+//! [`KeyManager::create_key`] and on the owner's first request of an encrypted
+//! vetkey, and [`KeyManager::list_owned_keys`] returns them together with all
+//! keys the owner has shared.
+//!
+//! [`KeyManager::delete_key`] revokes all grants of a key, removes its state in
+//! the enabled optional features, i.e., token gates, prices, approver sets and
+//! proposals, access requests, invites, freezes, recoveries and metadata, and records a
+//! final `Deleted` audit entry. The audit log itself is kept. Since keys are
+//! derived from their ID, the owner can still request the same key again later.
+
+use crate::policy::Operation;
+use crate::{KeyId, KeyManager};
+use candid::Principal;
+use ic_stable_structures::storable::Blob;
+use ic_stable_structures::{Memory, StableBTreeMap};
+use ic_vetkd_cdk_types::{now, AuditEntry, KeyName};
+use std::collections::BTreeSet;
+
+impl<M: Memory> KeyManager<M> {
+    /// Enables the registry of owned keys, see [`crate::lifecycle`].
+    #[must_use]
+    pub fn with_key_lifecycle(mut self, memory: M) -> Self {
+        self.owned_keys = Some(StableBTreeMap::init(memory));
+        self
+    }
+
+    /// Creates a key owned by the caller and returns its ID.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key lifecycle is not enabled, if the caller is
+    /// anonymous or if the key already exists.
+    pub fn create_key(&mut self, caller: Principal, key_name: KeyName) -> Result<KeyId, String> {
+        if caller == Principal::anonymous() {
+            return Err("unauthorized".to_string());
+        }
+        let key_id = (caller, key_name);
+        let owned_keys = self
+            .owned_keys
+            .as_mut()
+            .ok_or_else(|| "key lifecycle is not enabled".to_string())?;
+        if owned_keys.contains_key(&key_id) {
+            return Err("key already exists".to_string());
+        }
+        owned_keys.insert(key_id, now());
+        self.add_audit_log(key_id, move || AuditEntry::created(now(), caller));
+        self.record_key_creation(caller, key_id);
+        Ok(key_id)
+    }
+
+    /// Lists the keys owned by the caller: registered keys and keys the caller
+    /// has shared with other users.
+    #[must_use]
+    pub fn list_owned_keys(&self, caller: Principal) -> Vec<KeyId> {
+        let mut key_ids: BTreeSet<KeyId> = self
+            .shared_keys
+            .range(((caller, Blob::default()), Principal::management_canister())..)
+            .take_while(|((key_id, _), ())| key_id.0 == caller)
+            .map(|((key_id, _), ())| key_id)
+            .collect();
+        if let Some(owned_keys) = self.owned_keys.as_ref() {
+            key_ids.extend(
+                owned_keys
+                    .range((caller, Blob::default())..)
+                    .take_while(|(key_id, _)| key_id.0 == caller)
+                    .map(|(key_id, _)| key_id),
+            );
+        }
+        key_ids.into_iter().collect()
+    }
+
+    /// Deletes a key, see [`crate::lifecycle`], and returns the users whose
+    /// grants were revoked.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caller is not the owner of the key or may not
+    /// manage it.
+    pub fn delete_key(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+    ) -> Result<Vec<Principal>, String> {
+        if caller != key_id.0 {
+            return Err("unauthorized".to_string());
+        }
+        self.authorize(caller, key_id, Operation::Manage)?;
+
+        let users: Vec<Principal> = self
+            .shared_keys
+            .range((key_id, Principal::management_canister())..)
+            .take_while(|((k, _), ())| k == &key_id)
+            .map(|((_, user), ())| user)
+            .collect();
+        let mut revoked = vec![];
+        for user in users {
+            self.shared_keys.remove(&(key_id, user));
+            if let Some(previous_rights) = self.access_control.remove(&(user, key_id)) {
+                self.notify_subscribers(|subscriber| {
+                    subscriber.on_unshare(caller, key_id, user, previous_rights);
+                });
+            }
+            revoked.push(user);
+        }
+
+        self.remove_key_state(key_id);
+        self.add_audit_log(key_id, move || AuditEntry::deleted(now(), caller));
+        self.notify_subscribers(|subscriber| subscriber.on_key_deleted(caller, key_id));
+        Ok(revoked)
+    }
+
+    /// Removes the state of a deleted key from the registry and the enabled
+    /// optional features.
+    fn remove_key_state(&mut self, key_id: KeyId) {
+        if let Some(owned_keys) = self.owned_keys.as_mut() {
+            owned_keys.remove(&key_id);
+        }
+        if let Some(store) = self.token_gates.as_mut() {
+            store.gates.remove(&key_id);
+            store.owners.remove(&key_id);
+        }
+        if let Some(store) = self.payments.as_mut() {
+            store.prices.remove(&key_id);
+            let buyers: Vec<_> = store
+                .purchased_rights
+                .range((key_id, Principal::management_canister())..)
+                .take_while(|((k, _), _)| k == &key_id)
+                .map(|((_, buyer), _)| buyer)
+                .collect();
+            for buyer in buyers {
+                store.purchased_rights.remove(&(key_id, buyer));
+            }
+        }
+        if let Some(store) = self.approvals.as_mut() {
+            store.configs.remove(&key_id);
+            let proposals: Vec<_> = store
+                .proposals
+                .range((key_id, 0)..)
+                .take_while(|((k, _), _)| k == &key_id)
+                .map(|(proposal_key, _)| proposal_key)
+                .collect();
+            for proposal_key in proposals {
+                store.proposals.remove(&proposal_key);
+            }
+        }
+        if let Some(store) = self.access_requests.as_mut() {
+            let requests: Vec<_> = store
+                .requests
+                .range((key_id, Principal::management_canister())..)
+                .take_while(|((k, _), _)| k == &key_id)
+                .map(|(request_key, _)| request_key)
+                .collect();
+            for request_key in requests {
+                store.requests.remove(&request_key);
+            }
+        }
+        self.revoke_invites_of_key(key_id);
+        if let Some(store) = self.freezes.as_mut() {
+            store.keys.remove(&key_id);
+        }
+        if let Some(store) = self.admins.as_mut() {
+            store.recovery_principals.remove(&key_id);
+        }
+        self.remove_recoveries_of_key(key_id);
+        self.remove_key_metadata(key_id);
+    }
+
+    /// Registers a key on its owner's first request of an encrypted vetkey,
+    /// if the key lifecycle is enabled.
+    pub(crate) fn register_owned_key(&mut self, key_id: KeyId) {
+        if let Some(owned_keys) = self.owned_keys.as_mut() {
+            if !owned_keys.contains_key(&key_id) {
+                owned_keys.insert(key_id, now());
+            }
+        }
+    }
+}
@@ -0,0 +1,599 @@
+//! Versioned stable-memory schema and migrations.
+//!
+//! The schema version is stored in the same stable cell as the domain
+//! separator. All stored values use versioned encodings, so values written by
+//! older versions can still be decoded and are re-encoded when written again.
+//!
+//! Schema version 2 increased the maximum length of key names and map keys
+//! from 32 to [`MAX_NAME_BYTES`] bytes. Since the names are padded to their
+//! maximum length in the keys of the stable maps, this changes the layout of
+//! every map keyed by a key ID, i.e., of every [`MigratedStructure`]. These
+//! maps are stored as [`NamedMap`]s, and their data written before schema
+//! version 2 is moved into new memories in batches:
+//!
+//! 1. The canister passes new, empty memories for these maps to
+//!    [`KeyManager::init`] and the `with_*` methods, and attaches the memory
+//!    each map was stored in before with [`KeyManager::with_legacy_memory`]
+//!    (or `EncryptedMaps::with_legacy_memory`), which queues its migration.
+//! 2. In `post_upgrade`, the canister starts a timer that calls
+//!    [`KeyManager::run_migration_batch`] (or
+//!    `EncryptedMaps::run_migration_batch`) and starts the next timer until
+//!    [`KeyManager::is_migration_complete`] returns true or a batch migrates
+//!    no entries, which happens if the legacy memory of a pending migration
+//!    is not attached. Each batch runs in its own message, moves at most
+//!    [`MIGRATION_BATCH_SIZE`] entries into the new maps and re-encodes them
+//!    with the current encodings. The progress is stored in the maps
+//!    themselves, so migrations continue across further upgrades as long as
+//!    the legacy memories stay attached. The example canisters of this
+//!    repository implement this driver.
+//!
+//! Entries that are not migrated yet are read from the legacy maps, and
+//! writes and removals apply to both maps, so the maps can be used during the
+//! migration. The data of an optional feature that is not enabled during the
+//! upgrade stays in its legacy memory until the feature is enabled and the
+//! memory is attached in a later upgrade. To keep data written before schema
+//! version 2 from being read with the new layout, a memory that already holds
+//! data cannot be opened as the new memory of a map unless the map was stored
+//! in it with the new layout before; [`KeyManager::init`] and the `with_*`
+//! methods panic instead.
+
+use crate::export::ImportSession;
+use crate::{KeyId, KeyManager};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_stable_structures::storable::{Blob, Bound};
+use ic_stable_structures::{Memory, StableBTreeMap, StableCell, Storable};
+use ic_vetkd_cdk_types::{decode_versioned, encode_versioned, KeyName, MAX_NAME_BYTES};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::iter::Peekable;
+use std::ops::{Bound as RangeBound, RangeBounds};
+
+/// The current schema version.
+///
+/// - 0: unversioned encodings, the cell only holds the domain separator.
+/// - 1: versioned encodings of all stored types.
+/// - 2: key names and map keys of up to [`MAX_NAME_BYTES`] bytes.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// Key names and map keys as stored before schema version 2.
+pub type LegacyName = Blob<32>;
+/// Key IDs as stored before schema version 2.
+pub type LegacyKeyId = (Principal, LegacyName);
+
+/// Maximum number of entries migrated in a single batch.
+pub const MIGRATION_BATCH_SIZE: usize = 500;
+
+/// A stable map keyed by key IDs whose data written before schema version 2
+/// is migrated, see [`crate::migration`].
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, strum_macros::EnumIter)]
+pub enum MigratedStructure {
+    AccessControl,
+    AuditLogs,
+    EncryptedMapValues,
+    Tombstones,
+    SharedKeys,
+    ApprovalConfigs,
+    GrantProposals,
+    AccessRequests,
+    TokenGates,
+    TokenOwners,
+    Prices,
+    FrozenKeys,
+}
+
+/// A queued or partially completed migration of a stable structure.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PendingMigration {
+    pub structure: MigratedStructure,
+}
+
+/// The contents of the `KeyManager`'s metadata cell.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    pub domain_separator: String,
+    pub schema_version: u32,
+    pub pending_migrations: Vec<PendingMigration>,
+    /// The structures whose memories hold data with the current layout.
+    pub current_structures: Vec<MigratedStructure>,
+    /// The import in progress, if any, see [`crate::export`].
+    pub import_session: Option<ImportSession>,
+}
+
+impl Metadata {
+    pub(crate) fn new(domain_separator: &str) -> Self {
+        Self {
+            domain_separator: domain_separator.to_string(),
+            schema_version: SCHEMA_VERSION,
+            pending_migrations: vec![],
+            current_structures: vec![],
+            import_session: None,
+        }
+    }
+}
+
+/// Stored as Candid, prefixed with the encoding version. Schema version 0
+/// stored the domain separator as a plain UTF-8 string, which never starts
+/// with the versioned encoding tag.
+impl Storable for Metadata {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let payload = Encode!(self).expect("failed to encode Metadata");
+        Cow::Owned(encode_versioned(2, &payload))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        match decode_versioned(bytes.as_ref()) {
+            (0, payload) => Self {
+                domain_separator: String::from_utf8(payload.to_vec())
+                    .expect("failed to decode domain separator"),
+                schema_version: 0,
+                pending_migrations: vec![],
+                current_structures: vec![],
+                import_session: None,
+            },
+            (2, payload) => Decode!(payload, Self).expect("failed to decode Metadata"),
+            (version, _) => panic!("unsupported Metadata encoding version {version}"),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A key of a [`NamedMap`] and its encoding before schema version 2.
+pub trait LegacyKey: Storable + Ord + Clone {
+    type Legacy: Storable + Ord + Clone;
+
+    /// Converts a key stored before schema version 2.
+    fn from_legacy(legacy: Self::Legacy) -> Self;
+
+    /// Returns the key with all names truncated to their former maximum
+    /// length. No legacy key that converts to a key at or after `self` comes
+    /// before the result.
+    fn legacy_floor(&self) -> Self::Legacy;
+
+    /// Returns the legacy key that converts to `self`, if any.
+    fn to_legacy(&self) -> Option<Self::Legacy> {
+        let legacy = self.legacy_floor();
+        (Self::from_legacy(legacy.clone()) == *self).then_some(legacy)
+    }
+}
+
+impl LegacyKey for Principal {
+    type Legacy = Self;
+
+    fn from_legacy(legacy: Self) -> Self {
+        legacy
+    }
+
+    fn legacy_floor(&self) -> Self {
+        *self
+    }
+}
+
+impl LegacyKey for u64 {
+    type Legacy = Self;
+
+    fn from_legacy(legacy: Self) -> Self {
+        legacy
+    }
+
+    fn legacy_floor(&self) -> Self {
+        *self
+    }
+}
+
+impl LegacyKey for KeyName {
+    type Legacy = LegacyName;
+
+    fn from_legacy(legacy: LegacyName) -> Self {
+        convert_legacy_name(&legacy)
+    }
+
+    fn legacy_floor(&self) -> LegacyName {
+        let name = self.as_slice();
+        LegacyName::try_from(&name[..name.len().min(32)]).expect("truncated name fits")
+    }
+}
+
+impl<A: LegacyKey, B: LegacyKey> LegacyKey for (A, B) {
+    type Legacy = (A::Legacy, B::Legacy);
+
+    fn from_legacy((a, b): Self::Legacy) -> Self {
+        (A::from_legacy(a), B::from_legacy(b))
+    }
+
+    fn legacy_floor(&self) -> Self::Legacy {
+        (self.0.legacy_floor(), self.1.legacy_floor())
+    }
+}
+
+/// A stable map keyed by key IDs together with the map it was stored in
+/// before schema version 2, if attached, see [`crate::migration`]. Every
+/// entry is stored in exactly one of the two maps.
+pub struct NamedMap<K: LegacyKey, V: Storable, M: Memory> {
+    map: StableBTreeMap<K, V, M>,
+    legacy: Option<StableBTreeMap<K::Legacy, V, M>>,
+}
+
+impl<K: LegacyKey, V: Storable, M: Memory> NamedMap<K, V, M> {
+    /// Opens the map stored in `memory` without a legacy map.
+    #[must_use]
+    pub fn init(memory: M) -> Self {
+        Self {
+            map: StableBTreeMap::init(memory),
+            legacy: None,
+        }
+    }
+
+    /// Returns the map that holds the migrated and all new entries.
+    #[must_use]
+    pub fn current(&self) -> &StableBTreeMap<K, V, M> {
+        &self.map
+    }
+
+    /// Returns the map that holds the migrated and all new entries.
+    pub fn current_mut(&mut self) -> &mut StableBTreeMap<K, V, M> {
+        &mut self.map
+    }
+
+    #[must_use]
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.map.get(key).or_else(|| {
+            let legacy = self.legacy.as_ref()?;
+            legacy.get(&key.to_legacy()?)
+        })
+    }
+
+    #[must_use]
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let previous = self.remove_legacy(&key);
+        self.map.insert(key, value).or(previous)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let previous = self.remove_legacy(key);
+        self.map.remove(key).or(previous)
+    }
+
+    #[must_use]
+    pub fn len(&self) -> u64 {
+        self.map.len() + self.legacy.as_ref().map_or(0, StableBTreeMap::len)
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the entries in `key_range` of both maps in the order of their keys.
+    pub fn range(&self, key_range: impl RangeBounds<K>) -> impl Iterator<Item = (K, V)> + '_ {
+        let start = key_range.start_bound().cloned();
+        let end = key_range.end_bound().cloned();
+        let legacy_start = match &start {
+            RangeBound::Included(key) | RangeBound::Excluded(key) => {
+                RangeBound::Included(key.legacy_floor())
+            }
+            RangeBound::Unbounded => RangeBound::Unbounded,
+        };
+        let (after_start, before_end) = (start.clone(), end.clone());
+        let legacy = self
+            .legacy
+            .iter()
+            .flat_map(move |legacy| legacy.range((legacy_start.clone(), RangeBound::Unbounded)))
+            .map(|(key, value)| (K::from_legacy(key), value))
+            .skip_while(move |(key, _)| !is_after_start(key, &after_start))
+            .take_while(move |(key, _)| is_before_end(key, &before_end));
+        Merge {
+            left: self.map.range((start, end)).peekable(),
+            right: legacy.peekable(),
+        }
+    }
+
+    /// Returns the keys in `key_range` of both maps in order.
+    pub fn keys_range(&self, key_range: impl RangeBounds<K>) -> impl Iterator<Item = K> + '_ {
+        self.range(key_range).map(|(key, _)| key)
+    }
+
+    /// Returns all entries of both maps in the order of their keys.
+    pub fn iter(&self) -> impl Iterator<Item = (K, V)> + '_ {
+        self.range(..)
+    }
+
+    fn remove_legacy(&mut self, key: &K) -> Option<V> {
+        let legacy = self.legacy.as_mut()?;
+        legacy.remove(&key.to_legacy()?)
+    }
+}
+
+fn is_after_start<K: Ord>(key: &K, start: &RangeBound<K>) -> bool {
+    match start {
+        RangeBound::Included(start) => key >= start,
+        RangeBound::Excluded(start) => key > start,
+        RangeBound::Unbounded => true,
+    }
+}
+
+fn is_before_end<K: Ord>(key: &K, end: &RangeBound<K>) -> bool {
+    match end {
+        RangeBound::Included(end) => key <= end,
+        RangeBound::Excluded(end) => key < end,
+        RangeBound::Unbounded => true,
+    }
+}
+
+/// Merges two iterators over entries with disjoint keys, each in key order.
+struct Merge<L: Iterator, R: Iterator> {
+    left: Peekable<L>,
+    right: Peekable<R>,
+}
+
+impl<K: Ord, V, L, R> Iterator for Merge<L, R>
+where
+    L: Iterator<Item = (K, V)>,
+    R: Iterator<Item = (K, V)>,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let take_right = match (self.left.peek(), self.right.peek()) {
+            (Some((left, _)), Some((right, _))) => right < left,
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        if take_right {
+            self.right.next()
+        } else {
+            self.left.next()
+        }
+    }
+}
+
+/// The migration of a [`NamedMap`], independent of its key and value types.
+pub trait LegacyEntries<M: Memory> {
+    /// Attaches the memory the map was stored in before schema version 2.
+    fn attach_legacy_memory(&mut self, memory: M);
+
+    /// Returns the number of entries that are not migrated yet.
+    fn legacy_len(&self) -> u64;
+
+    /// Moves up to `limit` entries from the legacy map into the current map.
+    /// Returns the number of moved entries, or `None` if no legacy memory is
+    /// attached.
+    fn migrate_map_batch(&mut self, limit: usize) -> Option<usize>;
+}
+
+impl<K: LegacyKey, V: Storable, M: Memory> LegacyEntries<M> for NamedMap<K, V, M> {
+    fn attach_legacy_memory(&mut self, memory: M) {
+        self.legacy = Some(StableBTreeMap::init(memory));
+    }
+
+    fn legacy_len(&self) -> u64 {
+        self.legacy.as_ref().map_or(0, StableBTreeMap::len)
+    }
+
+    fn migrate_map_batch(&mut self, limit: usize) -> Option<usize> {
+        let legacy = self.legacy.as_mut()?;
+        let keys: Vec<K::Legacy> = legacy.iter().take(limit).map(|(key, _)| key).collect();
+        for key in &keys {
+            let value = legacy.remove(key).expect("listed legacy entry exists");
+            self.map.insert(K::from_legacy(key.clone()), value);
+        }
+        Some(keys.len())
+    }
+}
+
+/// Converts a name stored before schema version 2.
+///
+/// # Panics
+///
+/// Panics if `N` is smaller than the length of `name`, which cannot happen
+/// for key names and map keys of up to [`MAX_NAME_BYTES`] bytes.
+#[must_use]
+pub fn convert_legacy_name<const N: usize>(name: &LegacyName) -> Blob<N> {
+    Blob::try_from(name.as_slice()).expect("legacy name is too long")
+}
+
+/// Converts a key ID stored before schema version 2.
+#[must_use]
+pub fn convert_legacy_key_id(key_id: LegacyKeyId) -> KeyId {
+    KeyId::from_legacy(key_id)
+}
+
+/// Opens the [`NamedMap`] of `structure` stored in `memory` and records that
+/// the memory holds the map with the current layout.
+///
+/// # Panics
+///
+/// Panics if `memory` holds data but not the map with the current layout,
+/// e.g., if it is the memory of the map before schema version 2.
+pub(crate) fn open_named_map<K, V, M>(
+    metadata: &mut StableCell<Metadata, M>,
+    structure: MigratedStructure,
+    memory: M,
+) -> NamedMap<K, V, M>
+where
+    K: LegacyKey,
+    V: Storable,
+    M: Memory,
+{
+    let mut contents = metadata.get().clone();
+    if !contents.current_structures.contains(&structure) {
+        assert!(
+            memory.size() == 0,
+            "the memory of {structure:?} holds data without the current layout; \
+             attach it with `with_legacy_memory` and pass a new memory instead"
+        );
+        contents.current_structures.push(structure);
+        metadata.set(contents).expect("failed to store metadata");
+    }
+    NamedMap::init(memory)
+}
+
+/// Updates the stored schema version and returns the previous one if it was
+/// older than [`SCHEMA_VERSION`]. Pending migrations are kept.
+pub(crate) fn upgrade_schema<M: Memory>(metadata: &mut StableCell<Metadata, M>) -> Option<u32> {
+    let mut contents = metadata.get().clone();
+    if contents.schema_version >= SCHEMA_VERSION {
+        return None;
+    }
+    let previous = contents.schema_version;
+    contents.schema_version = SCHEMA_VERSION;
+    metadata.set(contents).expect("failed to store metadata");
+    Some(previous)
+}
+
+impl<M: Memory> KeyManager<M> {
+    /// Returns the schema version found in stable memory by [`KeyManager::init`]
+    /// if it was older than [`SCHEMA_VERSION`], i.e., if this `init` upgraded the schema.
+    #[must_use]
+    pub fn previous_schema_version(&self) -> Option<u32> {
+        self.previous_schema_version
+    }
+
+    /// Opens the [`NamedMap`] of `structure` stored in `memory`, e.g., for
+    /// structures that are owned by a wrapper such as `EncryptedMaps`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `memory` holds data but not the map with the current layout,
+    /// see [`crate::migration`].
+    pub fn init_named_map<K: LegacyKey, V: Storable>(
+        &mut self,
+        structure: MigratedStructure,
+        memory: M,
+    ) -> NamedMap<K, V, M> {
+        open_named_map(&mut self.metadata, structure, memory)
+    }
+
+    /// Attaches the memory `structure` was stored in before schema version 2
+    /// and queues its migration, see [`crate::migration`]. The structure must
+    /// be enabled first.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `structure` is not enabled or not stored by the `KeyManager`.
+    #[must_use]
+    pub fn with_legacy_memory(mut self, structure: MigratedStructure, memory: M) -> Self {
+        let map = self
+            .named_map(structure)
+            .unwrap_or_else(|| panic!("{structure:?} is not enabled"));
+        map.attach_legacy_memory(memory);
+        if map.legacy_len() > 0 {
+            self.queue_migration(structure);
+        }
+        self
+    }
+
+    /// Queues a migration of `structure`, e.g., for structures that are owned
+    /// by a wrapper such as `EncryptedMaps`.
+    pub fn queue_migration(&mut self, structure: MigratedStructure) {
+        if self.is_migration_pending(structure) {
+            return;
+        }
+        let mut metadata = self.metadata.get().clone();
+        metadata
+            .pending_migrations
+            .push(PendingMigration { structure });
+        self.set_metadata(metadata);
+    }
+
+    /// Returns true if a migration of `structure` is pending.
+    #[must_use]
+    pub fn is_migration_pending(&self, structure: MigratedStructure) -> bool {
+        self.metadata
+            .get()
+            .pending_migrations
+            .iter()
+            .any(|migration| migration.structure == structure)
+    }
+
+    /// Marks the migration of `structure` as complete.
+    pub fn complete_migration(&mut self, structure: MigratedStructure) {
+        let mut metadata = self.metadata.get().clone();
+        metadata
+            .pending_migrations
+            .retain(|migration| migration.structure != structure);
+        self.set_metadata(metadata);
+    }
+
+    /// Returns true if no migrations are pending.
+    #[must_use]
+    pub fn is_migration_complete(&self) -> bool {
+        self.metadata.get().pending_migrations.is_empty()
+    }
+
+    /// Migrates up to `limit` entries of the structures owned by the `KeyManager`.
+    /// Structures whose legacy memory is not attached are skipped and stay
+    /// pending. Returns the number of migrated entries.
+    pub fn run_migration_batch(&mut self, limit: usize) -> usize {
+        let mut migrated = 0;
+        let pending = self.metadata.get().pending_migrations.clone();
+        for PendingMigration { structure } in pending {
+            if migrated >= limit {
+                break;
+            }
+            let Some(map) = self.named_map(structure) else {
+                continue;
+            };
+            let Some(count) = map.migrate_map_batch(limit - migrated) else {
+                continue;
+            };
+            let complete = map.legacy_len() == 0;
+            migrated += count;
+            if complete {
+                self.complete_migration(structure);
+            }
+        }
+        migrated
+    }
+
+    /// Returns the map of `structure` if it is stored by the `KeyManager` and enabled.
+    fn named_map(&mut self, structure: MigratedStructure) -> Option<&mut dyn LegacyEntries<M>> {
+        match structure {
+            MigratedStructure::AccessControl => Some(&mut self.access_control),
+            MigratedStructure::SharedKeys => Some(&mut self.shared_keys),
+            MigratedStructure::AuditLogs => self
+                .audit_logs
+                .as_mut()
+                .map(|map| map as &mut dyn LegacyEntries<M>),
+            MigratedStructure::ApprovalConfigs => self
+                .approvals
+                .as_mut()
+                .map(|store| &mut store.configs as &mut dyn LegacyEntries<M>),
+            MigratedStructure::GrantProposals => self
+                .approvals
+                .as_mut()
+                .map(|store| &mut store.proposals as &mut dyn LegacyEntries<M>),
+            MigratedStructure::AccessRequests => self
+                .access_requests
+                .as_mut()
+                .map(|store| &mut store.requests as &mut dyn LegacyEntries<M>),
+            MigratedStructure::TokenGates => self
+                .token_gates
+                .as_mut()
+                .map(|store| &mut store.gates as &mut dyn LegacyEntries<M>),
+            MigratedStructure::TokenOwners => self
+                .token_gates
+                .as_mut()
+                .map(|store| &mut store.owners as &mut dyn LegacyEntries<M>),
+            MigratedStructure::Prices => self
+                .payments
+                .as_mut()
+                .map(|store| &mut store.prices as &mut dyn LegacyEntries<M>),
+            MigratedStructure::FrozenKeys => self
+                .freezes
+                .as_mut()
+                .map(|store| &mut store.keys as &mut dyn LegacyEntries<M>),
+            MigratedStructure::EncryptedMapValues | MigratedStructure::Tombstones => None,
+        }
+    }
+
+    pub(crate) fn set_metadata(&mut self, metadata: Metadata) {
+        self.metadata
+            .set(metadata)
+            .expect("failed to store metadata");
+    }
+}
@@ -0,0 +1,577 @@
+//! Time-limited access to keys sold for ICRC-2 tokens.
+//!
+//! A user with the `MANAGE` permission sets the price of a key with
+//! [`KeyManager::set_access_price`]. A buyer approves the canister as a spender
+//! on the price's ledger and then purchases access in several steps, since the
+//! `KeyManager` cannot be borrowed across the ledger calls:
+//!
+//! ```ignore
+//! let payment = KEY_MANAGER
+//!     .with_borrow(|km| km.start_purchase(caller, key_id, now()))?
+//!     .await?;
+//! let purchase_id = KEY_MANAGER.with_borrow_mut(|km| km.record_payment(caller, key_id, payment))?;
+//! let result = KEY_MANAGER.with_borrow_mut(|km| km.complete_purchase(caller, purchase_id));
+//! if result.is_err() {
+//!     let refund = KEY_MANAGER
+//!         .with_borrow_mut(|km| km.refund_purchase(caller, purchase_id))?
+//!         .await;
+//!     KEY_MANAGER.with_borrow_mut(|km| km.record_refund(caller, purchase_id, &refund));
+//!     refund?;
+//! }
+//! result
+//! ```
+//!
+//! [`KeyManager::start_purchase`] pulls the price with `icrc2_transfer_from`
+//! into the key owner's proceeds account, a subaccount of the canister (see
+//! [`proceeds_subaccount`]). [`KeyManager::record_payment`] stores the payment
+//! as a pending purchase of the buyer. [`KeyManager::complete_purchase`] grants
+//! the priced permissions until `duration` after the purchase, extending a
+//! previous purchase that is still valid, and removes the pending purchase. If
+//! the grant fails, e.g., because the price changed in the meantime,
+//! [`KeyManager::refund_purchase`] returns the stored payment minus the ledger
+//! fee. Failed refunds are recorded in the pending purchase and the audit log
+//! by [`KeyManager::record_refund`] and can be retried by the buyer, who lists
+//! their pending purchases with [`KeyManager::get_pending_purchases`].
+//!
+//! The access rights granted by the last purchase of each buyer are stored
+//! separately from the grants, so that only purchased access is extended by a
+//! purchase, and not a grant of the key owner with the same permissions. Access
+//! to keys whose grants require approval (see [`crate::approvals`]) cannot be
+//! purchased. Key owners collect their proceeds with [`withdraw_proceeds`].
+
+use crate::icrc::{Account, TransferArg, TransferError, TransferFromArgs, TransferFromError};
+use crate::migration::{MigratedStructure, NamedMap};
+use crate::policy::Operation;
+use crate::{Caller, DefaultMemory, KeyId, KeyManager};
+use candid::{CandidType, Decode, Encode, Nat, Principal};
+use futures::future::FutureExt;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Memory, StableBTreeMap, Storable};
+use ic_vetkd_cdk_types::{
+    decode_versioned, encode_versioned, now, AccessRights, AuditEntry, ByteBuf, KeyName,
+    Permissions,
+};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::future::Future;
+
+/// The price of access to a key.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AccessPrice {
+    /// The ICRC-2 ledger of the token the price is paid in.
+    pub ledger: Principal,
+    /// The price in the ledger's smallest unit, excluding fees.
+    pub price: Nat,
+    /// Time in nanoseconds a purchase grants access for.
+    pub duration: u64,
+    /// The permissions a purchase grants.
+    pub permissions: Permissions,
+}
+
+impl Storable for AccessPrice {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let payload = Encode!(self).expect("failed to encode AccessPrice");
+        Cow::Owned(encode_versioned(1, &payload))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        match decode_versioned(bytes.as_ref()) {
+            (0 | 1, payload) => Decode!(payload, Self).expect("failed to decode AccessPrice"),
+            (version, _) => panic!("unsupported AccessPrice encoding version {version}"),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A payment made with [`KeyManager::start_purchase`].
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Payment {
+    pub ledger: Principal,
+    pub amount: Nat,
+    /// The proceeds account the payment was made to.
+    pub to: Account,
+    /// The index of the ledger block of the transfer.
+    pub block_index: Nat,
+}
+
+/// The id of a pending purchase, unique per buyer.
+pub type PurchaseId = u64;
+
+/// A payment recorded with [`KeyManager::record_payment`] for which access
+/// was neither granted nor refunded yet.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PendingPurchase {
+    /// The owner of the key the access was paid for.
+    pub key_owner: Caller,
+    /// The name of the key, stored as bytes since `KeyName` is not a Candid type.
+    pub key_name: ByteBuf,
+    pub payment: Payment,
+    pub status: PurchaseStatus,
+}
+
+impl PendingPurchase {
+    /// Returns the key the access was paid for.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stored key name is not a valid `KeyName`.
+    #[must_use]
+    pub fn key_id(&self) -> KeyId {
+        let key_name =
+            KeyName::try_from(self.key_name.as_ref()).expect("invalid key name in purchase");
+        (self.key_owner, key_name)
+    }
+}
+
+/// The state of a pending purchase.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum PurchaseStatus {
+    /// The payment was made, but access was not granted.
+    Paid,
+    /// A refund of the payment is in progress.
+    Refunding,
+    /// The refund of the payment failed with the given error and can be retried.
+    RefundFailed(String),
+}
+
+impl Storable for PendingPurchase {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let payload = Encode!(self).expect("failed to encode PendingPurchase");
+        Cow::Owned(encode_versioned(1, &payload))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        match decode_versioned(bytes.as_ref()) {
+            (0 | 1, payload) => Decode!(payload, Self).expect("failed to decode PendingPurchase"),
+            (version, _) => panic!("unsupported PendingPurchase encoding version {version}"),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Stable storage of access prices and purchases.
+pub struct PaymentStore<M: Memory = DefaultMemory> {
+    pub prices: NamedMap<KeyId, AccessPrice, M>,
+    /// The pending purchases of each buyer.
+    pub pending_purchases: StableBTreeMap<(Caller, PurchaseId), PendingPurchase, M>,
+    /// The access rights granted by the last purchase of each buyer of a key.
+    pub purchased_rights: StableBTreeMap<(KeyId, Caller), AccessRights, M>,
+}
+
+impl<M: Memory> KeyManager<M> {
+    /// Enables purchases of access, see [`crate::payments`].
+    #[must_use]
+    pub fn with_payments(
+        mut self,
+        memory_prices: M,
+        memory_pending_purchases: M,
+        memory_purchased_rights: M,
+    ) -> Self {
+        self.payments = Some(PaymentStore {
+            prices: self.init_named_map(MigratedStructure::Prices, memory_prices),
+            pending_purchases: StableBTreeMap::init(memory_pending_purchases),
+            purchased_rights: StableBTreeMap::init(memory_purchased_rights),
+        });
+        self
+    }
+
+    /// Sets the price of access to a key, or stops selling access if `price` is `None`.
+    /// Returns the previous price.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if payments are not enabled, the caller does not have
+    /// the `MANAGE` permission, or the duration is zero.
+    pub fn set_access_price(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        price: Option<AccessPrice>,
+    ) -> Result<Option<AccessPrice>, String> {
+        self.authorize(caller, key_id, Operation::Manage)?;
+        if price.as_ref().is_some_and(|price| price.duration == 0) {
+            return Err("invalid access duration".to_string());
+        }
+        let store = self
+            .payments
+            .as_mut()
+            .ok_or_else(|| "payments are not enabled".to_string())?;
+        Ok(match price {
+            Some(price) => store.prices.insert(key_id, price),
+            None => store.prices.remove(&key_id),
+        })
+    }
+
+    /// Retrieves the price of access to a key, if access is for sale.
+    #[must_use]
+    pub fn get_access_price(&self, key_id: KeyId) -> Option<AccessPrice> {
+        self.payments
+            .as_ref()
+            .and_then(|store| store.prices.get(&key_id))
+    }
+
+    /// Checks that `caller` can purchase access to `key_id` at `time` and
+    /// returns a future that pulls the price from the caller's account.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if:
+    /// - The caller is anonymous or the key owner
+    /// - Access to the key is not for sale
+    /// - Grants of the key require approval (see [`crate::approvals`])
+    /// - The caller has access rights that a purchase would not extend
+    ///
+    /// The future resolves to an error if the ledger rejects the transfer,
+    /// e.g., because the caller did not approve the canister as a spender.
+    pub fn start_purchase(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+        time: u64,
+    ) -> Result<impl Future<Output = Result<Payment, String>> + Send + Sync, String> {
+        let price = self.purchasable_price(caller, key_id, time)?;
+
+        let ledger = price.ledger;
+        let amount = price.price;
+        let to = proceeds_account(ic_cdk::id(), key_id.0);
+        let args = TransferFromArgs {
+            spender_subaccount: None,
+            from: Account::from(caller),
+            to: to.clone(),
+            amount: amount.clone(),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        };
+        let future = ic_cdk::api::call::call::<_, (Result<Nat, TransferFromError>,)>(
+            ledger,
+            "icrc2_transfer_from",
+            (args,),
+        );
+        Ok(future.map(move |call_result| {
+            let (result,) = call_result.map_err(|(code, message)| {
+                format!("call to icrc2_transfer_from failed: {code:?} {message}")
+            })?;
+            let block_index =
+                result.map_err(|error| format!("icrc2_transfer_from failed: {error:?}"))?;
+            Ok(Payment {
+                ledger,
+                amount,
+                to,
+                block_index,
+            })
+        }))
+    }
+
+    /// Stores a payment made with [`Self::start_purchase`] as a pending
+    /// purchase of `caller` and returns its id.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if payments are not enabled.
+    pub fn record_payment(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        payment: Payment,
+    ) -> Result<PurchaseId, String> {
+        let store = self
+            .payments
+            .as_mut()
+            .ok_or_else(|| "payments are not enabled".to_string())?;
+        let purchase_id = store
+            .pending_purchases
+            .range((caller, 0)..)
+            .take_while(|((buyer, _), _)| buyer == &caller)
+            .map(|((_, id), _)| id + 1)
+            .last()
+            .unwrap_or(0);
+        store.pending_purchases.insert(
+            (caller, purchase_id),
+            PendingPurchase {
+                key_owner: key_id.0,
+                key_name: ByteBuf::from(key_id.1.as_ref().to_vec()),
+                payment,
+                status: PurchaseStatus::Paid,
+            },
+        );
+        Ok(purchase_id)
+    }
+
+    /// Retrieves the pending purchases of `caller`.
+    #[must_use]
+    pub fn get_pending_purchases(&self, caller: Principal) -> Vec<(PurchaseId, PendingPurchase)> {
+        self.payments.as_ref().map_or_else(Vec::new, |store| {
+            store
+                .pending_purchases
+                .range((caller, 0)..)
+                .take_while(|((buyer, _), _)| buyer == &caller)
+                .map(|((_, purchase_id), purchase)| (purchase_id, purchase))
+                .collect()
+        })
+    }
+
+    /// Grants access paid for by the pending purchase `purchase_id` of
+    /// `caller`, removes the pending purchase and returns the granted access
+    /// rights. The grant is recorded like a share by the key owner, in
+    /// addition to the purchase itself.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the pending purchase does not exist or is being
+    /// refunded, if the payment does not match the current price or if the
+    /// caller can no longer purchase access. In the latter cases, the payment
+    /// should be refunded with [`Self::refund_purchase`].
+    pub fn complete_purchase(
+        &mut self,
+        caller: Principal,
+        purchase_id: PurchaseId,
+    ) -> Result<AccessRights, String> {
+        let purchase = self.get_pending_purchase(caller, purchase_id)?;
+        if purchase.status != PurchaseStatus::Paid {
+            return Err("purchase is being refunded".to_string());
+        }
+        let key_id = purchase.key_id();
+        let payment = purchase.payment;
+        let time = now();
+        let price = self.purchasable_price(caller, key_id, time)?;
+        if price.ledger != payment.ledger || price.price > payment.amount {
+            return Err("access price changed".to_string());
+        }
+
+        let access_rights = self.purchased_rights(caller, key_id, &price, time)?;
+        let block_index = u64::try_from(payment.block_index.0).ok();
+        self.add_audit_log(key_id, move || {
+            let entry = AuditEntry::access_purchased(now(), caller, access_rights);
+            match block_index {
+                Some(block_index) => entry.with_reference_id(block_index),
+                None => entry,
+            }
+        });
+        // The key owner set the price, so the grant is made on their behalf
+        self.apply_user_rights(key_id.0, key_id, caller, access_rights, None);
+        if let Some(store) = self.payments.as_mut() {
+            store.pending_purchases.remove(&(caller, purchase_id));
+            store
+                .purchased_rights
+                .insert((key_id, caller), access_rights);
+        }
+        Ok(access_rights)
+    }
+
+    /// Starts the refund of the pending purchase `purchase_id` of `caller`,
+    /// i.e., of the stored payment minus the ledger fee. Returns a future that
+    /// resolves to the index of the ledger block of the refund, which must be
+    /// passed to [`Self::record_refund`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the pending purchase does not exist or is already
+    /// being refunded.
+    pub fn refund_purchase(
+        &mut self,
+        caller: Principal,
+        purchase_id: PurchaseId,
+    ) -> Result<impl Future<Output = Result<Nat, String>> + Send + Sync, String> {
+        let mut purchase = self.get_pending_purchase(caller, purchase_id)?;
+        if purchase.status == PurchaseStatus::Refunding {
+            return Err("purchase is being refunded".to_string());
+        }
+        let payment = purchase.payment.clone();
+        purchase.status = PurchaseStatus::Refunding;
+        if let Some(store) = self.payments.as_mut() {
+            store
+                .pending_purchases
+                .insert((caller, purchase_id), purchase);
+        }
+        Ok(transfer_minus_fee(
+            payment.ledger,
+            payment.to.subaccount,
+            Account::from(caller),
+            payment.amount,
+        ))
+    }
+
+    /// Records the `result` of a refund started with [`Self::refund_purchase`]
+    /// in the audit log. A refunded purchase is removed. A failed refund is
+    /// kept as [`PurchaseStatus::RefundFailed`], so that it can be retried.
+    pub fn record_refund(
+        &mut self,
+        caller: Principal,
+        purchase_id: PurchaseId,
+        result: &Result<Nat, String>,
+    ) {
+        let Ok(mut purchase) = self.get_pending_purchase(caller, purchase_id) else {
+            return;
+        };
+        let key_id = purchase.key_id();
+        let block_index = u64::try_from(purchase.payment.block_index.0.clone()).ok();
+        let Some(store) = self.payments.as_mut() else {
+            return;
+        };
+        match result {
+            Ok(_) => {
+                store.pending_purchases.remove(&(caller, purchase_id));
+            }
+            Err(error) => {
+                purchase.status = PurchaseStatus::RefundFailed(error.clone());
+                store
+                    .pending_purchases
+                    .insert((caller, purchase_id), purchase);
+            }
+        }
+        let refunded = result.is_ok();
+        self.add_audit_log(key_id, move || {
+            let entry = if refunded {
+                AuditEntry::purchase_refunded(now(), caller)
+            } else {
+                AuditEntry::refund_failed(now(), caller)
+            };
+            match block_index {
+                Some(block_index) => entry.with_reference_id(block_index),
+                None => entry,
+            }
+        });
+    }
+
+    /// Forgets the access rights granted by the last purchase of `user`,
+    /// since they were replaced or removed by another grant.
+    pub(crate) fn forget_purchased_rights(&mut self, key_id: KeyId, user: Principal) {
+        if let Some(store) = self.payments.as_mut() {
+            store.purchased_rights.remove(&(key_id, user));
+        }
+    }
+
+    fn get_pending_purchase(
+        &self,
+        caller: Principal,
+        purchase_id: PurchaseId,
+    ) -> Result<PendingPurchase, String> {
+        self.payments
+            .as_ref()
+            .ok_or_else(|| "payments are not enabled".to_string())?
+            .pending_purchases
+            .get(&(caller, purchase_id))
+            .ok_or_else(|| "purchase not found".to_string())
+    }
+
+    /// Returns the access rights granted by purchasing access for `price` at
+    /// `time`. Only access rights granted by a previous purchase are extended.
+    fn purchased_rights(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+        price: &AccessPrice,
+        time: u64,
+    ) -> Result<AccessRights, String> {
+        let previous_purchase = self
+            .payments
+            .as_ref()
+            .and_then(|store| store.purchased_rights.get(&(key_id, caller)));
+        let start = match self.access_control.get(&(caller, key_id)) {
+            None => time,
+            // Extend a previous purchase
+            Some(access_rights)
+                if Some(access_rights) == previous_purchase
+                    && access_rights.permissions() == price.permissions =>
+            {
+                access_rights.end().map_or(time, |end| end.max(time))
+            }
+            Some(_) => return Err("user already has access rights for the key".to_string()),
+        };
+        Ok(AccessRights::with_permissions(
+            price.permissions,
+            None,
+            Some(start.saturating_add(price.duration)),
+        ))
+    }
+
+    fn purchasable_price(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+        time: u64,
+    ) -> Result<AccessPrice, String> {
+        if caller == Principal::anonymous() {
+            return Err("unauthorized".to_string());
+        }
+        if caller == key_id.0 {
+            return Err("cannot purchase access to own key".to_string());
+        }
+        let price = self
+            .get_access_price(key_id)
+            .ok_or_else(|| "access is not for sale".to_string())?;
+        if self.get_approval_config(key_id).is_some() {
+            return Err("grants of the key require approval".to_string());
+        }
+        self.purchased_rights(caller, key_id, &price, time)?;
+        Ok(price)
+    }
+}
+
+/// Returns the subaccount of the canister that holds the proceeds of `owner`'s keys:
+/// the length of the principal followed by its bytes, padded with zeros.
+#[must_use]
+pub fn proceeds_subaccount(owner: Principal) -> [u8; 32] {
+    let bytes = owner.as_slice();
+    let mut subaccount = [0u8; 32];
+    subaccount[0] = u8::try_from(bytes.len()).expect("principals have at most 29 bytes");
+    subaccount[1..=bytes.len()].copy_from_slice(bytes);
+    subaccount
+}
+
+/// Transfers `amount` minus the ledger fee from `caller`'s proceeds account
+/// on `ledger` to `to`. Returns a future that resolves to the index of the
+/// ledger block of the transfer.
+pub fn withdraw_proceeds(
+    caller: Principal,
+    ledger: Principal,
+    to: Account,
+    amount: Nat,
+) -> impl Future<Output = Result<Nat, String>> + Send + Sync {
+    let subaccount = serde_bytes::ByteBuf::from(proceeds_subaccount(caller).to_vec());
+    transfer_minus_fee(ledger, Some(subaccount), to, amount)
+}
+
+fn proceeds_account(canister_id: Principal, owner: Principal) -> Account {
+    Account {
+        owner: canister_id,
+        subaccount: Some(serde_bytes::ByteBuf::from(
+            proceeds_subaccount(owner).to_vec(),
+        )),
+    }
+}
+
+fn transfer_minus_fee(
+    ledger: Principal,
+    from_subaccount: Option<serde_bytes::ByteBuf>,
+    to: Account,
+    amount: Nat,
+) -> impl Future<Output = Result<Nat, String>> + Send + Sync {
+    async move {
+        let (fee,) = ic_cdk::api::call::call::<_, (Nat,)>(ledger, "icrc1_fee", ())
+            .await
+            .map_err(|(code, message)| format!("call to icrc1_fee failed: {code:?} {message}"))?;
+        if amount <= fee {
+            return Err("amount does not cover the ledger fee".to_string());
+        }
+        let args = TransferArg {
+            from_subaccount,
+            to,
+            amount: amount - fee.clone(),
+            fee: Some(fee),
+            memo: None,
+            created_at_time: None,
+        };
+        let (result,) = ic_cdk::api::call::call::<_, (Result<Nat, TransferError>,)>(
+            ledger,
+            "icrc1_transfer",
+            (args,),
+        )
+        .await
+        .map_err(|(code, message)| format!("call to icrc1_transfer failed: {code:?} {message}"))?;
+        result.map_err(|error| format!("icrc1_transfer failed: {error:?}"))
+    }
+}
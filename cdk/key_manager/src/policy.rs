@@ -0,0 +1,373 @@
+//! The authorization policy shared by all `KeyManager` and `EncryptedMaps` operations.
+//!
+//! Every operation is authorized by [`KeyManager::evaluate_access`], which
+//! decides whether a caller may perform an [`Operation`] on a key at a given
+//! time and, if not, why:
+//!
+//! - The key owner may perform every operation.
+//! - Otherwise, the caller's grant must be valid at that time, i.e., `start <= time`
+//!   and `time < end` for the bounds that are set, and contain the permissions
+//!   required by the operation.
+//! - A grant to the anonymous principal applies to everyone, but only for
+//!   operations that read the key or its values, i.e., `Inspect`, `ReadValues`
+//!   and `FetchVetKey`. Any other permissions of such a grant are ignored.
+//! - The holder of the token a key is bound to has the access rights configured
+//!   for the token, see [`crate::token_gating`].
+//! - Operations blocked by a freeze are denied after all other rules and
+//!   policies, see [`crate::freeze`].
+//!
+//! [`KeyManager::explain_access`] reports which of these rules determined a
+//! decision, see [`crate::explain`].
+//!
+//! Decisions that depend on state outside the `KeyManager`, e.g., subscriptions
+//! or memberships, are made by custom [`AccessPolicy`]s registered with
+//! [`KeyManager::with_access_policy`]. Each policy is consulted in registration
+//! order with the decision made so far and returns the new decision, so it can
+//! grant access in addition to `access_control`, veto it, or ignore it entirely.
+//! An [`AsyncAccessPolicy`], registered with [`KeyManager::with_async_access_policy`],
+//! can make inter-canister calls. Since all other operations are synchronous, async
+//! policies are only consulted by [`KeyManager::get_encrypted_vetkey`], before the
+//! key derivation is requested, and by [`KeyManager::evaluate_access_async`].
+//!
+//! Policies are not persisted and have to be registered again after an upgrade.
+
+use crate::explain::{AccessExplanation, AccessSource};
+use crate::{DefaultMemory, KeyId, KeyManager};
+use candid::{CandidType, Principal};
+use ic_stable_structures::Memory;
+use ic_vetkd_cdk_types::{now, AccessRights, Permissions};
+use serde::Deserialize;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+/// An operation on a key or on the map it protects.
+#[derive(
+    CandidType,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    strum_macros::EnumIter,
+    strum_macros::Display,
+)]
+pub enum Operation {
+    /// Inspect the key's sharing state, e.g., who it is shared with.
+    /// Requires a valid grant but no particular permission.
+    Inspect,
+    ReadValues,
+    FetchVetKey,
+    Insert,
+    Update,
+    Delete,
+    Restore,
+    Purge,
+    Share,
+    ViewAudit,
+    Manage,
+}
+
+impl Operation {
+    /// Returns the permissions a grant must contain for this operation.
+    #[must_use]
+    pub const fn required_permissions(self) -> Permissions {
+        match self {
+            Self::Inspect => Permissions::empty(),
+            Self::ReadValues => Permissions::READ_VALUES,
+            Self::FetchVetKey => Permissions::FETCH_VETKEY,
+            Self::Insert => Permissions::INSERT,
+            Self::Update => Permissions::UPDATE,
+            Self::Delete => Permissions::DELETE,
+            Self::Restore => Permissions::RESTORE,
+            Self::Purge => Permissions::PURGE,
+            Self::Share => Permissions::SHARE,
+            Self::ViewAudit => Permissions::VIEW_AUDIT,
+            Self::Manage => Permissions::MANAGE,
+        }
+    }
+
+    /// Returns true if a grant to the anonymous principal may authorize this operation.
+    #[must_use]
+    pub const fn allows_anonymous_grant(self) -> bool {
+        matches!(self, Self::Inspect | Self::ReadValues | Self::FetchVetKey)
+    }
+}
+
+/// Why an operation was denied.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DenyReason {
+    /// The caller has no grant for the key.
+    NoGrant,
+    /// The caller's grant is not valid yet.
+    NotYetValid,
+    /// The caller's grant has expired.
+    Expired,
+    /// The caller's grant lacks permissions required by the operation.
+    MissingPermissions(Permissions),
+    /// A custom access policy denied the operation.
+    Policy,
+    /// The key or the canister is frozen.
+    Frozen,
+}
+
+impl fmt::Display for DenyReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoGrant => write!(f, "no access rights for the key"),
+            Self::NotYetValid => write!(f, "access rights are not valid yet"),
+            Self::Expired => write!(f, "access rights have expired"),
+            Self::MissingPermissions(permissions) => {
+                write!(f, "missing permissions {:#06x}", permissions.bits())
+            }
+            Self::Policy => write!(f, "denied by access policy"),
+            Self::Frozen => write!(f, "access is frozen"),
+        }
+    }
+}
+
+/// The outcome of an authorization check.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// The operation is allowed based on the given access rights.
+    Allow(AccessRights),
+    Deny(DenyReason),
+}
+
+impl Decision {
+    #[must_use]
+    pub const fn is_allowed(&self) -> bool {
+        matches!(self, Self::Allow(_))
+    }
+
+    /// Converts the decision into the `Result` returned by the library's methods.
+    ///
+    /// # Errors
+    ///
+    /// Returns `"unauthorized"` if the operation was denied.
+    pub fn into_result(self) -> Result<AccessRights, String> {
+        match self {
+            Self::Allow(access_rights) => Ok(access_rights),
+            Self::Deny(_) => Err("unauthorized".to_string()),
+        }
+    }
+}
+
+/// Decides whether `grant` authorizes `operation` at `time`, ignoring ownership
+/// and anonymous grants.
+#[must_use]
+pub fn evaluate_grant(grant: Option<AccessRights>, operation: Operation, time: u64) -> Decision {
+    let Some(access_rights) = grant else {
+        return Decision::Deny(DenyReason::NoGrant);
+    };
+    if access_rights.start().is_some_and(|start| time < start) {
+        return Decision::Deny(DenyReason::NotYetValid);
+    }
+    if access_rights.end().is_some_and(|end| end <= time) {
+        return Decision::Deny(DenyReason::Expired);
+    }
+    let required = operation.required_permissions();
+    if !access_rights.has(required) {
+        let missing =
+            Permissions::from_bits_truncate(required.bits() & !access_rights.permissions().bits());
+        return Decision::Deny(DenyReason::MissingPermissions(missing));
+    }
+    Decision::Allow(access_rights)
+}
+
+/// The operation an access policy decides on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccessQuery {
+    pub caller: Principal,
+    pub key_id: KeyId,
+    pub operation: Operation,
+    pub time: u64,
+}
+
+/// A custom authorization rule, see [`crate::policy`].
+pub trait AccessPolicy<M: Memory = DefaultMemory> {
+    /// Returns the decision for `query`, given the `decision` made by the
+    /// built-in rules and the policies registered before this one.
+    fn evaluate(
+        &self,
+        key_manager: &KeyManager<M>,
+        query: &AccessQuery,
+        decision: Decision,
+    ) -> Decision;
+}
+
+/// The future returned by an [`AsyncAccessPolicy`].
+pub type PolicyFuture = Pin<Box<dyn Future<Output = Decision> + Send + Sync>>;
+
+/// A custom authorization rule that may make inter-canister calls, see [`crate::policy`].
+pub trait AsyncAccessPolicy: Send + Sync {
+    /// Returns the decision for `query`, given the `decision` made by the
+    /// synchronous rules and the async policies registered before this one.
+    fn evaluate(&self, query: AccessQuery, decision: Decision) -> PolicyFuture;
+}
+
+impl<M: Memory> KeyManager<M> {
+    /// Registers a custom access policy that is consulted for all operations.
+    #[must_use]
+    pub fn with_access_policy(mut self, policy: impl AccessPolicy<M> + 'static) -> Self {
+        self.access_policies.push(Box::new(policy));
+        self
+    }
+
+    /// Registers a custom async access policy that is consulted before
+    /// encrypted vetkeys are derived.
+    #[must_use]
+    pub fn with_async_access_policy(mut self, policy: impl AsyncAccessPolicy + 'static) -> Self {
+        self.async_access_policies.push(Arc::new(policy));
+        self
+    }
+
+    /// Decides whether `caller` may perform `operation` on `key_id` at `time`,
+    /// see [`crate::policy`].
+    #[must_use]
+    pub fn evaluate_access(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+        operation: Operation,
+        time: u64,
+    ) -> Decision {
+        self.explain_query(&AccessQuery {
+            caller,
+            key_id,
+            operation,
+            time,
+        })
+        .decision
+    }
+
+    /// Applies all synchronous rules and policies to `query` and records the
+    /// rule that determined the decision, see [`crate::explain`].
+    pub(crate) fn explain_query(&self, query: &AccessQuery) -> AccessExplanation {
+        let (mut decision, source) = self.evaluate_grants(query);
+        let (mut source, mut access_rights) = source.unzip();
+
+        let token_decision = self.evaluate_token_gate(query, decision);
+        if token_decision != decision {
+            decision = token_decision;
+            source = Some(AccessSource::TokenHolder);
+            access_rights = self
+                .get_token_gate(query.key_id)
+                .map(|gate| gate.access_rights);
+        }
+
+        for policy in &self.access_policies {
+            let policy_decision = policy.evaluate(self, query, decision);
+            if policy_decision != decision {
+                decision = policy_decision;
+                source = Some(AccessSource::Policy);
+                access_rights = match decision {
+                    Decision::Allow(access_rights) => Some(access_rights),
+                    Decision::Deny(_) => None,
+                };
+            }
+        }
+
+        let decision = self.evaluate_freeze(query, decision);
+        AccessExplanation::new(query, decision, source, access_rights)
+    }
+
+    /// Decides whether `caller` may perform `operation` on `key_id` now,
+    /// consulting the async access policies after all synchronous rules.
+    /// The returned future does not borrow the `KeyManager`.
+    pub fn evaluate_access_async(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+        operation: Operation,
+    ) -> impl Future<Output = Decision> + Send + Sync + 'static {
+        let time = now();
+        let decision = self.evaluate_access(caller, key_id, operation, time);
+        self.apply_async_access_policies(
+            AccessQuery {
+                caller,
+                key_id,
+                operation,
+                time,
+            },
+            decision,
+        )
+    }
+
+    /// Consults the async access policies, starting from `decision`. A
+    /// decision denying a frozen operation is final.
+    pub(crate) fn apply_async_access_policies(
+        &self,
+        query: AccessQuery,
+        decision: Decision,
+    ) -> impl Future<Output = Decision> + Send + Sync + 'static {
+        let policies = self.async_access_policies.clone();
+        async move {
+            let mut decision = decision;
+            for policy in policies {
+                if decision == Decision::Deny(DenyReason::Frozen) {
+                    break;
+                }
+                decision = policy.evaluate(query, decision).await;
+            }
+            decision
+        }
+    }
+
+    /// Applies ownership and the grants in `access_control` and returns the
+    /// grant the decision is based on, if any.
+    fn evaluate_grants(
+        &self,
+        query: &AccessQuery,
+    ) -> (Decision, Option<(AccessSource, AccessRights)>) {
+        let AccessQuery {
+            caller,
+            key_id,
+            operation,
+            time,
+        } = *query;
+        if caller == key_id.0 {
+            let access_rights = AccessRights::read_write_manage();
+            return (
+                Decision::Allow(access_rights),
+                Some((AccessSource::Owner, access_rights)),
+            );
+        }
+
+        let grant = self.access_control.get(&(caller, key_id));
+        let direct = grant.map(|access_rights| (AccessSource::DirectGrant, access_rights));
+        let decision = evaluate_grant(grant, operation, time);
+        if decision.is_allowed() || !operation.allows_anonymous_grant() {
+            return (decision, direct);
+        }
+
+        // Recognize 2vxsx-fae as an "everyone" user.
+        let public_grant = self.access_control.get(&(Principal::anonymous(), key_id));
+        let public = public_grant.map(|access_rights| (AccessSource::PublicGrant, access_rights));
+        match evaluate_grant(public_grant, operation, time) {
+            Decision::Allow(access_rights) => (Decision::Allow(access_rights), public),
+            Decision::Deny(_) if decision != Decision::Deny(DenyReason::NoGrant) => {
+                (decision, direct)
+            }
+            anonymous_decision => (anonymous_decision, public),
+        }
+    }
+
+    /// Ensures that `caller` may perform `operation` on `key_id` now and
+    /// returns the access rights that authorize it.
+    ///
+    /// # Errors
+    ///
+    /// Returns `"unauthorized"` if the operation is denied.
+    pub fn authorize(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+        operation: Operation,
+    ) -> Result<AccessRights, String> {
+        self.evaluate_access(caller, key_id, operation, now())
+            .into_result()
+    }
+}
@@ -0,0 +1,236 @@
+//! Access to keys that follows the ownership of an ICRC-7 token.
+//!
+//! A user with the `MANAGE` permission binds a key to a token with
+//! [`KeyManager::set_token_gate`]. The current holder of the token, i.e., the
+//! owner principal of the account returned by the ledger's `icrc7_owner_of`,
+//! is then treated as having the gate's access rights for all operations.
+//!
+//! Since authorization is synchronous, the holder is taken from a cache that
+//! is valid for [`TokenGateStore::cache_ttl`] nanoseconds. Canisters refresh it
+//! before serving a request with the future returned by
+//! [`KeyManager::fetch_token_owner`] and store the result with
+//! [`KeyManager::record_token_owner`]:
+//!
+//! ```ignore
+//! if let Some(fetch) = KEY_MANAGER.with_borrow(|km| km.fetch_token_owner(key_id, now())) {
+//!     let token_owner = fetch.await?;
+//!     KEY_MANAGER.with_borrow_mut(|km| km.record_token_owner(key_id, token_owner));
+//! }
+//! ```
+//!
+//! After a transfer, the previous holder keeps access until the cached
+//! ownership expires.
+
+use crate::icrc::Account;
+use crate::migration::{MigratedStructure, NamedMap};
+use crate::policy::{evaluate_grant, AccessQuery, Decision, DenyReason, Operation};
+use crate::{DefaultMemory, KeyId, KeyManager};
+use candid::{CandidType, Decode, Encode, Nat, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Memory, Storable};
+use ic_vetkd_cdk_types::{decode_versioned, encode_versioned, now, AccessRights, AuditEntry};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::future::Future;
+
+/// Binds a key to an ICRC-7 token.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TokenGate {
+    /// The ICRC-7 ledger canister.
+    pub ledger: Principal,
+    pub token_id: Nat,
+    /// The access rights of the token's current holder.
+    pub access_rights: AccessRights,
+}
+
+impl Storable for TokenGate {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let payload = Encode!(self).expect("failed to encode TokenGate");
+        Cow::Owned(encode_versioned(1, &payload))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        match decode_versioned(bytes.as_ref()) {
+            (0 | 1, payload) => Decode!(payload, Self).expect("failed to decode TokenGate"),
+            (version, _) => panic!("unsupported TokenGate encoding version {version}"),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// The holder of a token as reported by its ledger.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TokenOwner {
+    pub ledger: Principal,
+    pub token_id: Nat,
+    /// The owner principal of the holding account, or `None` if the token does not exist.
+    pub owner: Option<Principal>,
+    pub fetched_at: u64,
+}
+
+impl Storable for TokenOwner {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let payload = Encode!(self).expect("failed to encode TokenOwner");
+        Cow::Owned(encode_versioned(1, &payload))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        match decode_versioned(bytes.as_ref()) {
+            (0 | 1, payload) => Decode!(payload, Self).expect("failed to decode TokenOwner"),
+            (version, _) => panic!("unsupported TokenOwner encoding version {version}"),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Stable storage of token gates and the cached token holders.
+pub struct TokenGateStore<M: Memory = DefaultMemory> {
+    pub gates: NamedMap<KeyId, TokenGate, M>,
+    pub owners: NamedMap<KeyId, TokenOwner, M>,
+    /// Time in nanoseconds a fetched token holder is considered current.
+    pub cache_ttl: u64,
+}
+
+impl<M: Memory> KeyManager<M> {
+    /// Enables token-gated access, see [`crate::token_gating`].
+    #[must_use]
+    pub fn with_token_gating(mut self, memory_gates: M, memory_owners: M, cache_ttl: u64) -> Self {
+        self.token_gates = Some(TokenGateStore {
+            gates: self.init_named_map(MigratedStructure::TokenGates, memory_gates),
+            owners: self.init_named_map(MigratedStructure::TokenOwners, memory_owners),
+            cache_ttl,
+        });
+        self
+    }
+
+    /// Binds a key to a token, or removes the binding if `gate` is `None`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if token gating is not enabled or if the caller does
+    /// not have the `MANAGE` permission.
+    pub fn set_token_gate(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        gate: Option<TokenGate>,
+    ) -> Result<Option<TokenGate>, String> {
+        self.authorize(caller, key_id, Operation::Manage)?;
+        let store = self
+            .token_gates
+            .as_mut()
+            .ok_or_else(|| "token gating is not enabled".to_string())?;
+
+        let access_rights = gate.as_ref().map(|gate| gate.access_rights);
+        store.owners.remove(&key_id);
+        let previous = match gate {
+            Some(gate) => store.gates.insert(key_id, gate),
+            None => store.gates.remove(&key_id),
+        };
+        self.add_audit_log(key_id, move || {
+            AuditEntry::token_gate_changed(now(), caller, access_rights)
+        });
+        Ok(previous)
+    }
+
+    /// Retrieves the token a key is bound to, if any.
+    #[must_use]
+    pub fn get_token_gate(&self, key_id: KeyId) -> Option<TokenGate> {
+        self.token_gates
+            .as_ref()
+            .and_then(|store| store.gates.get(&key_id))
+    }
+
+    /// Returns a future that asks the ledger for the current holder of the
+    /// token `key_id` is bound to, or `None` if the key is not bound to a token
+    /// or the cached holder is still current at `time`.
+    ///
+    /// The future resolves to an error if the ledger call fails.
+    #[must_use]
+    pub fn fetch_token_owner(
+        &self,
+        key_id: KeyId,
+        time: u64,
+    ) -> Option<impl Future<Output = Result<TokenOwner, String>> + Send + Sync> {
+        use futures::future::FutureExt;
+
+        let store = self.token_gates.as_ref()?;
+        let gate = store.gates.get(&key_id)?;
+        if store
+            .owners
+            .get(&key_id)
+            .is_some_and(|owner| is_current(&owner, &gate, store.cache_ttl, time))
+        {
+            return None;
+        }
+
+        let ledger = gate.ledger;
+        let token_id = gate.token_id.clone();
+        let future = ic_cdk::api::call::call::<_, (Vec<Option<Account>>,)>(
+            ledger,
+            "icrc7_owner_of",
+            (vec![token_id.clone()],),
+        );
+        Some(future.map(move |call_result| {
+            let (accounts,) = call_result.map_err(|(code, message)| {
+                format!("call to icrc7_owner_of failed: {code:?} {message}")
+            })?;
+            Ok(TokenOwner {
+                ledger,
+                token_id,
+                owner: accounts
+                    .into_iter()
+                    .next()
+                    .flatten()
+                    .map(|account| account.owner),
+                fetched_at: now(),
+            })
+        }))
+    }
+
+    /// Caches a token holder fetched with [`Self::fetch_token_owner`]. The holder
+    /// is discarded if the key was bound to a different token in the meantime.
+    pub fn record_token_owner(&mut self, key_id: KeyId, token_owner: TokenOwner) {
+        let Some(store) = self.token_gates.as_mut() else {
+            return;
+        };
+        if store.gates.get(&key_id).is_some_and(|gate| {
+            gate.ledger == token_owner.ledger && gate.token_id == token_owner.token_id
+        }) {
+            store.owners.insert(key_id, token_owner);
+        }
+    }
+
+    /// Grants the gate's access rights to the token holder if `decision` denies
+    /// the operation, see [`crate::policy`].
+    pub(crate) fn evaluate_token_gate(&self, query: &AccessQuery, decision: Decision) -> Decision {
+        if decision.is_allowed() {
+            return decision;
+        }
+        let Some(store) = self.token_gates.as_ref() else {
+            return decision;
+        };
+        let Some(gate) = store.gates.get(&query.key_id) else {
+            return decision;
+        };
+        let is_holder = store.owners.get(&query.key_id).is_some_and(|owner| {
+            is_current(&owner, &gate, store.cache_ttl, query.time)
+                && owner.owner == Some(query.caller)
+        });
+        if !is_holder {
+            return decision;
+        }
+        match evaluate_grant(Some(gate.access_rights), query.operation, query.time) {
+            Decision::Deny(_) if decision != Decision::Deny(DenyReason::NoGrant) => decision,
+            token_decision => token_decision,
+        }
+    }
+}
+
+fn is_current(owner: &TokenOwner, gate: &TokenGate, cache_ttl: u64, time: u64) -> bool {
+    owner.ledger == gate.ledger
+        && owner.token_id == gate.token_id
+        && time < owner.fetched_at.saturating_add(cache_ttl)
+}
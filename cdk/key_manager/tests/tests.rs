@@ -1,17 +1,49 @@
-use std::collections::BTreeSet;
+use std::{borrow::Cow, cell::RefCell, collections::BTreeSet, rc::Rc};
 
 use assert_matches::assert_matches;
+use candid::Principal;
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager},
-    DefaultMemoryImpl,
+    DefaultMemoryImpl, StableBTreeMap, StableCell, Storable, VectorMemory,
+};
+use ic_vetkd_cdk_key_manager::{
+    access_requests::{AccessRequestLimits, AccessRequestStatus},
+    api,
+    approvals::{
+        ApprovalConfig, ApprovalConfigOutcome, GrantOutcome, ProposalStatus, ProposedChange,
+    },
+    authorization::AccessCheck,
+    events::EventSubscriber,
+    explain::{AccessSource, WindowStatus},
+    export::{ExportChunk, ExportCursor, ExportSection},
+    freeze::{Freeze, FreezeScope},
+    invites,
+    key_metadata::{KeyMetadataLimits, KeyMetadataUpdate},
+    layout::{MemoryLayout, StableStructure, RESERVED_MEMORY_IDS},
+    migration::{
+        convert_legacy_key_id, LegacyKeyId, MigratedStructure, MIGRATION_BATCH_SIZE, SCHEMA_VERSION,
+    },
+    payments::{proceeds_subaccount, AccessPrice, Payment, PurchaseStatus},
+    policy::{
+        evaluate_grant, AccessPolicy, AccessQuery, AsyncAccessPolicy, Decision, DenyReason,
+        Operation, PolicyFuture,
+    },
+    token_gating::{TokenGate, TokenOwner},
+    verification, KeyId, KeyManager,
 };
-use ic_vetkd_cdk_key_manager::KeyManager;
 use ic_vetkd_cdk_test_utils::{
-    random_access_rights, random_name, random_self_authenticating_principal,
-    random_unique_memory_ids, random_utf8_string, reproducible_rng,
+    random_access_rights, random_blob, random_bytebuf, random_name,
+    random_self_authenticating_principal, random_unique_memory_ids, random_utf8_string,
+    reproducible_rng,
+};
+use ic_vetkd_cdk_types::{
+    AccessRights, AuditEntry, AuditEntryType, AuditLog, ByteBuf, KeyName, Permissions, Rights,
+    MAX_NAME_BYTES, VERSIONED_ENCODING_TAG,
 };
-use ic_vetkd_cdk_types::AccessRights;
+use ic_vetkd_cdk_vetkd_mock as vetkd_mock;
+use ic_vetkd_utils::TransportSecretKey;
 use rand::{CryptoRng, Rng};
+use strum::IntoEnumIterator;
 
 #[test]
 fn can_init_memory() {
@@ -245,35 +277,3012 @@ fn add_or_remove_user_by_unauthorized_fails() {
 }
 
 #[test]
-fn can_instantiate_two_key_managers() {
+fn cannot_grant_or_revoke_permissions_beyond_own() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let sharer = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager(rng);
+
+    let sharer_permissions = Permissions::SHARE | Permissions::READ_VALUES;
+    key_manager
+        .set_user_rights(
+            owner,
+            key_id,
+            sharer,
+            AccessRights::with_permissions(sharer_permissions, None, None),
+        )
+        .unwrap();
+
+    let read_values = AccessRights::with_permissions(Permissions::READ_VALUES, None, None);
+    assert_eq!(
+        key_manager.set_user_rights(sharer, key_id, user, read_values),
+        Ok(None)
+    );
+    assert_eq!(
+        key_manager.get_user_rights(owner, key_id, user),
+        Ok(Some(read_values))
+    );
+
+    for access_rights in [AccessRights::read_only(), AccessRights::read_write()] {
+        assert_eq!(
+            key_manager.set_user_rights(sharer, key_id, user, access_rights),
+            Err("unauthorized".to_string())
+        );
+    }
+
+    key_manager
+        .set_user_rights(owner, key_id, user, AccessRights::read_write_manage())
+        .unwrap();
+    assert_eq!(
+        key_manager.remove_user(sharer, key_id, user),
+        Err("unauthorized".to_string())
+    );
+}
+
+#[test]
+fn access_rights_encoding_is_backwards_compatible() {
+    let rng = &mut reproducible_rng();
+    for access_rights in [
+        random_access_rights(rng),
+        AccessRights::new(Rights::ReadWrite, Some(1), Some(2)),
+        AccessRights::with_permissions(Permissions::INSERT | Permissions::PURGE, None, Some(3)),
+        AccessRights::with_permissions(Permissions::empty(), None, None),
+        AccessRights::new(Rights::Read, Some(0), Some(0)),
+    ] {
+        assert_eq!(
+            AccessRights::from_bytes(access_rights.to_bytes()),
+            access_rights
+        );
+    }
+
+    let mut legacy_bytes = vec![Rights::ReadWrite as u8];
+    legacy_bytes.extend_from_slice(&5u64.to_le_bytes());
+    legacy_bytes.extend_from_slice(&0u64.to_le_bytes());
+    let decoded = AccessRights::from_bytes(Cow::Owned(legacy_bytes));
+    assert_eq!(decoded, AccessRights::new(Rights::ReadWrite, Some(5), None));
+    assert_eq!(decoded.permissions(), Permissions::READ_WRITE_PRESET);
+}
+
+#[test]
+fn legacy_candid_encodings_can_be_decoded() {
+    let rng = &mut reproducible_rng();
+    let value = random_bytebuf(rng, 0..100);
+    let legacy_bytes = candid::encode_one(&value).unwrap();
+    assert_eq!(ByteBuf::from_bytes(Cow::Owned(legacy_bytes)), value);
+    assert_eq!(ByteBuf::from_bytes(value.to_bytes()), value);
+    assert_eq!(value.to_bytes()[0], VERSIONED_ENCODING_TAG);
+
+    let caller = random_self_authenticating_principal(rng);
+    let log = AuditLog(vec![AuditEntry::created(1, caller)]);
+    let legacy_bytes = candid::encode_one(&log.0).unwrap();
+    assert_eq!(AuditLog::from_bytes(Cow::Owned(legacy_bytes)), log);
+    assert_eq!(AuditLog::from_bytes(log.to_bytes()), log);
+}
+
+#[test]
+fn init_migrates_legacy_schema() {
+    let rng = &mut reproducible_rng();
     let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
-    let key_manager_1 = KeyManager::init(
-        "key_manager_1",
-        memory_manager.get(MemoryId::new(0)),
-        memory_manager.get(MemoryId::new(1)),
-        memory_manager.get(MemoryId::new(2)),
-        Some(memory_manager.get(MemoryId::new(3))),
+    let memory = |id| memory_manager.get(MemoryId::new(id));
+    let owner = random_self_authenticating_principal(rng);
+    let legacy_key_id: LegacyKeyId = (owner, random_blob(rng));
+    let key_id = convert_legacy_key_id(legacy_key_id);
+
+    StableCell::init(memory(0), "legacy domain separator".to_string()).unwrap();
+    let mut legacy_access_control: StableBTreeMap<(Principal, LegacyKeyId), Vec<u8>, _> =
+        StableBTreeMap::init(memory(1));
+    let users: Vec<_> = (0..5)
+        .map(|_| random_self_authenticating_principal(rng))
+        .collect();
+    for user in &users {
+        let mut legacy_bytes = vec![Rights::ReadWrite as u8];
+        legacy_bytes.extend_from_slice(&0u64.to_le_bytes());
+        legacy_bytes.extend_from_slice(&5000u64.to_le_bytes());
+        legacy_access_control.insert((*user, legacy_key_id), legacy_bytes);
+    }
+
+    let mut key_manager = KeyManager::init("new", memory(0), memory(10), memory(11), None)
+        .with_legacy_memory(MigratedStructure::AccessControl, memory(1));
+    assert_eq!(key_manager.domain_separator(), "legacy domain separator");
+    assert_eq!(key_manager.previous_schema_version(), Some(0));
+    assert_eq!(key_manager.metadata.get().schema_version, SCHEMA_VERSION);
+    assert!(!key_manager.is_migration_complete());
+
+    // Entries that are not migrated yet can be read.
+    let expected_rights = AccessRights::new(Rights::ReadWrite, None, Some(5000));
+    for user in &users {
+        assert_eq!(
+            key_manager.access_control.get(&(*user, key_id)),
+            Some(expected_rights)
+        );
+    }
+
+    // Migrations move the entries in bounded batches.
+    assert_eq!(key_manager.run_migration_batch(2), 2);
+    assert_eq!(key_manager.run_migration_batch(2), 2);
+    assert!(!key_manager.is_migration_complete());
+    assert_eq!(key_manager.run_migration_batch(2), 1);
+    assert!(key_manager.is_migration_complete());
+
+    let migrated_access_control: StableBTreeMap<(Principal, KeyId), Vec<u8>, _> =
+        StableBTreeMap::init(memory(10));
+    for user in &users {
+        assert_eq!(
+            key_manager.access_control.get(&(*user, key_id)),
+            Some(expected_rights)
+        );
+        assert_eq!(
+            migrated_access_control.get(&(*user, key_id)).unwrap()[0],
+            VERSIONED_ENCODING_TAG
+        );
+    }
+    let legacy_access_control: StableBTreeMap<(Principal, LegacyKeyId), Vec<u8>, _> =
+        StableBTreeMap::init(memory(1));
+    assert!(legacy_access_control.is_empty());
+
+    // A re-initialization does not migrate again.
+    let key_manager = KeyManager::init("new", memory(0), memory(10), memory(11), None);
+    assert_eq!(key_manager.previous_schema_version(), None);
+    assert!(key_manager.is_migration_complete());
+    assert_eq!(key_manager.domain_separator(), "legacy domain separator");
+    assert_eq!(
+        key_manager.access_control.get(&(users[0], key_id)),
+        Some(expected_rights)
     );
-    let key_manager_2 = KeyManager::init(
-        "key_manager_2",
-        memory_manager.get(MemoryId::new(4)),
-        memory_manager.get(MemoryId::new(5)),
-        memory_manager.get(MemoryId::new(6)),
-        Some(memory_manager.get(MemoryId::new(7))),
+}
+
+/// Stores the domain separator as schema version 0 did.
+fn init_legacy_metadata<M: ic_stable_structures::Memory>(memory: M) {
+    StableCell::init(memory, "legacy domain separator".to_string()).unwrap();
+}
+
+#[test]
+fn init_migrates_legacy_names() {
+    let rng = &mut reproducible_rng();
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    let memory = |id| memory_manager.get(MemoryId::new(id));
+    let owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let legacy_key_id: LegacyKeyId = (owner, random_blob(rng));
+    let key_id = convert_legacy_key_id(legacy_key_id);
+    let rights = AccessRights::new(Rights::Read, None, None);
+    let log = AuditLog(vec![AuditEntry::created(1, owner)]);
+
+    init_legacy_metadata(memory(0));
+    let mut legacy_access_control =
+        StableBTreeMap::<(Principal, LegacyKeyId), AccessRights, _>::init(memory(1));
+    legacy_access_control.insert((user, legacy_key_id), rights);
+    let other_users: Vec<_> = (0..3)
+        .map(|_| random_self_authenticating_principal(rng))
+        .collect();
+    for other_user in &other_users {
+        legacy_access_control.insert((*other_user, legacy_key_id), rights);
+    }
+    StableBTreeMap::<(LegacyKeyId, Principal), (), _>::init(memory(2))
+        .insert((legacy_key_id, user), ());
+    StableBTreeMap::<LegacyKeyId, AuditLog, _>::init(memory(3)).insert(legacy_key_id, log.clone());
+
+    let init = || {
+        KeyManager::init("new", memory(0), memory(11), memory(12), Some(memory(13)))
+            .with_legacy_memory(MigratedStructure::AccessControl, memory(1))
+            .with_legacy_memory(MigratedStructure::SharedKeys, memory(2))
+            .with_legacy_memory(MigratedStructure::AuditLogs, memory(3))
+    };
+    let mut key_manager = init();
+    assert_eq!(key_manager.previous_schema_version(), Some(0));
+    assert_eq!(key_manager.domain_separator(), "legacy domain separator");
+    assert!(key_manager.is_migration_pending(MigratedStructure::AuditLogs));
+    assert!(key_manager.is_migration_pending(MigratedStructure::AccessControl));
+    assert!(key_manager.is_migration_pending(MigratedStructure::SharedKeys));
+    assert_eq!(
+        key_manager.get_user_rights(owner, key_id, user),
+        Ok(Some(rights))
     );
-    // prevent the compiler from optimizing away the function call
-    std::hint::black_box((key_manager_1, key_manager_2));
+    assert_eq!(
+        key_manager.get_accessible_shared_key_ids(user),
+        vec![key_id]
+    );
+    assert_eq!(
+        key_manager.audit_logs.as_ref().unwrap().get(&key_id),
+        Some(log.clone())
+    );
+
+    // Names of the new maximum length can be used alongside legacy ones, and
+    // ranges cover both.
+    let long_name = KeyName::try_from([7u8; MAX_NAME_BYTES].as_slice()).unwrap();
+    assert!(KeyName::try_from([7u8; MAX_NAME_BYTES + 1].as_slice()).is_err());
+    let long_key_id = (owner, long_name);
+    assert_eq!(
+        key_manager.set_user_rights(owner, long_key_id, user, rights),
+        Ok(None)
+    );
+    let accessible: BTreeSet<KeyId> = key_manager
+        .get_accessible_shared_key_ids(user)
+        .into_iter()
+        .collect();
+    assert_eq!(accessible, BTreeSet::from([key_id, long_key_id]));
+    let mut expected_keys = vec![(user, key_id), (user, long_key_id)];
+    expected_keys.sort();
+    let keys: Vec<_> = key_manager
+        .access_control
+        .keys_range(expected_keys[0]..)
+        .take_while(|(caller, _)| *caller == user)
+        .collect();
+    assert_eq!(keys, expected_keys);
+
+    // Entries that are not migrated yet can be overwritten and removed.
+    let new_rights = AccessRights::new(Rights::ReadWrite, None, None);
+    assert_eq!(
+        key_manager.set_user_rights(owner, key_id, user, new_rights),
+        Ok(Some(rights))
+    );
+    assert_eq!(
+        key_manager.remove_user(owner, key_id, other_users[0]),
+        Ok(Some(rights))
+    );
+    assert_eq!(key_manager.access_control.len(), 4);
+
+    // The migration continues after an upgrade.
+    assert_eq!(key_manager.run_migration_batch(1), 1);
+    let mut key_manager = init();
+    assert_eq!(key_manager.previous_schema_version(), None);
+    while !key_manager.is_migration_complete() {
+        assert!(key_manager.run_migration_batch(1) > 0);
+    }
+    assert_eq!(
+        key_manager.get_user_rights(owner, key_id, user),
+        Ok(Some(new_rights))
+    );
+    assert_eq!(
+        key_manager.get_user_rights(owner, key_id, other_users[0]),
+        Ok(None)
+    );
+    assert_eq!(
+        key_manager.get_user_rights(owner, long_key_id, user),
+        Ok(Some(rights))
+    );
+    assert_eq!(key_manager.access_control.len(), 4);
+    assert_eq!(key_manager.access_control.current().len(), 4);
+
+    // Later upgrades do not need the legacy memories anymore.
+    let key_manager = KeyManager::init("new", memory(0), memory(11), memory(12), Some(memory(13)));
+    assert_eq!(
+        key_manager
+            .audit_logs
+            .as_ref()
+            .unwrap()
+            .get(&key_id)
+            .unwrap()
+            .0[0],
+        log.0[0]
+    );
+    assert_eq!(key_manager.get_accessible_shared_key_ids(user).len(), 2);
 }
 
-fn random_key_manager<R: Rng + CryptoRng>(rng: &mut R) -> KeyManager {
+#[test]
+#[should_panic(expected = "holds data without the current layout")]
+fn init_rejects_legacy_data_in_new_memory() {
+    let rng = &mut reproducible_rng();
     let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
-    let (_memory_id_encrypted_maps, memory_ids_key_manager) = random_unique_memory_ids(rng);
-    let domain_separator_len = rng.gen_range(0..32);
-    KeyManager::init(
-        &random_utf8_string(rng, domain_separator_len),
-        memory_manager.get(MemoryId::new(memory_ids_key_manager[0])),
-        memory_manager.get(MemoryId::new(memory_ids_key_manager[1])),
-        memory_manager.get(MemoryId::new(memory_ids_key_manager[2])),
-        Some(memory_manager.get(MemoryId::new(memory_ids_key_manager[3]))),
+    let memory = |id| memory_manager.get(MemoryId::new(id));
+    let legacy_key_id: LegacyKeyId = (random_self_authenticating_principal(rng), random_blob(rng));
+
+    init_legacy_metadata(memory(0));
+    StableBTreeMap::<(Principal, LegacyKeyId), AccessRights, _>::init(memory(1)).insert(
+        (random_self_authenticating_principal(rng), legacy_key_id),
+        AccessRights::new(Rights::Read, None, None),
+    );
+
+    let _ = KeyManager::init("new", memory(0), memory(1), memory(2), None);
+}
+
+#[test]
+fn disabled_feature_migrates_in_later_upgrade() {
+    let rng = &mut reproducible_rng();
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    let memory = |id| memory_manager.get(MemoryId::new(id));
+    let owner = random_self_authenticating_principal(rng);
+    let legacy_key_id: LegacyKeyId = (owner, random_blob(rng));
+    let key_id = convert_legacy_key_id(legacy_key_id);
+    let freeze = Freeze {
+        scope: FreezeScope::VetKeys,
+        frozen_by: owner,
+        frozen_at: 1,
+    };
+
+    init_legacy_metadata(memory(0));
+    StableBTreeMap::<LegacyKeyId, Freeze, _>::init(memory(4)).insert(legacy_key_id, freeze);
+
+    // The first upgrade does not enable freezing.
+    let mut key_manager = KeyManager::init("new", memory(0), memory(11), memory(12), None);
+    assert_eq!(key_manager.previous_schema_version(), Some(0));
+    assert_eq!(key_manager.run_migration_batch(MIGRATION_BATCH_SIZE), 0);
+    assert!(key_manager.is_migration_complete());
+
+    // A later upgrade enables it and attaches its legacy memory.
+    let mut key_manager = KeyManager::init("new", memory(0), memory(11), memory(12), None)
+        .with_freezing(memory(14), memory(15))
+        .with_legacy_memory(MigratedStructure::FrozenKeys, memory(4));
+    assert_eq!(key_manager.previous_schema_version(), None);
+    assert!(!key_manager.is_migration_complete());
+    assert_eq!(key_manager.get_key_freeze(key_id), Some(freeze));
+    assert_eq!(key_manager.run_migration_batch(MIGRATION_BATCH_SIZE), 1);
+    assert!(key_manager.is_migration_complete());
+    assert_eq!(key_manager.get_key_freeze(key_id), Some(freeze));
+}
+
+#[test]
+fn get_encrypted_vetkey_fails_for_malformed_transport_key() {
+    let rng = &mut reproducible_rng();
+    let caller = random_self_authenticating_principal(rng);
+    let name = random_name(rng);
+    let key_manager = random_key_manager(rng);
+
+    let mut identity = vec![0u8; 48];
+    identity[0] = 0xc0;
+
+    for transport_key in [vec![], vec![0u8; 48], identity, vec![0u8; 96]] {
+        assert_eq!(
+            key_manager
+                .get_encrypted_vetkey(caller, (caller, name), ByteBuf::from(transport_key))
+                .err(),
+            Some("invalid transport key".to_string())
+        );
+    }
+}
+
+#[test]
+fn validate_transport_key_accepts_valid_keys() {
+    let rng = &mut reproducible_rng();
+    let transport_secret_key = random_transport_key(rng);
+
+    assert_eq!(
+        verification::validate_transport_key(&transport_secret_key.public_key()),
+        Ok(())
+    );
+}
+
+#[test]
+fn verify_encrypted_vetkey_rejects_malformed_keys() {
+    let rng = &mut reproducible_rng();
+    let transport_key = random_transport_key(rng).public_key();
+    let derived_public_key = random_bytebuf(rng, 96..97);
+
+    for encrypted_key in [
+        random_bytebuf(rng, 0..192),
+        random_bytebuf(rng, 192..193),
+        ByteBuf::from(vec![0u8; 192]),
+    ] {
+        assert!(verification::verify_encrypted_vetkey(
+            encrypted_key.as_ref(),
+            &transport_key,
+            derived_public_key.as_ref(),
+            b"derivation id",
+        )
+        .is_err());
+    }
+}
+
+#[test]
+fn verify_encrypted_vetkey_accepts_keys_that_decrypt() {
+    let rng = &mut reproducible_rng();
+    let key_id = vetkd_mock::VetKDKeyId {
+        curve: vetkd_mock::VetKDCurve::Bls12_381,
+        name: "insecure_test_key_1".to_string(),
+    };
+    let canister_id = random_self_authenticating_principal(rng);
+    let derivation_path = [b"key_manager".to_vec()];
+    let derivation_id = random_bytebuf(rng, 0..64);
+    let transport_secret_key = random_transport_key(rng);
+    let transport_key = transport_secret_key.public_key();
+
+    let derived_public_key = vetkd_mock::derived_public_key(&key_id, canister_id, &derivation_path);
+    let encrypted_key = vetkd_mock::encrypted_key(
+        &key_id,
+        canister_id,
+        &derivation_path,
+        derivation_id.as_ref(),
+        &transport_key,
     )
+    .unwrap();
+
+    // ic_vetkd_utils accepts the key when decrypting it
+    assert!(transport_secret_key
+        .decrypt(&encrypted_key, &derived_public_key, derivation_id.as_ref())
+        .is_ok());
+    assert_eq!(
+        verification::verify_encrypted_vetkey(
+            &encrypted_key,
+            &transport_key,
+            &derived_public_key,
+            derivation_id.as_ref(),
+        ),
+        Ok(())
+    );
+
+    let other_transport_key = random_transport_key(rng).public_key();
+    let other_derived_public_key =
+        vetkd_mock::derived_public_key(&key_id, canister_id, &[b"other path".to_vec()]);
+    for (transport_key, derived_public_key, derivation_id) in [
+        (
+            &other_transport_key,
+            &derived_public_key,
+            derivation_id.as_ref(),
+        ),
+        (
+            &transport_key,
+            &other_derived_public_key,
+            derivation_id.as_ref(),
+        ),
+        (
+            &transport_key,
+            &derived_public_key,
+            b"other derivation id".as_slice(),
+        ),
+    ] {
+        assert!(verification::verify_encrypted_vetkey(
+            &encrypted_key,
+            transport_key,
+            derived_public_key,
+            derivation_id,
+        )
+        .is_err());
+    }
+}
+
+#[test]
+fn verify_encrypted_vetkey_agrees_with_ic_vetkd_utils() {
+    let rng = &mut reproducible_rng();
+    let key_id = vetkd_mock::VetKDKeyId {
+        curve: vetkd_mock::VetKDCurve::Bls12_381,
+        name: "insecure_test_key_1".to_string(),
+    };
+    let canister_id = random_self_authenticating_principal(rng);
+    let derivation_path = [b"key_manager".to_vec()];
+    let derived_public_key = vetkd_mock::derived_public_key(&key_id, canister_id, &derivation_path);
+
+    for _ in 0..8 {
+        let transport_secret_key = random_transport_key(rng);
+        let transport_key = transport_secret_key.public_key();
+        let encrypted_key = |derivation_id: &[u8]| {
+            vetkd_mock::encrypted_key(
+                &key_id,
+                canister_id,
+                &derivation_path,
+                derivation_id,
+                &transport_key,
+            )
+            .unwrap()
+        };
+        let derivation_id = random_bytebuf(rng, 0..64);
+        let valid = encrypted_key(derivation_id.as_ref());
+        let other = encrypted_key(b"other derivation id");
+
+        // Replaces the bytes of `range` in the valid key by those of another
+        // valid key, which keeps every component a valid point.
+        let tampered = |range: std::ops::Range<usize>| {
+            let mut tampered = valid.clone();
+            tampered[range.clone()].copy_from_slice(&other[range]);
+            tampered
+        };
+        for candidate in [
+            valid.clone(),
+            other.clone(),
+            tampered(0..48),
+            tampered(144..192),
+        ] {
+            assert_eq!(
+                verification::verify_encrypted_vetkey(
+                    &candidate,
+                    &transport_key,
+                    &derived_public_key,
+                    derivation_id.as_ref(),
+                )
+                .is_ok(),
+                transport_secret_key
+                    .decrypt(&candidate, &derived_public_key, derivation_id.as_ref())
+                    .is_ok()
+            );
+        }
+        assert!(verification::verify_encrypted_vetkey(
+            &tampered(48..144),
+            &transport_key,
+            &derived_public_key,
+            derivation_id.as_ref(),
+        )
+        .is_err());
+    }
+}
+
+#[test]
+fn grants_require_approval_threshold() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let approvers: Vec<_> = (0..3)
+        .map(|_| random_self_authenticating_principal(rng))
+        .collect();
+    let user = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager_with_approvals(rng);
+    ic_vetkd_cdk_types::set_mock_now(1000);
+
+    let config = ApprovalConfig {
+        approvers: approvers.clone(),
+        threshold: 2,
+        proposal_ttl: 100,
+    };
+    assert_eq!(
+        key_manager.set_approval_config(approvers[0], key_id, Some(config.clone())),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        key_manager.set_approval_config(owner, key_id, Some(config)),
+        Ok(ApprovalConfigOutcome::Applied(None))
+    );
+
+    let access_rights = AccessRights::read_only();
+    assert_eq!(
+        key_manager.set_user_rights(owner, key_id, user, access_rights),
+        Ok(None)
+    );
+    assert_eq!(key_manager.get_user_rights(owner, key_id, user), Ok(None));
+
+    let proposals = key_manager
+        .get_grant_proposals(approvers[0], key_id)
+        .unwrap();
+    assert_eq!(proposals.len(), 1);
+    let (proposal_id, proposal) = &proposals[0];
+    assert_eq!(proposal.status, ProposalStatus::Pending);
+    assert_eq!(
+        proposal.change,
+        ProposedChange::UserRights {
+            user,
+            access_rights
+        }
+    );
+
+    assert_eq!(
+        key_manager.approve_grant_proposal(user, key_id, *proposal_id),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        key_manager.approve_grant_proposal(approvers[0], key_id, *proposal_id),
+        Ok(ProposalStatus::Pending)
+    );
+    assert_eq!(
+        key_manager.approve_grant_proposal(approvers[0], key_id, *proposal_id),
+        Err("already approved".to_string())
+    );
+    assert_eq!(key_manager.get_user_rights(owner, key_id, user), Ok(None));
+
+    assert_eq!(
+        key_manager.approve_grant_proposal(approvers[1], key_id, *proposal_id),
+        Ok(ProposalStatus::Executed)
+    );
+    assert_eq!(
+        key_manager.get_user_rights(owner, key_id, user),
+        Ok(Some(access_rights))
+    );
+
+    // Revocation takes effect immediately.
+    assert_eq!(
+        key_manager.remove_user(owner, key_id, user),
+        Ok(Some(access_rights))
+    );
+}
+
+#[test]
+fn grant_proposals_can_be_rejected_or_expire() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let approver = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager_with_approvals(rng);
+    ic_vetkd_cdk_types::set_mock_now(1000);
+
+    let config = ApprovalConfig {
+        approvers: vec![approver],
+        threshold: 1,
+        proposal_ttl: 100,
+    };
+    key_manager
+        .set_approval_config(owner, key_id, Some(config))
+        .unwrap();
+
+    key_manager
+        .set_user_rights(owner, key_id, user, AccessRights::read_only())
+        .unwrap();
+    assert_eq!(
+        key_manager.reject_grant_proposal(approver, key_id, 0),
+        Ok(())
+    );
+    assert_eq!(
+        key_manager.approve_grant_proposal(approver, key_id, 0),
+        Err("proposal is not pending".to_string())
+    );
+
+    assert_eq!(
+        key_manager.grant_user_rights(owner, key_id, user, AccessRights::read_only()),
+        Ok(GrantOutcome::Proposed {
+            proposal_id: 1,
+            status: ProposalStatus::Pending
+        })
+    );
+    ic_vetkd_cdk_types::set_mock_now(1100);
+    // Expired proposals are reported as such before anyone touches them.
+    assert_eq!(
+        key_manager.get_grant_proposals(owner, key_id).unwrap()[1]
+            .1
+            .status,
+        ProposalStatus::Expired
+    );
+    assert_eq!(
+        key_manager.approve_grant_proposal(approver, key_id, 1),
+        Err("proposal expired".to_string())
+    );
+    assert_eq!(
+        key_manager.approve_grant_proposal(approver, key_id, 1),
+        Err("proposal expired".to_string())
+    );
+    let expirations = key_manager
+        .get_audit_log(key_id)
+        .unwrap()
+        .0
+        .iter()
+        .filter(|entry| entry.audit_type == AuditEntryType::ApprovalExpired)
+        .count();
+    assert_eq!(expirations, 1);
+
+    let statuses: Vec<_> = key_manager
+        .get_grant_proposals(approver, key_id)
+        .unwrap()
+        .into_iter()
+        .map(|(_, proposal)| proposal.status)
+        .collect();
+    assert_eq!(
+        statuses,
+        vec![ProposalStatus::Rejected, ProposalStatus::Expired]
+    );
+    assert_eq!(key_manager.get_user_rights(owner, key_id, user), Ok(None));
+
+    // An approver proposing a grant counts as the first approval.
+    assert_eq!(
+        key_manager.set_approval_config(owner, key_id, None),
+        Ok(ApprovalConfigOutcome::Proposed {
+            proposal_id: 2,
+            status: ProposalStatus::Pending
+        })
+    );
+    assert_eq!(
+        key_manager.approve_grant_proposal(approver, key_id, 2),
+        Ok(ProposalStatus::Executed)
+    );
+    key_manager
+        .set_user_rights(owner, key_id, approver, AccessRights::read_write_manage())
+        .unwrap();
+    let config = ApprovalConfig {
+        approvers: vec![approver],
+        threshold: 1,
+        proposal_ttl: 100,
+    };
+    key_manager
+        .set_approval_config(owner, key_id, Some(config))
+        .unwrap();
+    assert_eq!(
+        key_manager.set_user_rights(approver, key_id, user, AccessRights::read_only()),
+        Ok(Some(AccessRights::read_only()))
+    );
+}
+
+#[test]
+fn approval_config_changes_require_approval() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let approvers: Vec<_> = (0..2)
+        .map(|_| random_self_authenticating_principal(rng))
+        .collect();
+    let user = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager_with_approvals(rng);
+    ic_vetkd_cdk_types::set_mock_now(1000);
+
+    let config = ApprovalConfig {
+        approvers: approvers.clone(),
+        threshold: 2,
+        proposal_ttl: 100,
+    };
+    key_manager
+        .set_approval_config(owner, key_id, Some(config.clone()))
+        .unwrap();
+
+    // The owner cannot drop the approvers to grant access directly.
+    assert_eq!(
+        key_manager.set_approval_config(owner, key_id, None),
+        Ok(ApprovalConfigOutcome::Proposed {
+            proposal_id: 0,
+            status: ProposalStatus::Pending
+        })
+    );
+    assert_eq!(key_manager.get_approval_config(key_id), Some(config));
+    assert_matches!(
+        key_manager.grant_user_rights(owner, key_id, user, AccessRights::read_only()),
+        Ok(GrantOutcome::Proposed { .. })
+    );
+    assert_eq!(key_manager.get_user_rights(owner, key_id, user), Ok(None));
+
+    assert_eq!(
+        key_manager.approve_grant_proposal(approvers[0], key_id, 0),
+        Ok(ProposalStatus::Pending)
+    );
+    assert_eq!(
+        key_manager.approve_grant_proposal(approvers[1], key_id, 0),
+        Ok(ProposalStatus::Executed)
+    );
+    assert_eq!(key_manager.get_approval_config(key_id), None);
+    let changes: Vec<_> = key_manager
+        .get_audit_log(key_id)
+        .unwrap()
+        .0
+        .into_iter()
+        .filter(|entry| entry.audit_type == AuditEntryType::ApprovalConfigChanged)
+        .map(|entry| (entry.caller, entry.reference_id))
+        .collect();
+    assert_eq!(changes, vec![(owner, None), (approvers[1], Some(0))]);
+
+    // An owner that is an approver counts as the first approval.
+    let config = ApprovalConfig {
+        approvers: vec![owner, approvers[0]],
+        threshold: 2,
+        proposal_ttl: 100,
+    };
+    key_manager
+        .set_approval_config(owner, key_id, Some(config.clone()))
+        .unwrap();
+    let replacement = ApprovalConfig {
+        approvers: vec![owner],
+        threshold: 1,
+        proposal_ttl: 100,
+    };
+    assert_eq!(
+        key_manager.set_approval_config(owner, key_id, Some(replacement.clone())),
+        Ok(ApprovalConfigOutcome::Proposed {
+            proposal_id: 2,
+            status: ProposalStatus::Pending
+        })
+    );
+    assert_eq!(key_manager.get_approval_config(key_id), Some(config));
+    assert_eq!(
+        key_manager.approve_grant_proposal(approvers[0], key_id, 2),
+        Ok(ProposalStatus::Executed)
+    );
+    assert_eq!(key_manager.get_approval_config(key_id), Some(replacement));
+}
+
+#[test]
+fn grant_proposals_require_proposer_to_remain_authorized() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let manager = random_self_authenticating_principal(rng);
+    let approver = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager_with_approvals(rng);
+    ic_vetkd_cdk_types::set_mock_now(1000);
+
+    key_manager
+        .set_user_rights(owner, key_id, manager, AccessRights::read_write_manage())
+        .unwrap();
+    let config = ApprovalConfig {
+        approvers: vec![approver],
+        threshold: 1,
+        proposal_ttl: 100,
+    };
+    key_manager
+        .set_approval_config(owner, key_id, Some(config))
+        .unwrap();
+    assert_eq!(
+        key_manager.grant_user_rights(manager, key_id, user, AccessRights::read_write()),
+        Ok(GrantOutcome::Proposed {
+            proposal_id: 0,
+            status: ProposalStatus::Pending
+        })
+    );
+
+    // Revoking the proposer's access also stops their pending grants.
+    key_manager.remove_user(owner, key_id, manager).unwrap();
+    assert_eq!(
+        key_manager.approve_grant_proposal(approver, key_id, 0),
+        Err("proposer is no longer authorized".to_string())
+    );
+    assert_eq!(key_manager.get_user_rights(owner, key_id, user), Ok(None));
+    assert_eq!(
+        key_manager.get_grant_proposals(owner, key_id).unwrap()[0]
+            .1
+            .status,
+        ProposalStatus::Pending
+    );
+}
+
+#[test]
+fn access_requests_can_be_approved_or_denied() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let requester = random_self_authenticating_principal(rng);
+    let other = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager_with_access_requests(rng);
+    ic_vetkd_cdk_types::set_mock_now(1000);
+
+    assert_eq!(
+        key_manager.request_access(owner, key_id, AccessRights::read_only(), String::new()),
+        Err("cannot request access to own key".to_string())
+    );
+    assert_eq!(
+        key_manager.request_access(
+            requester,
+            key_id,
+            AccessRights::read_write(),
+            "please".to_string()
+        ),
+        Ok(())
+    );
+    assert_eq!(
+        key_manager.request_access(requester, key_id, AccessRights::read_only(), String::new()),
+        Err("access request already pending".to_string())
+    );
+
+    assert_eq!(
+        key_manager.get_pending_access_requests(other, key_id),
+        Err("unauthorized".to_string())
+    );
+    let pending = key_manager
+        .get_pending_access_requests(owner, key_id)
+        .unwrap();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].0, requester);
+    assert_eq!(pending[0].1.message, "please");
+
+    assert_eq!(
+        key_manager.approve_access_request(other, key_id, requester, None),
+        Err("unauthorized".to_string())
+    );
+    let adjusted_rights = AccessRights::new(Rights::Read, None, Some(5000));
+    assert_eq!(
+        key_manager.approve_access_request(owner, key_id, requester, Some(adjusted_rights)),
+        Ok(None)
+    );
+    assert_eq!(
+        key_manager.get_user_rights(owner, key_id, requester),
+        Ok(Some(adjusted_rights))
+    );
+    let request = key_manager
+        .get_access_request_status(requester, key_id)
+        .unwrap();
+    assert_eq!(request.status, AccessRequestStatus::Approved);
+    assert_eq!(request.granted_rights, Some(adjusted_rights));
+    assert_eq!(request.decided_by, Some(owner));
+
+    key_manager
+        .request_access(other, key_id, AccessRights::read_only(), String::new())
+        .unwrap();
+    assert_eq!(
+        key_manager.deny_access_request(owner, key_id, other),
+        Ok(())
+    );
+    assert_eq!(
+        key_manager
+            .get_access_request_status(other, key_id)
+            .map(|request| request.status),
+        Some(AccessRequestStatus::Denied)
+    );
+    assert_eq!(key_manager.get_user_rights(owner, key_id, other), Ok(None));
+    assert_eq!(
+        key_manager.deny_access_request(owner, key_id, other),
+        Err("no pending access request".to_string())
+    );
+}
+
+#[test]
+fn access_requests_are_rate_limited() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let requester = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager_with_access_requests(rng);
+    ic_vetkd_cdk_types::set_mock_now(1000);
+
+    assert_eq!(
+        key_manager.request_access(requester, key_id, AccessRights::read_only(), "x".repeat(17)),
+        Err("message too long".to_string())
+    );
+
+    key_manager
+        .request_access(requester, key_id, AccessRights::read_only(), String::new())
+        .unwrap();
+    key_manager
+        .deny_access_request(owner, key_id, requester)
+        .unwrap();
+    assert_eq!(
+        key_manager.request_access(requester, key_id, AccessRights::read_only(), String::new()),
+        Err("access request rate limited".to_string())
+    );
+    ic_vetkd_cdk_types::set_mock_now(1100);
+    assert_eq!(
+        key_manager.request_access(requester, key_id, AccessRights::read_only(), String::new()),
+        Ok(())
+    );
+
+    let other = random_self_authenticating_principal(rng);
+    key_manager
+        .request_access(other, key_id, AccessRights::read_only(), String::new())
+        .unwrap();
+    let third = random_self_authenticating_principal(rng);
+    assert_eq!(
+        key_manager.request_access(third, key_id, AccessRights::read_only(), String::new()),
+        Err("too many pending access requests".to_string())
+    );
+}
+
+thread_local! {
+    static INVITES_KEY_MANAGER: RefCell<KeyManager> =
+        RefCell::new(random_key_manager_with_invites(&mut reproducible_rng()));
+}
+
+fn create_invite(
+    caller: Principal,
+    key_id: KeyId,
+    access_rights: AccessRights,
+    valid_from: Option<u64>,
+    valid_until: Option<u64>,
+    max_uses: u32,
+) -> Result<String, String> {
+    futures::executor::block_on(invites::create_invite(
+        &INVITES_KEY_MANAGER,
+        caller,
+        key_id,
+        access_rights,
+        valid_from,
+        valid_until,
+        max_uses,
+    ))
+}
+
+#[test]
+fn invites_can_be_redeemed() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let sharer = random_self_authenticating_principal(rng);
+    let invitees: Vec<_> = (0..3)
+        .map(|_| random_self_authenticating_principal(rng))
+        .collect();
+    let key_id = (owner, random_name(rng));
+    ic_vetkd_cdk_types::set_mock_now(1000);
+
+    assert_eq!(
+        create_invite(sharer, key_id, AccessRights::read_only(), None, None, 1),
+        Err("unauthorized".to_string())
+    );
+
+    let code = create_invite(
+        owner,
+        key_id,
+        AccessRights::read_write(),
+        None,
+        Some(2000),
+        2,
+    )
+    .unwrap();
+    // Each code has its own secret.
+    let other_code =
+        create_invite(owner, key_id, AccessRights::read_write(), None, None, 1).unwrap();
+    assert_ne!(code[16..], other_code[16..]);
+
+    let mut wrong_code = code.clone();
+    wrong_code.replace_range(code.len() - 2.., "00");
+    if wrong_code == code {
+        wrong_code.replace_range(code.len() - 2.., "01");
+    }
+    INVITES_KEY_MANAGER.with_borrow_mut(|key_manager| {
+        for invalid_code in [wrong_code.as_str(), "", "not hex"] {
+            assert_eq!(
+                key_manager.redeem_invite(invitees[0], invalid_code),
+                Err("invalid invite code".to_string())
+            );
+        }
+
+        for invitee in &invitees[..2] {
+            assert_eq!(key_manager.redeem_invite(*invitee, &code), Ok(key_id));
+            assert_eq!(
+                key_manager.get_user_rights(owner, key_id, *invitee),
+                Ok(Some(AccessRights::read_write()))
+            );
+        }
+        assert_eq!(
+            key_manager.redeem_invite(invitees[2], &code),
+            Err("invite is no longer valid".to_string())
+        );
+
+        let invites = key_manager.get_invites(owner, key_id).unwrap();
+        assert_eq!(invites.len(), 2);
+        assert_eq!(invites[0].1.uses, 2);
+    });
+}
+
+#[test]
+fn invites_can_expire_or_be_revoked() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let invitee = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    ic_vetkd_cdk_types::set_mock_now(1000);
+
+    let expiring_code = create_invite(
+        owner,
+        key_id,
+        AccessRights::read_only(),
+        Some(1000),
+        Some(1100),
+        1,
+    )
+    .unwrap();
+    let revoked_code =
+        create_invite(owner, key_id, AccessRights::read_only(), None, None, 1).unwrap();
+
+    INVITES_KEY_MANAGER.with_borrow_mut(|key_manager| {
+        assert_eq!(
+            key_manager.revoke_invite(invitee, 1),
+            Err("unauthorized".to_string())
+        );
+        assert_eq!(key_manager.revoke_invite(owner, 1), Ok(()));
+        assert_eq!(
+            key_manager.redeem_invite(invitee, &revoked_code),
+            Err("invite is no longer valid".to_string())
+        );
+
+        ic_vetkd_cdk_types::set_mock_now(1101);
+        assert_eq!(
+            key_manager.redeem_invite(invitee, &expiring_code),
+            Err("invite is no longer valid".to_string())
+        );
+        assert_eq!(
+            key_manager.get_user_rights(owner, key_id, invitee),
+            Ok(None)
+        );
+    });
+}
+
+#[test]
+fn invite_windows_end_like_access_rights() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let invitees: Vec<_> = (0..2)
+        .map(|_| random_self_authenticating_principal(rng))
+        .collect();
+    let key_id = (owner, random_name(rng));
+    ic_vetkd_cdk_types::set_mock_now(1000);
+
+    assert_eq!(
+        create_invite(
+            owner,
+            key_id,
+            AccessRights::read_only(),
+            Some(1100),
+            Some(1100),
+            1
+        ),
+        Err("invalid validity window".to_string())
+    );
+    let code = create_invite(
+        owner,
+        key_id,
+        AccessRights::read_only(),
+        Some(1000),
+        Some(1100),
+        2,
+    )
+    .unwrap();
+    let window = AccessRights::with_permissions(Permissions::READ_VALUES, Some(1000), Some(1100));
+
+    INVITES_KEY_MANAGER.with_borrow_mut(|key_manager| {
+        key_manager
+            .set_user_rights(owner, key_id, invitees[1], window)
+            .unwrap();
+
+        ic_vetkd_cdk_types::set_mock_now(1099);
+        assert_eq!(key_manager.redeem_invite(invitees[0], &code), Ok(key_id));
+        assert_matches!(
+            key_manager.evaluate_access(invitees[1], key_id, Operation::ReadValues, 1099),
+            Decision::Allow(_)
+        );
+
+        // Both windows exclude their end
+        ic_vetkd_cdk_types::set_mock_now(1100);
+        assert_eq!(
+            key_manager.redeem_invite(random_self_authenticating_principal(rng), &code),
+            Err("invite is no longer valid".to_string())
+        );
+        assert_eq!(
+            key_manager.evaluate_access(invitees[1], key_id, Operation::ReadValues, 1100),
+            Decision::Deny(DenyReason::Expired)
+        );
+    });
+}
+
+#[test]
+fn invites_are_listed_per_key() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let key_ids = [(owner, random_name(rng)), (owner, random_name(rng))];
+    ic_vetkd_cdk_types::set_mock_now(1000);
+
+    for key_id in [key_ids[0], key_ids[1], key_ids[0]] {
+        create_invite(owner, key_id, AccessRights::read_only(), None, None, 1).unwrap();
+    }
+
+    INVITES_KEY_MANAGER.with_borrow_mut(|key_manager| {
+        let invite_ids = |key_manager: &KeyManager, key_id| -> Vec<_> {
+            key_manager
+                .get_invites(owner, key_id)
+                .unwrap()
+                .into_iter()
+                .map(|(invite_id, invite)| {
+                    assert_eq!(invite.key_id(), key_id);
+                    invite_id
+                })
+                .collect()
+        };
+        assert_eq!(invite_ids(key_manager, key_ids[0]).len(), 2);
+        assert_eq!(invite_ids(key_manager, key_ids[1]).len(), 1);
+
+        key_manager.delete_key(owner, key_ids[0]).unwrap();
+        assert!(key_manager
+            .get_invites(owner, key_ids[1])
+            .unwrap()
+            .iter()
+            .all(|(_, invite)| !invite.revoked));
+    });
+}
+
+#[test]
+fn invites_never_replace_rights_or_wait_for_approval() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let manager = random_self_authenticating_principal(rng);
+    let approver = random_self_authenticating_principal(rng);
+    let invitee = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    ic_vetkd_cdk_types::set_mock_now(1000);
+
+    INVITES_KEY_MANAGER.with_borrow_mut(|key_manager| {
+        key_manager
+            .set_user_rights(owner, key_id, manager, AccessRights::read_write_manage())
+            .unwrap();
+    });
+    let code = create_invite(owner, key_id, AccessRights::read_only(), None, None, 1).unwrap();
+
+    INVITES_KEY_MANAGER.with_borrow_mut(|key_manager| {
+        for caller in [owner, manager] {
+            assert_eq!(
+                key_manager.redeem_invite(caller, &code),
+                Err("already has access to the key".to_string())
+            );
+        }
+        assert_eq!(
+            key_manager.get_user_rights(owner, key_id, manager),
+            Ok(Some(AccessRights::read_write_manage()))
+        );
+
+        let config = ApprovalConfig {
+            approvers: vec![approver],
+            threshold: 1,
+            proposal_ttl: 100,
+        };
+        key_manager
+            .set_approval_config(owner, key_id, Some(config))
+            .unwrap();
+        assert_eq!(
+            key_manager.redeem_invite(invitee, &code),
+            Err("grants of the key require approval".to_string())
+        );
+        assert_eq!(key_manager.get_grant_proposals(owner, key_id), Ok(vec![]));
+
+        // None of the failed redemptions used up the invite.
+        key_manager
+            .set_approval_config(owner, key_id, None)
+            .unwrap();
+        key_manager
+            .approve_grant_proposal(approver, key_id, 0)
+            .unwrap();
+        assert_eq!(key_manager.redeem_invite(invitee, &code), Ok(key_id));
+    });
+}
+
+/// The time at which all policy decisions below are evaluated.
+const POLICY_TIME: u64 = 1_000;
+
+/// `(start, end, expected reason for denial)` relative to `POLICY_TIME`.
+const POLICY_WINDOWS: [(Option<u64>, Option<u64>, Option<DenyReason>); 9] = [
+    (None, None, None),
+    (Some(POLICY_TIME - 1), None, None),
+    (Some(POLICY_TIME), None, None),
+    (Some(POLICY_TIME + 1), None, Some(DenyReason::NotYetValid)),
+    (None, Some(POLICY_TIME + 1), None),
+    (None, Some(POLICY_TIME), Some(DenyReason::Expired)),
+    (None, Some(POLICY_TIME - 1), Some(DenyReason::Expired)),
+    (Some(POLICY_TIME - 1), Some(POLICY_TIME + 1), None),
+    (
+        Some(POLICY_TIME + 1),
+        Some(POLICY_TIME + 2),
+        Some(DenyReason::NotYetValid),
+    ),
+];
+
+/// The operations each preset allows.
+fn operations_allowed_by(rights: Rights) -> Vec<Operation> {
+    use Operation::*;
+    match rights {
+        Rights::Read => vec![Inspect, ReadValues, FetchVetKey, ViewAudit],
+        Rights::ReadWrite => vec![
+            Inspect,
+            ReadValues,
+            FetchVetKey,
+            ViewAudit,
+            Insert,
+            Update,
+            Delete,
+            Restore,
+        ],
+        Rights::ReadWriteManage => Operation::iter().collect(),
+    }
+}
+
+fn expected_decision(
+    access_rights: AccessRights,
+    operation: Operation,
+    window_denial: Option<DenyReason>,
+) -> Decision {
+    if let Some(reason) = window_denial {
+        return Decision::Deny(reason);
+    }
+    if operations_allowed_by(access_rights.rights()).contains(&operation) {
+        Decision::Allow(access_rights)
+    } else {
+        Decision::Deny(DenyReason::MissingPermissions(
+            operation.required_permissions(),
+        ))
+    }
+}
+
+#[test]
+fn policy_decisions_match_table() {
+    for rights in Rights::iter() {
+        for (start, end, window_denial) in POLICY_WINDOWS {
+            let access_rights = AccessRights::new(rights, start, end);
+            for operation in Operation::iter() {
+                assert_eq!(
+                    evaluate_grant(Some(access_rights), operation, POLICY_TIME),
+                    expected_decision(access_rights, operation, window_denial),
+                    "{rights:?} {start:?}..{end:?} {operation}"
+                );
+            }
+        }
+    }
+
+    for operation in Operation::iter() {
+        assert_eq!(
+            evaluate_grant(None, operation, POLICY_TIME),
+            Decision::Deny(DenyReason::NoGrant)
+        );
+    }
+}
+
+#[test]
+fn key_manager_applies_policy_to_grants() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let stranger = random_self_authenticating_principal(rng);
+    let mut key_manager = random_key_manager(rng);
+
+    for rights in Rights::iter() {
+        for (start, end, window_denial) in POLICY_WINDOWS {
+            let key_id = (owner, random_name(rng));
+            let user = random_self_authenticating_principal(rng);
+            let access_rights = AccessRights::new(rights, start, end);
+            key_manager
+                .access_control
+                .insert((user, key_id), access_rights);
+
+            for operation in Operation::iter() {
+                assert_eq!(
+                    key_manager.evaluate_access(user, key_id, operation, POLICY_TIME),
+                    expected_decision(access_rights, operation, window_denial),
+                    "{rights:?} {start:?}..{end:?} {operation}"
+                );
+                assert_eq!(
+                    key_manager.evaluate_access(owner, key_id, operation, POLICY_TIME),
+                    Decision::Allow(AccessRights::read_write_manage())
+                );
+                assert_eq!(
+                    key_manager.evaluate_access(stranger, key_id, operation, POLICY_TIME),
+                    Decision::Deny(DenyReason::NoGrant)
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn anonymous_grants_apply_to_everyone_for_reading_only() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager(rng);
+
+    let everyone = AccessRights::read_write_manage();
+    key_manager
+        .access_control
+        .insert((Principal::anonymous(), key_id), everyone);
+    let expired = AccessRights::new(Rights::Read, None, Some(POLICY_TIME));
+    key_manager.access_control.insert((user, key_id), expired);
+
+    for operation in Operation::iter() {
+        let decision = key_manager.evaluate_access(user, key_id, operation, POLICY_TIME);
+        if operation.allows_anonymous_grant() {
+            assert_eq!(decision, Decision::Allow(everyone), "{operation}");
+        } else {
+            assert_eq!(decision, Decision::Deny(DenyReason::Expired), "{operation}");
+        }
+    }
+
+    let stranger = random_self_authenticating_principal(rng);
+    for operation in [Operation::ReadValues, Operation::FetchVetKey] {
+        assert_eq!(
+            key_manager.evaluate_access(stranger, key_id, operation, POLICY_TIME),
+            Decision::Allow(everyone)
+        );
+    }
+    for operation in [
+        Operation::Insert,
+        Operation::Purge,
+        Operation::ViewAudit,
+        Operation::Share,
+        Operation::Manage,
+    ] {
+        assert_eq!(
+            key_manager.evaluate_access(stranger, key_id, operation, POLICY_TIME),
+            Decision::Deny(DenyReason::NoGrant),
+            "{operation}"
+        );
+    }
+}
+
+/// Grants read access to subscribers and vetoes sharing.
+struct SubscriptionPolicy {
+    subscribers: BTreeSet<Principal>,
+}
+
+impl AccessPolicy for SubscriptionPolicy {
+    fn evaluate(
+        &self,
+        _key_manager: &KeyManager,
+        query: &AccessQuery,
+        decision: Decision,
+    ) -> Decision {
+        if query.operation == Operation::Share {
+            return Decision::Deny(DenyReason::Policy);
+        }
+        if decision.is_allowed() || !self.subscribers.contains(&query.caller) {
+            return decision;
+        }
+        let subscription = AccessRights::new(Rights::Read, None, None);
+        evaluate_grant(Some(subscription), query.operation, query.time)
+    }
+}
+
+/// Denies access to the listed callers.
+struct AsyncDenyList(BTreeSet<Principal>);
+
+impl AsyncAccessPolicy for AsyncDenyList {
+    fn evaluate(&self, query: AccessQuery, decision: Decision) -> PolicyFuture {
+        let denied = self.0.contains(&query.caller);
+        Box::pin(async move {
+            if denied {
+                Decision::Deny(DenyReason::Policy)
+            } else {
+                decision
+            }
+        })
+    }
+}
+
+#[test]
+fn access_policies_can_grant_and_veto() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let subscriber = random_self_authenticating_principal(rng);
+    let stranger = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager(rng).with_access_policy(SubscriptionPolicy {
+        subscribers: BTreeSet::from([subscriber]),
+    });
+
+    let read = AccessRights::new(Rights::Read, None, None);
+    assert_eq!(
+        key_manager.evaluate_access(subscriber, key_id, Operation::ReadValues, POLICY_TIME),
+        Decision::Allow(read)
+    );
+    assert_matches!(
+        key_manager.evaluate_access(subscriber, key_id, Operation::Insert, POLICY_TIME),
+        Decision::Deny(DenyReason::MissingPermissions(_))
+    );
+    assert_eq!(
+        key_manager.evaluate_access(stranger, key_id, Operation::ReadValues, POLICY_TIME),
+        Decision::Deny(DenyReason::NoGrant)
+    );
+    assert_eq!(
+        key_manager.evaluate_access(owner, key_id, Operation::Insert, POLICY_TIME),
+        Decision::Allow(AccessRights::read_write_manage())
+    );
+    assert_eq!(
+        key_manager.evaluate_access(owner, key_id, Operation::Share, POLICY_TIME),
+        Decision::Deny(DenyReason::Policy)
+    );
+    assert_eq!(
+        key_manager.set_user_rights(owner, key_id, stranger, read),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        key_manager.get_user_rights(subscriber, key_id, subscriber),
+        Ok(Some(read))
+    );
+}
+
+#[test]
+fn async_access_policies_are_consulted_before_deriving_keys() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let denied = random_self_authenticating_principal(rng);
+    let key_manager =
+        random_key_manager(rng).with_async_access_policy(AsyncDenyList(BTreeSet::from([denied])));
+
+    let key_id = (owner, random_name(rng));
+    assert_eq!(
+        futures::executor::block_on(key_manager.evaluate_access_async(
+            owner,
+            key_id,
+            Operation::FetchVetKey
+        )),
+        Decision::Allow(AccessRights::read_write_manage())
+    );
+
+    let key_id = (denied, random_name(rng));
+    assert_eq!(
+        futures::executor::block_on(key_manager.evaluate_access_async(
+            denied,
+            key_id,
+            Operation::FetchVetKey
+        )),
+        Decision::Deny(DenyReason::Policy)
+    );
+    let transport_key = random_transport_key(rng).public_key();
+    let encrypted_vetkey = key_manager
+        .get_encrypted_vetkey(denied, key_id, ByteBuf::from(transport_key))
+        .expect("synchronous checks should pass");
+    assert_eq!(
+        futures::executor::block_on(encrypted_vetkey),
+        Err("unauthorized".to_string())
+    );
+    // Denied derivations are not audited as accesses
+    assert_eq!(key_manager.get_audit_log(key_id), None);
+}
+
+const TOKEN_OWNER_CACHE_TTL: u64 = 1_000;
+
+#[test]
+fn token_holders_have_the_gate_access_rights() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let holder = random_self_authenticating_principal(rng);
+    let ledger = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager_with_token_gating(rng);
+
+    let gate = TokenGate {
+        ledger,
+        token_id: 1u32.into(),
+        access_rights: AccessRights::read_only(),
+    };
+    assert_eq!(
+        key_manager.set_token_gate(holder, key_id, Some(gate.clone())),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        key_manager.set_token_gate(owner, key_id, Some(gate.clone())),
+        Ok(None)
+    );
+    assert_eq!(key_manager.get_token_gate(key_id), Some(gate.clone()));
+
+    let token_owner = |token_id: u32, fetched_at: u64| TokenOwner {
+        ledger,
+        token_id: token_id.into(),
+        owner: Some(holder),
+        fetched_at,
+    };
+    // Holders of other tokens are ignored
+    key_manager.record_token_owner(key_id, token_owner(2, POLICY_TIME));
+    assert_eq!(
+        key_manager.evaluate_access(holder, key_id, Operation::ReadValues, POLICY_TIME),
+        Decision::Deny(DenyReason::NoGrant)
+    );
+
+    key_manager.record_token_owner(key_id, token_owner(1, POLICY_TIME));
+    assert!(key_manager.fetch_token_owner(key_id, POLICY_TIME).is_none());
+    for operation in Operation::iter() {
+        assert_eq!(
+            key_manager.evaluate_access(holder, key_id, operation, POLICY_TIME),
+            evaluate_grant(Some(gate.access_rights), operation, POLICY_TIME),
+            "{operation}"
+        );
+    }
+    assert_eq!(
+        key_manager.evaluate_access(
+            holder,
+            key_id,
+            Operation::ReadValues,
+            POLICY_TIME + TOKEN_OWNER_CACHE_TTL
+        ),
+        Decision::Deny(DenyReason::NoGrant)
+    );
+
+    assert_eq!(
+        key_manager.set_token_gate(owner, key_id, None),
+        Ok(Some(gate))
+    );
+    assert_eq!(
+        key_manager.evaluate_access(holder, key_id, Operation::ReadValues, POLICY_TIME),
+        Decision::Deny(DenyReason::NoGrant)
+    );
+    assert!(key_manager.fetch_token_owner(key_id, POLICY_TIME).is_none());
+}
+
+#[test]
+fn purchases_grant_time_limited_access() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let buyer = random_self_authenticating_principal(rng);
+    let ledger = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager_with_payments(rng);
+
+    let price = AccessPrice {
+        ledger,
+        price: 100u32.into(),
+        duration: 1_000,
+        permissions: Permissions::READ_VALUES,
+    };
+    assert_eq!(
+        key_manager.set_access_price(buyer, key_id, Some(price.clone())),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        key_manager.set_access_price(
+            owner,
+            key_id,
+            Some(AccessPrice {
+                duration: 0,
+                ..price.clone()
+            })
+        ),
+        Err("invalid access duration".to_string())
+    );
+    assert_eq!(
+        key_manager.start_purchase(buyer, key_id, 0).err(),
+        Some("access is not for sale".to_string())
+    );
+    assert_eq!(
+        key_manager.set_access_price(owner, key_id, Some(price.clone())),
+        Ok(None)
+    );
+    assert_eq!(key_manager.get_access_price(key_id), Some(price.clone()));
+    assert_eq!(
+        key_manager.start_purchase(owner, key_id, 0).err(),
+        Some("cannot purchase access to own key".to_string())
+    );
+
+    let payment = |amount: u32| Payment {
+        ledger,
+        amount: amount.into(),
+        to: Principal::management_canister().into(),
+        block_index: 0u32.into(),
+    };
+    let complete_purchase = |key_manager: &mut KeyManager, amount: u32| {
+        let purchase_id = key_manager
+            .record_payment(buyer, key_id, payment(amount))
+            .unwrap();
+        key_manager.complete_purchase(buyer, purchase_id)
+    };
+    ic_vetkd_cdk_types::set_mock_now(1000);
+    let underpaid = key_manager
+        .record_payment(buyer, key_id, payment(99))
+        .unwrap();
+    assert_eq!(
+        key_manager.complete_purchase(buyer, underpaid),
+        Err("access price changed".to_string())
+    );
+    assert_eq!(
+        key_manager.complete_purchase(owner, underpaid),
+        Err("purchase not found".to_string())
+    );
+    let purchased = AccessRights::with_permissions(Permissions::READ_VALUES, None, Some(2000));
+    assert_eq!(complete_purchase(&mut key_manager, 100), Ok(purchased));
+    assert_eq!(
+        key_manager.get_accessible_shared_key_ids(buyer),
+        vec![key_id]
+    );
+    // The purchase is recorded like a share by the owner
+    let audit_types: Vec<_> = key_manager
+        .get_audit_log(key_id)
+        .unwrap()
+        .0
+        .iter()
+        .map(|entry| (entry.audit_type, entry.caller))
+        .collect();
+    assert_eq!(
+        audit_types,
+        vec![
+            (AuditEntryType::AccessPurchased, buyer),
+            (AuditEntryType::Share, owner)
+        ]
+    );
+
+    // A purchase extends a previous purchase that is still valid
+    ic_vetkd_cdk_types::set_mock_now(1500);
+    let extended = AccessRights::with_permissions(Permissions::READ_VALUES, None, Some(3000));
+    assert_eq!(complete_purchase(&mut key_manager, 100), Ok(extended));
+    assert_eq!(
+        key_manager.evaluate_access(buyer, key_id, Operation::ReadValues, 2999),
+        Decision::Allow(extended)
+    );
+    assert_eq!(
+        key_manager.evaluate_access(buyer, key_id, Operation::ReadValues, 3000),
+        Decision::Deny(DenyReason::Expired)
+    );
+
+    // Other grants are not replaced by purchases
+    key_manager
+        .set_user_rights(owner, key_id, buyer, AccessRights::read_write())
+        .unwrap();
+    assert_eq!(
+        key_manager.start_purchase(buyer, key_id, 1500).err(),
+        Some("user already has access rights for the key".to_string())
+    );
+    assert_eq!(
+        complete_purchase(&mut key_manager, 100),
+        Err("user already has access rights for the key".to_string())
+    );
+
+    // Grants of the owner are not extended, even if they look like a purchase
+    key_manager
+        .set_user_rights(owner, key_id, buyer, extended)
+        .unwrap();
+    assert_eq!(
+        key_manager.start_purchase(buyer, key_id, 1500).err(),
+        Some("user already has access rights for the key".to_string())
+    );
+
+    // Access to keys whose grants require approval cannot be purchased
+    key_manager.remove_user(owner, key_id, buyer).unwrap();
+    let config = ApprovalConfig {
+        approvers: vec![owner],
+        threshold: 1,
+        proposal_ttl: 100,
+    };
+    key_manager
+        .set_approval_config(owner, key_id, Some(config))
+        .unwrap();
+    assert_eq!(
+        key_manager.start_purchase(buyer, key_id, 1500).err(),
+        Some("grants of the key require approval".to_string())
+    );
+    assert_eq!(
+        complete_purchase(&mut key_manager, 100),
+        Err("grants of the key require approval".to_string())
+    );
+    assert_eq!(key_manager.get_user_rights(owner, key_id, buyer), Ok(None));
+
+    // Failed purchases are refunded from the stored payments only, and
+    // failed refunds can be retried
+    let pending_ids: Vec<_> = key_manager
+        .get_pending_purchases(buyer)
+        .into_iter()
+        .map(|(purchase_id, _)| purchase_id)
+        .collect();
+    assert_eq!(pending_ids.len(), 3);
+    assert!(pending_ids.contains(&underpaid));
+    assert_eq!(
+        key_manager.refund_purchase(owner, underpaid).err(),
+        Some("purchase not found".to_string())
+    );
+    assert!(key_manager.refund_purchase(buyer, underpaid).is_ok());
+    assert_eq!(
+        key_manager.refund_purchase(buyer, underpaid).err(),
+        Some("purchase is being refunded".to_string())
+    );
+    assert_eq!(
+        key_manager.complete_purchase(buyer, underpaid),
+        Err("purchase is being refunded".to_string())
+    );
+    key_manager.record_refund(buyer, underpaid, &Err("ledger unavailable".to_string()));
+    let pending = key_manager.get_pending_purchases(buyer);
+    let (_, purchase) = pending
+        .iter()
+        .find(|(purchase_id, _)| *purchase_id == underpaid)
+        .unwrap();
+    assert_eq!(purchase.payment, payment(99));
+    assert_eq!(
+        purchase.status,
+        PurchaseStatus::RefundFailed("ledger unavailable".to_string())
+    );
+    assert!(key_manager.refund_purchase(buyer, underpaid).is_ok());
+    key_manager.record_refund(buyer, underpaid, &Ok(1u32.into()));
+    assert_eq!(key_manager.get_pending_purchases(buyer).len(), 2);
+    let refund_types: Vec<_> = key_manager
+        .get_audit_log(key_id)
+        .unwrap()
+        .0
+        .iter()
+        .rev()
+        .take(2)
+        .map(|entry| entry.audit_type)
+        .collect();
+    assert_eq!(
+        refund_types,
+        vec![
+            AuditEntryType::PurchaseRefunded,
+            AuditEntryType::RefundFailed
+        ]
+    );
+
+    let subaccount = proceeds_subaccount(owner);
+    assert_eq!(usize::from(subaccount[0]), owner.as_slice().len());
+    assert_eq!(&subaccount[1..=owner.as_slice().len()], owner.as_slice());
+}
+
+#[test]
+fn freezes_block_access_without_removing_grants() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let admin = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager_with_freezing(rng);
+    key_manager
+        .set_user_rights(owner, key_id, user, AccessRights::read_write())
+        .unwrap();
+
+    assert_eq!(
+        key_manager.freeze_key(user, key_id, FreezeScope::VetKeys),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        key_manager.freeze_key(owner, key_id, FreezeScope::VetKeys),
+        Ok(None)
+    );
+    assert_eq!(
+        key_manager.evaluate_access(user, key_id, Operation::FetchVetKey, POLICY_TIME),
+        Decision::Deny(DenyReason::Frozen)
+    );
+    assert_matches!(
+        key_manager.evaluate_access(user, key_id, Operation::ReadValues, POLICY_TIME),
+        Decision::Allow(_)
+    );
+    assert_matches!(
+        key_manager.evaluate_access(owner, key_id, Operation::FetchVetKey, POLICY_TIME),
+        Decision::Allow(_)
+    );
+
+    let frozen = key_manager
+        .freeze_key(owner, key_id, FreezeScope::VetKeysAndValues)
+        .unwrap()
+        .unwrap();
+    assert_eq!(frozen.scope, FreezeScope::VetKeys);
+    for operation in Operation::iter() {
+        let decision = key_manager.evaluate_access(user, key_id, operation, POLICY_TIME);
+        if FreezeScope::VetKeysAndValues.covers(operation) {
+            assert_eq!(decision, Decision::Deny(DenyReason::Frozen), "{operation}");
+        } else {
+            assert_eq!(
+                decision,
+                evaluate_grant(Some(AccessRights::read_write()), operation, POLICY_TIME),
+                "{operation}"
+            );
+        }
+    }
+    assert_eq!(
+        key_manager.get_user_rights(owner, key_id, user),
+        Ok(Some(AccessRights::read_write()))
+    );
+
+    assert_eq!(
+        key_manager.unfreeze_key(user, key_id),
+        Err("unauthorized".to_string())
+    );
+    assert_matches!(key_manager.unfreeze_key(owner, key_id), Ok(Some(_)));
+    assert_eq!(key_manager.get_key_freeze(key_id), None);
+    assert_matches!(
+        key_manager.evaluate_access(user, key_id, Operation::FetchVetKey, POLICY_TIME),
+        Decision::Allow(_)
+    );
+    let audit_types: Vec<_> = key_manager
+        .get_audit_log(key_id)
+        .unwrap()
+        .0
+        .into_iter()
+        .map(|entry| entry.audit_type)
+        .filter(|audit_type| {
+            matches!(
+                audit_type,
+                AuditEntryType::Frozen | AuditEntryType::Unfrozen
+            )
+        })
+        .collect();
+    assert_eq!(
+        audit_types,
+        vec![
+            AuditEntryType::Frozen,
+            AuditEntryType::Frozen,
+            AuditEntryType::Unfrozen
+        ]
+    );
+
+    // The canister-wide freeze also applies to owners and only admins may set it
+    for caller in [owner, Principal::anonymous()] {
+        assert_eq!(
+            key_manager.freeze_canister(caller, Some(FreezeScope::VetKeys)),
+            Err("unauthorized".to_string())
+        );
+    }
+    key_manager.init_admins([admin]);
+    assert_eq!(
+        key_manager.freeze_canister(admin, Some(FreezeScope::VetKeys)),
+        Ok(None)
+    );
+    for caller in [owner, user] {
+        assert_eq!(
+            key_manager.evaluate_access(caller, key_id, Operation::FetchVetKey, POLICY_TIME),
+            Decision::Deny(DenyReason::Frozen)
+        );
+    }
+    assert_matches!(key_manager.freeze_canister(admin, None), Ok(Some(_)));
+    assert_eq!(key_manager.get_canister_freeze(), None);
+    assert_matches!(
+        key_manager.evaluate_access(owner, key_id, Operation::FetchVetKey, POLICY_TIME),
+        Decision::Allow(_)
+    );
+    let log = key_manager.get_canister_freeze_log();
+    assert_eq!(log.0.len(), 2);
+    assert!(log.0.iter().all(|entry| entry.caller == admin));
+}
+
+#[test]
+fn canister_freeze_requires_admins() {
+    let rng = &mut reproducible_rng();
+    let caller = random_self_authenticating_principal(rng);
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    let mut key_manager = KeyManager::init(
+        &random_utf8_string(rng, 16),
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+        Some(memory_manager.get(MemoryId::new(3))),
+    )
+    .with_freezing(
+        memory_manager.get(MemoryId::new(4)),
+        memory_manager.get(MemoryId::new(5)),
+    );
+
+    for caller in [caller, Principal::anonymous()] {
+        assert_eq!(
+            key_manager.freeze_canister(caller, Some(FreezeScope::VetKeysAndValues)),
+            Err("admins are not enabled".to_string())
+        );
+    }
+    assert_eq!(key_manager.get_canister_freeze(), None);
+    assert!(key_manager.get_canister_freeze_log().0.is_empty());
+}
+
+#[test]
+fn access_decisions_can_be_explained() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let stranger = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let public_key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager_with_freezing(rng);
+    ic_vetkd_cdk_types::set_mock_now(POLICY_TIME);
+    let expiring = AccessRights::new(Rights::ReadWrite, None, Some(POLICY_TIME + 100));
+    key_manager
+        .set_user_rights(owner, key_id, user, expiring)
+        .unwrap();
+    key_manager
+        .set_user_rights(
+            owner,
+            public_key_id,
+            Principal::anonymous(),
+            AccessRights::read_only(),
+        )
+        .unwrap();
+
+    let explanation = key_manager.explain_access(owner, key_id, Operation::Manage);
+    assert_eq!(explanation.source, Some(AccessSource::Owner));
+    assert_eq!(explanation.window, Some(WindowStatus::Unbounded));
+    assert_eq!(
+        explanation.effective_rights(),
+        Some(AccessRights::read_write_manage())
+    );
+
+    let explanation = key_manager.explain_access(user, key_id, Operation::Insert);
+    assert_eq!(explanation.decision, Decision::Allow(expiring));
+    assert_eq!(explanation.source, Some(AccessSource::DirectGrant));
+    assert_eq!(explanation.window, Some(WindowStatus::Active));
+
+    let explanation = key_manager.explain_access(user, key_id, Operation::Share);
+    assert_eq!(explanation.source, Some(AccessSource::DirectGrant));
+    assert_eq!(explanation.access_rights, Some(expiring));
+    assert_matches!(
+        explanation.deny_reason(),
+        Some(DenyReason::MissingPermissions(_))
+    );
+
+    let explanation = key_manager.explain_access(stranger, key_id, Operation::ReadValues);
+    assert_eq!(explanation.deny_reason(), Some(DenyReason::NoGrant));
+    assert_eq!(explanation.source, None);
+    assert_eq!(explanation.window, None);
+
+    let explanation = key_manager.explain_access(stranger, public_key_id, Operation::ReadValues);
+    assert_eq!(explanation.source, Some(AccessSource::PublicGrant));
+    assert!(explanation.decision.is_allowed());
+
+    key_manager
+        .freeze_key(owner, key_id, FreezeScope::VetKeys)
+        .unwrap();
+    let explanation = key_manager.explain_access(user, key_id, Operation::FetchVetKey);
+    assert_eq!(explanation.deny_reason(), Some(DenyReason::Frozen));
+    assert_eq!(explanation.source, Some(AccessSource::DirectGrant));
+
+    let all = key_manager.explain_access_to_all_keys(user, Operation::Inspect);
+    assert_eq!(
+        all.iter()
+            .map(|(key_id, _)| *key_id)
+            .collect::<BTreeSet<_>>(),
+        BTreeSet::from([key_id, public_key_id])
+    );
+    for (id, explanation) in &all {
+        assert_eq!(
+            *explanation,
+            key_manager.explain_access(user, *id, Operation::Inspect)
+        );
+    }
+    assert_eq!(
+        key_manager
+            .explain_access_to_all_keys(owner, Operation::Inspect)
+            .len(),
+        2
+    );
+
+    ic_vetkd_cdk_types::set_mock_now(POLICY_TIME + 100);
+    let explanation = key_manager.explain_access(user, key_id, Operation::ReadValues);
+    assert_eq!(explanation.deny_reason(), Some(DenyReason::Expired));
+    assert_eq!(explanation.source, Some(AccessSource::DirectGrant));
+    assert_eq!(explanation.window, Some(WindowStatus::Expired));
+    assert_eq!(
+        explanation.decision,
+        key_manager.evaluate_access(user, key_id, Operation::ReadValues, POLICY_TIME + 100)
+    );
+}
+
+#[test]
+fn subscribers_are_notified_of_access_changes() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let manager = random_self_authenticating_principal(rng);
+    let stranger = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let events = Rc::new(RefCell::new(vec![]));
+    let mut key_manager =
+        random_key_manager(rng).with_event_subscriber(AccessChangeRecorder(events.clone()));
+
+    key_manager
+        .set_user_rights(owner, key_id, manager, AccessRights::read_write_manage())
+        .unwrap();
+    assert!(key_manager
+        .set_user_rights(stranger, key_id, stranger, AccessRights::read_only())
+        .is_err());
+    key_manager.remove_user(manager, key_id, owner).unwrap();
+    key_manager.remove_user(owner, key_id, manager).unwrap();
+
+    assert_eq!(
+        *events.borrow(),
+        vec![
+            format!("share {owner} {manager}"),
+            format!("delete {manager}"),
+            format!("unshare {owner} {manager}"),
+        ]
+    );
+}
+
+struct AccessChangeRecorder(Rc<RefCell<Vec<String>>>);
+
+impl EventSubscriber for AccessChangeRecorder {
+    fn on_share(&self, caller: Principal, _: KeyId, user: Principal, _: AccessRights) {
+        self.0.borrow_mut().push(format!("share {caller} {user}"));
+    }
+
+    fn on_unshare(&self, caller: Principal, _: KeyId, user: Principal, _: AccessRights) {
+        self.0.borrow_mut().push(format!("unshare {caller} {user}"));
+    }
+
+    fn on_key_deleted(&self, caller: Principal, _: KeyId) {
+        self.0.borrow_mut().push(format!("delete {caller}"));
+    }
+}
+
+#[test]
+fn admins_can_recover_keys_after_a_delay() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let admin = random_self_authenticating_principal(rng);
+    let other_admin = random_self_authenticating_principal(rng);
+    let recovery_principal = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager_with_admins(rng);
+
+    key_manager.init_admins([admin]);
+    key_manager.init_admins([other_admin]);
+    assert_eq!(key_manager.get_admins(), vec![admin]);
+    assert_eq!(
+        key_manager.add_admin(owner, other_admin),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(key_manager.add_admin(admin, other_admin), Ok(()));
+    assert!(key_manager.is_admin(other_admin));
+
+    // Admins have no implicit access to keys
+    for operation in Operation::iter() {
+        assert_eq!(
+            key_manager.evaluate_access(admin, key_id, operation, POLICY_TIME),
+            Decision::Deny(DenyReason::NoGrant),
+            "{operation}"
+        );
+    }
+    assert_eq!(
+        key_manager.propose_recovery(owner, key_id, recovery_principal),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        key_manager.propose_recovery(admin, key_id, other_admin),
+        Err("admins cannot be recovery principals".to_string())
+    );
+    assert_eq!(
+        key_manager.propose_recovery(admin, key_id, recovery_principal),
+        Err("recovery principal is not registered by the key owner".to_string())
+    );
+
+    // Only the owner registers the principal a key can be recovered to
+    assert_eq!(
+        key_manager.set_recovery_principal(admin, key_id, Some(recovery_principal)),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        key_manager.set_recovery_principal(owner, key_id, Some(other_admin)),
+        Err("admins cannot be recovery principals".to_string())
+    );
+    assert_eq!(
+        key_manager.set_recovery_principal(owner, key_id, Some(recovery_principal)),
+        Ok(None)
+    );
+    assert_eq!(
+        key_manager.get_recovery_principal(admin, key_id),
+        Ok(Some(recovery_principal))
+    );
+    assert_eq!(
+        key_manager.get_recovery_principal(recovery_principal, key_id),
+        Err("unauthorized".to_string())
+    );
+
+    ic_vetkd_cdk_types::set_mock_now(POLICY_TIME);
+    let recovery_id = key_manager
+        .propose_recovery(admin, key_id, recovery_principal)
+        .unwrap();
+    assert_eq!(
+        key_manager
+            .get_pending_recoveries(owner, key_id)
+            .unwrap()
+            .len(),
+        1
+    );
+    assert_eq!(
+        key_manager.execute_recovery(admin, recovery_id),
+        Err("recovery delay has not passed".to_string())
+    );
+    assert_matches!(key_manager.cancel_recovery(owner, recovery_id), Ok(_));
+    assert_eq!(
+        key_manager.execute_recovery(admin, recovery_id),
+        Err("recovery not found".to_string())
+    );
+
+    let recovery_id = key_manager
+        .propose_recovery(admin, key_id, recovery_principal)
+        .unwrap();
+    assert_eq!(
+        key_manager.cancel_recovery(recovery_principal, recovery_id),
+        Err("unauthorized".to_string())
+    );
+    ic_vetkd_cdk_types::set_mock_now(POLICY_TIME + RECOVERY_DELAY);
+
+    // The owner can withdraw the registration until the recovery is executed
+    assert_eq!(
+        key_manager.set_recovery_principal(owner, key_id, None),
+        Ok(Some(recovery_principal))
+    );
+    assert_eq!(
+        key_manager.execute_recovery(other_admin, recovery_id),
+        Err("recovery principal is not registered by the key owner".to_string())
+    );
+    key_manager
+        .set_recovery_principal(owner, key_id, Some(recovery_principal))
+        .unwrap();
+    assert_eq!(
+        key_manager.execute_recovery(other_admin, recovery_id),
+        Ok((key_id, None))
+    );
+    assert_eq!(
+        key_manager.get_user_rights(recovery_principal, key_id, recovery_principal),
+        Ok(Some(AccessRights::read_write_manage()))
+    );
+    assert_eq!(
+        key_manager.evaluate_access(admin, key_id, Operation::FetchVetKey, POLICY_TIME),
+        Decision::Deny(DenyReason::NoGrant)
+    );
+
+    let audit_types: Vec<_> = key_manager
+        .get_audit_log(key_id)
+        .unwrap()
+        .0
+        .into_iter()
+        .map(|entry| entry.audit_type)
+        .collect();
+    assert_eq!(
+        audit_types,
+        vec![
+            AuditEntryType::RecoveryPrincipalChanged,
+            AuditEntryType::RecoveryProposed,
+            AuditEntryType::RecoveryCancelled,
+            AuditEntryType::RecoveryProposed,
+            AuditEntryType::RecoveryPrincipalChanged,
+            AuditEntryType::RecoveryPrincipalChanged,
+            AuditEntryType::Share,
+            AuditEntryType::RecoveryExecuted,
+        ]
+    );
+
+    assert_eq!(key_manager.remove_admin(admin, admin), Ok(true));
+    assert_eq!(
+        key_manager.remove_admin(other_admin, other_admin),
+        Err("cannot remove the last admin".to_string())
+    );
+}
+
+#[test]
+fn controllers_are_admins_until_the_admin_set_is_seeded() {
+    let rng = &mut reproducible_rng();
+    let controller = random_self_authenticating_principal(rng);
+    let admin = random_self_authenticating_principal(rng);
+    let mut key_manager = random_key_manager_with_admins(rng);
+    ic_vetkd_cdk_types::set_mock_controllers(vec![controller]);
+
+    assert!(key_manager.is_admin(controller));
+    assert!(!key_manager.is_admin(admin));
+    assert!(key_manager.get_admins().is_empty());
+    assert_eq!(
+        key_manager.add_admin(admin, admin),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(key_manager.add_admin(controller, admin), Ok(()));
+    assert_eq!(key_manager.get_admins(), vec![admin]);
+    assert!(!key_manager.is_admin(controller));
+    assert!(key_manager.is_admin(admin));
+
+    ic_vetkd_cdk_types::set_mock_controllers(vec![]);
+}
+
+#[test]
+fn keys_with_an_approval_config_cannot_be_recovered() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let admin = random_self_authenticating_principal(rng);
+    let approver = random_self_authenticating_principal(rng);
+    let recovery_principal = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    let mut key_manager = KeyManager::init(
+        &random_utf8_string(rng, 16),
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+        Some(memory_manager.get(MemoryId::new(3))),
+    )
+    .with_approvals(
+        memory_manager.get(MemoryId::new(4)),
+        memory_manager.get(MemoryId::new(5)),
+    )
+    .with_admins(
+        memory_manager.get(MemoryId::new(6)),
+        memory_manager.get(MemoryId::new(7)),
+        memory_manager.get(MemoryId::new(8)),
+        memory_manager.get(MemoryId::new(9)),
+        RECOVERY_DELAY,
+    );
+    key_manager.init_admins([admin]);
+    key_manager
+        .set_recovery_principal(owner, key_id, Some(recovery_principal))
+        .unwrap();
+
+    ic_vetkd_cdk_types::set_mock_now(POLICY_TIME);
+    let recovery_id = key_manager
+        .propose_recovery(admin, key_id, recovery_principal)
+        .unwrap();
+    let config = ApprovalConfig {
+        approvers: vec![approver],
+        threshold: 1,
+        proposal_ttl: 100,
+    };
+    key_manager
+        .set_approval_config(owner, key_id, Some(config))
+        .unwrap();
+
+    ic_vetkd_cdk_types::set_mock_now(POLICY_TIME + RECOVERY_DELAY);
+    assert_eq!(
+        key_manager.execute_recovery(admin, recovery_id),
+        Err("grants of the key require approval".to_string())
+    );
+    assert_eq!(
+        key_manager.propose_recovery(admin, key_id, recovery_principal),
+        Err("grants of the key require approval".to_string())
+    );
+    assert_eq!(
+        key_manager.get_user_rights(owner, key_id, recovery_principal),
+        Ok(None)
+    );
+}
+
+#[test]
+fn pending_recoveries_are_listed_per_key() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let admin = random_self_authenticating_principal(rng);
+    let recovery_principal = random_self_authenticating_principal(rng);
+    let key_ids = [(owner, random_name(rng)), (owner, random_name(rng))];
+    let mut key_manager = random_key_manager_with_admins(rng);
+    key_manager.init_admins([admin]);
+
+    let mut recovery_ids = Vec::new();
+    for key_id in key_ids {
+        key_manager
+            .set_recovery_principal(owner, key_id, Some(recovery_principal))
+            .unwrap();
+        for _ in 0..2 {
+            recovery_ids.push(
+                key_manager
+                    .propose_recovery(admin, key_id, recovery_principal)
+                    .unwrap(),
+            );
+        }
+    }
+
+    let pending_ids = |key_manager: &KeyManager, key_id| -> Vec<_> {
+        key_manager
+            .get_pending_recoveries(owner, key_id)
+            .unwrap()
+            .into_iter()
+            .map(|(recovery_id, _)| recovery_id)
+            .collect()
+    };
+    assert_eq!(pending_ids(&key_manager, key_ids[0]), recovery_ids[..2]);
+    assert_eq!(pending_ids(&key_manager, key_ids[1]), recovery_ids[2..]);
+
+    key_manager.cancel_recovery(owner, recovery_ids[2]).unwrap();
+    assert_eq!(pending_ids(&key_manager, key_ids[0]), recovery_ids[..2]);
+    assert_eq!(pending_ids(&key_manager, key_ids[1]), recovery_ids[3..]);
+}
+
+#[test]
+fn allow_listed_canisters_can_check_access() {
+    let rng = &mut reproducible_rng();
+    let admin = random_self_authenticating_principal(rng);
+    let owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let canister_id = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager_with_admins(rng);
+    key_manager.init_admins([admin]);
+    key_manager
+        .set_user_rights(owner, key_id, user, AccessRights::read_only())
+        .unwrap();
+    let check = AccessCheck::new(user, key_id, Operation::ReadValues);
+
+    assert_eq!(
+        key_manager.check_access(canister_id, &check),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        key_manager.add_authorized_canister(owner, canister_id),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        key_manager.add_authorized_canister(admin, canister_id),
+        Ok(true)
+    );
+    assert_eq!(key_manager.get_authorized_canisters(), vec![canister_id]);
+    assert_eq!(
+        key_manager.check_access(canister_id, &check),
+        Ok(Decision::Allow(AccessRights::read_only()))
+    );
+    assert_eq!(
+        key_manager.check_access(
+            canister_id,
+            &AccessCheck::new(user, key_id, Operation::Share)
+        ),
+        Ok(Decision::Deny(DenyReason::MissingPermissions(
+            Permissions::SHARE
+        )))
+    );
+    assert_eq!(check.key_id(), Ok(key_id));
+
+    assert_eq!(
+        key_manager.remove_authorized_canister(admin, canister_id),
+        Ok(true)
+    );
+    assert_eq!(
+        key_manager.check_access(canister_id, &check),
+        Err("unauthorized".to_string())
+    );
+}
+
+#[test]
+fn key_metadata_is_recorded_and_shared() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let stranger = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager_with_key_metadata(rng);
+
+    ic_vetkd_cdk_types::set_mock_now(1000);
+    assert_eq!(key_manager.get_key_metadata(owner, key_id), Ok(None));
+    key_manager
+        .set_user_rights(owner, key_id, user, AccessRights::read_only())
+        .unwrap();
+    let metadata = key_manager.get_key_metadata(user, key_id).unwrap().unwrap();
+    assert_eq!(metadata.created_at, 1000);
+    assert_eq!(metadata.created_by, owner);
+    assert!(metadata.label.is_empty());
+
+    ic_vetkd_cdk_types::set_mock_now(2000);
+    let update = KeyMetadataUpdate {
+        label: Some("tax returns".to_string()),
+        tags: Some(vec!["finance".to_string()]),
+        app_data: Some(ByteBuf::from(b"application/pdf".to_vec())),
+        ..KeyMetadataUpdate::default()
+    };
+    assert_eq!(
+        key_manager.set_key_metadata(user, key_id, update.clone()),
+        Err("unauthorized".to_string())
+    );
+    let updated = key_manager.set_key_metadata(owner, key_id, update).unwrap();
+    assert_eq!(updated.created_at, 1000);
+    assert_eq!(updated.label, "tax returns");
+    assert_eq!(updated.tags, vec!["finance".to_string()]);
+    assert_eq!(
+        key_manager
+            .set_key_metadata(
+                owner,
+                key_id,
+                KeyMetadataUpdate {
+                    description: Some("2024".to_string()),
+                    ..KeyMetadataUpdate::default()
+                },
+            )
+            .unwrap()
+            .label,
+        "tax returns"
+    );
+    assert_eq!(
+        key_manager.set_key_metadata(
+            owner,
+            key_id,
+            KeyMetadataUpdate {
+                tags: Some(vec!["tag".to_string(); 3]),
+                ..KeyMetadataUpdate::default()
+            },
+        ),
+        Err("too many tags".to_string())
+    );
+    assert_eq!(
+        key_manager.set_key_metadata(
+            owner,
+            key_id,
+            KeyMetadataUpdate {
+                label: Some("x".repeat(17)),
+                ..KeyMetadataUpdate::default()
+            },
+        ),
+        Err("label too long".to_string())
+    );
+
+    assert_eq!(
+        key_manager.get_key_metadata(stranger, key_id),
+        Err("unauthorized".to_string())
+    );
+    let shared = key_manager.get_accessible_shared_keys_with_metadata(user);
+    assert_eq!(shared.len(), 1);
+    assert_eq!(shared[0].0, key_id);
+    let shared_metadata = shared[0].1.clone().unwrap();
+    assert_eq!(shared_metadata.label, "tax returns");
+    assert_eq!(shared_metadata.description, "2024");
+    assert!(key_manager
+        .get_accessible_shared_keys_with_metadata(stranger)
+        .is_empty());
+}
+
+#[test]
+fn keys_can_be_created_listed_and_deleted() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let mut key_manager = random_key_manager_with_key_lifecycle(rng);
+
+    let key_name = random_name(rng);
+    let key_id = key_manager.create_key(owner, key_name).unwrap();
+    assert_eq!(key_id, (owner, key_name));
+    assert_eq!(
+        key_manager.create_key(owner, key_name),
+        Err("key already exists".to_string())
+    );
+    assert_eq!(
+        key_manager.create_key(Principal::anonymous(), key_name),
+        Err("unauthorized".to_string())
+    );
+
+    // Keys that were shared without being created are listed as well.
+    let shared_key_id = (owner, random_name(rng));
+    key_manager
+        .set_user_rights(owner, shared_key_id, user, AccessRights::read_only())
+        .unwrap();
+    key_manager
+        .set_user_rights(owner, key_id, user, AccessRights::read_write())
+        .unwrap();
+    assert_eq!(
+        key_manager
+            .list_owned_keys(owner)
+            .into_iter()
+            .collect::<BTreeSet<_>>(),
+        BTreeSet::from([key_id, shared_key_id])
+    );
+    assert!(key_manager.list_owned_keys(user).is_empty());
+
+    assert_eq!(
+        key_manager.delete_key(user, key_id),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(key_manager.delete_key(owner, key_id), Ok(vec![user]));
+    assert_eq!(key_manager.get_user_rights(owner, key_id, user), Ok(None));
+    assert_eq!(
+        key_manager.get_accessible_shared_key_ids(user),
+        vec![shared_key_id]
+    );
+    assert_eq!(key_manager.list_owned_keys(owner), vec![shared_key_id]);
+    let audit_log = key_manager.get_audit_log(key_id).unwrap();
+    assert_eq!(
+        audit_log.0.first().unwrap().audit_type,
+        AuditEntryType::Created
+    );
+    assert_eq!(
+        audit_log.0.last().unwrap().audit_type,
+        AuditEntryType::Deleted
+    );
+
+    // A deleted key can be created again.
+    assert_eq!(key_manager.create_key(owner, key_name), Ok(key_id));
+}
+
+#[test]
+fn state_can_be_exported_and_imported_in_chunks() {
+    let rng = &mut reproducible_rng();
+    let admin = random_self_authenticating_principal(rng);
+    let owner = random_self_authenticating_principal(rng);
+    let mut source = key_manager_with_admins("export");
+    source.init_admins([admin]);
+    let mut key_ids = vec![];
+    for _ in 0..3 {
+        let key_id = (owner, random_name(rng));
+        for _ in 0..2 {
+            let user = random_self_authenticating_principal(rng);
+            source
+                .set_user_rights(owner, key_id, user, random_access_rights(rng))
+                .unwrap();
+        }
+        key_ids.push(key_id);
+    }
+
+    let export = |source: &KeyManager| -> Vec<ExportChunk> {
+        let mut chunks: Vec<ExportChunk> = vec![];
+        loop {
+            let cursor = chunks.last().map(|chunk| chunk.next.clone().unwrap());
+            let chunk = source.export_chunk(admin, cursor, 4).unwrap();
+            let done = chunk.next.is_none();
+            chunks.push(chunk);
+            if done {
+                return chunks;
+            }
+        }
+    };
+    assert_eq!(
+        source.export_summary(owner),
+        Err("unauthorized".to_string())
+    );
+    let summary = source.export_summary(admin).unwrap();
+    assert_eq!(
+        summary.sections,
+        vec![
+            (ExportSection::AccessControl, 6),
+            (ExportSection::SharedKeys, 6),
+            (ExportSection::AuditLogs, 3),
+        ]
+    );
+    let chunks = export(&source);
+    assert_eq!(chunks.len(), 5);
+
+    let mut other_domain = key_manager_with_admins("other");
+    other_domain.init_admins([admin]);
+    assert_eq!(
+        other_domain.begin_import(admin, summary.clone()),
+        Err("domain separator mismatch".to_string())
+    );
+
+    let mut target = key_manager_with_admins("export");
+    target.init_admins([admin]);
+    assert_eq!(
+        target.begin_import(owner, summary.clone()),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(target.begin_import(admin, summary.clone()), Ok(()));
+    let mut tampered = chunks[1].clone();
+    tampered.entries.pop();
+    assert_eq!(
+        target.import_chunk(admin, tampered),
+        Err("chunk does not continue the export".to_string())
+    );
+    target.import_chunk(admin, chunks[0].clone()).unwrap();
+    let mut tampered = chunks[1].clone();
+    tampered.entries.pop();
+    assert_eq!(
+        target.import_chunk(admin, tampered),
+        Err("chunk hash mismatch".to_string())
+    );
+    assert_eq!(
+        target.finish_import(admin),
+        Err("import is incomplete".to_string())
+    );
+    for chunk in &chunks[1..] {
+        target.import_chunk(admin, chunk.clone()).unwrap();
+    }
+    assert_eq!(target.finish_import(admin), Ok(()));
+
+    assert_eq!(target.export_summary(admin), Ok(summary.clone()));
+    for key_id in key_ids {
+        assert_eq!(
+            target.get_shared_user_access_for_key(owner, key_id),
+            source.get_shared_user_access_for_key(owner, key_id)
+        );
+        assert_eq!(target.get_audit_log(key_id), source.get_audit_log(key_id));
+    }
+    assert_eq!(
+        target.begin_import(admin, summary),
+        Err("import target is not empty".to_string())
+    );
+}
+
+#[test]
+fn imports_fail_if_the_source_changed_during_the_export() {
+    let rng = &mut reproducible_rng();
+    let admin = random_self_authenticating_principal(rng);
+    let owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut source = key_manager_with_admins("export");
+    source.init_admins([admin]);
+    for user in [user, random_self_authenticating_principal(rng)] {
+        source
+            .set_user_rights(owner, key_id, user, AccessRights::read_only())
+            .unwrap();
+    }
+
+    let summary = source.export_summary(admin).unwrap();
+    let first = source.export_chunk(admin, None, 2).unwrap();
+    let memory = DefaultMemoryImpl::default();
+    let mut target = key_manager_with_admins_in("export", memory.clone());
+    target.init_admins([admin]);
+    target.begin_import(admin, summary.clone()).unwrap();
+    target.import_chunk(admin, first.clone()).unwrap();
+
+    // Changing a grant keeps the number of entries but not their contents.
+    source
+        .set_user_rights(owner, key_id, user, AccessRights::read_write())
+        .unwrap();
+    let import = |target: &mut KeyManager, mut cursor: Option<ExportCursor>| loop {
+        let chunk = source.export_chunk(admin, cursor, 2).unwrap();
+        cursor = chunk.next.clone();
+        target.import_chunk(admin, chunk).unwrap();
+        if cursor.is_none() {
+            break;
+        }
+    };
+    // The import in progress survives an upgrade of the target.
+    let mut target = key_manager_with_admins_in("export", memory);
+    import(&mut target, first.next);
+    assert_eq!(
+        target.finish_import(admin),
+        Err("exported state changed after the summary was created".to_string())
+    );
+    assert_eq!(
+        target.begin_import(admin, summary),
+        Err("import target is not empty".to_string())
+    );
+
+    assert_eq!(target.abort_import(owner), Err("unauthorized".to_string()));
+    assert_eq!(target.abort_import(admin), Ok(()));
+    assert_eq!(
+        target.abort_import(admin),
+        Err("no import in progress".to_string())
+    );
+    let summary = source.export_summary(admin).unwrap();
+    target.begin_import(admin, summary.clone()).unwrap();
+    import(&mut target, None);
+    assert_eq!(target.finish_import(admin), Ok(()));
+    assert_eq!(target.export_summary(admin), Ok(summary));
+}
+
+#[test]
+fn can_instantiate_two_key_managers() {
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    let key_manager_1 = KeyManager::init(
+        "key_manager_1",
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+        Some(memory_manager.get(MemoryId::new(3))),
+    );
+    let key_manager_2 = KeyManager::init(
+        "key_manager_2",
+        memory_manager.get(MemoryId::new(4)),
+        memory_manager.get(MemoryId::new(5)),
+        memory_manager.get(MemoryId::new(6)),
+        Some(memory_manager.get(MemoryId::new(7))),
+    );
+    // prevent the compiler from optimizing away the function call
+    std::hint::black_box((key_manager_1, key_manager_2));
+}
+
+#[test]
+fn key_manager_works_over_vector_memory() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let memories: Vec<VectorMemory> = (0..5).map(|_| VectorMemory::default()).collect();
+    let init = |memories: &[VectorMemory]| -> KeyManager<VectorMemory> {
+        KeyManager::init(
+            "vector_memory",
+            memories[0].clone(),
+            memories[1].clone(),
+            memories[2].clone(),
+            Some(memories[3].clone()),
+        )
+        .with_key_lifecycle(memories[4].clone())
+    };
+
+    let mut key_manager = init(&memories);
+    let key_id = key_manager.create_key(owner, random_name(rng)).unwrap();
+    key_manager
+        .set_user_rights(owner, key_id, user, AccessRights::read_write())
+        .unwrap();
+
+    // The state is kept in the memories and survives a re-initialization.
+    let key_manager = init(&memories);
+    assert_eq!(key_manager.domain_separator(), "vector_memory");
+    assert_eq!(
+        key_manager.get_user_rights(owner, key_id, user),
+        Ok(Some(AccessRights::read_write()))
+    );
+    assert_eq!(key_manager.list_owned_keys(owner), vec![key_id]);
+    assert_eq!(
+        key_manager.get_audit_log(key_id).unwrap().0[0].audit_type,
+        AuditEntryType::Created
+    );
+}
+
+#[test]
+fn standard_memory_layout_assigns_distinct_ids() {
+    let layout = MemoryLayout::standard(10);
+    assert_eq!(layout.validate(), Ok(()));
+    assert_eq!(layout.memory_ids(), 10..10 + RESERVED_MEMORY_IDS);
+    assert_eq!(layout.memory_id(StableStructure::Metadata), Some(10));
+    assert_eq!(layout.memory_id(StableStructure::AccessControl), Some(11));
+    let memory_ids: BTreeSet<u8> = StableStructure::iter()
+        .map(|structure| layout.memory_id(structure).unwrap())
+        .collect();
+    assert_eq!(memory_ids.len(), StableStructure::iter().count());
+
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    let key_manager = KeyManager::init_with_layout("layout", &memory_manager, &layout)
+        .with_key_lifecycle(layout.memory(&memory_manager, StableStructure::OwnedKeys));
+    assert_eq!(key_manager.domain_separator(), "layout");
+    assert!(key_manager.audit_logs.is_some());
+}
+
+#[test]
+fn memory_layout_rejects_shared_and_out_of_range_ids() {
+    let layout = MemoryLayout::new(0..6)
+        .with_memory_id(StableStructure::Tombstones, 4)
+        .with_memory_id(StableStructure::AuditLogs, 4);
+    assert_eq!(
+        layout.validate(),
+        Err("memory id 4 is assigned to both AuditLogs and Tombstones".to_string())
+    );
+
+    let layout = MemoryLayout::new(0..6).with_memory_id(StableStructure::AuditLogs, 6);
+    assert_eq!(
+        layout.validate(),
+        Err("memory id 6 of AuditLogs is outside the reserved range 0..6".to_string())
+    );
+}
+
+#[test]
+#[should_panic(
+    expected = "invalid memory layout: memory id 0 is assigned to both Metadata and AccessControl"
+)]
+fn init_with_invalid_memory_layout_panics() {
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    let layout = MemoryLayout::new(0..3)
+        .with_memory_id(StableStructure::Metadata, 0)
+        .with_memory_id(StableStructure::AccessControl, 0)
+        .with_memory_id(StableStructure::SharedKeys, 2);
+    let _ = KeyManager::init_with_layout("layout", &memory_manager, &layout);
+}
+
+#[test]
+fn api_converts_key_names_of_up_to_max_name_bytes() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let key_name = random_bytebuf(rng, 0..MAX_NAME_BYTES + 1);
+
+    let (key_owner, name) = api::key_id(owner, &key_name).unwrap();
+    assert_eq!(key_owner, owner);
+    assert_eq!(name.as_slice(), key_name.as_ref());
+    assert_eq!(api::name_to_bytebuf(&name), key_name);
+
+    let too_long = ByteBuf::from(vec![0; MAX_NAME_BYTES + 1]);
+    assert_eq!(
+        api::key_id(owner, &too_long),
+        Err("too large input".to_string())
+    );
+}
+
+#[test]
+fn api_did_service_skips_excluded_methods() {
+    let all = api::did_service(&api::KEY_MANAGER_API_METHODS, &[]);
+    assert!(all.contains("type ByteBuf = record { inner : blob };"));
+    assert!(all.contains("get_vetkey_verification_key : () -> (ByteBuf);"));
+    for method in &api::KEY_MANAGER_API_METHODS {
+        assert!(all.contains(&format!("  {} : (", method.name)));
+    }
+
+    let without_remove_user = api::did_service(&api::KEY_MANAGER_API_METHODS, &["remove_user"]);
+    assert!(!without_remove_user.contains("remove_user"));
+    assert!(without_remove_user.contains("set_user_rights"));
+}
+
+fn random_key_manager<R: Rng + CryptoRng>(rng: &mut R) -> KeyManager {
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    let (_memory_id_encrypted_maps, memory_ids_key_manager) = random_unique_memory_ids(rng);
+    let domain_separator_len = rng.gen_range(0..32);
+    KeyManager::init(
+        &random_utf8_string(rng, domain_separator_len),
+        memory_manager.get(MemoryId::new(memory_ids_key_manager[0])),
+        memory_manager.get(MemoryId::new(memory_ids_key_manager[1])),
+        memory_manager.get(MemoryId::new(memory_ids_key_manager[2])),
+        Some(memory_manager.get(MemoryId::new(memory_ids_key_manager[3]))),
+    )
+}
+
+fn random_key_manager_with_approvals<R: Rng + CryptoRng>(rng: &mut R) -> KeyManager {
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    KeyManager::init(
+        &random_utf8_string(rng, 16),
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+        Some(memory_manager.get(MemoryId::new(3))),
+    )
+    .with_approvals(
+        memory_manager.get(MemoryId::new(4)),
+        memory_manager.get(MemoryId::new(5)),
+    )
+}
+
+fn random_key_manager_with_access_requests<R: Rng + CryptoRng>(rng: &mut R) -> KeyManager {
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    KeyManager::init(
+        &random_utf8_string(rng, 16),
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+        Some(memory_manager.get(MemoryId::new(3))),
+    )
+    .with_access_requests(
+        memory_manager.get(MemoryId::new(4)),
+        AccessRequestLimits {
+            max_message_bytes: 16,
+            max_pending_per_key: 2,
+            retry_cooldown: 100,
+        },
+    )
+}
+
+fn random_key_manager_with_invites<R: Rng + CryptoRng>(rng: &mut R) -> KeyManager {
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    KeyManager::init(
+        &random_utf8_string(rng, 16),
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+        Some(memory_manager.get(MemoryId::new(3))),
+    )
+    .with_invites(
+        memory_manager.get(MemoryId::new(4)),
+        memory_manager.get(MemoryId::new(7)),
+    )
+    .with_approvals(
+        memory_manager.get(MemoryId::new(5)),
+        memory_manager.get(MemoryId::new(6)),
+    )
+}
+
+fn random_key_manager_with_token_gating<R: Rng + CryptoRng>(rng: &mut R) -> KeyManager {
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    KeyManager::init(
+        &random_utf8_string(rng, 16),
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+        Some(memory_manager.get(MemoryId::new(3))),
+    )
+    .with_token_gating(
+        memory_manager.get(MemoryId::new(4)),
+        memory_manager.get(MemoryId::new(5)),
+        TOKEN_OWNER_CACHE_TTL,
+    )
+}
+
+fn random_key_manager_with_payments<R: Rng + CryptoRng>(rng: &mut R) -> KeyManager {
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    KeyManager::init(
+        &random_utf8_string(rng, 16),
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+        Some(memory_manager.get(MemoryId::new(3))),
+    )
+    .with_payments(
+        memory_manager.get(MemoryId::new(4)),
+        memory_manager.get(MemoryId::new(7)),
+        memory_manager.get(MemoryId::new(8)),
+    )
+    .with_approvals(
+        memory_manager.get(MemoryId::new(5)),
+        memory_manager.get(MemoryId::new(6)),
+    )
+}
+
+fn random_key_manager_with_freezing<R: Rng + CryptoRng>(rng: &mut R) -> KeyManager {
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    KeyManager::init(
+        &random_utf8_string(rng, 16),
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+        Some(memory_manager.get(MemoryId::new(3))),
+    )
+    .with_freezing(
+        memory_manager.get(MemoryId::new(4)),
+        memory_manager.get(MemoryId::new(5)),
+    )
+    .with_admins(
+        memory_manager.get(MemoryId::new(6)),
+        memory_manager.get(MemoryId::new(7)),
+        memory_manager.get(MemoryId::new(8)),
+        memory_manager.get(MemoryId::new(9)),
+        RECOVERY_DELAY,
+    )
+}
+
+const RECOVERY_DELAY: u64 = 1_000;
+
+fn random_key_manager_with_admins<R: Rng + CryptoRng>(rng: &mut R) -> KeyManager {
+    key_manager_with_admins(&random_utf8_string(rng, 16))
+}
+
+fn key_manager_with_admins(domain_separator: &str) -> KeyManager {
+    key_manager_with_admins_in(domain_separator, DefaultMemoryImpl::default())
+}
+
+fn key_manager_with_admins_in(domain_separator: &str, memory: DefaultMemoryImpl) -> KeyManager {
+    let memory_manager = MemoryManager::init(memory);
+    KeyManager::init(
+        domain_separator,
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+        Some(memory_manager.get(MemoryId::new(3))),
+    )
+    .with_admins(
+        memory_manager.get(MemoryId::new(4)),
+        memory_manager.get(MemoryId::new(5)),
+        memory_manager.get(MemoryId::new(6)),
+        memory_manager.get(MemoryId::new(8)),
+        RECOVERY_DELAY,
+    )
+    .with_authorized_canisters(memory_manager.get(MemoryId::new(7)))
+}
+
+fn random_key_manager_with_key_metadata<R: Rng + CryptoRng>(rng: &mut R) -> KeyManager {
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    KeyManager::init(
+        &random_utf8_string(rng, 16),
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+        Some(memory_manager.get(MemoryId::new(3))),
+    )
+    .with_key_metadata(
+        memory_manager.get(MemoryId::new(4)),
+        KeyMetadataLimits {
+            max_label_bytes: 16,
+            max_tags: 2,
+            ..KeyMetadataLimits::default()
+        },
+    )
+}
+
+fn random_key_manager_with_key_lifecycle<R: Rng + CryptoRng>(rng: &mut R) -> KeyManager {
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    KeyManager::init(
+        &random_utf8_string(rng, 16),
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+        Some(memory_manager.get(MemoryId::new(3))),
+    )
+    .with_key_lifecycle(memory_manager.get(MemoryId::new(4)))
+}
+
+fn random_transport_key<R: Rng + CryptoRng>(rng: &mut R) -> TransportSecretKey {
+    let mut seed = vec![0u8; 32];
+    rng.fill_bytes(&mut seed);
+    TransportSecretKey::from_seed(seed).unwrap()
 }
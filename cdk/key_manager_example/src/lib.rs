@@ -1,114 +1,402 @@
 use std::cell::RefCell;
 
+use candid::Nat;
 use candid::Principal;
-use ic_cdk::{query, update};
+use ic_cdk::{init, post_upgrade, query, update};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::storable::Blob;
 use ic_stable_structures::DefaultMemoryImpl;
-use ic_vetkd_cdk_key_manager::{KeyManager, VetKey, VetKeyVerificationKey};
-use ic_vetkd_cdk_types::{AccessRights, ByteBuf, TransportKey};
+use ic_vetkd_cdk_key_manager::admin::{Recovery, RecoveryId};
+use ic_vetkd_cdk_key_manager::explain::AccessExplanation;
+use ic_vetkd_cdk_key_manager::export::{ExportChunk, ExportCursor, ExportSummary};
+use ic_vetkd_cdk_key_manager::freeze::{Freeze, FreezeScope};
+use ic_vetkd_cdk_key_manager::icrc::Account;
+use ic_vetkd_cdk_key_manager::key_metadata::{KeyMetadata, KeyMetadataLimits, KeyMetadataUpdate};
+use ic_vetkd_cdk_key_manager::migration::{MigratedStructure, MIGRATION_BATCH_SIZE};
+use ic_vetkd_cdk_key_manager::payments::{AccessPrice, PendingPurchase, PurchaseId};
+use ic_vetkd_cdk_key_manager::policy::Operation;
+use ic_vetkd_cdk_key_manager::token_gating::TokenGate;
+use ic_vetkd_cdk_key_manager::KeyManager;
+use ic_vetkd_cdk_types::{now, AccessRights, ByteBuf, MAX_NAME_BYTES};
+use std::time::Duration;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
+/// How long the holder of a gating token is cached, in nanoseconds.
+const TOKEN_OWNER_CACHE_TTL: u64 = 5 * 60 * 1_000_000_000;
+
+/// How long a proposed recovery of a key can be cancelled, in nanoseconds.
+const RECOVERY_DELAY: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+// Memories 1 to 3 hold the access control, shared keys and audit log maps
+// written before schema version 2. They are attached as legacy memories and
+// migrated into memories 15 to 17 after an upgrade, see `post_upgrade`.
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
         RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
-    static KEY_MANAGER: RefCell<KeyManager> = RefCell::new(KeyManager::init("key_manager", id_to_memory(0), id_to_memory(1), id_to_memory(2), Some(id_to_memory(3))));
+    static KEY_MANAGER: RefCell<KeyManager> = RefCell::new(
+        KeyManager::init("key_manager", id_to_memory(0), id_to_memory(15), id_to_memory(16), Some(id_to_memory(17)))
+            .with_vetkey_verification()
+            .with_token_gating(id_to_memory(4), id_to_memory(5), TOKEN_OWNER_CACHE_TTL)
+            .with_payments(id_to_memory(6), id_to_memory(19), id_to_memory(20))
+            .with_freezing(id_to_memory(7), id_to_memory(8))
+            .with_admins(id_to_memory(9), id_to_memory(10), id_to_memory(14), id_to_memory(18), RECOVERY_DELAY)
+            .with_authorized_canisters(id_to_memory(11))
+            .with_key_metadata(id_to_memory(12), KeyMetadataLimits::default())
+            .with_key_lifecycle(id_to_memory(13))
+            .with_legacy_memory(MigratedStructure::AccessControl, id_to_memory(1))
+            .with_legacy_memory(MigratedStructure::SharedKeys, id_to_memory(2))
+            .with_legacy_memory(MigratedStructure::AuditLogs, id_to_memory(3))
+    );
+}
+
+/// Seeds the admin set with the principals passed in the init arguments, if
+/// any. Otherwise, the controllers of the canister are the admins.
+#[init]
+fn init(admins: Option<Vec<Principal>>) {
+    if let Some(admins) = admins {
+        KEY_MANAGER.with_borrow_mut(|km| km.init_admins(admins));
+    }
+}
+
+/// Continues the migration of the data written before schema version 2.
+#[post_upgrade]
+fn post_upgrade() {
+    schedule_migration_batch();
+}
+
+/// Migrates the next batch of legacy entries in a timer, so that each batch
+/// runs in its own message, until no entries are left.
+fn schedule_migration_batch() {
+    if KEY_MANAGER.with_borrow(KeyManager::is_migration_complete) {
+        return;
+    }
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        let migrated =
+            KEY_MANAGER.with_borrow_mut(|km| km.run_migration_batch(MIGRATION_BATCH_SIZE));
+        if migrated > 0 {
+            schedule_migration_batch();
+        }
+    });
+}
+
+ic_vetkd_cdk_key_manager::export_key_manager_api!(KEY_MANAGER);
+
+#[update]
+#[allow(clippy::needless_pass_by_value)]
+fn create_key(key_name: ByteBuf) -> Result<(Principal, ByteBuf), String> {
+    let key_name = bytebuf_to_blob(&key_name)?;
+    let key_id = KEY_MANAGER.with_borrow_mut(|km| km.create_key(ic_cdk::caller(), key_name))?;
+    Ok((key_id.0, ByteBuf::from(key_id.1.as_ref().to_vec())))
 }
 
 #[query]
-fn get_accessible_shared_key_ids() -> Vec<(Principal, ByteBuf)> {
+fn list_owned_keys() -> Vec<(Principal, ByteBuf)> {
     KEY_MANAGER.with_borrow(|km| {
-        km.get_accessible_shared_key_ids(ic_cdk::caller())
+        km.list_owned_keys(ic_cdk::caller())
             .into_iter()
             .map(|key_id| (key_id.0, ByteBuf::from(key_id.1.as_ref().to_vec())))
             .collect()
     })
 }
 
+#[update]
+#[allow(clippy::needless_pass_by_value)]
+fn delete_key(key_owner: Principal, key_name: ByteBuf) -> Result<Vec<Principal>, String> {
+    let key_name = bytebuf_to_blob(&key_name)?;
+    let key_id = (key_owner, key_name);
+    KEY_MANAGER.with_borrow_mut(|km| km.delete_key(ic_cdk::caller(), key_id))
+}
+
+#[query]
+fn get_accessible_shared_keys_with_metadata() -> Vec<(Principal, ByteBuf, Option<KeyMetadata>)> {
+    KEY_MANAGER.with_borrow(|km| {
+        km.get_accessible_shared_keys_with_metadata(ic_cdk::caller())
+            .into_iter()
+            .map(|(key_id, metadata)| {
+                (
+                    key_id.0,
+                    ByteBuf::from(key_id.1.as_ref().to_vec()),
+                    metadata,
+                )
+            })
+            .collect()
+    })
+}
+
 #[query]
 #[allow(clippy::needless_pass_by_value)]
-fn get_shared_user_access_for_key(
+fn get_key_metadata(
     key_owner: Principal,
     key_name: ByteBuf,
-) -> Result<Vec<(Principal, AccessRights)>, String> {
+) -> Result<Option<KeyMetadata>, String> {
     let key_name = bytebuf_to_blob(&key_name)?;
     let key_id = (key_owner, key_name);
-    KEY_MANAGER.with_borrow(|km| km.get_shared_user_access_for_key(ic_cdk::caller(), key_id))
+    KEY_MANAGER.with_borrow(|km| km.get_key_metadata(ic_cdk::caller(), key_id))
 }
 
 #[update]
-async fn get_vetkey_verification_key() -> VetKeyVerificationKey {
-    KEY_MANAGER
-        .with_borrow(ic_vetkd_cdk_key_manager::KeyManager::get_vetkey_verification_key)
-        .await
+#[allow(clippy::needless_pass_by_value)]
+fn set_key_metadata(
+    key_owner: Principal,
+    key_name: ByteBuf,
+    update: KeyMetadataUpdate,
+) -> Result<KeyMetadata, String> {
+    let key_name = bytebuf_to_blob(&key_name)?;
+    let key_id = (key_owner, key_name);
+    KEY_MANAGER.with_borrow_mut(|km| km.set_key_metadata(ic_cdk::caller(), key_id, update))
+}
+
+#[query]
+#[allow(clippy::needless_pass_by_value)]
+fn explain_access(
+    key_owner: Principal,
+    key_name: ByteBuf,
+    operation: Operation,
+) -> Result<AccessExplanation, String> {
+    let key_name = bytebuf_to_blob(&key_name)?;
+    let key_id = (key_owner, key_name);
+    Ok(KEY_MANAGER.with_borrow(|km| km.explain_access(ic_cdk::caller(), key_id, operation)))
+}
+
+#[query]
+fn explain_access_to_all_keys(
+    operation: Operation,
+) -> Vec<(Principal, ByteBuf, AccessExplanation)> {
+    KEY_MANAGER.with_borrow(|km| {
+        km.explain_access_to_all_keys(ic_cdk::caller(), operation)
+            .into_iter()
+            .map(|(key_id, explanation)| {
+                (
+                    key_id.0,
+                    ByteBuf::from(key_id.1.as_ref().to_vec()),
+                    explanation,
+                )
+            })
+            .collect()
+    })
 }
 
 #[update]
 #[allow(clippy::needless_pass_by_value)]
-async fn get_encrypted_vetkey(
+fn set_token_gate(
     key_owner: Principal,
     key_name: ByteBuf,
-    transport_key: TransportKey,
-) -> Result<VetKey, String> {
+    gate: Option<TokenGate>,
+) -> Result<Option<TokenGate>, String> {
     let key_name = bytebuf_to_blob(&key_name)?;
     let key_id = (key_owner, key_name);
+    KEY_MANAGER.with_borrow_mut(|km| km.set_token_gate(ic_cdk::caller(), key_id, gate))
+}
 
-    // Use KEY_MANAGER.with_borrow_mut to ensure we get a mutable reference
-    // This is required because get_encrypted_vetkey now requires &mut self
-    // to support audit logging
-    let encrypted_vetkey_future = KEY_MANAGER
-        .with_borrow_mut(|km| km.get_encrypted_vetkey(ic_cdk::caller(), key_id, transport_key))?;
+#[query]
+#[allow(clippy::needless_pass_by_value)]
+fn get_token_gate(key_owner: Principal, key_name: ByteBuf) -> Result<Option<TokenGate>, String> {
+    let key_name = bytebuf_to_blob(&key_name)?;
+    let key_id = (key_owner, key_name);
+    Ok(KEY_MANAGER.with_borrow(|km| km.get_token_gate(key_id)))
+}
 
-    // Now await the future
-    Ok(encrypted_vetkey_future.await)
+#[update]
+#[allow(clippy::needless_pass_by_value)]
+fn set_access_price(
+    key_owner: Principal,
+    key_name: ByteBuf,
+    price: Option<AccessPrice>,
+) -> Result<Option<AccessPrice>, String> {
+    let key_name = bytebuf_to_blob(&key_name)?;
+    let key_id = (key_owner, key_name);
+    KEY_MANAGER.with_borrow_mut(|km| km.set_access_price(ic_cdk::caller(), key_id, price))
 }
 
 #[query]
 #[allow(clippy::needless_pass_by_value)]
-fn get_user_rights(
+fn get_access_price(
     key_owner: Principal,
     key_name: ByteBuf,
-    user: Principal,
-) -> Result<Option<AccessRights>, String> {
+) -> Result<Option<AccessPrice>, String> {
     let key_name = bytebuf_to_blob(&key_name)?;
     let key_id = (key_owner, key_name);
-    KEY_MANAGER.with_borrow(|km| km.get_user_rights(ic_cdk::caller(), key_id, user))
+    Ok(KEY_MANAGER.with_borrow(|km| km.get_access_price(key_id)))
 }
 
 #[update]
 #[allow(clippy::needless_pass_by_value)]
-fn set_user_rights(
+async fn purchase_access(key_owner: Principal, key_name: ByteBuf) -> Result<AccessRights, String> {
+    let key_name = bytebuf_to_blob(&key_name)?;
+    let key_id = (key_owner, key_name);
+    let caller = ic_cdk::caller();
+
+    let payment = KEY_MANAGER
+        .with_borrow(|km| km.start_purchase(caller, key_id, now()))?
+        .await?;
+    let purchase_id =
+        KEY_MANAGER.with_borrow_mut(|km| km.record_payment(caller, key_id, payment))?;
+    let result = KEY_MANAGER.with_borrow_mut(|km| km.complete_purchase(caller, purchase_id));
+    if result.is_err() {
+        refund(caller, purchase_id).await?;
+    }
+    result
+}
+
+/// Retries the refund of a purchase whose refund failed.
+#[update]
+async fn refund_purchase(purchase_id: PurchaseId) -> Result<Nat, String> {
+    refund(ic_cdk::caller(), purchase_id).await
+}
+
+#[query]
+fn get_pending_purchases() -> Vec<(PurchaseId, PendingPurchase)> {
+    KEY_MANAGER.with_borrow(|km| km.get_pending_purchases(ic_cdk::caller()))
+}
+
+async fn refund(caller: Principal, purchase_id: PurchaseId) -> Result<Nat, String> {
+    let refund = KEY_MANAGER
+        .with_borrow_mut(|km| km.refund_purchase(caller, purchase_id))?
+        .await;
+    KEY_MANAGER.with_borrow_mut(|km| km.record_refund(caller, purchase_id, &refund));
+    refund
+}
+
+#[update]
+async fn withdraw_proceeds(ledger: Principal, to: Account, amount: Nat) -> Result<Nat, String> {
+    ic_vetkd_cdk_key_manager::payments::withdraw_proceeds(ic_cdk::caller(), ledger, to, amount)
+        .await
+}
+
+#[update]
+#[allow(clippy::needless_pass_by_value)]
+fn freeze_key(
     key_owner: Principal,
     key_name: ByteBuf,
-    user: Principal,
-    access_rights: AccessRights,
-) -> Result<Option<AccessRights>, String> {
+    scope: FreezeScope,
+) -> Result<Option<Freeze>, String> {
     let key_name = bytebuf_to_blob(&key_name)?;
     let key_id = (key_owner, key_name);
-    KEY_MANAGER
-        .with_borrow_mut(|km| km.set_user_rights(ic_cdk::caller(), key_id, user, access_rights))
+    KEY_MANAGER.with_borrow_mut(|km| km.freeze_key(ic_cdk::caller(), key_id, scope))
+}
+
+#[update]
+#[allow(clippy::needless_pass_by_value)]
+fn unfreeze_key(key_owner: Principal, key_name: ByteBuf) -> Result<Option<Freeze>, String> {
+    let key_name = bytebuf_to_blob(&key_name)?;
+    let key_id = (key_owner, key_name);
+    KEY_MANAGER.with_borrow_mut(|km| km.unfreeze_key(ic_cdk::caller(), key_id))
+}
+
+#[update]
+fn freeze_canister(scope: Option<FreezeScope>) -> Result<Option<Freeze>, String> {
+    KEY_MANAGER.with_borrow_mut(|km| km.freeze_canister(ic_cdk::caller(), scope))
+}
+
+#[query]
+fn get_canister_freeze() -> Option<Freeze> {
+    KEY_MANAGER.with_borrow(KeyManager::get_canister_freeze)
+}
+
+#[query]
+fn get_admins() -> Vec<Principal> {
+    KEY_MANAGER.with_borrow(KeyManager::get_admins)
+}
+
+#[update]
+fn add_admin(admin: Principal) -> Result<(), String> {
+    KEY_MANAGER.with_borrow_mut(|km| km.add_admin(ic_cdk::caller(), admin))
+}
+
+#[update]
+fn remove_admin(admin: Principal) -> Result<bool, String> {
+    KEY_MANAGER.with_borrow_mut(|km| km.remove_admin(ic_cdk::caller(), admin))
 }
 
 #[update]
 #[allow(clippy::needless_pass_by_value)]
-fn remove_user(
+fn set_recovery_principal(
+    key_name: ByteBuf,
+    recovery_principal: Option<Principal>,
+) -> Result<Option<Principal>, String> {
+    let key_name = bytebuf_to_blob(&key_name)?;
+    let caller = ic_cdk::caller();
+    KEY_MANAGER.with_borrow_mut(|km| {
+        km.set_recovery_principal(caller, (caller, key_name), recovery_principal)
+    })
+}
+
+#[query]
+#[allow(clippy::needless_pass_by_value)]
+fn get_recovery_principal(
     key_owner: Principal,
     key_name: ByteBuf,
-    user: Principal,
-) -> Result<Option<AccessRights>, String> {
+) -> Result<Option<Principal>, String> {
+    let key_name = bytebuf_to_blob(&key_name)?;
+    KEY_MANAGER.with_borrow(|km| km.get_recovery_principal(ic_cdk::caller(), (key_owner, key_name)))
+}
+
+#[update]
+#[allow(clippy::needless_pass_by_value)]
+fn propose_recovery(
+    key_owner: Principal,
+    key_name: ByteBuf,
+    recovery_principal: Principal,
+) -> Result<RecoveryId, String> {
     let key_name = bytebuf_to_blob(&key_name)?;
     let key_id = (key_owner, key_name);
-    KEY_MANAGER.with_borrow_mut(|km| km.remove_user(ic_cdk::caller(), key_id, user))
+    KEY_MANAGER
+        .with_borrow_mut(|km| km.propose_recovery(ic_cdk::caller(), key_id, recovery_principal))
 }
 
+#[update]
+fn execute_recovery(recovery_id: RecoveryId) -> Result<Option<AccessRights>, String> {
+    KEY_MANAGER.with_borrow_mut(|km| {
+        km.execute_recovery(ic_cdk::caller(), recovery_id)
+            .map(|(_, previous)| previous)
+    })
+}
+
+#[update]
+fn cancel_recovery(recovery_id: RecoveryId) -> Result<Recovery, String> {
+    KEY_MANAGER.with_borrow_mut(|km| km.cancel_recovery(ic_cdk::caller(), recovery_id))
+}
+
+#[query]
+fn export_summary() -> Result<ExportSummary, String> {
+    KEY_MANAGER.with_borrow(|km| km.export_summary(ic_cdk::caller()))
+}
+
+#[query]
+fn export_chunk(cursor: Option<ExportCursor>, max_entries: u32) -> Result<ExportChunk, String> {
+    KEY_MANAGER.with_borrow(|km| km.export_chunk(ic_cdk::caller(), cursor, max_entries as usize))
+}
+
+#[update]
+fn begin_import(summary: ExportSummary) -> Result<(), String> {
+    KEY_MANAGER.with_borrow_mut(|km| km.begin_import(ic_cdk::caller(), summary))
+}
+
+#[update]
+fn import_chunk(chunk: ExportChunk) -> Result<(), String> {
+    KEY_MANAGER.with_borrow_mut(|km| km.import_chunk(ic_cdk::caller(), chunk))
+}
+
+#[update]
+fn finish_import() -> Result<(), String> {
+    KEY_MANAGER.with_borrow_mut(|km| km.finish_import(ic_cdk::caller()))
+}
+
+#[update]
+fn abort_import() -> Result<(), String> {
+    KEY_MANAGER.with_borrow_mut(|km| km.abort_import(ic_cdk::caller()))
+}
+
+ic_vetkd_cdk_key_manager::export_authorization_api!(KEY_MANAGER);
+
 #[cfg(feature = "expose-testing-api")]
 #[update]
 fn set_vetkd_testing_canister_id(vetkd_testing_canister: Principal) {
     ic_vetkd_cdk_key_manager::set_vetkd_testing_canister_id(vetkd_testing_canister)
 }
 
-fn bytebuf_to_blob(buf: &ByteBuf) -> Result<Blob<32>, String> {
+fn bytebuf_to_blob(buf: &ByteBuf) -> Result<Blob<MAX_NAME_BYTES>, String> {
     Blob::try_from(buf.as_ref()).map_err(|_| "too large input".to_string())
 }
 
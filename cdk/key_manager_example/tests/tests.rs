@@ -1,12 +1,21 @@
-use candid::{decode_one, encode_args, encode_one, CandidType, Principal};
+use candid::{decode_one, encode_args, encode_one, CandidType, Nat, Principal};
+use ic_vetkd_cdk_key_manager::authorization::AccessCheck;
+use ic_vetkd_cdk_key_manager::icrc::{Account, Subaccount};
+use ic_vetkd_cdk_key_manager::key_metadata::{KeyMetadata, KeyMetadataUpdate};
+use ic_vetkd_cdk_key_manager::payments::{
+    proceeds_subaccount, AccessPrice, PendingPurchase, PurchaseId,
+};
+use ic_vetkd_cdk_key_manager::policy::{Decision, DenyReason, Operation};
+use ic_vetkd_cdk_key_manager::token_gating::TokenGate;
 use ic_vetkd_cdk_key_manager::{VetKey, VetKeyVerificationKey};
 use ic_vetkd_cdk_test_utils::random_self_authenticating_principal;
-use ic_vetkd_cdk_types::{AccessRights, ByteBuf, Rights, TransportKey};
+use ic_vetkd_cdk_types::{AccessRights, ByteBuf, Permissions, Rights, TransportKey};
 use ic_vetkd_utils::TransportSecretKey;
 use pocket_ic::{PocketIc, PocketIcBuilder};
 use rand::{CryptoRng, Rng, SeedableRng};
 use rand_chacha::ChaCha20Rng;
 use std::path::Path;
+use std::time::Duration;
 
 pub fn reproducible_rng() -> ChaCha20Rng {
     let seed = rand::thread_rng().gen();
@@ -167,6 +176,472 @@ fn key_sharing_should_work() {
     assert_eq!(get_vetkey(env.principal_0), get_vetkey(env.principal_1));
 }
 
+#[test]
+fn token_holder_should_obtain_encrypted_vetkey() {
+    let rng = &mut reproducible_rng();
+    let env = TestEnvironment::new(rng);
+    let ledger = env.install_ledger(load_icrc7_ledger_mock_canister_wasm());
+
+    let key_owner = env.principal_0;
+    let holder = env.principal_1;
+    let buyer = random_self_authenticating_principal(rng);
+    let key_name = random_key_name(rng);
+    let token_id = candid::Nat::from(7u32);
+
+    let mint_result: Result<(), String> = env.update_canister(
+        ledger,
+        key_owner,
+        "mint",
+        encode_args((token_id.clone(), holder)).unwrap(),
+    );
+    mint_result.unwrap();
+
+    let gate = TokenGate {
+        ledger,
+        token_id: token_id.clone(),
+        access_rights: AccessRights::read_only(),
+    };
+    let previous_gate = env
+        .update::<Result<Option<TokenGate>, String>>(
+            key_owner,
+            "set_token_gate",
+            encode_args((key_owner, key_name.clone(), Some(gate.clone()))).unwrap(),
+        )
+        .unwrap();
+    assert_eq!(previous_gate, None);
+    assert_eq!(
+        env.query::<Result<Option<TokenGate>, String>>(
+            buyer,
+            "get_token_gate",
+            encode_args((key_owner, key_name.clone())).unwrap(),
+        ),
+        Ok(Some(gate))
+    );
+
+    let mut get_vetkey = |caller: Principal| -> Result<VetKey, String> {
+        let transport_key = random_transport_key(rng);
+        let transport_key_bytes = TransportKey::from(transport_key.public_key());
+        env.update::<Result<VetKey, String>>(
+            caller,
+            "get_encrypted_vetkey",
+            encode_args((key_owner, key_name.clone(), transport_key_bytes)).unwrap(),
+        )
+    };
+
+    assert!(get_vetkey(holder).is_ok());
+    assert_eq!(get_vetkey(buyer), Err("unauthorized".to_string()));
+
+    let transfer_result: Result<(), String> = env.update_canister(
+        ledger,
+        holder,
+        "transfer",
+        encode_args((token_id, buyer)).unwrap(),
+    );
+    transfer_result.unwrap();
+
+    // The previous holder is cached until the cache expires
+    assert!(get_vetkey(holder).is_ok());
+    assert_eq!(get_vetkey(buyer), Err("unauthorized".to_string()));
+
+    env.pic.advance_time(Duration::from_secs(5 * 60));
+    assert!(get_vetkey(buyer).is_ok());
+    assert_eq!(get_vetkey(holder), Err("unauthorized".to_string()));
+    assert!(get_vetkey(key_owner).is_ok());
+}
+
+#[derive(CandidType)]
+struct ApproveArgs {
+    spender: Account,
+    amount: Nat,
+}
+
+#[test]
+fn purchased_access_should_expire_and_failed_purchases_should_be_refunded() {
+    let rng = &mut reproducible_rng();
+    let env = TestEnvironment::new(rng);
+    let ledger = env.install_ledger(load_icrc2_ledger_mock_canister_wasm());
+
+    let key_owner = env.principal_0;
+    let buyer = env.principal_1;
+    let key_name = random_key_name(rng);
+    let proceeds = Account {
+        owner: env.example_canister_id,
+        subaccount: Some(Subaccount::from(proceeds_subaccount(key_owner).to_vec())),
+    };
+    let balance_of = |account: Account| -> Nat {
+        env.update_canister(
+            ledger,
+            key_owner,
+            "icrc1_balance_of",
+            encode_one(account).unwrap(),
+        )
+    };
+
+    let _: Nat = env.update_canister(
+        ledger,
+        key_owner,
+        "mint",
+        encode_args((Account::from(buyer), Nat::from(1_000u32))).unwrap(),
+    );
+
+    let price = AccessPrice {
+        ledger,
+        price: Nat::from(100u32),
+        duration: 60 * 60 * 1_000_000_000,
+        permissions: Permissions::READ_VALUES.union(Permissions::FETCH_VETKEY),
+    };
+    let set_price = |price: Option<AccessPrice>| -> Vec<u8> {
+        encode_args((key_owner, key_name.clone(), price)).unwrap()
+    };
+    let previous_price = env
+        .update::<Result<Option<AccessPrice>, String>>(
+            key_owner,
+            "set_access_price",
+            set_price(Some(price.clone())),
+        )
+        .unwrap();
+    assert_eq!(previous_price, None);
+
+    let purchase_args = encode_args((key_owner, key_name.clone())).unwrap();
+    let error = env
+        .update::<Result<AccessRights, String>>(buyer, "purchase_access", purchase_args.clone())
+        .unwrap_err();
+    assert!(error.contains("InsufficientAllowance"), "{error}");
+
+    let approve_result: Result<Nat, candid::Reserved> = env.update_canister(
+        ledger,
+        buyer,
+        "icrc2_approve",
+        encode_one(ApproveArgs {
+            spender: Account::from(env.example_canister_id),
+            amount: Nat::from(300u32),
+        })
+        .unwrap(),
+    );
+    assert!(approve_result.is_ok());
+
+    let access_rights = env
+        .update::<Result<AccessRights, String>>(buyer, "purchase_access", purchase_args.clone())
+        .unwrap();
+    assert_eq!(access_rights.permissions(), price.permissions);
+    assert!(access_rights.end().is_some());
+    assert_eq!(balance_of(Account::from(buyer)), Nat::from(880u32));
+    assert_eq!(balance_of(proceeds.clone()), Nat::from(100u32));
+
+    let mut get_vetkey = |caller: Principal| -> Result<VetKey, String> {
+        let transport_key = random_transport_key(rng);
+        let transport_key_bytes = TransportKey::from(transport_key.public_key());
+        env.update::<Result<VetKey, String>>(
+            caller,
+            "get_encrypted_vetkey",
+            encode_args((key_owner, key_name.clone(), transport_key_bytes)).unwrap(),
+        )
+    };
+    assert!(get_vetkey(buyer).is_ok());
+
+    // The price is removed while the payment is in flight, so the purchase
+    // fails after the payment and is refunded minus the fee.
+    let purchase = env
+        .pic
+        .submit_call(
+            env.example_canister_id,
+            buyer,
+            "purchase_access",
+            purchase_args,
+        )
+        .unwrap();
+    let remove_price = env
+        .pic
+        .submit_call(
+            env.example_canister_id,
+            key_owner,
+            "set_access_price",
+            set_price(None),
+        )
+        .unwrap();
+    let purchase_result: Result<AccessRights, String> =
+        decode_one(&env.pic.await_call(purchase).unwrap()).unwrap();
+    let _: Result<Option<AccessPrice>, String> =
+        decode_one(&env.pic.await_call(remove_price).unwrap()).unwrap();
+    assert_eq!(purchase_result, Err("access is not for sale".to_string()));
+    assert_eq!(balance_of(Account::from(buyer)), Nat::from(860u32));
+    assert_eq!(balance_of(proceeds.clone()), Nat::from(100u32));
+    // The refunded purchase is no longer pending
+    let pending: Vec<(PurchaseId, PendingPurchase)> =
+        env.query(buyer, "get_pending_purchases", encode_one(()).unwrap());
+    assert!(pending.is_empty());
+
+    let withdrawn: Result<Nat, String> = env.update(
+        key_owner,
+        "withdraw_proceeds",
+        encode_args((ledger, Account::from(key_owner), Nat::from(100u32))).unwrap(),
+    );
+    assert!(withdrawn.is_ok());
+    assert_eq!(balance_of(Account::from(key_owner)), Nat::from(90u32));
+    assert_eq!(balance_of(proceeds), Nat::from(0u32));
+
+    env.pic.advance_time(Duration::from_secs(60 * 60));
+    assert_eq!(get_vetkey(buyer), Err("unauthorized".to_string()));
+}
+
+#[test]
+fn allow_listed_canisters_should_check_access() {
+    let rng = &mut reproducible_rng();
+    let env = TestEnvironment::new(rng);
+    let service_canister_id = env.pic.create_canister();
+
+    let key_owner = env.principal_0;
+    let key_name = random_key_name(rng);
+    env.update::<Result<Option<AccessRights>, String>>(
+        key_owner,
+        "set_user_rights",
+        encode_args((
+            key_owner,
+            key_name.clone(),
+            env.principal_1,
+            AccessRights::read_only(),
+        ))
+        .unwrap(),
+    )
+    .unwrap();
+
+    let check = AccessCheck {
+        user: env.principal_1,
+        key_owner,
+        key_name,
+        operation: Operation::ReadValues,
+    };
+    assert_eq!(
+        env.query::<Result<Decision, String>>(
+            service_canister_id,
+            "check_access",
+            encode_one(check.clone()).unwrap(),
+        ),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        env.update::<Result<bool, String>>(
+            env.principal_1,
+            "add_authorized_canister",
+            encode_one(service_canister_id).unwrap(),
+        ),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        env.update::<Result<bool, String>>(
+            env.principal_0,
+            "add_authorized_canister",
+            encode_one(service_canister_id).unwrap(),
+        ),
+        Ok(true)
+    );
+
+    assert_eq!(
+        env.query::<Result<Decision, String>>(
+            service_canister_id,
+            "check_access",
+            encode_one(check.clone()).unwrap(),
+        ),
+        Ok(Decision::Allow(AccessRights::read_only()))
+    );
+    let insert = AccessCheck {
+        operation: Operation::Insert,
+        ..check
+    };
+    assert_eq!(
+        env.query::<Result<Decision, String>>(
+            service_canister_id,
+            "check_access",
+            encode_one(insert).unwrap(),
+        ),
+        Ok(Decision::Deny(DenyReason::MissingPermissions(
+            Permissions::INSERT
+        )))
+    );
+}
+
+#[test]
+fn shared_keys_should_be_listed_with_metadata() {
+    let rng = &mut reproducible_rng();
+    let env = TestEnvironment::new(rng);
+    let key_owner = env.principal_0;
+    let key_name = random_key_name(rng);
+    env.update::<Result<Option<AccessRights>, String>>(
+        key_owner,
+        "set_user_rights",
+        encode_args((
+            key_owner,
+            key_name.clone(),
+            env.principal_1,
+            AccessRights::read_only(),
+        ))
+        .unwrap(),
+    )
+    .unwrap();
+
+    let update = KeyMetadataUpdate {
+        label: Some("photos".to_string()),
+        ..KeyMetadataUpdate::default()
+    };
+    assert_eq!(
+        env.update::<Result<KeyMetadata, String>>(
+            env.principal_1,
+            "set_key_metadata",
+            encode_args((key_owner, key_name.clone(), update.clone())).unwrap(),
+        ),
+        Err("unauthorized".to_string())
+    );
+    let metadata = env
+        .update::<Result<KeyMetadata, String>>(
+            key_owner,
+            "set_key_metadata",
+            encode_args((key_owner, key_name.clone(), update)).unwrap(),
+        )
+        .unwrap();
+    assert_eq!(metadata.label, "photos");
+    assert_eq!(metadata.created_by, key_owner);
+
+    assert_eq!(
+        env.query::<Result<Option<KeyMetadata>, String>>(
+            env.principal_1,
+            "get_key_metadata",
+            encode_args((key_owner, key_name.clone())).unwrap(),
+        ),
+        Ok(Some(metadata.clone()))
+    );
+    assert_eq!(
+        env.query::<Vec<(Principal, ByteBuf, Option<KeyMetadata>)>>(
+            env.principal_1,
+            "get_accessible_shared_keys_with_metadata",
+            encode_one(()).unwrap(),
+        ),
+        vec![(key_owner, key_name, Some(metadata))]
+    );
+}
+
+#[test]
+fn keys_should_be_created_listed_and_deleted() {
+    let rng = &mut reproducible_rng();
+    let env = TestEnvironment::new(rng);
+    let key_owner = env.principal_0;
+    let key_name = random_key_name(rng);
+
+    assert_eq!(
+        env.update::<Result<(Principal, ByteBuf), String>>(
+            key_owner,
+            "create_key",
+            encode_one(key_name.clone()).unwrap(),
+        ),
+        Ok((key_owner, key_name.clone()))
+    );
+    env.update::<Result<Option<AccessRights>, String>>(
+        key_owner,
+        "set_user_rights",
+        encode_args((
+            key_owner,
+            key_name.clone(),
+            env.principal_1,
+            AccessRights::read_only(),
+        ))
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        env.query::<Vec<(Principal, ByteBuf)>>(
+            key_owner,
+            "list_owned_keys",
+            encode_one(()).unwrap()
+        ),
+        vec![(key_owner, key_name.clone())]
+    );
+
+    assert_eq!(
+        env.update::<Result<Vec<Principal>, String>>(
+            env.principal_1,
+            "delete_key",
+            encode_args((key_owner, key_name.clone())).unwrap(),
+        ),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        env.update::<Result<Vec<Principal>, String>>(
+            key_owner,
+            "delete_key",
+            encode_args((key_owner, key_name.clone())).unwrap(),
+        ),
+        Ok(vec![env.principal_1])
+    );
+    assert!(env
+        .query::<Vec<(Principal, ByteBuf)>>(key_owner, "list_owned_keys", encode_one(()).unwrap())
+        .is_empty());
+    assert!(env
+        .query::<Vec<(Principal, ByteBuf)>>(
+            env.principal_1,
+            "get_accessible_shared_key_ids",
+            encode_one(()).unwrap(),
+        )
+        .is_empty());
+}
+
+#[test]
+fn access_rights_should_be_migrated_when_upgrading_from_baseline() {
+    let rng = &mut reproducible_rng();
+    let env =
+        TestEnvironment::with_example_wasm(rng, load_baseline_key_manager_example_canister_wasm());
+    let key_owner = env.principal_0;
+    let key_names: Vec<ByteBuf> = (0..3).map(|_| random_key_name(rng)).collect();
+    for key_name in &key_names {
+        assert_eq!(
+            env.update::<Result<Option<AccessRights>, String>>(
+                key_owner,
+                "set_user_rights",
+                encode_args((
+                    key_owner,
+                    key_name.clone(),
+                    env.principal_1,
+                    AccessRights::read_write()
+                ))
+                .unwrap(),
+            ),
+            Ok(None)
+        );
+    }
+
+    env.pic
+        .upgrade_canister(
+            env.example_canister_id,
+            load_key_manager_example_canister_wasm(),
+            encode_one(()).unwrap(),
+            Some(env.principal_0),
+        )
+        .expect("failed to upgrade the example canister");
+    // The migration runs in timers started by `post_upgrade`.
+    fast_forward(&env.pic, 5);
+
+    for key_name in &key_names {
+        assert_eq!(
+            env.query::<Result<Option<AccessRights>, String>>(
+                key_owner,
+                "get_user_rights",
+                encode_args((key_owner, key_name.clone(), env.principal_1)).unwrap(),
+            ),
+            Ok(Some(AccessRights::read_write()))
+        );
+    }
+    let mut shared_key_ids = env.query::<Vec<(Principal, ByteBuf)>>(
+        env.principal_1,
+        "get_accessible_shared_key_ids",
+        encode_one(()).unwrap(),
+    );
+    shared_key_ids.sort();
+    let mut expected: Vec<_> = key_names
+        .into_iter()
+        .map(|key_name| (key_owner, key_name))
+        .collect();
+    expected.sort();
+    assert_eq!(shared_key_ids, expected);
+}
+
 struct TestEnvironment {
     pic: PocketIc,
     example_canister_id: Principal,
@@ -176,6 +651,10 @@ struct TestEnvironment {
 
 impl TestEnvironment {
     fn new<R: Rng + CryptoRng>(rng: &mut R) -> Self {
+        Self::with_example_wasm(rng, load_key_manager_example_canister_wasm())
+    }
+
+    fn with_example_wasm<R: Rng + CryptoRng>(rng: &mut R, example_wasm_bytes: Vec<u8>) -> Self {
         let pic = PocketIcBuilder::new()
             .with_application_subnet()
             .with_ii_subnet()
@@ -188,11 +667,18 @@ impl TestEnvironment {
         let vetkd_mock_wasm_bytes = load_vetkd_mock_canister_wasm();
         pic.install_canister(vetkd_mock_canister_id, vetkd_mock_wasm_bytes, vec![], None);
 
-        let example_canister_id = pic.create_canister();
+        // Without admins in the init arguments, the controller `principal_0`
+        // is an admin.
+        let principal_0 = random_self_authenticating_principal(rng);
+        let example_canister_id = pic.create_canister_with_settings(Some(principal_0), None);
         pic.add_cycles(example_canister_id, 2_000_000_000_000);
 
-        let example_wasm_bytes = load_key_manager_example_canister_wasm();
-        pic.install_canister(example_canister_id, example_wasm_bytes, vec![], None);
+        pic.install_canister(
+            example_canister_id,
+            example_wasm_bytes,
+            encode_one(None::<Vec<Principal>>).unwrap(),
+            Some(principal_0),
+        );
 
         // Make sure the canister is properly initialized
         fast_forward(&pic, 5);
@@ -200,7 +686,7 @@ impl TestEnvironment {
         let env = Self {
             pic,
             example_canister_id,
-            principal_0: random_self_authenticating_principal(rng),
+            principal_0,
             principal_1: random_self_authenticating_principal(rng),
         };
 
@@ -215,15 +701,31 @@ impl TestEnvironment {
         env
     }
 
+    fn install_ledger(&self, wasm_bytes: Vec<u8>) -> Principal {
+        let ledger_canister_id = self.pic.create_canister();
+        self.pic.add_cycles(ledger_canister_id, 2_000_000_000_000);
+        self.pic
+            .install_canister(ledger_canister_id, wasm_bytes, vec![], None);
+        ledger_canister_id
+    }
+
     fn update<T: CandidType + for<'de> candid::Deserialize<'de>>(
         &self,
         caller: Principal,
         method_name: &str,
         args: Vec<u8>,
     ) -> T {
-        let reply = self
-            .pic
-            .update_call(self.example_canister_id, caller, method_name, args);
+        self.update_canister(self.example_canister_id, caller, method_name, args)
+    }
+
+    fn update_canister<T: CandidType + for<'de> candid::Deserialize<'de>>(
+        &self,
+        canister_id: Principal,
+        caller: Principal,
+        method_name: &str,
+        args: Vec<u8>,
+    ) -> T {
+        let reply = self.pic.update_call(canister_id, caller, method_name, args);
         match reply {
             Ok(data) => decode_one(&data).expect("failed to decode reply"),
             Err(user_error) => panic!("canister returned a user error: {user_error}"),
@@ -257,6 +759,34 @@ fn load_key_manager_example_canister_wasm() -> Vec<u8> {
     wasm_bytes
 }
 
+/// Loads the example canister built from the baseline revision, whose maps
+/// use the layout before schema version 2.
+fn load_baseline_key_manager_example_canister_wasm() -> Vec<u8> {
+    let wasm_path = Path::new(
+        "../../target/baseline/wasm32-unknown-unknown/release/ic_vetkd_cdk_key_manager_example.wasm",
+    );
+    std::fs::read(wasm_path)
+        .expect("baseline wasm does not exist - run `make compile-baseline-wasm`")
+}
+
+fn load_icrc7_ledger_mock_canister_wasm() -> Vec<u8> {
+    let wasm_path = Path::new(
+        "../../target/wasm32-unknown-unknown/release/ic_vetkd_cdk_icrc7_ledger_mock.wasm",
+    );
+    std::fs::read(wasm_path).expect(
+        "wasm does not exist - run `cargo build --release --target wasm32-unknown-unknown -p ic-vetkd-cdk-icrc7-ledger-mock`",
+    )
+}
+
+fn load_icrc2_ledger_mock_canister_wasm() -> Vec<u8> {
+    let wasm_path = Path::new(
+        "../../target/wasm32-unknown-unknown/release/ic_vetkd_cdk_icrc2_ledger_mock.wasm",
+    );
+    std::fs::read(wasm_path).expect(
+        "wasm does not exist - run `cargo build --release --target wasm32-unknown-unknown -p ic-vetkd-cdk-icrc2-ledger-mock`",
+    )
+}
+
 fn load_vetkd_mock_canister_wasm() -> Vec<u8> {
     let wasm_url = "https://github.com/dfinity/chainkey-testing-canister/releases/download/v0.1.0/chainkey_testing_canister.wasm.gz";
     reqwest::blocking::get(wasm_url)
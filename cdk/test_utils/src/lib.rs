@@ -2,7 +2,7 @@ use std::{convert::TryInto, ops::Range};
 
 use candid::Principal;
 use ic_stable_structures::storable::Blob;
-use ic_vetkd_cdk_types::{AccessRights, ByteBuf, KeyName};
+use ic_vetkd_cdk_types::{AccessRights, ByteBuf, KeyName, MapKey};
 use rand::{CryptoRng, Rng, SeedableRng};
 use rand_chacha::ChaCha20Rng;
 use std::convert::TryFrom;
@@ -60,7 +60,7 @@ pub fn random_bytebuf<R: Rng + CryptoRng>(rng: &mut R, range: Range<usize>) -> B
     ByteBuf::from(result)
 }
 
-pub fn random_key<R: Rng + CryptoRng>(rng: &mut R) -> Blob<32> {
+pub fn random_key<R: Rng + CryptoRng>(rng: &mut R) -> MapKey {
     random_blob(rng)
 }
 
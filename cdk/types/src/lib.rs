@@ -1,4 +1,5 @@
 use std::borrow::Cow;
+use std::ops::{BitAnd, BitOr};
 
 use candid::{CandidType, Decode, Encode};
 use ic_stable_structures::{
@@ -7,11 +8,14 @@ use ic_stable_structures::{
 };
 use serde::Deserialize;
 
-pub type KeyName = Blob<32>;
+/// The maximum length in bytes of key names and map keys.
+pub const MAX_NAME_BYTES: usize = 256;
+
+pub type KeyName = Blob<MAX_NAME_BYTES>;
 pub type MapName = KeyName;
 pub type MapId = KeyId;
 pub type KeyId = (candid::Principal, KeyName);
-pub type MapKey = Blob<32>;
+pub type MapKey = Blob<MAX_NAME_BYTES>;
 pub type TransportKey = ByteBuf;
 pub type EncryptedMapValue = ByteBuf;
 
@@ -35,11 +39,158 @@ pub enum Rights {
     ReadWriteManage = 2,
 }
 
+impl Rights {
+    /// Returns the permission set this preset level stands for.
+    #[must_use]
+    pub const fn permissions(self) -> Permissions {
+        match self {
+            Self::Read => Permissions::READ_PRESET,
+            Self::ReadWrite => Permissions::READ_WRITE_PRESET,
+            Self::ReadWriteManage => Permissions::READ_WRITE_MANAGE_PRESET,
+        }
+    }
+
+    /// Returns the highest preset level whose permissions are all contained in
+    /// `permissions`, or `Read` if not even the read preset is contained.
+    #[must_use]
+    pub const fn closest_preset(permissions: Permissions) -> Self {
+        if permissions.contains(Permissions::READ_WRITE_MANAGE_PRESET) {
+            Self::ReadWriteManage
+        } else if permissions.contains(Permissions::READ_WRITE_PRESET) {
+            Self::ReadWrite
+        } else {
+            Self::Read
+        }
+    }
+}
+
+/// A set of fine-grained permissions on a key or map, stored as bitflags.
+#[derive(
+    CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Default,
+)]
+pub struct Permissions {
+    bits: u16,
+}
+
+impl Permissions {
+    /// Read the (encrypted) values stored in a map.
+    pub const READ_VALUES: Self = Self::from_bits_truncate(1 << 0);
+    /// Obtain the encrypted vetkey for a key.
+    pub const FETCH_VETKEY: Self = Self::from_bits_truncate(1 << 1);
+    /// Insert new values into a map.
+    pub const INSERT: Self = Self::from_bits_truncate(1 << 2);
+    /// Overwrite existing values in a map.
+    pub const UPDATE: Self = Self::from_bits_truncate(1 << 3);
+    /// Remove values from a map, both soft and hard.
+    pub const DELETE: Self = Self::from_bits_truncate(1 << 4);
+    /// Restore soft-deleted values from tombstones.
+    pub const RESTORE: Self = Self::from_bits_truncate(1 << 5);
+    /// Permanently purge tombstones.
+    pub const PURGE: Self = Self::from_bits_truncate(1 << 6);
+    /// Grant and revoke access for other users.
+    pub const SHARE: Self = Self::from_bits_truncate(1 << 7);
+    /// View the audit log.
+    pub const VIEW_AUDIT: Self = Self::from_bits_truncate(1 << 8);
+    /// Manage the key itself, e.g., delegate management rights.
+    pub const MANAGE: Self = Self::from_bits_truncate(1 << 9);
+
+    const ALL_BITS: u16 = (1 << 10) - 1;
+
+    /// Permissions of the [`Rights::Read`] preset.
+    pub const READ_PRESET: Self = Self::READ_VALUES
+        .union(Self::FETCH_VETKEY)
+        .union(Self::VIEW_AUDIT);
+    /// Permissions of the [`Rights::ReadWrite`] preset.
+    pub const READ_WRITE_PRESET: Self = Self::READ_PRESET
+        .union(Self::INSERT)
+        .union(Self::UPDATE)
+        .union(Self::DELETE)
+        .union(Self::RESTORE);
+    /// Permissions of the [`Rights::ReadWriteManage`] preset.
+    pub const READ_WRITE_MANAGE_PRESET: Self = Self::all();
+
+    #[must_use]
+    pub const fn empty() -> Self {
+        Self { bits: 0 }
+    }
+
+    #[must_use]
+    pub const fn all() -> Self {
+        Self {
+            bits: Self::ALL_BITS,
+        }
+    }
+
+    /// Creates a permission set from raw bits, dropping unknown bits.
+    #[must_use]
+    pub const fn from_bits_truncate(bits: u16) -> Self {
+        Self {
+            bits: bits & Self::ALL_BITS,
+        }
+    }
+
+    #[must_use]
+    pub const fn bits(self) -> u16 {
+        self.bits
+    }
+
+    #[must_use]
+    pub const fn is_empty(self) -> bool {
+        self.bits == 0
+    }
+
+    /// Returns true if all permissions in `other` are contained in `self`.
+    #[must_use]
+    pub const fn contains(self, other: Self) -> bool {
+        self.bits & other.bits == other.bits
+    }
+
+    #[must_use]
+    pub const fn union(self, other: Self) -> Self {
+        Self {
+            bits: self.bits | other.bits,
+        }
+    }
+
+    #[must_use]
+    pub const fn intersection(self, other: Self) -> Self {
+        Self {
+            bits: self.bits & other.bits,
+        }
+    }
+}
+
+impl BitOr for Permissions {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        self.union(rhs)
+    }
+}
+
+impl BitAnd for Permissions {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        self.intersection(rhs)
+    }
+}
+
+impl From<Rights> for Permissions {
+    fn from(rights: Rights) -> Self {
+        rights.permissions()
+    }
+}
+
 #[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub struct AccessRights {
+    /// The preset level of these rights. If `permissions` is set, this is the
+    /// closest preset, kept for clients that are not aware of permissions.
     pub rights: Rights,
     pub start: Option<u64>,
     pub end: Option<u64>,
+    /// Fine-grained permissions overriding the preset given by `rights`.
+    pub permissions: Option<Permissions>,
 }
 
 impl Default for AccessRights {
@@ -48,6 +199,7 @@ impl Default for AccessRights {
             rights: Rights::Read,
             start: None,
             end: None,
+            permissions: None,
         }
     }
 }
@@ -78,12 +230,29 @@ impl AccessRights {
         self.end
     }
 
+    /// Returns the effective permissions, i.e., the explicit permissions if
+    /// set and the permissions of the preset otherwise.
+    #[must_use]
+    pub const fn permissions(&self) -> Permissions {
+        match self.permissions {
+            Some(permissions) => permissions,
+            None => self.rights.permissions(),
+        }
+    }
+
+    /// Returns true if the effective permissions contain all of `permissions`.
+    #[must_use]
+    pub const fn has(&self, permissions: Permissions) -> bool {
+        self.permissions().contains(permissions)
+    }
+
     #[must_use]
     pub const fn read_only() -> Self {
         Self {
             rights: Rights::Read,
             start: None,
             end: None,
+            permissions: None,
         }
     }
 
@@ -93,6 +262,7 @@ impl AccessRights {
             rights: Rights::ReadWrite,
             start: None,
             end: None,
+            permissions: None,
         }
     }
 
@@ -102,6 +272,7 @@ impl AccessRights {
             rights: Rights::ReadWriteManage,
             start: None,
             end: None,
+            permissions: None,
         }
     }
 
@@ -119,38 +290,136 @@ impl AccessRights {
                 "start time must be before or equal to end time"
             );
         }
-        Self { rights, start, end }
+        Self {
+            rights,
+            start,
+            end,
+            permissions: None,
+        }
+    }
+
+    /// Creates a new `AccessRights` with fine-grained permissions and optional
+    /// start/end times. The preset `rights` is set to the closest preset.
+    ///
+    /// # Panics
+    ///
+    /// Panics if both start and end times are provided and start time is greater than end time.
+    #[must_use]
+    pub fn with_permissions(
+        permissions: Permissions,
+        start: Option<u64>,
+        end: Option<u64>,
+    ) -> Self {
+        Self {
+            permissions: Some(permissions),
+            ..Self::new(Rights::closest_preset(permissions), start, end)
+        }
     }
 }
 
-impl Storable for AccessRights {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        let rights = self.rights as u8;
-        let start = self.start.unwrap_or(0);
-        let end: u64 = self.end.unwrap_or(0);
-        let mut bytes = [0u8; 1 + 8 + 8];
-        bytes[0] = rights;
-        bytes[1..9].copy_from_slice(&start.to_le_bytes()); // or .to_be_bytes()
-        bytes[9..].copy_from_slice(&end.to_le_bytes()); // or .to_be_bytes()
-        Cow::Owned(bytes.to_vec())
+/// First byte of every versioned encoding. Encodings written before versioning
+/// was introduced are either Candid, which starts with `DIDL`, or the unversioned
+/// `AccessRights` layouts, which start with the `Rights` discriminant, so they
+/// never start with this byte.
+pub const VERSIONED_ENCODING_TAG: u8 = 0xFF;
+
+/// Prefixes `payload` with [`VERSIONED_ENCODING_TAG`] and `version`.
+#[must_use]
+pub fn encode_versioned(version: u8, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(2 + payload.len());
+    bytes.push(VERSIONED_ENCODING_TAG);
+    bytes.push(version);
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+/// Splits stored bytes into the encoding version and the payload. Bytes
+/// written before encodings were versioned are returned as version 0.
+#[must_use]
+pub fn decode_versioned(bytes: &[u8]) -> (u8, &[u8]) {
+    match bytes {
+        [VERSIONED_ENCODING_TAG, version, payload @ ..] => (*version, payload),
+        _ => (0, bytes),
     }
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        let bytes = bytes.as_ref();
-        assert!(bytes.len() == 17, "Invalid format: expected 17 bytes");
-        let rights = bytes[0];
+impl AccessRights {
+    /// Size of the unversioned encoding used before fine-grained permissions were introduced.
+    const V0_ENCODING_BYTES: usize = 1 + 8 + 8;
+    /// Version of the current encoding.
+    const ENCODING_VERSION: u8 = 2;
+    /// Size of the current payload: presence flags, rights, start, end and permission bits.
+    const ENCODING_PAYLOAD_BYTES: usize = 1 + 1 + 8 + 8 + 2;
+
+    const HAS_START: u8 = 1 << 0;
+    const HAS_END: u8 = 1 << 1;
+    const HAS_PERMISSIONS: u8 = 1 << 2;
+
+    /// Decodes the unversioned layout, where a zero timestamp means "no bound".
+    fn from_unversioned_bytes(bytes: &[u8]) -> Self {
         let start = u64::from_le_bytes(bytes[1..9].try_into().unwrap());
         let end = u64::from_le_bytes(bytes[9..17].try_into().unwrap());
         Self {
-            rights: Rights::from_repr(rights).unwrap(),
+            rights: Rights::from_repr(bytes[0]).unwrap(),
             start: if start != 0 { Some(start) } else { None },
             end: if end != 0 { Some(end) } else { None },
+            permissions: None,
+        }
+    }
+}
+
+impl Storable for AccessRights {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut flags = 0;
+        let mut payload = [0u8; Self::ENCODING_PAYLOAD_BYTES];
+        payload[1] = self.rights as u8;
+        if let Some(start) = self.start {
+            flags |= Self::HAS_START;
+            payload[2..10].copy_from_slice(&start.to_le_bytes());
+        }
+        if let Some(end) = self.end {
+            flags |= Self::HAS_END;
+            payload[10..18].copy_from_slice(&end.to_le_bytes());
+        }
+        if let Some(permissions) = self.permissions {
+            flags |= Self::HAS_PERMISSIONS;
+            payload[18..20].copy_from_slice(&permissions.bits().to_le_bytes());
         }
+        payload[0] = flags;
+        Cow::Owned(encode_versioned(Self::ENCODING_VERSION, &payload))
     }
-    // Self::from_repr(v).unwrap()
+
+    /// Decodes the current encoding as well as the unversioned layout of 17
+    /// bytes. Entries without explicit permissions use their preset.
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let bytes = bytes.as_ref();
+        if bytes.len() == Self::V0_ENCODING_BYTES {
+            return Self::from_unversioned_bytes(bytes);
+        }
+        let (version, payload) = decode_versioned(bytes);
+        assert!(
+            version == Self::ENCODING_VERSION && payload.len() == Self::ENCODING_PAYLOAD_BYTES,
+            "Invalid format: unsupported AccessRights encoding"
+        );
+        let flags = payload[0];
+        let field = |flag: u8, range: std::ops::Range<usize>| {
+            (flags & flag != 0).then(|| u64::from_le_bytes(payload[range].try_into().unwrap()))
+        };
+        Self {
+            rights: Rights::from_repr(payload[1]).unwrap(),
+            start: field(Self::HAS_START, 2..10),
+            end: field(Self::HAS_END, 10..18),
+            permissions: (flags & Self::HAS_PERMISSIONS != 0).then(|| {
+                Permissions::from_bits_truncate(u16::from_le_bytes(
+                    payload[18..20].try_into().unwrap(),
+                ))
+            }),
+        }
+    }
+
     const BOUND: Bound = Bound::Bounded {
-        max_size: 17,
-        is_fixed_size: true,
+        max_size: 2 + Self::ENCODING_PAYLOAD_BYTES as u32,
+        is_fixed_size: false,
     };
 }
 
@@ -191,12 +460,17 @@ impl Default for ByteBuf {
     }
 }
 
+/// Stored as the raw bytes since encoding version 1 and as Candid before.
 impl Storable for ByteBuf {
     fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+        Cow::Owned(encode_versioned(1, &self.inner))
     }
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        match decode_versioned(bytes.as_ref()) {
+            (0, payload) => Decode!(payload, Self).unwrap(),
+            (1, payload) => Self::from(payload.to_vec()),
+            (version, _) => panic!("unsupported ByteBuf encoding version {version}"),
+        }
     }
     const BOUND: Bound = Bound::Unbounded;
 }
@@ -235,6 +509,48 @@ pub enum AuditEntryType {
     SoftDeleted = 7,
     /// A soft-deleted resource was restored
     Restored = 8,
+    /// A grant or approver set change requiring multi-party approval was proposed
+    ApprovalRequested = 9,
+    /// An approver signed off a pending grant
+    ApprovalGranted = 10,
+    /// A pending grant was rejected by an approver
+    ApprovalRejected = 11,
+    /// A pending grant expired before reaching its approval threshold
+    ApprovalExpired = 12,
+    /// The approver set of a key was changed
+    ApprovalConfigChanged = 13,
+    /// A user asked for access to a resource
+    AccessRequested = 14,
+    /// A pending access request was approved
+    AccessRequestApproved = 15,
+    /// A pending access request was denied
+    AccessRequestDenied = 16,
+    /// An invitation code was created
+    InviteCreated = 17,
+    /// An invitation code was redeemed
+    InviteRedeemed = 18,
+    /// An invitation code was revoked
+    InviteRevoked = 19,
+    /// A resource was bound to a token or the binding was removed
+    TokenGateChanged = 20,
+    /// Access to a resource was purchased
+    AccessPurchased = 21,
+    /// The payment for a purchase of access was refunded
+    PurchaseRefunded = 22,
+    /// Access to a resource, or to all resources, was frozen
+    Frozen = 23,
+    /// A freeze was lifted
+    Unfrozen = 24,
+    /// An administrator proposed to grant a recovery principal access to a resource
+    RecoveryProposed = 25,
+    /// A recovery was executed, granting the recovery principal access
+    RecoveryExecuted = 26,
+    /// A pending recovery was cancelled
+    RecoveryCancelled = 27,
+    /// The owner of a resource registered or removed its recovery principal
+    RecoveryPrincipalChanged = 28,
+    /// The refund of the payment for a purchase of access failed and can be retried
+    RefundFailed = 29,
 }
 
 #[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
@@ -244,19 +560,27 @@ pub struct AuditEntry {
     pub caller: candid::Principal,
     pub user: Option<candid::Principal>,
     pub access_rights: Option<AccessRights>,
+    /// Identifier of the proposal, request or invite this entry refers to, if any
+    pub reference_id: Option<u64>,
 }
 
+/// Stored as Candid, prefixed with the encoding version since version 1.
 impl Storable for AuditEntry {
     fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(&self).expect("Failed to encode AuditEntry"))
+        let payload = Encode!(&self).expect("Failed to encode AuditEntry");
+        Cow::Owned(encode_versioned(1, &payload))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(&bytes, AuditEntry).expect("Failed to decode AuditEntry")
+        match decode_versioned(bytes.as_ref()) {
+            (0 | 1, payload) => Decode!(payload, AuditEntry).expect("Failed to decode AuditEntry"),
+            (version, _) => panic!("unsupported AuditEntry encoding version {version}"),
+        }
     }
 
+    // The Candid type table alone takes up a large part of the encoding.
     const BOUND: Bound = Bound::Bounded {
-        max_size: 256,
+        max_size: 1024,
         is_fixed_size: false,
     };
 }
@@ -264,15 +588,22 @@ impl Storable for AuditEntry {
 #[derive(Debug, Clone, PartialEq, Eq, Default, CandidType, Deserialize)]
 pub struct AuditLog(pub Vec<AuditEntry>);
 
+/// Stored as Candid, prefixed with the encoding version since version 1.
 impl Storable for AuditLog {
     fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(&self.0).expect("failed to encode AuditLog"))
+        let payload = Encode!(&self.0).expect("failed to encode AuditLog");
+        Cow::Owned(encode_versioned(1, &payload))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        let vec: Vec<AuditEntry> =
-            Decode!(&bytes, Vec<AuditEntry>).expect("failed to decode AuditLog");
-        AuditLog(vec)
+        match decode_versioned(bytes.as_ref()) {
+            (0 | 1, payload) => {
+                let vec: Vec<AuditEntry> =
+                    Decode!(payload, Vec<AuditEntry>).expect("failed to decode AuditLog");
+                AuditLog(vec)
+            }
+            (version, _) => panic!("unsupported AuditLog encoding version {version}"),
+        }
     }
 
     const BOUND: Bound = Bound::Unbounded;
@@ -293,8 +624,16 @@ impl AuditEntry {
             caller,
             user,
             access_rights,
+            reference_id: None,
         }
     }
+
+    /// Links this entry to a proposal, request or invite
+    #[must_use]
+    pub fn with_reference_id(mut self, reference_id: u64) -> Self {
+        self.reference_id = Some(reference_id);
+        self
+    }
     pub fn audit_type(&self) -> AuditEntryType {
         self.audit_type
     }
@@ -310,6 +649,9 @@ impl AuditEntry {
     pub fn access_rights(&self) -> Option<AccessRights> {
         self.access_rights
     }
+    pub fn reference_id(&self) -> Option<u64> {
+        self.reference_id
+    }
 
     /// A new resource was created
     pub fn created(timestamp: u64, caller: candid::Principal) -> Self {
@@ -348,7 +690,11 @@ impl AuditEntry {
     }
 
     /// A VET key was accessed by the owner or a user with rights
-    pub fn access_vet_key(timestamp: u64, caller: candid::Principal, access_rights: AccessRights) -> Self {
+    pub fn access_vet_key(
+        timestamp: u64,
+        caller: candid::Principal,
+        access_rights: AccessRights,
+    ) -> Self {
         Self::new(
             AuditEntryType::AccessVetKey,
             timestamp,
@@ -372,18 +718,318 @@ impl AuditEntry {
             Some(access_rights),
         )
     }
-    
+
     /// A resource was marked as logically deleted but preserved for audit purposes
     pub fn soft_deleted(timestamp: u64, caller: candid::Principal) -> Self {
         Self::new(AuditEntryType::SoftDeleted, timestamp, caller, None, None)
     }
-    
+
     /// A soft-deleted resource was restored
     pub fn restored(timestamp: u64, caller: candid::Principal) -> Self {
         Self::new(AuditEntryType::Restored, timestamp, caller, None, None)
     }
+
+    /// A grant requiring multi-party approval was proposed
+    pub fn approval_requested(
+        timestamp: u64,
+        caller: candid::Principal,
+        user: candid::Principal,
+        access_rights: AccessRights,
+        proposal_id: u64,
+    ) -> Self {
+        Self::new(
+            AuditEntryType::ApprovalRequested,
+            timestamp,
+            caller,
+            Some(user),
+            Some(access_rights),
+        )
+        .with_reference_id(proposal_id)
+    }
+
+    /// A change of the approver set requiring multi-party approval was proposed
+    pub fn approval_config_proposed(
+        timestamp: u64,
+        caller: candid::Principal,
+        proposal_id: u64,
+    ) -> Self {
+        Self::new(
+            AuditEntryType::ApprovalRequested,
+            timestamp,
+            caller,
+            None,
+            None,
+        )
+        .with_reference_id(proposal_id)
+    }
+
+    /// An approver signed off a pending grant
+    pub fn approval_granted(timestamp: u64, caller: candid::Principal, proposal_id: u64) -> Self {
+        Self::new(
+            AuditEntryType::ApprovalGranted,
+            timestamp,
+            caller,
+            None,
+            None,
+        )
+        .with_reference_id(proposal_id)
+    }
+
+    /// A pending grant was rejected by an approver
+    pub fn approval_rejected(timestamp: u64, caller: candid::Principal, proposal_id: u64) -> Self {
+        Self::new(
+            AuditEntryType::ApprovalRejected,
+            timestamp,
+            caller,
+            None,
+            None,
+        )
+        .with_reference_id(proposal_id)
+    }
+
+    /// A pending grant expired before reaching its approval threshold
+    pub fn approval_expired(timestamp: u64, caller: candid::Principal, proposal_id: u64) -> Self {
+        Self::new(
+            AuditEntryType::ApprovalExpired,
+            timestamp,
+            caller,
+            None,
+            None,
+        )
+        .with_reference_id(proposal_id)
+    }
+
+    /// The approver set of a key was changed
+    pub fn approval_config_changed(timestamp: u64, caller: candid::Principal) -> Self {
+        Self::new(
+            AuditEntryType::ApprovalConfigChanged,
+            timestamp,
+            caller,
+            None,
+            None,
+        )
+    }
+
+    /// A user asked for access to a resource
+    pub fn access_requested(
+        timestamp: u64,
+        caller: candid::Principal,
+        access_rights: AccessRights,
+    ) -> Self {
+        Self::new(
+            AuditEntryType::AccessRequested,
+            timestamp,
+            caller,
+            None,
+            Some(access_rights),
+        )
+    }
+
+    /// A pending access request was approved
+    pub fn access_request_approved(
+        timestamp: u64,
+        caller: candid::Principal,
+        user: candid::Principal,
+        access_rights: AccessRights,
+    ) -> Self {
+        Self::new(
+            AuditEntryType::AccessRequestApproved,
+            timestamp,
+            caller,
+            Some(user),
+            Some(access_rights),
+        )
+    }
+
+    /// A pending access request was denied
+    pub fn access_request_denied(
+        timestamp: u64,
+        caller: candid::Principal,
+        user: candid::Principal,
+    ) -> Self {
+        Self::new(
+            AuditEntryType::AccessRequestDenied,
+            timestamp,
+            caller,
+            Some(user),
+            None,
+        )
+    }
+
+    /// An invitation code was created
+    pub fn invite_created(
+        timestamp: u64,
+        caller: candid::Principal,
+        access_rights: AccessRights,
+        invite_id: u64,
+    ) -> Self {
+        Self::new(
+            AuditEntryType::InviteCreated,
+            timestamp,
+            caller,
+            None,
+            Some(access_rights),
+        )
+        .with_reference_id(invite_id)
+    }
+
+    /// An invitation code was redeemed
+    pub fn invite_redeemed(
+        timestamp: u64,
+        caller: candid::Principal,
+        access_rights: AccessRights,
+        invite_id: u64,
+    ) -> Self {
+        Self::new(
+            AuditEntryType::InviteRedeemed,
+            timestamp,
+            caller,
+            None,
+            Some(access_rights),
+        )
+        .with_reference_id(invite_id)
+    }
+
+    /// An invitation code was revoked
+    pub fn invite_revoked(timestamp: u64, caller: candid::Principal, invite_id: u64) -> Self {
+        Self::new(AuditEntryType::InviteRevoked, timestamp, caller, None, None)
+            .with_reference_id(invite_id)
+    }
+
+    /// A resource was bound to a token with the given access rights for its
+    /// holder, or the binding was removed
+    pub fn token_gate_changed(
+        timestamp: u64,
+        caller: candid::Principal,
+        access_rights: Option<AccessRights>,
+    ) -> Self {
+        Self::new(
+            AuditEntryType::TokenGateChanged,
+            timestamp,
+            caller,
+            None,
+            access_rights,
+        )
+    }
+
+    /// Access to a resource was purchased, granting the given access rights
+    pub fn access_purchased(
+        timestamp: u64,
+        caller: candid::Principal,
+        access_rights: AccessRights,
+    ) -> Self {
+        Self::new(
+            AuditEntryType::AccessPurchased,
+            timestamp,
+            caller,
+            None,
+            Some(access_rights),
+        )
+    }
+
+    /// The payment for a purchase of access was refunded
+    pub fn purchase_refunded(timestamp: u64, caller: candid::Principal) -> Self {
+        Self::new(
+            AuditEntryType::PurchaseRefunded,
+            timestamp,
+            caller,
+            None,
+            None,
+        )
+    }
+
+    /// The refund of the payment for a purchase of access failed
+    pub fn refund_failed(timestamp: u64, caller: candid::Principal) -> Self {
+        Self::new(AuditEntryType::RefundFailed, timestamp, caller, None, None)
+    }
+
+    /// Access to a resource, or to all resources, was frozen
+    pub fn frozen(timestamp: u64, caller: candid::Principal) -> Self {
+        Self::new(AuditEntryType::Frozen, timestamp, caller, None, None)
+    }
+
+    /// A freeze was lifted
+    pub fn unfrozen(timestamp: u64, caller: candid::Principal) -> Self {
+        Self::new(AuditEntryType::Unfrozen, timestamp, caller, None, None)
+    }
+
+    /// An administrator proposed to grant a recovery principal access to a resource
+    pub fn recovery_proposed(
+        timestamp: u64,
+        caller: candid::Principal,
+        user: candid::Principal,
+        recovery_id: u64,
+    ) -> Self {
+        Self::new(
+            AuditEntryType::RecoveryProposed,
+            timestamp,
+            caller,
+            Some(user),
+            None,
+        )
+        .with_reference_id(recovery_id)
+    }
+
+    /// A recovery was executed, granting the recovery principal the given access rights
+    pub fn recovery_executed(
+        timestamp: u64,
+        caller: candid::Principal,
+        user: candid::Principal,
+        access_rights: AccessRights,
+        recovery_id: u64,
+    ) -> Self {
+        Self::new(
+            AuditEntryType::RecoveryExecuted,
+            timestamp,
+            caller,
+            Some(user),
+            Some(access_rights),
+        )
+        .with_reference_id(recovery_id)
+    }
+
+    /// A pending recovery was cancelled
+    pub fn recovery_cancelled(timestamp: u64, caller: candid::Principal, recovery_id: u64) -> Self {
+        Self::new(
+            AuditEntryType::RecoveryCancelled,
+            timestamp,
+            caller,
+            None,
+            None,
+        )
+        .with_reference_id(recovery_id)
+    }
+
+    /// The owner of a resource registered the given recovery principal, or removed it
+    pub fn recovery_principal_changed(
+        timestamp: u64,
+        caller: candid::Principal,
+        user: Option<candid::Principal>,
+    ) -> Self {
+        Self::new(
+            AuditEntryType::RecoveryPrincipalChanged,
+            timestamp,
+            caller,
+            user,
+            None,
+        )
+    }
 }
 
+// The mocks below replace system API calls for tests on the host and must
+// never end up in a canister.
+#[cfg(all(
+    target_arch = "wasm32",
+    any(
+        feature = "mock-controllers",
+        feature = "mock-rand",
+        feature = "mock-time"
+    )
+))]
+compile_error!(
+    "the mock-controllers, mock-rand and mock-time features are for tests and cannot be enabled for wasm32 builds"
+);
+
 #[must_use]
 pub fn now() -> u64 {
     inner_now()
@@ -408,3 +1054,66 @@ thread_local! {
 pub fn set_mock_now(t: u64) {
     MOCK_NOW.with(|v| *v.borrow_mut() = t);
 }
+
+/// Returns true if `principal` is a controller of the canister.
+#[must_use]
+pub fn is_controller(principal: &candid::Principal) -> bool {
+    inner_is_controller(principal)
+}
+
+#[cfg(not(any(test, feature = "mock-controllers")))]
+fn inner_is_controller(principal: &candid::Principal) -> bool {
+    ic_cdk::api::is_controller(principal)
+}
+
+#[cfg(any(test, feature = "mock-controllers"))]
+fn inner_is_controller(principal: &candid::Principal) -> bool {
+    MOCK_CONTROLLERS.with(|controllers| controllers.borrow().contains(principal))
+}
+
+#[cfg(any(test, feature = "mock-controllers"))]
+thread_local! {
+    static MOCK_CONTROLLERS: std::cell::RefCell<Vec<candid::Principal>> =
+        const { std::cell::RefCell::new(Vec::new()) };
+}
+
+#[cfg(any(test, feature = "mock-controllers"))]
+pub fn set_mock_controllers(controllers: Vec<candid::Principal>) {
+    MOCK_CONTROLLERS.with(|v| *v.borrow_mut() = controllers);
+}
+
+/// Returns 32 random bytes from the management canister's `raw_rand`.
+///
+/// # Errors
+///
+/// Returns an error if the call to `raw_rand` fails.
+pub async fn raw_rand() -> Result<[u8; 32], String> {
+    inner_raw_rand().await
+}
+
+#[cfg(not(any(test, feature = "mock-rand")))]
+async fn inner_raw_rand() -> Result<[u8; 32], String> {
+    let (bytes,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(code, message)| format!("call to raw_rand failed: {code:?} {message}"))?;
+    bytes
+        .try_into()
+        .map_err(|_| "raw_rand returned an unexpected number of bytes".to_string())
+}
+
+/// Returns distinct, predictable bytes on each call.
+#[cfg(any(test, feature = "mock-rand"))]
+async fn inner_raw_rand() -> Result<[u8; 32], String> {
+    let counter = MOCK_RAND.with(|counter| {
+        *counter.borrow_mut() += 1;
+        *counter.borrow()
+    });
+    let mut bytes = [0; 32];
+    bytes[..8].copy_from_slice(&counter.to_be_bytes());
+    Ok(bytes)
+}
+
+#[cfg(any(test, feature = "mock-rand"))]
+thread_local! {
+    static MOCK_RAND: std::cell::RefCell<u64> = const { std::cell::RefCell::new(0) };
+}
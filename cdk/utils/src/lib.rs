@@ -1 +1,3 @@
 pub use ic_vetkd_utils::*;
+
+pub mod verification;
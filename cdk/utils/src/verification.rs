@@ -0,0 +1,120 @@
+//! Checks of transport keys and encrypted vetkeys that only need public inputs.
+//!
+//! `ic_vetkd_utils` verifies an encrypted key while decrypting it with
+//! `TransportSecretKey::decrypt`, which requires the transport secret key that a
+//! canister never sees, and exposes no check that only needs public inputs.
+//! The checks here are the public counterpart used by canisters: an encrypted
+//! key `(c1, c2, c3)` is valid for a transport public key `tpk`, a derived
+//! public key `dpk` and a derivation id `did` iff
+//!
+//! - `e(c1, g2) == e(g1, c2)`, and
+//! - `e(c3, g2) == e(tpk, c2) * e(H(dpk || did), dpk)`.
+//!
+//! `H` is the same augmented hash to G1 that `ic_vetkd_utils` uses to verify
+//! the decrypted key. The tests of the `KeyManager` check that these checks
+//! accept exactly the encrypted keys that `TransportSecretKey::decrypt`
+//! accepts, except for keys with a `c2` that does not match `c1`, which
+//! `decrypt` does not read and which are rejected here.
+
+use ic_bls12_381::hash_to_curve::{ExpandMsgXmd, HashToCurve};
+use ic_bls12_381::{multi_miller_loop, G1Affine, G1Projective, G2Affine, G2Prepared, Gt};
+
+/// Size of a compressed BLS12-381 G1 point, i.e., of a transport public key.
+pub const TRANSPORT_KEY_BYTES: usize = 48;
+/// Size of a compressed BLS12-381 G2 point, i.e., of a derived public key.
+pub const DERIVED_PUBLIC_KEY_BYTES: usize = 96;
+/// Size of an encrypted vetkey `(c1, c2, c3)` in G1 x G2 x G1.
+pub const ENCRYPTED_KEY_BYTES: usize = 2 * TRANSPORT_KEY_BYTES + DERIVED_PUBLIC_KEY_BYTES;
+
+/// Domain separator used by vetKD to hash a derivation id to G1.
+const G1_HASH_DOMAIN_SEPARATOR: &[u8] = b"BLS_SIG_BLS12381G1_XMD:SHA-256_SSWU_RO_AUG_";
+
+/// Checks that the given bytes are a well-formed transport public key, i.e., a
+/// compressed, non-identity BLS12-381 G1 point.
+///
+/// # Errors
+///
+/// Returns an error if the key has the wrong length or is not a valid point.
+pub fn validate_transport_key(transport_key: &[u8]) -> Result<(), String> {
+    let point = deserialize_g1(transport_key).ok_or_else(|| "invalid transport key".to_string())?;
+    if bool::from(point.is_identity()) {
+        return Err("invalid transport key".to_string());
+    }
+    Ok(())
+}
+
+/// Verifies that `encrypted_key` is a valid encryption of the vetkey for
+/// `derivation_id` under `derived_public_key`, encrypted to `transport_key`.
+///
+/// # Errors
+///
+/// Returns an error if any of the inputs cannot be deserialized or if the
+/// encrypted key is inconsistent with the other inputs.
+pub fn verify_encrypted_vetkey(
+    encrypted_key: &[u8],
+    transport_key: &[u8],
+    derived_public_key: &[u8],
+    derivation_id: &[u8],
+) -> Result<(), String> {
+    if encrypted_key.len() != ENCRYPTED_KEY_BYTES {
+        return Err("invalid encrypted key length".to_string());
+    }
+    let (c1, rest) = encrypted_key.split_at(TRANSPORT_KEY_BYTES);
+    let (c2, c3) = rest.split_at(DERIVED_PUBLIC_KEY_BYTES);
+
+    let c1 = deserialize_g1(c1).ok_or_else(|| "invalid encrypted key".to_string())?;
+    let c2 = deserialize_g2(c2).ok_or_else(|| "invalid encrypted key".to_string())?;
+    let c3 = deserialize_g1(c3).ok_or_else(|| "invalid encrypted key".to_string())?;
+    let tpk = deserialize_g1(transport_key).ok_or_else(|| "invalid transport key".to_string())?;
+    let dpk = deserialize_g2(derived_public_key)
+        .ok_or_else(|| "invalid derived public key".to_string())?;
+
+    let g2 = G2Prepared::from(G2Affine::generator());
+    let c2_prepared = G2Prepared::from(c2);
+
+    let c1_c2_consistent =
+        multi_miller_loop(&[(&c1, &g2), (&-G1Affine::generator(), &c2_prepared)])
+            .final_exponentiation()
+            == Gt::identity();
+    if !c1_c2_consistent {
+        return Err("invalid encrypted key".to_string());
+    }
+
+    let msg = augmented_hash_to_g1(&dpk, derivation_id);
+    let c3_consistent = multi_miller_loop(&[
+        (&c3, &g2),
+        (&-tpk, &c2_prepared),
+        (&-msg, &G2Prepared::from(dpk)),
+    ])
+    .final_exponentiation()
+        == Gt::identity();
+    if !c3_consistent {
+        return Err("invalid encrypted key".to_string());
+    }
+
+    Ok(())
+}
+
+/// Hashes `data` to G1, augmented with the derived public key, as vetKD does
+/// to derive the key for a derivation id.
+#[must_use]
+pub fn augmented_hash_to_g1(public_key: &G2Affine, data: &[u8]) -> G1Affine {
+    let mut input = Vec::with_capacity(DERIVED_PUBLIC_KEY_BYTES + data.len());
+    input.extend_from_slice(&public_key.to_compressed());
+    input.extend_from_slice(data);
+    let point = <G1Projective as HashToCurve<ExpandMsgXmd<sha2::Sha256>>>::hash_to_curve(
+        input,
+        G1_HASH_DOMAIN_SEPARATOR,
+    );
+    G1Affine::from(point)
+}
+
+fn deserialize_g1(bytes: &[u8]) -> Option<G1Affine> {
+    let bytes: &[u8; TRANSPORT_KEY_BYTES] = bytes.try_into().ok()?;
+    Option::from(G1Affine::from_compressed(bytes))
+}
+
+fn deserialize_g2(bytes: &[u8]) -> Option<G2Affine> {
+    let bytes: &[u8; DERIVED_PUBLIC_KEY_BYTES] = bytes.try_into().ok()?;
+    Option::from(G2Affine::from_compressed(bytes))
+}
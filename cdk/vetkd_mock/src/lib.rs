@@ -0,0 +1,183 @@
+//! A mock of the vetKD system API for tests and local development.
+//!
+//! Implements `vetkd_public_key` and `vetkd_encrypted_key` with the Candid
+//! interface of the management canister, so that canisters using a
+//! `KeyManager` or `EncryptedMaps` can be pointed at it with
+//! `set_vetkd_testing_canister_id` on replicas and PocketIC instances without
+//! vetKD support.
+//!
+//! The master secret key of a key ID is derived deterministically from its
+//! name, so the keys are the same across canisters, installations and
+//! machines, and are public. The derived keys and encrypted keys have the
+//! format expected by `ic_vetkd_utils::TransportSecretKey::decrypt`: a derived
+//! public key is a G2 point `g2 * (msk + t)`, where the offset `t` is hashed
+//! from the canister ID and the derivation path, and an encrypted key is
+//! `(g1 * r, g2 * r, tpk * r + H(dpk || did) * (msk + t))` with `r` hashed
+//! from the inputs. `H` is the augmented hash to G1 of
+//! `ic_vetkd_cdk_utils::verification`, which the `KeyManager` also uses to
+//! verify encrypted keys. The mock must never be used with real secrets.
+
+use candid::{CandidType, Principal};
+use ic_bls12_381::{G1Affine, G2Affine, Scalar};
+use ic_cdk::update;
+use ic_vetkd_cdk_utils::verification::augmented_hash_to_g1;
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha512};
+
+/// Domain separator from which master secret keys are hashed.
+const MASTER_KEY_DOMAIN_SEPARATOR: &[u8] = b"ic-vetkd-cdk-vetkd-mock-master-key";
+/// Domain separator from which the offsets of derived keys are hashed.
+const DERIVATION_DOMAIN_SEPARATOR: &[u8] = b"ic-vetkd-cdk-vetkd-mock-derivation";
+/// Domain separator from which the randomness of encrypted keys is hashed.
+const ENCRYPTION_DOMAIN_SEPARATOR: &[u8] = b"ic-vetkd-cdk-vetkd-mock-encryption";
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum VetKDCurve {
+    #[serde(rename = "bls12_381")]
+    Bls12_381,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct VetKDKeyId {
+    pub curve: VetKDCurve,
+    pub name: String,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct VetKDPublicKeyRequest {
+    pub canister_id: Option<Principal>,
+    pub derivation_path: Vec<ByteBuf>,
+    pub key_id: VetKDKeyId,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct VetKDPublicKeyReply {
+    pub public_key: ByteBuf,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct VetKDEncryptedKeyRequest {
+    pub public_key_derivation_path: Vec<ByteBuf>,
+    pub derivation_id: ByteBuf,
+    pub key_id: VetKDKeyId,
+    pub encryption_public_key: ByteBuf,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct VetKDEncryptedKeyReply {
+    pub encrypted_key: ByteBuf,
+}
+
+/// Returns the public key derived for `canister_id`, or the caller if not
+/// given, and the derivation path.
+#[update]
+fn vetkd_public_key(request: VetKDPublicKeyRequest) -> VetKDPublicKeyReply {
+    let canister_id = request.canister_id.unwrap_or_else(ic_cdk::caller);
+    let public_key = derived_public_key(&request.key_id, canister_id, &request.derivation_path);
+    VetKDPublicKeyReply {
+        public_key: ByteBuf::from(public_key),
+    }
+}
+
+/// Returns the key derived for the caller, the derivation path and the
+/// derivation id, encrypted to the transport key. Traps if the transport key
+/// is not a valid G1 point.
+#[update]
+fn vetkd_encrypted_key(request: VetKDEncryptedKeyRequest) -> VetKDEncryptedKeyReply {
+    let encrypted_key = encrypted_key(
+        &request.key_id,
+        ic_cdk::caller(),
+        &request.public_key_derivation_path,
+        &request.derivation_id,
+        &request.encryption_public_key,
+    )
+    .unwrap_or_else(|error| ic_cdk::trap(&error));
+    VetKDEncryptedKeyReply {
+        encrypted_key: ByteBuf::from(encrypted_key),
+    }
+}
+
+/// Returns the compressed G2 public key derived from the master key of
+/// `key_id` for `canister_id` and `derivation_path`.
+#[must_use]
+pub fn derived_public_key<P: AsRef<[u8]>>(
+    key_id: &VetKDKeyId,
+    canister_id: Principal,
+    derivation_path: &[P],
+) -> Vec<u8> {
+    let secret_key = derived_secret_key(key_id, canister_id, derivation_path);
+    G2Affine::from(G2Affine::generator() * secret_key)
+        .to_compressed()
+        .to_vec()
+}
+
+/// Returns the key derived from the master key of `key_id` for
+/// `canister_id`, `derivation_path` and `derivation_id`, encrypted to
+/// `transport_public_key`.
+///
+/// # Errors
+///
+/// Returns an error if the transport public key is not a compressed,
+/// non-identity G1 point.
+pub fn encrypted_key<P: AsRef<[u8]>>(
+    key_id: &VetKDKeyId,
+    canister_id: Principal,
+    derivation_path: &[P],
+    derivation_id: &[u8],
+    transport_public_key: &[u8],
+) -> Result<Vec<u8>, String> {
+    let transport_public_key = <&[u8; 48]>::try_from(transport_public_key)
+        .ok()
+        .and_then(|bytes| Option::<G1Affine>::from(G1Affine::from_compressed(bytes)))
+        .filter(|point| !bool::from(point.is_identity()))
+        .ok_or_else(|| "invalid transport key".to_string())?;
+
+    let secret_key = derived_secret_key(key_id, canister_id, derivation_path);
+    let public_key = G2Affine::from(G2Affine::generator() * secret_key);
+    let key = augmented_hash_to_g1(&public_key, derivation_id) * secret_key;
+
+    let r = hash_to_scalar(
+        ENCRYPTION_DOMAIN_SEPARATOR,
+        &[
+            &secret_key.to_bytes(),
+            derivation_id,
+            &transport_public_key.to_compressed(),
+        ],
+    );
+    let c1 = G1Affine::from(G1Affine::generator() * r);
+    let c2 = G2Affine::from(G2Affine::generator() * r);
+    let c3 = G1Affine::from(transport_public_key * r + key);
+
+    Ok([
+        c1.to_compressed().as_slice(),
+        c2.to_compressed().as_slice(),
+        c3.to_compressed().as_slice(),
+    ]
+    .concat())
+}
+
+fn derived_secret_key<P: AsRef<[u8]>>(
+    key_id: &VetKDKeyId,
+    canister_id: Principal,
+    derivation_path: &[P],
+) -> Scalar {
+    let master_secret_key = hash_to_scalar(MASTER_KEY_DOMAIN_SEPARATOR, &[key_id.name.as_bytes()]);
+    let mut inputs = vec![key_id.name.as_bytes(), canister_id.as_slice()];
+    inputs.extend(derivation_path.iter().map(|element| element.as_ref()));
+    master_secret_key + hash_to_scalar(DERIVATION_DOMAIN_SEPARATOR, &inputs)
+}
+
+/// Hashes the length-prefixed inputs to a scalar.
+fn hash_to_scalar(domain_separator: &[u8], inputs: &[&[u8]]) -> Scalar {
+    let mut hasher = Sha512::new();
+    for input in std::iter::once(&domain_separator).chain(inputs) {
+        hasher.update((input.len() as u64).to_be_bytes());
+        hasher.update(input);
+    }
+    let mut wide = [0u8; 64];
+    wide.copy_from_slice(&hasher.finalize());
+    Scalar::from_bytes_wide(&wide)
+}
+
+ic_cdk::export_candid!();
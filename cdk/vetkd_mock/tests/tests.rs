@@ -0,0 +1,209 @@
+use candid::{decode_one, encode_args, encode_one};
+use ic_vetkd_cdk_key_manager::verification::verify_encrypted_vetkey;
+use ic_vetkd_cdk_test_utils::{random_self_authenticating_principal, reproducible_rng};
+use ic_vetkd_cdk_types::{ByteBuf, TransportKey};
+use ic_vetkd_cdk_vetkd_mock::{derived_public_key, encrypted_key, VetKDCurve, VetKDKeyId};
+use ic_vetkd_utils::TransportSecretKey;
+use pocket_ic::PocketIcBuilder;
+use rand::{CryptoRng, Rng};
+use std::path::Path;
+
+#[test]
+fn encrypted_keys_should_decrypt_and_verify() {
+    let rng = &mut reproducible_rng();
+    let key_id = test_key_id();
+    let canister_id = random_self_authenticating_principal(rng);
+    let derivation_path = [b"domain separator".to_vec()];
+    let derivation_id: [u8; 32] = rng.gen();
+    let transport_key = random_transport_key(rng);
+
+    let public_key = derived_public_key(&key_id, canister_id, &derivation_path);
+    let encrypted_key = encrypted_key(
+        &key_id,
+        canister_id,
+        &derivation_path,
+        &derivation_id,
+        &transport_key.public_key(),
+    )
+    .unwrap();
+
+    assert_eq!(public_key.len(), 96);
+    assert_eq!(encrypted_key.len(), 192);
+    assert_eq!(
+        verify_encrypted_vetkey(
+            &encrypted_key,
+            &transport_key.public_key(),
+            &public_key,
+            &derivation_id
+        ),
+        Ok(())
+    );
+    assert!(transport_key
+        .decrypt(&encrypted_key, &public_key, &derivation_id)
+        .is_ok());
+    assert!(transport_key
+        .decrypt(&encrypted_key, &public_key, b"other derivation id")
+        .is_err());
+}
+
+#[test]
+fn keys_should_be_deterministic_and_depend_on_all_inputs() {
+    let rng = &mut reproducible_rng();
+    let key_id = test_key_id();
+    let other_key_id = VetKDKeyId {
+        curve: VetKDCurve::Bls12_381,
+        name: "test_key_1".to_string(),
+    };
+    let canister_id = random_self_authenticating_principal(rng);
+    let other_canister_id = random_self_authenticating_principal(rng);
+    let path = [b"path".to_vec()];
+
+    let public_key = derived_public_key(&key_id, canister_id, &path);
+    assert_eq!(derived_public_key(&key_id, canister_id, &path), public_key);
+    assert_ne!(
+        derived_public_key(&other_key_id, canister_id, &path),
+        public_key
+    );
+    assert_ne!(
+        derived_public_key(&key_id, other_canister_id, &path),
+        public_key
+    );
+    assert_ne!(
+        derived_public_key(&key_id, canister_id, &[b"other path".to_vec()]),
+        public_key
+    );
+    // The elements of the path are length-prefixed
+    assert_ne!(
+        derived_public_key(&key_id, canister_id, &[b"pa".to_vec(), b"th".to_vec()]),
+        public_key
+    );
+
+    // Different transport keys yield the same vetkey
+    let derivation_id: [u8; 32] = rng.gen();
+    let mut vetkey = || {
+        let transport_key = random_transport_key(rng);
+        let encrypted_key = encrypted_key(
+            &key_id,
+            canister_id,
+            &path,
+            &derivation_id,
+            &transport_key.public_key(),
+        )
+        .unwrap();
+        transport_key
+            .decrypt(&encrypted_key, &public_key, &derivation_id)
+            .unwrap()
+    };
+    assert_eq!(vetkey(), vetkey());
+}
+
+#[test]
+fn invalid_transport_keys_should_be_rejected() {
+    let rng = &mut reproducible_rng();
+    let key_id = test_key_id();
+    let canister_id = random_self_authenticating_principal(rng);
+    let valid = random_transport_key(rng).public_key();
+    let malformed = vec![0xff; 48];
+    let mut identity = vec![0; 48];
+    identity[0] = 0xc0;
+
+    for transport_key in [&valid[..47], &malformed[..], &identity[..]] {
+        assert_eq!(
+            encrypted_key(&key_id, canister_id, &[b"path"], b"id", transport_key),
+            Err("invalid transport key".to_string())
+        );
+    }
+}
+
+#[test]
+fn key_manager_example_should_serve_vetkeys_of_the_mock() {
+    let rng = &mut reproducible_rng();
+    let pic = PocketIcBuilder::new().with_application_subnet().build();
+
+    let vetkd_mock_canister_id = pic.create_canister();
+    pic.add_cycles(vetkd_mock_canister_id, 2_000_000_000_000);
+    pic.install_canister(
+        vetkd_mock_canister_id,
+        load_canister_wasm("ic_vetkd_cdk_vetkd_mock"),
+        vec![],
+        None,
+    );
+
+    let key_manager_canister_id = pic.create_canister();
+    pic.add_cycles(key_manager_canister_id, 2_000_000_000_000);
+    pic.install_canister(
+        key_manager_canister_id,
+        load_canister_wasm("ic_vetkd_cdk_key_manager_example"),
+        vec![],
+        None,
+    );
+    pic.update_call(
+        key_manager_canister_id,
+        vetkd_mock_canister_id,
+        "set_vetkd_testing_canister_id",
+        encode_one(vetkd_mock_canister_id).unwrap(),
+    )
+    .expect("failed to set the vetkd testing canister");
+
+    let key_owner = random_self_authenticating_principal(rng);
+    let update = |method: &str, args: Vec<u8>| {
+        pic.update_call(key_manager_canister_id, key_owner, method, args)
+            .expect("update call failed")
+    };
+
+    // The key manager example uses the domain separator as derivation path
+    let verification_key: ByteBuf = decode_one(&update(
+        "get_vetkey_verification_key",
+        encode_one(()).unwrap(),
+    ))
+    .unwrap();
+    assert_eq!(
+        verification_key.as_ref(),
+        derived_public_key(
+            &test_key_id(),
+            key_manager_canister_id,
+            &[b"key_manager".to_vec()]
+        )
+    );
+
+    // The key manager example verifies the encrypted key before returning it
+    let key_name = ByteBuf::from(b"key".to_vec());
+    let transport_key = random_transport_key(rng);
+    let encrypted_vetkey: Result<ByteBuf, String> = decode_one(&update(
+        "get_encrypted_vetkey",
+        encode_args((
+            key_owner,
+            key_name.clone(),
+            TransportKey::from(transport_key.public_key()),
+        ))
+        .unwrap(),
+    ))
+    .unwrap();
+    let derivation_id = [key_owner.as_slice(), key_name.as_ref()].concat();
+    assert!(transport_key
+        .decrypt(
+            encrypted_vetkey.unwrap().as_ref(),
+            verification_key.as_ref(),
+            &derivation_id
+        )
+        .is_ok());
+}
+
+fn test_key_id() -> VetKDKeyId {
+    VetKDKeyId {
+        curve: VetKDCurve::Bls12_381,
+        name: "insecure_test_key_1".to_string(),
+    }
+}
+
+fn random_transport_key<R: Rng + CryptoRng>(rng: &mut R) -> TransportSecretKey {
+    let seed: [u8; 32] = rng.gen();
+    TransportSecretKey::from_seed(seed.to_vec()).unwrap()
+}
+
+fn load_canister_wasm(wasm_name: &str) -> Vec<u8> {
+    let wasm_path = format!("../../target/wasm32-unknown-unknown/release/{wasm_name}.wasm");
+    std::fs::read(Path::new(&wasm_path)).expect(
+        "wasm does not exist - run `cargo build --release --target wasm32-unknown-unknown --features expose-testing-api`",
+    )
+}
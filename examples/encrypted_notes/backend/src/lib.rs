@@ -1,14 +1,20 @@
 use candid::{CandidType, Principal};
-use ic_cdk::{query, update};
+use ic_cdk::{post_upgrade, query, update};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::storable::Blob;
 use ic_stable_structures::{storable::Bound, Storable};
 use ic_stable_structures::{BTreeMap as StableBTreeMap, DefaultMemoryImpl};
-use ic_vetkd_cdk_encrypted_maps::{EncryptedMaps, VetKey, VetKeyVerificationKey};
-use ic_vetkd_cdk_types::{AccessRights, AuditLog, ByteBuf, EncryptedMapValue, TransportKey};
+use ic_vetkd_cdk_encrypted_maps::{EncryptedMaps, TombstoneEntry};
+use ic_vetkd_cdk_key_manager::layout::{MemoryLayout, StableStructure};
+use ic_vetkd_cdk_key_manager::migration::{
+    convert_legacy_key_id, convert_legacy_name, LegacyKeyId, LegacyName, MigratedStructure,
+    MIGRATION_BATCH_SIZE,
+};
+use ic_vetkd_cdk_types::{AuditLog, ByteBuf, EncryptedMapValue, MapKey, MapName, MAX_NAME_BYTES};
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::cell::RefCell;
+use std::time::Duration;
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct MetadataWrapper {
@@ -60,8 +66,6 @@ impl Storable for MetadataWrapper {
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type MapOwner = Principal;
-type MapName = Blob<32>;
-type MapKey = Blob<32>;
 // To understand the intuition how a stable map over a tuple type works, see
 // https://mmapped.blog/posts/14-stable-structures#stable-btree.
 type StableMetadataMap = StableBTreeMap<(MapOwner, MapName, MapKey), MetadataWrapper, Memory>;
@@ -75,45 +79,126 @@ type MapValueWithMetadata = (
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
         RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
-    static ENCRYPTED_MAPS: RefCell<EncryptedMaps> = RefCell::new(EncryptedMaps::init(
-        "note_manager",
-        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
-        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
-        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
-        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
-        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
-        Some(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))),
-    ));
+    static ENCRYPTED_MAPS: RefCell<EncryptedMaps> = RefCell::new(
+        MEMORY_MANAGER.with_borrow(|memory_manager| {
+            let legacy_memory = |memory_id| memory_manager.get(MemoryId::new(memory_id));
+            EncryptedMaps::init_with_layout("note_manager", memory_manager, &encrypted_maps_layout())
+                .with_legacy_memory(MigratedStructure::AccessControl, legacy_memory(1))
+                .with_legacy_memory(MigratedStructure::SharedKeys, legacy_memory(2))
+                .with_legacy_memory(MigratedStructure::EncryptedMapValues, legacy_memory(3))
+        }),
+    );
     static METADATA: RefCell<StableMetadataMap> = RefCell::new(StableBTreeMap::new(
-        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))),
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(METADATA_MEMORY_ID))),
     ));
+    static SHARED_LEGACY_MAP: RefCell<StableBTreeMap<Vec<u8>, Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(SHARED_LEGACY_MEMORY_ID))),
+        ),
+    );
 }
 
-#[query]
-fn get_accessible_shared_map_names() -> Vec<(Principal, ByteBuf)> {
-    ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
-        encrypted_maps
-            .get_accessible_shared_map_names(ic_cdk::caller())
-            .into_iter()
-            .map(|map_id| (map_id.0, ByteBuf::from(map_id.1.as_ref().to_vec())))
-            .collect()
-    })
+const METADATA_MEMORY_ID: u8 = 5;
+
+/// The memory that the audit logs and the tombstones shared before schema
+/// version 2. Since both maps were opened on it, it holds a single B-tree with
+/// the entries of both, which are told apart by the length of their keys and
+/// moved into the maps of `ENCRYPTED_MAPS` after an upgrade.
+const SHARED_LEGACY_MEMORY_ID: u8 = 4;
+
+/// The memory ids of `ENCRYPTED_MAPS`. Ids 1 to 3 hold the access control,
+/// shared keys and value maps written before schema version 2, which are
+/// attached as legacy memories and migrated into ids 7 to 9 after an upgrade,
+/// see `post_upgrade`. Id 4 is split by `split_shared_legacy_entries` and id 5
+/// is used by `METADATA`.
+fn encrypted_maps_layout() -> MemoryLayout {
+    MemoryLayout::new(0..11)
+        .with_memory_id(StableStructure::Metadata, 0)
+        .with_memory_id(StableStructure::AuditLogs, 6)
+        .with_memory_id(StableStructure::AccessControl, 7)
+        .with_memory_id(StableStructure::SharedKeys, 8)
+        .with_memory_id(StableStructure::EncryptedMapValues, 9)
+        .with_memory_id(StableStructure::Tombstones, 10)
 }
 
-#[query]
-fn get_shared_user_access_for_map(
-    map_owner: Principal,
-    map_name: ByteBuf,
-) -> Result<Vec<(Principal, AccessRights)>, String> {
-    let caller = ic_cdk::caller();
-    let key_id = (
-        map_owner,
-        Blob::try_from(map_name.as_ref()).map_err(|_e| "name too long")?,
-    );
-    ENCRYPTED_MAPS
-        .with_borrow(|encrypted_maps| encrypted_maps.get_shared_user_access_for_map(caller, key_id))
+/// Continues the migration of the data written before schema version 2.
+#[post_upgrade]
+fn post_upgrade() {
+    schedule_migration_batch();
 }
 
+/// Migrates the next batch of legacy entries in a timer, so that each batch
+/// runs in its own message, until no entries are left.
+fn schedule_migration_batch() {
+    let complete = ENCRYPTED_MAPS.with_borrow(EncryptedMaps::is_migration_complete)
+        && SHARED_LEGACY_MAP.with_borrow(StableBTreeMap::is_empty);
+    if complete {
+        return;
+    }
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        let migrated = ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+            let split = split_shared_legacy_entries(encrypted_maps, MIGRATION_BATCH_SIZE);
+            split + encrypted_maps.run_migration_batch(MIGRATION_BATCH_SIZE - split)
+        });
+        if migrated > 0 {
+            schedule_migration_batch();
+        }
+    });
+}
+
+/// Moves up to `limit` entries of the memory shared by the audit logs and the
+/// tombstones before schema version 2 into the maps of `encrypted_maps`.
+/// Audit logs are keyed by key IDs and tombstones by key IDs and map keys, so
+/// the encodings of their keys differ in length. Entries written since the
+/// upgrade take precedence: audit entries are appended to the legacy ones and
+/// newer tombstones are kept. Returns the number of moved entries.
+fn split_shared_legacy_entries(encrypted_maps: &mut EncryptedMaps, limit: usize) -> usize {
+    let audit_log_key_bytes = LegacyKeyId::BOUND.max_size() as usize;
+    SHARED_LEGACY_MAP.with_borrow_mut(|legacy| {
+        let entries: Vec<_> = legacy.iter().take(limit).collect();
+        for (key, value) in &entries {
+            legacy.remove(key);
+            if key.len() == audit_log_key_bytes {
+                let key_id = convert_legacy_key_id(LegacyKeyId::from_bytes(Cow::Borrowed(key)));
+                let mut log = AuditLog::from_bytes(Cow::Borrowed(value));
+                let audit_logs = encrypted_maps
+                    .key_manager
+                    .audit_logs
+                    .as_mut()
+                    .expect("the layout assigns a memory to the audit logs");
+                if let Some(newer) = audit_logs.get(&key_id) {
+                    log.0.extend(newer.0);
+                }
+                audit_logs.insert(key_id, log);
+            } else {
+                let (key_id, map_key) = <(LegacyKeyId, LegacyName)>::from_bytes(Cow::Borrowed(key));
+                let key = (convert_legacy_key_id(key_id), convert_legacy_name(&map_key));
+                if !encrypted_maps.tombstones.contains_key(&key) {
+                    let tombstone = TombstoneEntry::from_bytes(Cow::Borrowed(value));
+                    encrypted_maps.tombstones.insert(key, tombstone);
+                }
+            }
+        }
+        entries.len()
+    })
+}
+
+ic_vetkd_cdk_encrypted_maps::export_encrypted_maps_api!(
+    ENCRYPTED_MAPS,
+    only: [
+        get_accessible_shared_map_names,
+        get_owned_non_empty_map_names,
+        get_shared_user_access_for_map,
+        get_vetkey_verification_key,
+        get_encrypted_vetkey,
+        get_user_rights,
+        set_user_rights,
+        remove_user,
+        get_audit_log,
+        get_tombstones,
+    ]
+);
+
 #[query]
 fn get_encrypted_values_for_map_with_metadata(
     map_owner: Principal,
@@ -150,17 +235,6 @@ fn get_encrypted_values_for_map_with_metadata(
     })
 }
 
-#[query]
-fn get_owned_non_empty_map_names() -> Vec<ByteBuf> {
-    ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
-        encrypted_maps
-            .get_owned_non_empty_map_names(ic_cdk::caller())
-            .into_iter()
-            .map(|map_name| ByteBuf::from(map_name.as_slice().to_vec()))
-            .collect()
-    })
-}
-
 #[update]
 fn insert_encrypted_value_with_metadata(
     map_owner: Principal,
@@ -211,75 +285,13 @@ fn remove_encrypted_value_with_metadata(
     })
 }
 
-#[update]
-async fn get_vetkey_verification_key() -> VetKeyVerificationKey {
-    ENCRYPTED_MAPS
-        .with_borrow(|encrypted_maps| encrypted_maps.get_vetkey_verification_key())
-        .await
-}
-
-#[update]
-async fn get_encrypted_vetkey(
-    map_owner: Principal,
-    map_name: ByteBuf,
-    transport_key: TransportKey,
-) -> Result<VetKey, String> {
-    let map_name = bytebuf_to_blob(map_name)?;
-    let map_id = (map_owner, map_name);
-    Ok(ENCRYPTED_MAPS
-        .with_borrow_mut(|encrypted_maps| {
-            encrypted_maps.get_encrypted_vetkey(ic_cdk::caller(), map_id, transport_key)
-        })?
-        .await)
-}
-
-#[query]
-fn get_user_rights(
-    map_owner: Principal,
-    map_name: ByteBuf,
-    user: Principal,
-) -> Result<Option<AccessRights>, String> {
-    let map_name = bytebuf_to_blob(map_name)?;
-    let map_id = (map_owner, map_name);
-    ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
-        encrypted_maps.get_user_rights(ic_cdk::caller(), map_id, user)
-    })
-}
-
-#[update]
-fn set_user_rights(
-    map_owner: Principal,
-    map_name: ByteBuf,
-    user: Principal,
-    access_rights: AccessRights,
-) -> Result<Option<AccessRights>, String> {
-    let map_name = bytebuf_to_blob(map_name)?;
-    let map_id = (map_owner, map_name);
-    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
-        encrypted_maps.set_user_rights(ic_cdk::caller(), map_id, user, access_rights)
-    })
-}
-
-#[update]
-fn remove_user(
-    map_owner: Principal,
-    map_name: ByteBuf,
-    user: Principal,
-) -> Result<Option<AccessRights>, String> {
-    let map_name = bytebuf_to_blob(map_name)?;
-    let map_id = (map_owner, map_name);
-    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
-        encrypted_maps.remove_user(ic_cdk::caller(), map_id, user)
-    })
-}
-
 #[cfg(feature = "expose-testing-api")]
 #[update]
 fn set_vetkd_testing_canister_id(vetkd_testing_canister: Principal) {
     ic_vetkd_cdk_encrypted_maps::set_vetkd_testing_canister_id(vetkd_testing_canister)
 }
 
-fn bytebuf_to_blob(buf: ByteBuf) -> Result<Blob<32>, String> {
+fn bytebuf_to_blob(buf: ByteBuf) -> Result<Blob<MAX_NAME_BYTES>, String> {
     Blob::try_from(buf.as_ref()).map_err(|_| "too large input".to_string())
 }
 
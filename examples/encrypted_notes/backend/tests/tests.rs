@@ -0,0 +1,134 @@
+use candid::{decode_one, encode_args, encode_one, CandidType, Principal, Reserved};
+use ic_vetkd_cdk_encrypted_maps::TombstoneEntry;
+use ic_vetkd_cdk_test_utils::{random_self_authenticating_principal, reproducible_rng};
+use ic_vetkd_cdk_types::{AccessRights, AuditEntry, AuditEntryType, ByteBuf};
+use pocket_ic::PocketIc;
+use rand::{CryptoRng, Rng};
+use std::path::Path;
+
+#[test]
+fn audit_logs_and_tombstones_should_be_split_when_upgrading_from_baseline() {
+    let rng = &mut reproducible_rng();
+    let pic = PocketIc::new();
+    let canister_id = pic.create_canister();
+    pic.add_cycles(canister_id, 2_000_000_000_000);
+    pic.install_canister(canister_id, load_baseline_backend_wasm(), vec![], None);
+
+    let map_owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let map_name = random_name(rng);
+    let map_keys: Vec<ByteBuf> = (0..3).map(|_| random_name(rng)).collect();
+    for map_key in &map_keys {
+        let _: Reserved = update(
+            &pic,
+            canister_id,
+            map_owner,
+            "insert_encrypted_value_with_metadata",
+            encode_args((
+                map_owner,
+                map_name.clone(),
+                map_key.clone(),
+                map_key.clone(),
+                Vec::<String>::new(),
+                ByteBuf::from(vec![]),
+            ))
+            .unwrap(),
+        );
+    }
+    let removed_key = map_keys[0].clone();
+    let _: Reserved = update(
+        &pic,
+        canister_id,
+        map_owner,
+        "remove_encrypted_value_with_metadata",
+        encode_args((map_owner, map_name.clone(), removed_key.clone())).unwrap(),
+    );
+    let _: Reserved = update(
+        &pic,
+        canister_id,
+        map_owner,
+        "set_user_rights",
+        encode_args((map_owner, map_name.clone(), user, AccessRights::read_only())).unwrap(),
+    );
+
+    pic.upgrade_canister(
+        canister_id,
+        load_backend_wasm(),
+        encode_one(()).unwrap(),
+        None,
+    )
+    .expect("failed to upgrade the backend canister");
+    // The migration runs in timers started by `post_upgrade`.
+    for _ in 0..5 {
+        pic.tick();
+    }
+
+    let audit_log: Result<Vec<AuditEntry>, String> = update(
+        &pic,
+        canister_id,
+        map_owner,
+        "get_audit_log",
+        encode_args((map_owner, map_name.clone())).unwrap(),
+    );
+    assert!(audit_log
+        .unwrap()
+        .iter()
+        .any(|entry| { entry.audit_type == AuditEntryType::Share && entry.user == Some(user) }));
+    let tombstones: Result<Vec<(ByteBuf, TombstoneEntry)>, String> = update(
+        &pic,
+        canister_id,
+        map_owner,
+        "get_tombstones",
+        encode_args((map_owner, map_name.clone())).unwrap(),
+    );
+    let tombstones = tombstones.unwrap();
+    assert_eq!(tombstones.len(), 1);
+    assert_eq!(tombstones[0].0, removed_key);
+    assert_eq!(tombstones[0].1.value, removed_key);
+    let rights: Result<Option<AccessRights>, String> = update(
+        &pic,
+        canister_id,
+        user,
+        "get_user_rights",
+        encode_args((map_owner, map_name, user)).unwrap(),
+    );
+    assert_eq!(rights, Ok(Some(AccessRights::read_only())));
+}
+
+fn update<T: CandidType + for<'de> candid::Deserialize<'de>>(
+    pic: &PocketIc,
+    canister_id: Principal,
+    caller: Principal,
+    method_name: &str,
+    args: Vec<u8>,
+) -> T {
+    match pic.update_call(canister_id, caller, method_name, args) {
+        Ok(data) => decode_one(&data).expect("failed to decode reply"),
+        Err(user_error) => panic!("canister returned a user error: {user_error}"),
+    }
+}
+
+fn load_backend_wasm() -> Vec<u8> {
+    let wasm_path = Path::new(
+        "../../../target/wasm32-unknown-unknown/release/ic_vetkd_example_encrypted_notes_backend.wasm",
+    );
+    std::fs::read(wasm_path)
+        .expect("wasm does not exist - run `cargo build --release --target wasm32-unknown-unknown`")
+}
+
+/// Loads the backend built from the baseline revision, in which the audit
+/// logs and the tombstones share a memory.
+fn load_baseline_backend_wasm() -> Vec<u8> {
+    let wasm_path = Path::new(
+        "../../../target/baseline/wasm32-unknown-unknown/release/ic_vetkd_example_encrypted_notes_backend.wasm",
+    );
+    std::fs::read(wasm_path)
+        .expect("baseline wasm does not exist - run `make compile-baseline-wasm`")
+}
+
+fn random_name<R: Rng + CryptoRng>(rng: &mut R) -> ByteBuf {
+    let length = rng.gen_range(1..32);
+    let mut name = vec![0u8; length];
+    rng.fill_bytes(&mut name);
+    ByteBuf::from(name)
+}
@@ -1,14 +1,16 @@
 use candid::{CandidType, Principal};
-use ic_cdk::{query, update};
+use ic_cdk::{post_upgrade, query, update};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::storable::Blob;
 use ic_stable_structures::{storable::Bound, Storable};
 use ic_stable_structures::{BTreeMap as StableBTreeMap, DefaultMemoryImpl};
-use ic_vetkd_cdk_encrypted_maps::{EncryptedMaps, VetKey, VetKeyVerificationKey};
-use ic_vetkd_cdk_types::{now, AccessRights, ByteBuf, EncryptedMapValue, TransportKey};
+use ic_vetkd_cdk_encrypted_maps::EncryptedMaps;
+use ic_vetkd_cdk_key_manager::migration::{MigratedStructure, MIGRATION_BATCH_SIZE};
+use ic_vetkd_cdk_types::{now, ByteBuf, EncryptedMapValue, MapKey, MapName, MAX_NAME_BYTES};
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::cell::RefCell;
+use std::time::Duration;
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct PasswordMetadata {
@@ -62,55 +64,82 @@ impl Storable for PasswordMetadata {
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type MapOwner = Principal;
-type MapName = Blob<32>;
-type MapKey = Blob<32>;
 // To understand the intuition how a stable map over a tuple type works, see
 // https://mmapped.blog/posts/14-stable-structures#stable-btree.
 type StableMetadataMap = StableBTreeMap<(MapOwner, MapName, MapKey), PasswordMetadata, Memory>;
 
+// Memories 1 to 4 hold the maps of `ENCRYPTED_MAPS` written before schema
+// version 2. They are attached as legacy memories and migrated into memories
+// 6 to 9 after an upgrade, see `post_upgrade`.
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
         RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
     static ENCRYPTED_MAPS: RefCell<EncryptedMaps> = RefCell::new(EncryptedMaps::init(
         "password_manager",
         MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))),
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))),
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))),
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))),
+        None
+    )
+    .with_legacy_memory(
+        MigratedStructure::AccessControl,
         MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
+    )
+    .with_legacy_memory(
+        MigratedStructure::SharedKeys,
         MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
+    )
+    .with_legacy_memory(
+        MigratedStructure::EncryptedMapValues,
         MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
+    )
+    .with_legacy_memory(
+        MigratedStructure::Tombstones,
         MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
-        None
     ));
     static METADATA: RefCell<StableMetadataMap> = RefCell::new(StableBTreeMap::new(
         MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))),
     ));
 }
 
-#[query]
-fn get_accessible_shared_map_names() -> Vec<(Principal, ByteBuf)> {
-    ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
-        encrypted_maps
-            .get_accessible_shared_map_names(ic_cdk::caller())
-            .into_iter()
-            .map(|map_id| (map_id.0, ByteBuf::from(map_id.1.as_ref().to_vec())))
-            .collect()
-    })
+/// Continues the migration of the data written before schema version 2.
+#[post_upgrade]
+fn post_upgrade() {
+    schedule_migration_batch();
 }
 
-#[query]
-#[allow(clippy::needless_pass_by_value)]
-fn get_shared_user_access_for_map(
-    map_owner: Principal,
-    map_name: ByteBuf,
-) -> Result<Vec<(Principal, AccessRights)>, String> {
-    let caller = ic_cdk::caller();
-    let key_id = (
-        map_owner,
-        Blob::try_from(map_name.as_ref()).map_err(|_e| "name too long")?,
-    );
-    ENCRYPTED_MAPS
-        .with_borrow(|encrypted_maps| encrypted_maps.get_shared_user_access_for_map(caller, key_id))
+/// Migrates the next batch of legacy entries in a timer, so that each batch
+/// runs in its own message, until no entries are left.
+fn schedule_migration_batch() {
+    if ENCRYPTED_MAPS.with_borrow(EncryptedMaps::is_migration_complete) {
+        return;
+    }
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        let migrated = ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+            encrypted_maps.run_migration_batch(MIGRATION_BATCH_SIZE)
+        });
+        if migrated > 0 {
+            schedule_migration_batch();
+        }
+    });
 }
 
+ic_vetkd_cdk_encrypted_maps::export_encrypted_maps_api!(
+    ENCRYPTED_MAPS,
+    only: [
+        get_accessible_shared_map_names,
+        get_owned_non_empty_map_names,
+        get_shared_user_access_for_map,
+        get_vetkey_verification_key,
+        get_encrypted_vetkey,
+        get_user_rights,
+        set_user_rights,
+        remove_user,
+    ]
+);
+
 #[query]
 #[allow(clippy::needless_pass_by_value)]
 fn get_encrypted_values_for_map_with_metadata(
@@ -144,17 +173,6 @@ fn get_encrypted_values_for_map_with_metadata(
     })
 }
 
-#[query]
-fn get_owned_non_empty_map_names() -> Vec<ByteBuf> {
-    ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
-        encrypted_maps
-            .get_owned_non_empty_map_names(ic_cdk::caller())
-            .into_iter()
-            .map(|map_name| ByteBuf::from(map_name.as_slice().to_vec()))
-            .collect()
-    })
-}
-
 #[update]
 #[allow(clippy::needless_pass_by_value)]
 fn insert_encrypted_value_with_metadata(
@@ -211,78 +229,13 @@ fn remove_encrypted_value_with_metadata(
     })
 }
 
-#[update]
-async fn get_vetkey_verification_key() -> VetKeyVerificationKey {
-    ENCRYPTED_MAPS
-        .with_borrow(EncryptedMaps::get_vetkey_verification_key)
-        .await
-}
-
-#[update]
-async fn get_encrypted_vetkey(
-    map_owner: Principal,
-    map_name: ByteBuf,
-    transport_key: TransportKey,
-) -> Result<VetKey, String> {
-    let map_name = bytebuf_to_blob(&map_name)?;
-    let map_id = (map_owner, map_name);
-    Ok(ENCRYPTED_MAPS
-        .with_borrow_mut(|encrypted_maps| {
-            encrypted_maps.get_encrypted_vetkey(ic_cdk::caller(), map_id, transport_key)
-        })?
-        .await)
-}
-
-#[query]
-#[allow(clippy::needless_pass_by_value)]
-fn get_user_rights(
-    map_owner: Principal,
-    map_name: ByteBuf,
-    user: Principal,
-) -> Result<Option<AccessRights>, String> {
-    let map_name = bytebuf_to_blob(&map_name)?;
-    let map_id = (map_owner, map_name);
-    ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
-        encrypted_maps.get_user_rights(ic_cdk::caller(), map_id, user)
-    })
-}
-
-#[update]
-#[allow(clippy::needless_pass_by_value)]
-fn set_user_rights(
-    map_owner: Principal,
-    map_name: ByteBuf,
-    user: Principal,
-    access_rights: AccessRights,
-) -> Result<Option<AccessRights>, String> {
-    let map_name = bytebuf_to_blob(&map_name)?;
-    let map_id = (map_owner, map_name);
-    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
-        encrypted_maps.set_user_rights(ic_cdk::caller(), map_id, user, access_rights)
-    })
-}
-
-#[update]
-#[allow(clippy::needless_pass_by_value)]
-fn remove_user(
-    map_owner: Principal,
-    map_name: ByteBuf,
-    user: Principal,
-) -> Result<Option<AccessRights>, String> {
-    let map_name = bytebuf_to_blob(&map_name)?;
-    let map_id = (map_owner, map_name);
-    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
-        encrypted_maps.remove_user(ic_cdk::caller(), map_id, user)
-    })
-}
-
 #[cfg(feature = "expose-testing-api")]
 #[update]
 fn set_vetkd_testing_canister_id(vetkd_testing_canister: Principal) {
     ic_vetkd_cdk_encrypted_maps::set_vetkd_testing_canister_id(vetkd_testing_canister)
 }
 
-fn bytebuf_to_blob(buf: &ByteBuf) -> Result<Blob<32>, String> {
+fn bytebuf_to_blob(buf: &ByteBuf) -> Result<Blob<MAX_NAME_BYTES>, String> {
     Blob::try_from(buf.as_ref()).map_err(|_| "too large input".to_string())
 }
 
@@ -53,4 +53,4 @@ export const idlFactory = ({ IDL }) => {
       ),
   });
 };
-export const init = ({ IDL }) => { return []; };
+export const init = ({ IDL }) => { return [IDL.Opt(IDL.Vec(IDL.Principal))]; };